@@ -1,5 +1,6 @@
-use crate::storage::{Key, Storage};
+use crate::storage::{AsyncStorage, Key};
 use anyhow::{Error, Result};
+use futures::stream::{BoxStream, StreamExt};
 use serde::{Deserialize, Serialize};
 
 const READ: u32 = 0x4;
@@ -133,7 +134,7 @@ impl From<Permissions> for ACL {
 #[derive(Clone)]
 pub struct ACLStorage<S>
 where
-    S: Storage,
+    S: AsyncStorage,
 {
     inner: S,
 }
@@ -144,22 +145,22 @@ where
  */
 impl<S> ACLStorage<S>
 where
-    S: Storage,
+    S: AsyncStorage,
 {
     pub fn new(storage: S) -> ACLStorage<S> {
         ACLStorage { inner: storage }
     }
 
     /// Creates a new ACL and return the key
-    pub fn create(&mut self, acl: &ACL) -> Result<Key> {
+    pub async fn create(&mut self, acl: &ACL) -> Result<Key> {
         let bytes = serde_json::to_vec(acl)?;
-        let key = self.inner.set(None, &bytes)?;
+        let key = self.inner.set(None, &bytes).await?;
         Ok(key)
     }
 
     /// Get an ACL with key
-    pub fn get(&mut self, key: Key) -> Result<Option<ACL>> {
-        let bytes = self.inner.get(key)?;
+    pub async fn get(&mut self, key: Key) -> Result<Option<ACL>> {
+        let bytes = self.inner.get(key).await?;
         match bytes {
             None => Ok(None),
             Some(bytes) => {
@@ -170,24 +171,38 @@ where
     }
 
     /// Overrides a value of an ACL
-    pub fn update(&mut self, key: Key, acl: &ACL) -> Result<()> {
+    pub async fn update(&mut self, key: Key, acl: &ACL) -> Result<()> {
         let bytes = serde_json::to_vec(acl)?;
-        self.inner.set(Some(key), &bytes)?;
+        self.inner.set(Some(key), &bytes).await?;
         Ok(())
     }
 
     /// iterates over all configured ACLs
-    pub fn list<'a>(&'a mut self) -> Result<impl Iterator<Item = Result<(Key, ACL)>> + 'a> {
+    pub async fn list(&mut self) -> Result<BoxStream<'static, Result<(Key, ACL)>>> {
+        let storage = self.clone();
         Ok(self
             .inner
-            .keys()?
-            .filter_map(move |r| match self.get(r.key) {
-                Ok(acl) => match acl {
-                    Some(acl) => Some(Ok((r.key, acl))),
-                    None => None,
-                },
-                Err(err) => Some(Err(err)),
-            }))
+            .keys()
+            .await?
+            .filter_map(move |r| {
+                let mut storage = storage.clone();
+                async move {
+                    // a failed scan is reported, instead of ending the list early
+                    let r = match r {
+                        Ok(r) => r,
+                        Err(err) => return Some(Err(err.into())),
+                    };
+
+                    match storage.get(r.key).await {
+                        Ok(acl) => match acl {
+                            Some(acl) => Some(Ok((r.key, acl))),
+                            None => None,
+                        },
+                        Err(err) => Some(Err(err)),
+                    }
+                }
+            })
+            .boxed())
     }
 }
 
@@ -251,17 +266,19 @@ mod tests {
         assert_eq!(p.is_delete(), false);
     }
 
-    #[test]
-    fn storage_default() {
+    #[tokio::test]
+    async fn storage_default() {
         use crate::storage::memory::MemoryStorage;
         let db = MemoryStorage::new();
         let mut storage = ACLStorage::new(db);
 
         let key = storage
             .create(&ACL::default())
+            .await
             .expect("failed to create acl object");
         let acl = storage
             .get(key)
+            .await
             .expect("failed to get acl")
             .expect("got nil value");
 
@@ -269,17 +286,21 @@ mod tests {
         assert_eq!(acl.users.len(), 0);
     }
 
-    #[test]
-    fn storage_custom() {
+    #[tokio::test]
+    async fn storage_custom() {
         use crate::storage::memory::MemoryStorage;
         let db = MemoryStorage::new();
         let mut storage = ACLStorage::new(db);
         let mut acl = ACL::from(Permissions::default().set_read(true));
         acl.users.push(100);
 
-        let key = storage.create(&acl).expect("failed to create acl object");
+        let key = storage
+            .create(&acl)
+            .await
+            .expect("failed to create acl object");
         let acl = storage
             .get(key)
+            .await
             .expect("failed to get acl")
             .expect("got nil value");
 
@@ -288,8 +309,8 @@ mod tests {
         assert_eq!(acl.users[0], 100);
     }
 
-    #[test]
-    fn storage_list() {
+    #[tokio::test]
+    async fn storage_list() {
         use crate::storage::memory::MemoryStorage;
         let db = MemoryStorage::new();
         let mut storage = ACLStorage::new(db);
@@ -297,11 +318,16 @@ mod tests {
         let mut acl = ACL::from(Permissions::default().set_read(true));
         acl.users.push(100);
 
-        let key = storage.create(&acl).expect("failed to create acl object");
+        let key = storage
+            .create(&acl)
+            .await
+            .expect("failed to create acl object");
         let (k, v) = storage
             .list()
+            .await
             .expect("failed to list")
             .next()
+            .await
             .expect("failed to get next value")
             .unwrap();
 
@@ -1,6 +1,8 @@
 pub use crate::storage::Key;
+use aggregate::Facets;
 use anyhow::Result;
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::iter::IntoIterator;
@@ -8,11 +10,22 @@ use thiserror::Error;
 use tokio::sync::mpsc;
 use tonic::metadata::MetadataMap;
 
+pub mod aggregate;
 pub mod data;
 pub mod index;
+pub mod page;
+pub mod query;
+pub mod versions;
 
+#[cfg(test)]
+mod resilience;
+
+pub use aggregate::{Aggregate, Facet};
 pub use data::BcdbDatabase;
 pub use index::SqliteIndexBuilder;
+pub use page::{Cursor, Order, Page};
+pub use query::{Condition, Expr, Number, Query};
+pub use versions::{Retention, Version};
 
 const TAG_COLLECTION: &str = ":collection";
 const TAG_ACL: &str = ":acl";
@@ -20,6 +33,7 @@ const TAG_CREATED: &str = ":created";
 const TAG_UPDATED: &str = ":updated";
 const TAG_DELETED: &str = ":deleted";
 const TAG_SIZE: &str = ":size";
+const TAG_VERSION: &str = ":version";
 
 #[derive(Error, Debug, Clone, PartialEq)]
 pub enum Reason {
@@ -35,6 +49,9 @@ pub enum Reason {
     #[error("invalid tag")]
     InvalidTag,
 
+    #[error("invalid query: {0}")]
+    InvalidQuery(String),
+
     #[error("Cannot get peer: {0}")]
     CannotGetPeer(String),
 
@@ -64,22 +81,127 @@ impl From<&anyhow::Error> for Reason {
     }
 }
 
+/// System tags whose values are numbers
+const NUMBER_TAGS: &[&str] = &[
+    TAG_ACL,
+    TAG_CREATED,
+    TAG_UPDATED,
+    TAG_DELETED,
+    TAG_SIZE,
+    TAG_VERSION,
+];
+
 pub fn is_reserved(tag: &str) -> bool {
     tag.starts_with(":")
 }
 
-#[derive(Default, Debug, Clone)]
-pub struct Meta(HashMap<String, String>);
+/// The value of a tag. Values are text unless they are set as numbers, only
+/// numbers are compared by value, sorted as numbers and aggregated. So text
+/// which looks like a number, like "007", stays text.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Value {
+    Text(String),
+    /// a number, kept as the text it was set with
+    Number(String),
+}
+
+impl Value {
+    /// a numeric value, fails if the value is not a number
+    pub fn number<V: Into<String>>(value: V) -> Result<Value> {
+        let value = value.into();
+        if Number::parse(&value).is_none() {
+            bail!(Reason::InvalidTag);
+        }
+
+        Ok(Value::Number(value))
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.as_string()
+    }
+
+    fn as_string(&self) -> &String {
+        match self {
+            Value::Text(value) => value,
+            Value::Number(value) => value,
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        match self {
+            Value::Number(_) => true,
+            Value::Text(_) => false,
+        }
+    }
+
+    /// the numeric value, `None` for text
+    pub fn as_number(&self) -> Option<Number> {
+        match self {
+            Value::Number(value) => Number::parse(value),
+            Value::Text(_) => None,
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.into())
+    }
+}
+
+impl From<Value> for String {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Text(value) => value,
+            Value::Number(value) => value,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Tags", into = "Tags")]
+pub struct Meta(HashMap<String, Value>);
+
+/// The serialized form of metadata, the values of all tags, and the names of
+/// the tags whose values are numbers. Metadata which was serialized before tags
+/// were typed has no numbers.
+#[derive(Serialize, Deserialize)]
+struct Tags {
+    tags: HashMap<String, String>,
+    #[serde(default)]
+    numbers: Vec<String>,
+}
 
 impl Meta {
+    /// creates metadata from tags without types, the system tags which hold
+    /// numbers are numbers, all other values are text
     pub fn new(tags: HashMap<String, String>) -> Self {
+        let tags = tags
+            .into_iter()
+            .map(|(key, value)| {
+                let value = if NUMBER_TAGS.contains(&key.as_str()) {
+                    Value::number(value.clone()).unwrap_or(Value::Text(value))
+                } else {
+                    Value::Text(value)
+                };
+
+                (key, value)
+            })
+            .collect();
+
         Meta(tags)
     }
 
     pub fn insert<K, V>(&mut self, key: K, value: V)
     where
         K: Into<String>,
-        V: Into<String>,
+        V: Into<Value>,
     {
         self.0.insert(key.into(), value.into());
     }
@@ -89,6 +211,11 @@ impl Meta {
     }
 
     pub fn get<K: AsRef<str>>(&self, key: K) -> Option<&String> {
+        self.0.get(key.as_ref()).map(Value::as_string)
+    }
+
+    /// the typed value of a tag
+    pub fn value<K: AsRef<str>>(&self, key: K) -> Option<&Value> {
         self.0.get(key.as_ref())
     }
 
@@ -126,17 +253,22 @@ impl Meta {
         self.get_u64(TAG_UPDATED)
     }
 
+    pub fn version(&self) -> Option<u64> {
+        self.get_u64(TAG_VERSION)
+    }
+
     pub fn deleted(&self) -> bool {
         self.get_u64(TAG_DELETED).map(|v| v >= 1).unwrap_or(false)
     }
 
     pub fn with_collection<V: Into<String>>(mut self, collection: V) -> Self {
-        self.0.insert(TAG_COLLECTION.into(), collection.into());
+        self.0
+            .insert(TAG_COLLECTION.into(), Value::Text(collection.into()));
         self
     }
 
     fn with_u64<K: Into<String>>(mut self, key: K, v: u64) -> Self {
-        self.0.insert(key.into(), format!("{}", v));
+        self.0.insert(key.into(), Value::Number(format!("{}", v)));
         self
     }
 
@@ -156,6 +288,10 @@ impl Meta {
         self.with_u64(TAG_UPDATED, updated)
     }
 
+    pub fn with_version(self, version: u64) -> Self {
+        self.with_u64(TAG_VERSION, version)
+    }
+
     pub fn with_deleted(self, deleted: bool) -> Self {
         self.with_u64(TAG_DELETED, if deleted { 1 } else { 0 })
     }
@@ -164,24 +300,65 @@ impl Meta {
 impl Into<HashMap<String, String>> for Meta {
     fn into(self) -> HashMap<String, String> {
         self.0
+            .into_iter()
+            .map(|(key, value)| (key, value.into()))
+            .collect()
     }
 }
 
 impl IntoIterator for Meta {
-    type Item = (String, String);
-    type IntoIter = std::collections::hash_map::IntoIter<String, String>;
+    type Item = (String, Value);
+    type IntoIter = std::collections::hash_map::IntoIter<String, Value>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.0.into_iter()
     }
 }
 
-impl TryFrom<HashMap<String, String>> for Meta {
+impl std::iter::FromIterator<(String, Value)> for Meta {
+    /// collects typed tags, unlike `try_from` system tags are allowed
+    fn from_iter<T: IntoIterator<Item = (String, Value)>>(iter: T) -> Self {
+        Meta(iter.into_iter().collect())
+    }
+}
+
+impl From<Tags> for Meta {
+    fn from(tags: Tags) -> Self {
+        let mut meta = Meta::new(tags.tags);
+        for key in tags.numbers {
+            if let Some(value) = meta.0.get_mut(&key) {
+                if let Ok(number) = Value::number(value.as_str()) {
+                    *value = number;
+                }
+            }
+        }
+
+        meta
+    }
+}
+
+impl From<Meta> for Tags {
+    fn from(meta: Meta) -> Self {
+        let numbers = meta
+            .0
+            .iter()
+            .filter(|(_, value)| value.is_number())
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        Tags {
+            tags: meta.into(),
+            numbers: numbers,
+        }
+    }
+}
+
+impl TryFrom<HashMap<String, Value>> for Meta {
     type Error = anyhow::Error;
 
     /// try_from should be used when converting user input to meta object
     /// it makes sure that no internal tags are used by the user.
-    fn try_from(m: HashMap<String, String>) -> Result<Self> {
+    fn try_from(m: HashMap<String, Value>) -> Result<Self> {
         for (k, _) in m.iter() {
             if is_reserved(k) {
                 bail!(Reason::InvalidTag);
@@ -199,6 +376,21 @@ pub struct Object {
     pub data: Option<Vec<u8>>,
 }
 
+/// A part of a streamed object upload. The data of the object is sent
+/// in any number of `Data` parts, followed by exactly one `Meta` part.
+#[derive(Debug)]
+pub enum Part {
+    Data(Vec<u8>),
+    Meta {
+        collection: String,
+        tags: HashMap<String, Value>,
+        acl: Option<u64>,
+    },
+}
+
+/// A stream of object data chunks
+pub type Chunks = mpsc::Receiver<Result<Vec<u8>>>;
+
 #[async_trait]
 pub trait Index: Send + Sync + 'static {
     /// set operation is used to associate meta data to key
@@ -211,7 +403,46 @@ pub trait Index: Send + Sync + 'static {
     /// the metadata changes associated with a key.
     async fn set(&self, key: Key, meta: Meta) -> Result<()>;
     async fn get(&self, key: Key) -> Result<Meta>;
-    async fn find(&self, meta: Meta) -> Result<mpsc::Receiver<Result<Key>>>;
+    /// find the keys of the objects which match the query
+    async fn find(&self, query: Query) -> Result<mpsc::Receiver<Result<Key>>>;
+
+    /// count the objects which match the query, the page of the query is ignored
+    async fn count(&self, query: Query) -> Result<u64> {
+        let mut keys = self.find(query.with_page(Page::new())).await?;
+        let mut count = 0;
+        while let Some(key) = keys.recv().await {
+            key?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// aggregate the numeric values of a tag over the objects which match the query
+    async fn aggregate(&self, tag: &str, query: Query) -> Result<Aggregate> {
+        let mut keys = self.find(query.with_page(Page::new())).await?;
+        let mut aggregate = Aggregate::default();
+        while let Some(key) = keys.recv().await {
+            if let Some(value) = self.get(key?).await?.value(tag) {
+                aggregate.add(value);
+            }
+        }
+
+        Ok(aggregate)
+    }
+
+    /// facets of a tag over the objects which match the query, the most common values first
+    async fn facets(&self, tag: &str, query: Query, limit: Option<usize>) -> Result<Vec<Facet>> {
+        let mut keys = self.find(query.with_page(Page::new())).await?;
+        let mut facets = Facets::new();
+        while let Some(key) = keys.recv().await {
+            if let Some(value) = self.get(key?).await?.get(tag) {
+                facets.add(value.as_str());
+            }
+        }
+
+        Ok(facets.into_vec(limit))
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -307,14 +538,58 @@ pub trait Database: Send + Sync + 'static {
         ctx: &Context,
         collection: &str,
         data: Vec<u8>,
-        meta: HashMap<String, String>,
+        meta: HashMap<String, Value>,
         acl: Option<u64>,
     ) -> Result<Key>;
 
+    /// set_stream is similar to set, but the object data is received as a stream
+    /// of parts. The data is written as it is received, so the object is never
+    /// held in memory completely.
+    async fn set_stream(
+        &mut self,
+        ctx: &Context,
+        parts: mpsc::Receiver<Result<Part>>,
+    ) -> Result<Key>;
+
     async fn fetch(&mut self, ctx: &Context, key: Key) -> Result<Object>;
 
     async fn get(&mut self, ctx: &Context, key: Key, collection: &str) -> Result<Object>;
 
+    /// get_stream is similar to get, but the object data is returned as a stream
+    /// of chunks instead of being set on the returned object.
+    async fn get_stream(
+        &mut self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+    ) -> Result<(Object, Chunks)>;
+
+    /// get_many gets multiple objects of a collection at once. Objects that do
+    /// not exist (or are not part of the collection, or can't be read by the
+    /// caller) are left out, the others are returned in the order of the keys.
+    async fn get_many(
+        &mut self,
+        ctx: &Context,
+        keys: Vec<Key>,
+        collection: &str,
+    ) -> Result<Vec<Object>>;
+
+    /// get_version is similar to get, but gets the given version of the object.
+    /// The returned metadata is the metadata the object had while the version was
+    /// current, with the number of the version.
+    async fn get_version(
+        &mut self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+        version: u64,
+    ) -> Result<Object>;
+
+    /// versions lists the versions of an object that are kept, oldest first. The
+    /// last version is the current version of the object.
+    async fn versions(&mut self, ctx: &Context, key: Key, collection: &str)
+        -> Result<Vec<Version>>;
+
     async fn head(&mut self, ctx: &Context, key: Key, collection: &str) -> Result<Object>;
 
     async fn delete(&mut self, ctx: &Context, key: Key, collection: &str) -> Result<()>;
@@ -325,23 +600,63 @@ pub trait Database: Send + Sync + 'static {
         key: Key,
         collection: &str,
         data: Option<Vec<u8>>,
-        tags: HashMap<String, String>,
+        tags: HashMap<String, Value>,
+        acl: Option<u64>,
+    ) -> Result<()>;
+
+    /// update_stream is similar to update, but the new data of the object is
+    /// received as a stream of chunks, which are written as they are received.
+    /// If no data is received, only the metadata of the object is updated.
+    async fn update_stream(
+        &mut self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+        data: Chunks,
+        tags: HashMap<String, Value>,
         acl: Option<u64>,
     ) -> Result<()>;
 
     async fn list(
         &mut self,
         ctx: &Context,
-        tags: HashMap<String, String>,
+        query: Query,
         collection: Option<&str>,
     ) -> Result<mpsc::Receiver<Result<Key>>>;
 
     async fn find(
         &mut self,
         ctx: &Context,
-        tags: HashMap<String, String>,
+        query: Query,
         collection: Option<&str>,
     ) -> Result<mpsc::Receiver<Result<Object>>>;
+
+    /// count counts the objects which match a query
+    async fn count(&mut self, ctx: &Context, query: Query, collection: Option<&str>)
+        -> Result<u64>;
+
+    /// aggregate computes the count, sum, min, max and average of the numeric
+    /// values of a tag, over the objects which match a query. Values which are
+    /// not numbers are left out.
+    async fn aggregate(
+        &mut self,
+        ctx: &Context,
+        tag: &str,
+        query: Query,
+        collection: Option<&str>,
+    ) -> Result<Aggregate>;
+
+    /// facets returns the distinct values of a tag, over the objects which
+    /// match a query, with the number of objects which have them. The most
+    /// common values come first.
+    async fn facets(
+        &mut self,
+        ctx: &Context,
+        tag: &str,
+        query: Query,
+        collection: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Facet>>;
 }
 
 #[cfg(test)]
@@ -351,7 +666,7 @@ mod tests {
 
     #[test]
     fn meta_try_from_ok() {
-        let mut tags: HashMap<String, String> = HashMap::new();
+        let mut tags: HashMap<String, Value> = HashMap::new();
         tags.insert("name".into(), "some name".into());
         tags.insert("parent".into(), "some other value".into());
         let meta = Meta::try_from(tags);
@@ -361,13 +676,45 @@ mod tests {
 
     #[test]
     fn meta_try_from_reserved() {
-        let mut tags: HashMap<String, String> = HashMap::new();
+        let mut tags: HashMap<String, Value> = HashMap::new();
         tags.insert(":reserved".into(), "some name".into());
         let meta = Meta::try_from(tags);
 
         assert_eq!(meta.is_err(), true);
     }
 
+    #[test]
+    fn meta_typed() {
+        let mut tags = HashMap::new();
+        tags.insert(":size".to_string(), "10".to_string());
+        tags.insert("code".to_string(), "007".to_string());
+        let mut meta = Meta::new(tags);
+
+        // values without a type are text, except for numeric system tags
+        assert_eq!(meta.value(":size"), Some(&Value::Number("10".into())));
+        assert_eq!(meta.value("code"), Some(&Value::Text("007".into())));
+        assert_eq!(meta.value("code").unwrap().as_number(), None);
+
+        assert!(Value::number("abc").is_err());
+        meta.insert("count", Value::number("1e3").unwrap());
+        assert_eq!(
+            meta.value("count").unwrap().as_number(),
+            Some(Number::Float(1000.0))
+        );
+        assert_eq!(meta.get("count").unwrap(), "1e3");
+
+        // the types are kept when metadata is serialized
+        let encoded = serde_json::to_vec(&meta).unwrap();
+        let decoded: Meta = serde_json::from_slice(&encoded).unwrap();
+        assert_eq!(decoded, meta);
+
+        // metadata serialized before tags were typed has text values
+        let decoded: Meta =
+            serde_json::from_str(r#"{"tags": {"count": "10", ":size": "10"}}"#).unwrap();
+        assert_eq!(decoded.value("count"), Some(&Value::Text("10".into())));
+        assert_eq!(decoded.size(), Some(10));
+    }
+
     #[test]
     fn meta_with_fns() {
         let meta = Meta::default()
@@ -376,6 +723,7 @@ mod tests {
             .with_acl(10)
             .with_created(2000)
             .with_updated(3000)
+            .with_version(4)
             .with_deleted(true);
 
         assert_eq!(meta.collection(), Some("collection".into()));
@@ -383,6 +731,7 @@ mod tests {
         assert_eq!(meta.acl(), Some(10));
         assert_eq!(meta.created(), Some(2000));
         assert_eq!(meta.updated(), Some(3000));
+        assert_eq!(meta.version(), Some(4));
         assert_eq!(meta.deleted(), true);
     }
 
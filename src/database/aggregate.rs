@@ -0,0 +1,132 @@
+//! Aggregations over the objects which match a query: statistics of the numeric values of a tag,
+//! and the facets of a tag, the distinct values of the tag with the number of objects which have
+//! them.
+
+use super::{Number, Value};
+use std::collections::HashMap;
+
+/// Statistics of the numeric values of a tag. Text values are left out, also if they look
+/// like numbers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Aggregate {
+    /// the number of numeric values
+    pub count: u64,
+    pub sum: f64,
+    pub min: Option<Number>,
+    pub max: Option<Number>,
+}
+
+impl Aggregate {
+    /// adds a tag value, text values are ignored
+    pub fn add(&mut self, value: &Value) {
+        let number = match value.as_number() {
+            Some(number) => number,
+            None => return,
+        };
+
+        self.count += 1;
+        self.sum += number.as_f64();
+        if self.min.map_or(true, |min| number < min) {
+            self.min = Some(number);
+        }
+
+        if self.max.map_or(true, |max| number > max) {
+            self.max = Some(number);
+        }
+    }
+
+    /// the average of the values, `None` if there are no values
+    pub fn avg(&self) -> Option<f64> {
+        match self.count {
+            0 => None,
+            count => Some(self.sum / count as f64),
+        }
+    }
+}
+
+/// A distinct value of a tag, with the number of objects which have it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Facet {
+    pub value: String,
+    pub count: u64,
+}
+
+/// Counts the distinct values of a tag
+#[derive(Debug, Default)]
+pub struct Facets {
+    counts: HashMap<String, u64>,
+}
+
+impl Facets {
+    pub fn new() -> Self {
+        Facets::default()
+    }
+
+    pub fn add<V: Into<String>>(&mut self, value: V) {
+        *self.counts.entry(value.into()).or_default() += 1;
+    }
+
+    /// the facets, the most common values first. Values which are equally common are ordered
+    /// by value.
+    pub fn into_vec(self, limit: Option<usize>) -> Vec<Facet> {
+        let mut facets: Vec<Facet> = self
+            .counts
+            .into_iter()
+            .map(|(value, count)| Facet {
+                value: value,
+                count: count,
+            })
+            .collect();
+
+        facets.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+        if let Some(limit) = limit {
+            facets.truncate(limit);
+        }
+
+        facets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate() {
+        let mut aggregate = Aggregate::default();
+        assert_eq!(aggregate.avg(), None);
+
+        for value in &["10", "2.5", "-3", "10"] {
+            aggregate.add(&Value::number(*value).unwrap());
+        }
+
+        // text is left out, also if it looks like a number
+        for value in &["abc", "007"] {
+            aggregate.add(&Value::Text(value.to_string()));
+        }
+
+        assert_eq!(aggregate.count, 4);
+        assert_eq!(aggregate.sum, 19.5);
+        assert_eq!(aggregate.min, Some(Number::Integer(-3)));
+        assert_eq!(aggregate.max, Some(Number::Integer(10)));
+        assert_eq!(aggregate.avg(), Some(4.875));
+    }
+
+    #[test]
+    fn facets() {
+        let mut facets = Facets::new();
+        for value in &["pdf", "txt", "pdf", "png", "txt", "pdf", "jpg"] {
+            facets.add(*value);
+        }
+
+        let facet = |value: &str, count| Facet {
+            value: value.into(),
+            count: count,
+        };
+
+        assert_eq!(
+            facets.into_vec(Some(3)),
+            vec![facet("pdf", 3), facet("txt", 2), facet("jpg", 1)]
+        );
+    }
+}
@@ -1,40 +1,749 @@
+use super::versions::History;
 use super::*;
 use crate::acl::*;
-use crate::storage::Storage;
+use crate::storage::chunked::{ChunkedStorage, Content, Manifest, CHUNK_SIZE};
+use crate::storage::namespaces::{Collections, Location, Namespaces};
+use crate::storage::AsyncStorage;
 use anyhow::Context as ErrorContext;
 use std::collections::HashMap;
-use tokio::sync::mpsc;
-use tokio::task::spawn_blocking;
+use std::sync::{Arc, Mutex as SyncMutex};
+use tokio::sync::{mpsc, Mutex};
 
-//TODO: use generics for both object store type and meta factory type.
 #[derive(Clone)]
-pub struct BcdbDatabase<S, I>
+pub struct BcdbDatabase<C, I, A>
 where
-    S: Storage,
+    C: Collections,
     I: Index,
+    A: AsyncStorage,
 {
-    data: S,
+    data: Namespaces<C>,
+    chunk_size: usize,
+    retention: Retention,
     meta: I,
-    acl: ACLStorage<S>,
+    acl: ACLStorage<A>,
+    locks: Locks,
 }
 
-impl<S, I> BcdbDatabase<S, I>
+/// locks of the objects which are being changed. Changing an object reads its
+/// forward record and metadata, and writes them again, so concurrent changes of
+/// the same object are serialized. Otherwise both would link their version to
+/// the same previous version, and one of the versions would be lost.
+#[derive(Clone, Default)]
+struct Locks(Arc<SyncMutex<HashMap<Key, Arc<Mutex<()>>>>>);
+
+impl Locks {
+    /// gets the lock of an object, the locks of objects which are not
+    /// changed anymore are dropped
+    fn get(&self, key: Key) -> Arc<Mutex<()>> {
+        let mut locks = self.0.lock().unwrap();
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(key).or_default().clone()
+    }
+}
+
+/// a new version of the data of an object, which is linked but whose metadata
+/// is not updated yet
+struct Written<S> {
+    storage: ChunkedStorage<S>,
+    /// id of the storage collection of the object
+    collection: u32,
+    /// data and history record of the previous version
+    previous: (Key, Option<Key>),
+    /// data and history record of the new version
+    current: (Key, Key),
+    /// data of the past versions dropped by the retention policy
+    dropped: Vec<Key>,
+}
+
+/// the new data of an object which is updated
+enum NewData {
+    Complete(Vec<u8>),
+    /// data which is received in chunks, a stream without data only
+    /// changes the metadata of the object
+    Streamed(Chunks),
+}
+
+/// a streamed object which is being written
+struct Upload<S> {
+    /// storage the chunks are written to, the staging collection until
+    /// the collection of the object is known
+    storage: ChunkedStorage<S>,
+    /// id of the collection of the object
+    collection: Option<u32>,
+    manifest: Manifest,
+}
+
+impl<C, I, A> BcdbDatabase<C, I, A>
 where
-    S: Storage,
+    C: Collections,
     I: Index + Clone,
+    A: AsyncStorage,
 {
-    pub fn new(data: S, meta: I, acl: ACLStorage<S>) -> Self {
+    /// creates a new database, every collection is stored in its own
+    /// storage collection. Objects that are larger than a single
+    /// storage record are split in chunks. The last 10 versions of all
+    /// objects are kept, unless a retention policy is set.
+    pub fn new(data: Namespaces<C>, meta: I, acl: ACLStorage<A>) -> Self {
         BcdbDatabase {
             data: data,
+            chunk_size: CHUNK_SIZE,
+            retention: Retention::new(),
             meta: meta,
             acl: acl,
+            locks: Locks::default(),
+        }
+    }
+
+    /// sets the maximum size of a single chunk of object data
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0);
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// sets the number of versions that are kept of the objects of every collection
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// moves all objects which were written before collections were split
+    /// to the storage collection of their collection. Objects keep their
+    /// keys. Returns the number of moved objects.
+    pub async fn migrate(&self) -> Result<usize> {
+        // collect the keys first, so the index is not read while objects
+        // are moved
+        let mut keys = vec![];
+        let mut found = self.meta.find(Query::new()).await?;
+        while let Some(key) = found.recv().await {
+            keys.push(key?);
+        }
+
+        let mut count = 0;
+        for key in keys {
+            let collection = match self.meta.get(key).await?.collection() {
+                Some(collection) => collection,
+                None => continue,
+            };
+
+            let moved = self
+                .data
+                .migrate(key, &collection)
+                .await
+                .with_context(|| format!("failed to migrate object '{}'", key))?;
+
+            if moved.is_some() {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn chunked(&self, storage: C::Storage) -> ChunkedStorage<C::Storage> {
+        ChunkedStorage::new(storage).with_chunk_size(self.chunk_size)
+    }
+
+    /// gets the id and storage of a collection, the collection is created
+    /// on first use.
+    async fn collection(&self, collection: &str) -> Result<(u32, ChunkedStorage<C::Storage>)> {
+        let (id, storage) = self
+            .data
+            .collection(collection)
+            .await
+            .context("failed to open collection")?;
+
+        Ok((id, self.chunked(storage)))
+    }
+
+    /// gets the storage of an object, and its key within that storage
+    async fn locate(&self, key: Key) -> Result<Location<ChunkedStorage<C::Storage>>> {
+        let location = self
+            .data
+            .resolve(key)
+            .await
+            .context("failed to resolve object")?;
+
+        match location {
+            Some(location) => Ok(Location {
+                collection: location.collection,
+                storage: self.chunked(location.storage),
+                key: location.key,
+                forward: location.forward,
+                history: location.history,
+            }),
+            None => bail!(Reason::NotFound),
+        }
+    }
+
+    /// reads the history of an object, an object without history record was never
+    /// updated
+    async fn history(
+        &self,
+        location: &Location<ChunkedStorage<C::Storage>>,
+        meta: &Meta,
+    ) -> Result<History> {
+        let key = match location.history {
+            Some(key) => key,
+            None => return Ok(History::new(meta.created().unwrap_or_default())),
+        };
+
+        match location.storage.get(key).await {
+            Ok(Some(data)) => History::decode(&data),
+            Ok(None) => bail!("history record of object is missing"),
+            Err(err) => Err(err).context("failed to get history"),
+        }
+    }
+
+    /// gets the storage of an object which gets a new version. Objects written
+    /// before collections were split have no forward record to point to their
+    /// history, so they are moved first.
+    async fn locate_current(
+        &self,
+        key: Key,
+        collection: &str,
+    ) -> Result<Location<ChunkedStorage<C::Storage>>> {
+        let location = self.locate(key).await?;
+        if location.forward {
+            return Ok(location);
+        }
+
+        self.data
+            .migrate(key, collection)
+            .await
+            .with_context(|| format!("failed to migrate object '{}'", key))?;
+        self.locate(key).await
+    }
+
+    /// writes a new version of the data of an object, the current data of the
+    /// object is kept as a past version as far as the retention policy of its
+    /// collection allows. The forward record of the object is switched to the
+    /// new data and history at once, so a failed update leaves the object as it
+    /// was. The metadata of the object is kept with the previous version. Once
+    /// the metadata of the object is updated, the new version must be committed,
+    /// or reverted if that fails. The lock of the object must be held.
+    async fn write_version(
+        &self,
+        key: Key,
+        collection: &str,
+        meta: &Meta,
+        data: &[u8],
+        timestamp: u64,
+    ) -> Result<Written<C::Storage>> {
+        let location = self.locate_current(key, collection).await?;
+        let version = location
+            .storage
+            .set(None, data)
+            .await
+            .context("failed to set data")?;
+
+        self.link_version(key, collection, location, meta, version, timestamp)
+            .await
+    }
+
+    /// writes a new version of the data of an object like `write_version`, but
+    /// the data is received in chunks, which are written as they come in.
+    /// Returns the new version and its size, `None` if no data is received.
+    async fn write_version_stream(
+        &self,
+        key: Key,
+        collection: &str,
+        meta: &Meta,
+        data: &mut Chunks,
+        timestamp: u64,
+    ) -> Result<Option<(Written<C::Storage>, u64)>> {
+        let location = self.locate_current(key, collection).await?;
+        let mut upload = Upload {
+            storage: location.storage.clone(),
+            collection: Some(location.collection),
+            manifest: Manifest::default(),
+        };
+
+        if let Err(err) = self.receive(data, &mut upload).await {
+            upload.storage.delete_chunks(&upload.manifest.chunks).await;
+            return Err(err);
+        }
+
+        let manifest = upload.manifest;
+        if manifest.size == 0 {
+            return Ok(None);
+        }
+
+        let version = match location.storage.set_manifest(None, &manifest).await {
+            Ok(version) => version,
+            Err(err) => {
+                location.storage.delete_chunks(&manifest.chunks).await;
+                return Err(err).context("failed to set data");
+            }
+        };
+
+        let written = self
+            .link_version(key, collection, location, meta, version, timestamp)
+            .await?;
+        Ok(Some((written, manifest.size)))
+    }
+
+    /// makes a written record the current version of an object, the record is
+    /// dropped again if that fails
+    async fn link_version(
+        &self,
+        key: Key,
+        collection: &str,
+        location: Location<ChunkedStorage<C::Storage>>,
+        meta: &Meta,
+        version: Key,
+        timestamp: u64,
+    ) -> Result<Written<C::Storage>> {
+        let db = &location.storage;
+        let mut history = match self.history(&location, meta).await {
+            Ok(history) => history,
+            Err(err) => {
+                Self::discard(db, &[version]).await;
+                return Err(err);
+            }
+        };
+
+        history.push(
+            location.key,
+            meta.size().unwrap_or_default(),
+            meta.clone(),
+            timestamp,
+        );
+        let dropped = history.retain(self.retention.versions(collection));
+        let encoded = match history.encode() {
+            Ok(encoded) => encoded,
+            Err(err) => {
+                Self::discard(db, &[version]).await;
+                return Err(err).context("failed to encode history");
+            }
+        };
+
+        let record = match db.set(None, &encoded).await {
+            Ok(record) => record,
+            Err(err) => {
+                Self::discard(db, &[version]).await;
+                return Err(err).context("failed to set history");
+            }
+        };
+
+        let linked = self
+            .data
+            .relink(key, location.collection, version, Some(record))
+            .await;
+        if let Err(err) = linked {
+            Self::discard(db, &[version, record]).await;
+            return Err(err).context("failed to set data");
+        }
+
+        Ok(Written {
+            storage: location.storage,
+            collection: location.collection,
+            previous: (location.key, location.history),
+            current: (version, record),
+            dropped: dropped,
+        })
+    }
+
+    /// drops the records a new version of an object replaced
+    async fn commit(written: Written<C::Storage>) {
+        let db = &written.storage;
+        Self::discard(db, &written.dropped).await;
+        if let Some(previous) = written.previous.1 {
+            Self::discard(db, &[previous]).await;
+        }
+    }
+
+    /// links an object to its previous version again, and drops the new version
+    async fn revert(&self, key: Key, written: Written<C::Storage>) {
+        let (previous, history) = written.previous;
+        let result = self
+            .data
+            .relink(key, written.collection, previous, history)
+            .await;
+
+        match result {
+            Ok(_) => {
+                let (version, record) = written.current;
+                Self::discard(&written.storage, &[version, record]).await;
+            }
+            Err(err) => error!(
+                "failed to revert data of object '{}', its metadata is stale: {}",
+                key, err
+            ),
+        }
+    }
+
+    /// deletes the data of an object, and its past versions
+    async fn remove(&self, key: Key, meta: &Meta) -> Result<()> {
+        let location = self.locate(key).await?;
+        let history = self.history(&location, meta).await?;
+        let result = match location.storage.delete(location.key).await {
+            Ok(_) if location.forward => self.data.unlink(key).await,
+            result => result,
+        };
+        result.context("failed to delete data")?;
+
+        let mut keys: Vec<Key> = history.past.iter().map(|past| past.key).collect();
+        keys.extend(location.history);
+        Self::discard(&location.storage, &keys).await;
+
+        Ok(())
+    }
+
+    /// drops a new object whose metadata could not be set, so no data is left
+    /// behind which can't be found. The metadata is cleared as well, in case it
+    /// was set after all.
+    async fn abandon(&self, key: Key) {
+        let deleted = Meta::default().with_deleted(true);
+        if let Err(err) = self.meta.set(key, deleted).await {
+            warn!("failed to clear metadata of object '{}': {}", key, err);
+        }
+
+        if let Err(err) = self.remove(key, &Meta::default()).await {
+            warn!("failed to delete data of object '{}': {}", key, err);
+        }
+    }
+
+    /// deletes records which are not referenced anymore, failures are only logged
+    /// since the records can't be reached anyway
+    async fn discard(db: &ChunkedStorage<C::Storage>, keys: &[Key]) {
+        for key in keys {
+            if let Err(err) = db.delete(*key).await {
+                warn!("failed to delete unreferenced record '{}': {}", key, err);
+            }
+        }
+    }
+
+    /// reads the data of multiple objects. The forward records, and the
+    /// objects in every storage collection are read in a single batch.
+    async fn read_many(&self, keys: Vec<Key>) -> Result<Vec<Option<Vec<u8>>>> {
+        let locations = self
+            .data
+            .resolve_many(&keys)
+            .await
+            .context("failed to resolve objects")?;
+
+        // positions and keys of the objects in every storage collection
+        let mut groups = HashMap::new();
+        for (idx, location) in locations.into_iter().enumerate() {
+            if let Some(location) = location {
+                let (id, storage) = (location.collection, location.storage);
+                let group = groups
+                    .entry(id)
+                    .or_insert_with(|| (storage, Vec::new(), Vec::new()));
+                group.1.push(idx);
+                group.2.push(location.key);
+            }
+        }
+
+        let mut objects = vec![None; keys.len()];
+        for (_, (storage, positions, local)) in groups {
+            // the chunk size is only used for writing
+            let storage = ChunkedStorage::new(storage);
+            let contents = storage
+                .get_many(&local)
+                .await
+                .context("failed to get data")?;
+            for (idx, object) in positions.into_iter().zip(contents) {
+                objects[idx] = object;
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// writes an object to the storage of its collection, and links it so
+    /// it can be found by its key
+    async fn write(&self, collection: &str, data: Vec<u8>) -> Result<Key> {
+        let (id, db) = self.collection(collection).await?;
+        let key = db.set(None, &data).await.context("failed to set data")?;
+        self.link(&db, id, key).await.context("failed to set data")
+    }
+
+    /// builds the metadata of a new object
+    fn new_meta(
+        collection: &str,
+        tags: HashMap<String, Value>,
+        acl: Option<u64>,
+        size: u64,
+    ) -> Result<Meta> {
+        let mut meta = Meta::try_from(tags)?;
+        if let Some(acl) = acl {
+            meta = meta.with_acl(acl);
+        }
+
+        Ok(meta
+            .with_collection(collection)
+            .with_size(size)
+            .with_created(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            ))
+    }
+
+    /// links an object, so it can be found by its key. The object is
+    /// deleted again if it can't be linked.
+    async fn link(
+        &self,
+        db: &ChunkedStorage<C::Storage>,
+        id: u32,
+        key: Key,
+    ) -> Result<Key, crate::storage::Error> {
+        let result = self.data.link(id, key).await;
+        if result.is_err() {
+            if let Err(err) = db.delete(key).await {
+                warn!("failed to delete unlinked object '{}': {}", key, err);
+            }
+        }
+
+        result
+    }
+
+    /// adds received data to the buffer of an upload, and writes the chunks
+    /// which are full
+    async fn buffer(
+        &self,
+        upload: &mut Upload<C::Storage>,
+        buffer: &mut Vec<u8>,
+        data: &[u8],
+    ) -> Result<()> {
+        upload.manifest.size += data.len() as u64;
+        buffer.extend_from_slice(data);
+        while buffer.len() >= self.chunk_size {
+            let rest = buffer.split_off(self.chunk_size);
+            let chunk = std::mem::replace(buffer, rest);
+            self.set_chunk(upload, chunk).await?;
+        }
+
+        Ok(())
+    }
+
+    /// receives the data of an object, and writes it in chunks as it comes in
+    async fn receive(&self, data: &mut Chunks, upload: &mut Upload<C::Storage>) -> Result<()> {
+        let mut buffer = Vec::with_capacity(self.chunk_size);
+        while let Some(chunk) = data.recv().await {
+            self.buffer(upload, &mut buffer, &chunk?).await?;
+        }
+
+        if !buffer.is_empty() {
+            self.set_chunk(upload, buffer).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn set_chunk(&self, upload: &mut Upload<C::Storage>, chunk: Vec<u8>) -> Result<()> {
+        let key = upload
+            .storage
+            .set_chunk(&chunk)
+            .await
+            .context("failed to set data")?;
+
+        upload.manifest.chunks.push(key);
+        Ok(())
+    }
+
+    /// moves the chunks of an upload from the staging collection to the
+    /// storage of its collection
+    async fn unstage(
+        &self,
+        upload: &mut Upload<C::Storage>,
+        id: u32,
+        storage: ChunkedStorage<C::Storage>,
+    ) -> Result<()> {
+        let chunks = &upload.manifest.chunks;
+        let mut moved = Vec::with_capacity(chunks.len());
+        for chunk in chunks.iter() {
+            let result = match upload.storage.get_chunk(*chunk).await {
+                Ok(data) => storage.set_chunk(&data).await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(key) => moved.push(key),
+                Err(err) => {
+                    storage.delete_chunks(&moved).await;
+                    return Err(err).context("failed to set data");
+                }
+            }
+        }
+
+        upload.storage.delete_chunks(chunks).await;
+
+        upload.storage = storage;
+        upload.collection = Some(id);
+        upload.manifest.chunks = moved;
+        Ok(())
+    }
+
+    /// receives the parts of a streamed object, and writes the data in chunks
+    /// as it comes in. The metadata is either the first or the last part, if
+    /// it comes last the chunks are staged until the collection of the object
+    /// is known. The keys of the written chunks are recorded in the upload,
+    /// so they can be dropped again if the upload fails.
+    async fn upload(
+        &self,
+        parts: &mut mpsc::Receiver<Result<Part>>,
+        upload: &mut Upload<C::Storage>,
+    ) -> Result<(Meta, u32)> {
+        let mut buffer: Vec<u8> = Vec::with_capacity(self.chunk_size);
+        let mut metadata = None;
+        // set once the metadata is received after the data
+        let mut last = false;
+
+        while let Some(part) = parts.recv().await {
+            if last {
+                bail!("object metadata must be the first or the last part");
+            }
+
+            match part? {
+                Part::Data(data) => self.buffer(upload, &mut buffer, &data).await?,
+                Part::Meta {
+                    collection,
+                    tags,
+                    acl,
+                } => {
+                    if metadata.is_some() {
+                        bail!("object metadata must be sent once");
+                    }
+
+                    if upload.manifest.size == 0 {
+                        let (id, storage) = self.collection(&collection).await?;
+                        upload.storage = storage;
+                        upload.collection = Some(id);
+                    } else {
+                        last = true;
+                    }
+
+                    metadata = Some((collection, tags, acl));
+                }
+            }
+        }
+
+        let (collection, tags, acl) = match metadata {
+            Some(metadata) => metadata,
+            None => bail!("object metadata is required"),
+        };
+
+        let id = match upload.collection {
+            Some(id) => id,
+            None => {
+                let (id, storage) = self.collection(&collection).await?;
+                self.unstage(upload, id, storage).await?;
+                id
+            }
+        };
+
+        if !buffer.is_empty() {
+            self.set_chunk(upload, buffer).await?;
+        }
+
+        let meta = Self::new_meta(&collection, tags, acl, upload.manifest.size)?;
+        Ok((meta, id))
+    }
+
+    /// updates the metadata of an object, and its data if new data is given
+    async fn change(
+        &self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+        data: Option<NewData>,
+        tags: HashMap<String, Value>,
+        acl: Option<u64>,
+    ) -> Result<()> {
+        let lock = self.locks.get(key);
+        let _guard = lock.lock().await;
+        let current = self.meta.get(key).await?;
+
+        self.is_authorized(&ctx, &current, "-w-".parse().unwrap())
+            .await?;
+
+        if !current.is_collection(&collection) {
+            bail!(Reason::NotFound);
+        }
+
+        let mut meta = Meta::try_from(tags)?;
+        if let Some(acl) = acl {
+            if !ctx.is_owner() {
+                bail!(Reason::Unauthorized);
+            }
+
+            meta = meta.with_acl(acl);
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        meta = meta.with_updated(now);
+
+        let written = match data {
+            Some(NewData::Complete(data)) => {
+                meta = meta.with_size(data.len() as u64);
+                let written = self
+                    .write_version(key, collection, &current, &data, now)
+                    .await?;
+                Some(written)
+            }
+            Some(NewData::Streamed(mut data)) => {
+                let written = self
+                    .write_version_stream(key, collection, &current, &mut data, now)
+                    .await?;
+                match written {
+                    Some((written, size)) => {
+                        meta = meta.with_size(size);
+                        Some(written)
+                    }
+                    None => None,
+                }
+            }
+            None => None,
+        };
+
+        if let Err(err) = self.meta.set(key, meta).await {
+            if let Some(written) = written {
+                self.revert(key, written).await;
+            }
+
+            return Err(err);
+        }
+
+        if let Some(written) = written {
+            Self::commit(written).await;
+        }
+
+        Ok(())
+    }
+
+    /// gets the metadata of an object of a collection, if it can be read. Objects
+    /// which don't exist, are not part of the collection, or can't be read by the
+    /// caller are left out of batch gets.
+    async fn readable(&self, ctx: &Context, key: Key, collection: &str) -> Result<Option<Object>> {
+        let meta = self.meta.get(key).await?;
+        if !meta.is_collection(&collection) {
+            return Ok(None);
+        }
+
+        match self
+            .is_authorized(&ctx, &meta, "r--".parse().unwrap())
+            .await
+        {
+            Ok(_) => Ok(Some(Object {
+                key: key,
+                data: None,
+                meta: meta,
+            })),
+            Err(err) if Reason::from(&err) == Reason::Unauthorized => Ok(None),
+            Err(err) => Err(err),
         }
     }
 
-    fn get_permissions(&self, acl: u64, user: u64) -> Result<Permissions> {
-        // self.acl.g
+    async fn get_permissions(&self, acl: u64, user: u64) -> Result<Permissions> {
         let mut store = self.acl.clone();
-        let acl = match store.get(acl as u32)? {
+        let acl = match store.get(acl).await? {
             Some(acl) => acl,
             None => return Ok(Permissions::default()),
         };
@@ -46,13 +755,14 @@ where
         Ok(Permissions::default())
     }
 
-    fn is_authorized(&self, ctx: &Context, meta: &Meta, perm: Permissions) -> Result<()> {
+    async fn is_authorized(&self, ctx: &Context, meta: &Meta, perm: Permissions) -> Result<()> {
         match ctx.authorization {
             Authorization::Owner => Ok(()),
             Authorization::User(user) => {
                 if let Some(acl) = meta.acl() {
                     let stored = self
                         .get_permissions(acl, user as u64)
+                        .await
                         .context("failed to get assigned permissions")?;
 
                     if stored.grants(perm) {
@@ -68,44 +778,79 @@ where
 }
 
 #[tonic::async_trait]
-impl<S, I> Database for BcdbDatabase<S, I>
+impl<C, I, A> Database for BcdbDatabase<C, I, A>
 where
-    S: Storage + Send + Sync + 'static,
+    C: Collections,
     I: Index + Clone,
+    A: AsyncStorage,
 {
     async fn set(
         &mut self,
         ctx: &Context,
         collection: &str,
         data: Vec<u8>,
-        tags: HashMap<String, String>,
+        tags: HashMap<String, Value>,
         acl: Option<u64>,
     ) -> Result<Key> {
         if !ctx.is_owner() {
             bail!(Reason::Unauthorized)
         }
 
-        let mut meta = Meta::try_from(tags)?;
-        if let Some(acl) = acl {
-            meta = meta.with_acl(acl);
+        let meta = Self::new_meta(collection, tags, acl, data.len() as u64)?;
+        let id = self.write(collection, data).await?;
+
+        if let Err(err) = self.meta.set(id, meta).await {
+            self.abandon(id).await;
+            return Err(err);
         }
 
-        meta = meta
-            .with_collection(collection)
-            .with_size(data.len() as u64)
-            .with_created(
-                std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            );
+        Ok(id)
+    }
+
+    async fn set_stream(
+        &mut self,
+        ctx: &Context,
+        mut parts: mpsc::Receiver<Result<Part>>,
+    ) -> Result<Key> {
+        if !ctx.is_owner() {
+            bail!(Reason::Unauthorized)
+        }
+
+        let mut upload = Upload {
+            storage: self.chunked(self.data.staging()),
+            collection: None,
+            manifest: Manifest::default(),
+        };
+        let result = self.upload(&mut parts, &mut upload).await;
+
+        let db = upload.storage;
+        let manifest = upload.manifest;
+        let (meta, collection) = match result {
+            Ok(result) => result,
+            Err(err) => {
+                // drop the chunks of the incomplete object
+                db.delete_chunks(&manifest.chunks).await;
+                return Err(err);
+            }
+        };
+
+        let key = match db.set_manifest(None, &manifest).await {
+            Ok(key) => key,
+            Err(err) => {
+                db.delete_chunks(&manifest.chunks).await;
+                return Err(err).context("failed to set data");
+            }
+        };
 
-        let db = self.data.clone();
-        let id = spawn_blocking(move || db.set(None, &data).expect("failed to set data"))
+        let id = self
+            .link(&db, collection, key)
             .await
-            .context("failed to run blocking task")?;
+            .context("failed to set data")?;
 
-        self.meta.set(id, meta).await?;
+        if let Err(err) = self.meta.set(id, meta).await {
+            self.abandon(id).await;
+            return Err(err);
+        }
 
         Ok(id)
     }
@@ -113,37 +858,152 @@ where
     async fn fetch(&mut self, ctx: &Context, key: Key) -> Result<Object> {
         let meta = self.meta.get(key).await?;
 
-        self.is_authorized(&ctx, &meta, "r--".parse().unwrap())?;
+        self.is_authorized(&ctx, &meta, "r--".parse().unwrap())
+            .await?;
 
-        let db = self.data.clone();
-        let data = spawn_blocking(move || db.get(key))
-            .await
-            .context("failed to run blocking task")?
-            .context("failed to get data")?;
+        let location = self.locate(key).await?;
+        let (db, local) = (location.storage, location.key);
+        let data = db.get(local).await.context("failed to get data")?;
+        if data.is_none() {
+            bail!(Reason::NotFound);
+        }
+
+        Ok(Object {
+            key: key,
+            data: Some(data.unwrap()),
+            meta: meta,
+        })
+    }
+
+    async fn get(&mut self, ctx: &Context, key: Key, collection: &str) -> Result<Object> {
+        let meta = self.meta.get(key).await?;
+
+        if !meta.is_collection(&collection) {
+            bail!(Reason::NotFound);
+        }
+
+        self.is_authorized(&ctx, &meta, "r--".parse().unwrap())
+            .await?;
+
+        let location = self.locate(key).await?;
+        let (db, local) = (location.storage, location.key);
+        let data = db.get(local).await.context("failed to get data")?;
         if data.is_none() {
             bail!(Reason::NotFound);
         }
 
-        Ok(Object {
-            key: key,
-            data: Some(data.unwrap()),
-            meta: meta,
-        })
+        Ok(Object {
+            key: key,
+            data: Some(data.unwrap()),
+            meta: meta,
+        })
+    }
+
+    async fn get_stream(
+        &mut self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+    ) -> Result<(Object, Chunks)> {
+        let object = self.head(ctx, key, collection).await?;
+
+        let location = self.locate(key).await?;
+        let db = location.storage;
+        let content = db.open(location.key).await.context("failed to get data")?;
+
+        let content = match content {
+            Some(content) => content,
+            None => bail!(Reason::NotFound),
+        };
+
+        // the channel only buffers a single chunk, so only a couple of chunks
+        // are held in memory at any time.
+        let (mut tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let chunks = match content {
+                Content::Inline(data) => {
+                    let _ = tx.send(Ok(data)).await;
+                    return;
+                }
+                Content::Chunked(manifest) => manifest.chunks,
+            };
+
+            for chunk in chunks {
+                let result = db.get_chunk(chunk).await.context("failed to get data");
+                let failed = result.is_err();
+                if let Err(err) = tx.send(result).await {
+                    debug!("failed to send result, broken stream: {}", err);
+                    break;
+                }
+
+                if failed {
+                    break;
+                }
+            }
+        });
+
+        Ok((object, rx))
     }
 
-    async fn get(&mut self, ctx: &Context, key: Key, collection: &str) -> Result<Object> {
-        let meta = self.meta.get(key).await?;
-
-        if !meta.is_collection(&collection) {
-            bail!(Reason::NotFound);
+    async fn get_many(
+        &mut self,
+        ctx: &Context,
+        keys: Vec<Key>,
+        collection: &str,
+    ) -> Result<Vec<Object>> {
+        // the metadata of all objects is read concurrently
+        let db = &*self;
+        let heads = keys
+            .into_iter()
+            .map(|key| db.readable(ctx, key, collection));
+
+        let mut objects = Vec::new();
+        for object in futures::future::join_all(heads).await {
+            if let Some(object) = object? {
+                objects.push(object);
+            }
         }
 
-        self.is_authorized(&ctx, &meta, "r--".parse().unwrap())?;
+        let keys = objects.iter().map(|object| object.key).collect();
+        let contents = self.read_many(keys).await?;
+
+        Ok(objects
+            .into_iter()
+            .zip(contents)
+            .filter_map(|(object, data)| match data {
+                Some(data) => Some(Object {
+                    data: Some(data),
+                    ..object
+                }),
+                None => None,
+            })
+            .collect())
+    }
+
+    async fn get_version(
+        &mut self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+        version: u64,
+    ) -> Result<Object> {
+        let object = self.head(ctx, key, collection).await?;
+
+        let location = self.locate(key).await?;
+        let history = self.history(&location, &object.meta).await?;
+        let (local, meta) = if version == history.current {
+            (location.key, object.meta)
+        } else {
+            match history.get(version) {
+                Some(past) => (past.key, past.meta.clone().with_size(past.version.size)),
+                None => bail!(Reason::NotFound),
+            }
+        };
 
-        let db = self.data.clone();
-        let data = spawn_blocking(move || db.get(key))
+        let data = location
+            .storage
+            .get(local)
             .await
-            .context("failed to run blocking task")?
             .context("failed to get data")?;
         if data.is_none() {
             bail!(Reason::NotFound);
@@ -151,11 +1011,31 @@ where
 
         Ok(Object {
             key: key,
-            data: Some(data.unwrap()),
-            meta: meta,
+            data: data,
+            meta: meta.with_version(version),
         })
     }
 
+    async fn versions(
+        &mut self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+    ) -> Result<Vec<Version>> {
+        let object = self.head(ctx, key, collection).await?;
+
+        let location = self.locate(key).await?;
+        let history = self.history(&location, &object.meta).await?;
+        let mut versions: Vec<Version> = history.past.into_iter().map(|p| p.version).collect();
+        versions.push(Version {
+            version: history.current,
+            size: object.meta.size().unwrap_or_default(),
+            timestamp: history.timestamp,
+        });
+
+        Ok(versions)
+    }
+
     async fn head(&mut self, ctx: &Context, key: Key, collection: &str) -> Result<Object> {
         let meta = self.meta.get(key).await?;
 
@@ -163,7 +1043,8 @@ where
             bail!(Reason::NotFound);
         }
 
-        self.is_authorized(&ctx, &meta, "r--".parse().unwrap())?;
+        self.is_authorized(&ctx, &meta, "r--".parse().unwrap())
+            .await?;
 
         Ok(Object {
             key: key,
@@ -173,28 +1054,27 @@ where
     }
 
     async fn delete(&mut self, ctx: &Context, key: Key, collection: &str) -> Result<()> {
+        let lock = self.locks.get(key);
+        let _guard = lock.lock().await;
         let meta = self.meta.get(key).await?;
 
         if !meta.is_collection(&collection) {
             bail!(Reason::NotFound);
         }
 
-        self.is_authorized(&ctx, &meta, "--d".parse().unwrap())?;
-
-        let meta = Meta::default().with_deleted(true);
-        self.meta.set(key, meta).await?;
-
-        // TODO: should the data associated with that object also
-        // be deleted? changes to metadata can be restored if you
-        // replay the transaction log to the point until the object
-        // is not deleted.
+        self.is_authorized(&ctx, &meta, "--d".parse().unwrap())
+            .await?;
 
-        let db = self.data.clone();
+        self.meta
+            .set(key, Meta::default().with_deleted(true))
+            .await?;
 
-        spawn_blocking(move || db.delete(key))
-            .await
-            .context("failed to run blocking task")?
-            .context("failed to delete data")?;
+        // the object is gone once its metadata is deleted, a failure to delete
+        // its data (and past versions) only leaves records behind which can't
+        // be reached anymore.
+        if let Err(err) = self.remove(key, &meta).await {
+            warn!("failed to delete data of object '{}': {}", key, err);
+        }
 
         Ok(())
     }
@@ -205,90 +1085,67 @@ where
         key: Key,
         collection: &str,
         data: Option<Vec<u8>>,
-        tags: HashMap<String, String>,
+        tags: HashMap<String, Value>,
         acl: Option<u64>,
     ) -> Result<()> {
-        let current = self.meta.get(key).await?;
-
-        self.is_authorized(&ctx, &current, "-w-".parse().unwrap())?;
-
-        if !current.is_collection(&collection) {
-            bail!(Reason::NotFound);
-        }
-
-        let mut meta = Meta::try_from(tags)?;
-        if let Some(acl) = acl {
-            if !ctx.is_owner() {
-                bail!(Reason::Unauthorized);
-            }
-
-            meta = meta.with_acl(acl);
-        }
-
-        meta = meta.with_updated(
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-        );
-
-        if let Some(data) = data {
-            meta = meta.with_size(data.len() as u64);
-            let db = self.data.clone();
-            spawn_blocking(move || db.set(Some(key), &data))
-                .await
-                .context("failed to run blocking task")?
-                .context("failed to set data")?;
-        }
-
-        self.meta.set(key, meta).await?;
+        let data = data.map(NewData::Complete);
+        self.change(ctx, key, collection, data, tags, acl).await
+    }
 
-        Ok(())
+    async fn update_stream(
+        &mut self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+        data: Chunks,
+        tags: HashMap<String, Value>,
+        acl: Option<u64>,
+    ) -> Result<()> {
+        let data = Some(NewData::Streamed(data));
+        self.change(ctx, key, collection, data, tags, acl).await
     }
 
     async fn list(
         &mut self,
         ctx: &Context,
-        tags: HashMap<String, String>,
+        query: Query,
         collection: Option<&str>,
     ) -> Result<mpsc::Receiver<Result<Key>>> {
         if !ctx.is_owner() {
             bail!(Reason::Unauthorized);
         }
 
-        let mut meta = Meta::new(tags);
-
-        if let Some(collection) = collection {
-            meta.insert(TAG_COLLECTION, collection);
-        }
+        let query = match collection {
+            Some(collection) => query.with_collection(collection),
+            None => query,
+        };
 
-        self.meta.find(meta).await
+        self.meta.find(query).await
     }
 
     async fn find(
         &mut self,
         ctx: &Context,
-        tags: HashMap<String, String>,
+        query: Query,
         collection: Option<&str>,
     ) -> Result<mpsc::Receiver<Result<Object>>> {
         if !ctx.is_owner() {
             bail!(Reason::Unauthorized);
         }
 
-        let mut meta = Meta::new(tags);
-
-        if let Some(collection) = collection {
-            meta.insert(TAG_COLLECTION, collection);
-        }
+        let query = match collection {
+            Some(collection) => query.with_collection(collection),
+            None => query,
+        };
 
         let index = self.meta.clone();
 
         let (mut tx, rx) = mpsc::channel(10);
         tokio::spawn(async move {
-            let mut rx = match index.find(meta).await {
+            let mut rx = match index.find(query).await {
                 Ok(rx) => rx,
                 Err(err) => {
-                    tx.send(Err(anyhow!("{}", err))).await.unwrap();
+                    let _ = tx.send(Err(anyhow!("{}", err))).await;
                     return;
                 }
             };
@@ -297,7 +1154,7 @@ where
                 let id = match id {
                     Ok(id) => id,
                     Err(err) => {
-                        tx.send(Err(err)).await.unwrap();
+                        let _ = tx.send(Err(err)).await;
                         return;
                     }
                 };
@@ -305,7 +1162,7 @@ where
                 let meta = match index.get(id).await {
                     Ok(meta) => meta,
                     Err(err) => {
-                        tx.send(Err(err)).await.unwrap();
+                        let _ = tx.send(Err(err)).await;
                         return;
                     }
                 };
@@ -329,6 +1186,63 @@ where
 
         Ok(rx)
     }
+
+    async fn count(
+        &mut self,
+        ctx: &Context,
+        query: Query,
+        collection: Option<&str>,
+    ) -> Result<u64> {
+        if !ctx.is_owner() {
+            bail!(Reason::Unauthorized);
+        }
+
+        let query = match collection {
+            Some(collection) => query.with_collection(collection),
+            None => query,
+        };
+
+        self.meta.count(query).await
+    }
+
+    async fn aggregate(
+        &mut self,
+        ctx: &Context,
+        tag: &str,
+        query: Query,
+        collection: Option<&str>,
+    ) -> Result<Aggregate> {
+        if !ctx.is_owner() {
+            bail!(Reason::Unauthorized);
+        }
+
+        let query = match collection {
+            Some(collection) => query.with_collection(collection),
+            None => query,
+        };
+
+        self.meta.aggregate(tag, query).await
+    }
+
+    async fn facets(
+        &mut self,
+        ctx: &Context,
+        tag: &str,
+        query: Query,
+        collection: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Facet>> {
+        if !ctx.is_owner() {
+            bail!(Reason::Unauthorized);
+        }
+
+        let query = match collection {
+            Some(collection) => query.with_collection(collection),
+            None => query,
+        };
+
+        self.meta.facets(tag, query, limit).await
+    }
 }
 
 #[cfg(test)]
@@ -338,10 +1252,11 @@ pub mod database_tests {
     use crate::acl::ACL;
     use crate::database::index::memory::MemoryIndex;
     use crate::database::*;
-    use crate::storage::memory::MemoryStorage;
+    use crate::storage::memory::{MemoryCollections, MemoryStorage};
+    use futures::StreamExt;
 
-    pub fn get_in_memory_db() -> BcdbDatabase<MemoryStorage, MemoryIndex> {
-        let data = MemoryStorage::new();
+    pub async fn get_in_memory_db() -> BcdbDatabase<MemoryCollections, MemoryIndex, MemoryStorage> {
+        let data = Namespaces::new(MemoryCollections::new()).await.unwrap();
         let acl = MemoryStorage::new();
         let index = MemoryIndex::new();
 
@@ -350,7 +1265,7 @@ pub mod database_tests {
 
     #[tokio::test]
     async fn database_owner_set() {
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
 
         // default context has no authorization
         let ctx = Context::default();
@@ -378,7 +1293,7 @@ pub mod database_tests {
     #[tokio::test]
     async fn database_owner_get() {
         let collection = "test";
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
 
         let ctx = Context::default().with_auth(Authorization::Owner);
         let mut tags = HashMap::default();
@@ -430,7 +1345,7 @@ pub mod database_tests {
     #[tokio::test]
     async fn database_user_get() {
         let collection = "test";
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
 
         let ctx = Context::default().with_auth(Authorization::Owner);
         let mut tags = HashMap::default();
@@ -441,7 +1356,7 @@ pub mod database_tests {
             users: vec![100],
         };
 
-        let acl_id = db.acl.create(&acl).unwrap();
+        let acl_id = db.acl.create(&acl).await.unwrap();
 
         let result = db
             .set(&ctx, collection, data.clone(), tags, Some(acl_id as u64))
@@ -502,7 +1417,7 @@ pub mod database_tests {
     #[tokio::test]
     async fn database_user_update() {
         let collection = "test";
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
 
         let ctx = Context::default().with_auth(Authorization::Owner);
         let mut tags = HashMap::default();
@@ -514,7 +1429,7 @@ pub mod database_tests {
             users: vec![100],
         };
 
-        let acl_id = db.acl.create(&acl).unwrap();
+        let acl_id = db.acl.create(&acl).await.unwrap();
 
         let result = db
             .set(&ctx, collection, data.clone(), tags, Some(acl_id as u64))
@@ -541,7 +1456,7 @@ pub mod database_tests {
             users: vec![100],
         };
 
-        db.acl.update(acl_id, &acl).unwrap();
+        db.acl.update(acl_id, &acl).await.unwrap();
 
         let ctx = Context::default().with_auth(Authorization::User(100)); //authorized user
         let mut tags = HashMap::default();
@@ -568,7 +1483,7 @@ pub mod database_tests {
     #[tokio::test]
     async fn database_update() {
         let collection = "test";
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
 
         let ctx = Context::default().with_auth(Authorization::Owner);
         let mut tags = HashMap::default();
@@ -634,10 +1549,517 @@ pub mod database_tests {
         assert_eq!(obj.data.unwrap(), data);
     }
 
+    #[tokio::test]
+    async fn database_chunked() {
+        let collection = "test";
+        let collections = MemoryCollections::new();
+        let mut db = BcdbDatabase::new(
+            Namespaces::new(collections.clone()).await.unwrap(),
+            MemoryIndex::new(),
+            ACLStorage::new(MemoryStorage::new()),
+        )
+        .with_chunk_size(4);
+
+        let ctx = Context::default().with_auth(Authorization::Owner);
+        let content: Vec<u8> = "hello chunked world".into();
+        let key = db
+            .set(&ctx, collection, content.clone(), HashMap::default(), None)
+            .await
+            .unwrap();
+
+        let obj = db.get(&ctx, key, collection).await.unwrap();
+        assert_eq!(obj.meta.size(), Some(content.len() as u64));
+        assert_eq!(obj.data.unwrap(), content);
+
+        let obj = db.fetch(&ctx, key).await.unwrap();
+        assert_eq!(obj.data.unwrap(), content);
+
+        db.delete(&ctx, key, collection).await.unwrap();
+        let data = collections.collection("objects-1").unwrap();
+        assert_eq!(data.keys().await.unwrap().count().await, 0);
+        let links = collections.collection("objects").unwrap();
+        assert_eq!(links.keys().await.unwrap().count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn database_get_many() {
+        let collections = MemoryCollections::new();
+        let index = MemoryIndex::new();
+
+        // an object written before collections were split
+        let shared = collections.collection("objects").unwrap();
+        let legacy = shared.set(None, b"legacy object").await.unwrap();
+        index
+            .set(legacy, Meta::default().with_collection("test"))
+            .await
+            .unwrap();
+
+        let mut db = BcdbDatabase::new(
+            Namespaces::new(collections.clone()).await.unwrap(),
+            index,
+            ACLStorage::new(MemoryStorage::new()),
+        )
+        .with_chunk_size(4);
+
+        let ctx = Context::default().with_auth(Authorization::Owner);
+        let chunked = db
+            .set(
+                &ctx,
+                "test",
+                "chunked object".into(),
+                HashMap::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        let other = db
+            .set(
+                &ctx,
+                "other",
+                "other object".into(),
+                HashMap::default(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let objects = db
+            .get_many(&ctx, vec![chunked, other, 100, legacy], "test")
+            .await
+            .unwrap();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0].key, chunked);
+        assert_eq!(objects[0].data, Some(b"chunked object".to_vec()));
+        assert_eq!(objects[0].meta.collection(), Some("test".into()));
+        assert_eq!(objects[1].key, legacy);
+        assert_eq!(objects[1].data, Some(b"legacy object".to_vec()));
+
+        // objects which can't be read are left out
+        let acl = ACL {
+            perm: "r--".parse().unwrap(),
+            users: vec![10],
+        };
+        let acl_id = db.acl.create(&acl).await.unwrap();
+        let shared = db
+            .set(
+                &ctx,
+                "test",
+                "shared object".into(),
+                HashMap::default(),
+                Some(acl_id as u64),
+            )
+            .await
+            .unwrap();
+
+        let ctx = Context::default().with_auth(Authorization::User(10));
+        let objects = db
+            .get_many(&ctx, vec![chunked, shared, legacy], "test")
+            .await
+            .unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].key, shared);
+        assert_eq!(objects[0].data, Some(b"shared object".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn database_versions() {
+        let collection = "test";
+        let collections = MemoryCollections::new();
+        let mut db = BcdbDatabase::new(
+            Namespaces::new(collections.clone()).await.unwrap(),
+            MemoryIndex::new(),
+            ACLStorage::new(MemoryStorage::new()),
+        )
+        .with_retention(Retention::new().with_collection("limited", 2));
+
+        let ctx = Context::default().with_auth(Authorization::Owner);
+        let key = db
+            .set(&ctx, collection, "first".into(), HashMap::default(), None)
+            .await
+            .unwrap();
+
+        let versions = db.versions(&ctx, key, collection).await.unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].version, 1);
+        assert_eq!(versions[0].size, 5);
+
+        for data in &["second version", "third"] {
+            db.update(
+                &ctx,
+                key,
+                collection,
+                Some(data.as_bytes().into()),
+                HashMap::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        }
+
+        // tag updates don't create versions
+        let mut tags = HashMap::default();
+        tags.insert("tag".into(), "value".into());
+        db.update(&ctx, key, collection, None, tags, None)
+            .await
+            .unwrap();
+
+        let versions = db.versions(&ctx, key, collection).await.unwrap();
+        let sizes: Vec<(u64, u64)> = versions.iter().map(|v| (v.version, v.size)).collect();
+        assert_eq!(sizes, vec![(1, 5), (2, 14), (3, 5)]);
+
+        let obj = db.get(&ctx, key, collection).await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"third".to_vec());
+
+        let obj = db.get_version(&ctx, key, collection, 2).await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"second version".to_vec());
+        assert_eq!(obj.meta.size(), Some(14));
+        assert_eq!(obj.meta.version(), Some(2));
+        assert_eq!(obj.meta.collection(), Some(collection.into()));
+
+        // past versions keep the tags they had, the tag was set on the
+        // current version only
+        assert_eq!(obj.meta.get("tag"), None);
+
+        let obj = db.get_version(&ctx, key, collection, 1).await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"first".to_vec());
+        assert_eq!(obj.meta.size(), Some(5));
+
+        let obj = db.get_version(&ctx, key, collection, 3).await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"third".to_vec());
+        assert_eq!(obj.meta.get("tag").unwrap(), "value");
+
+        let result = db
+            .get_version(&ctx, key, collection, 4)
+            .await
+            .map_err(|e| Reason::from(&e));
+        assert_eq!(result.err(), Some(Reason::NotFound));
+
+        let result = db
+            .versions(&Context::default(), key, collection)
+            .await
+            .map_err(|e| Reason::from(&e));
+        assert_eq!(result.err(), Some(Reason::Unauthorized));
+
+        // the retention policy of a collection drops the oldest versions
+        let limited = db
+            .set(&ctx, "limited", "first".into(), HashMap::default(), None)
+            .await
+            .unwrap();
+        for data in &["second", "third"] {
+            db.update(
+                &ctx,
+                limited,
+                "limited",
+                Some(data.as_bytes().into()),
+                HashMap::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        }
+
+        let versions = db.versions(&ctx, limited, "limited").await.unwrap();
+        let numbers: Vec<u64> = versions.iter().map(|v| v.version).collect();
+        assert_eq!(numbers, vec![2, 3]);
+        let result = db
+            .get_version(&ctx, limited, "limited", 1)
+            .await
+            .map_err(|e| Reason::from(&e));
+        assert_eq!(result.err(), Some(Reason::NotFound));
+
+        // the second and third version, and the history
+        let data = collections.collection("objects-2").unwrap();
+        assert_eq!(data.keys().await.unwrap().count().await, 3);
+
+        // deleting an object drops all its versions
+        db.delete(&ctx, key, collection).await.unwrap();
+        let data = collections.collection("objects-1").unwrap();
+        assert_eq!(data.keys().await.unwrap().count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn database_versions_legacy() {
+        let collections = MemoryCollections::new();
+        let index = MemoryIndex::new();
+
+        // an object written before collections were split
+        let shared = collections.collection("objects").unwrap();
+        let legacy = shared.set(None, b"legacy object").await.unwrap();
+        index
+            .set(
+                legacy,
+                Meta::default().with_collection("test").with_size(13),
+            )
+            .await
+            .unwrap();
+
+        let mut db = BcdbDatabase::new(
+            Namespaces::new(collections.clone()).await.unwrap(),
+            index,
+            ACLStorage::new(MemoryStorage::new()),
+        );
+
+        let ctx = Context::default().with_auth(Authorization::Owner);
+        db.update(
+            &ctx,
+            legacy,
+            "test",
+            Some("updated".into()),
+            HashMap::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // the object is moved to its collection, and keeps its key
+        let obj = db.get(&ctx, legacy, "test").await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"updated".to_vec());
+        let obj = db.get_version(&ctx, legacy, "test", 1).await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"legacy object".to_vec());
+        assert_eq!(obj.meta.size(), Some(13));
+    }
+
+    #[tokio::test]
+    async fn database_update_concurrent() {
+        let collection = "test";
+        let collections = MemoryCollections::new();
+        let db = BcdbDatabase::new(
+            Namespaces::new(collections.clone()).await.unwrap(),
+            MemoryIndex::new(),
+            ACLStorage::new(MemoryStorage::new()),
+        )
+        .with_retention(Retention::new().with_default(11));
+
+        let ctx = Context::default().with_auth(Authorization::Owner);
+        let key = db
+            .clone()
+            .set(&ctx, collection, "first".into(), HashMap::default(), None)
+            .await
+            .unwrap();
+
+        let updates = (0..10).map(|idx| {
+            let mut db = db.clone();
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let data = format!("version {}", idx + 2);
+                db.update(
+                    &ctx,
+                    key,
+                    collection,
+                    Some(data.into()),
+                    HashMap::default(),
+                    None,
+                )
+                .await
+            })
+        });
+
+        for result in futures::future::join_all(updates).await {
+            result.unwrap().unwrap();
+        }
+
+        // every update kept the version it replaced
+        let mut db = db;
+        let versions = db.versions(&ctx, key, collection).await.unwrap();
+        let numbers: Vec<u64> = versions.iter().map(|v| v.version).collect();
+        assert_eq!(numbers, (1..=11).collect::<Vec<u64>>());
+
+        // all versions and a single history record are left
+        let data = collections.collection("objects-1").unwrap();
+        assert_eq!(data.keys().await.unwrap().count().await, 12);
+    }
+
+    #[tokio::test]
+    async fn database_stream() {
+        let collection = "test";
+        let collections = MemoryCollections::new();
+        let mut db = BcdbDatabase::new(
+            Namespaces::new(collections.clone()).await.unwrap(),
+            MemoryIndex::new(),
+            ACLStorage::new(MemoryStorage::new()),
+        )
+        .with_chunk_size(4);
+        let data = collections.collection("objects-1").unwrap();
+        let staging = collections.collection("uploads").unwrap();
+
+        let ctx = Context::default().with_auth(Authorization::Owner);
+        let (mut tx, rx) = mpsc::channel(10);
+        for part in &["hello", " stre", "amed world"] {
+            tx.send(Ok(Part::Data(part.as_bytes().into())))
+                .await
+                .unwrap();
+        }
+        tx.send(Ok(Part::Meta {
+            collection: collection.into(),
+            tags: HashMap::default(),
+            acl: None,
+        }))
+        .await
+        .unwrap();
+        drop(tx);
+
+        let key = db.set_stream(&ctx, rx).await.unwrap();
+        // 5 chunks and the manifest, the staged chunks are moved to the collection
+        assert_eq!(data.keys().await.unwrap().count().await, 6);
+        assert_eq!(staging.keys().await.unwrap().count().await, 0);
+
+        let obj = db.get(&ctx, key, collection).await.unwrap();
+        assert_eq!(obj.meta.size(), Some(20));
+        assert_eq!(obj.data.unwrap(), b"hello streamed world".to_vec());
+
+        let (obj, chunks) = db.get_stream(&ctx, key, collection).await.unwrap();
+        assert_eq!(obj.meta.collection().unwrap(), collection);
+
+        let chunks: Vec<Result<Vec<u8>>> = chunks.collect().await;
+        assert_eq!(chunks.len(), 5);
+        let content: Vec<u8> = chunks.into_iter().map(|c| c.unwrap()).flatten().collect();
+        assert_eq!(content, b"hello streamed world".to_vec());
+
+        // an upload without metadata leaves nothing behind
+        let (mut tx, rx) = mpsc::channel(10);
+        tx.send(Ok(Part::Data("lost data".into()))).await.unwrap();
+        drop(tx);
+
+        assert_eq!(db.set_stream(&ctx, rx).await.is_err(), true);
+        assert_eq!(data.keys().await.unwrap().count().await, 6);
+        assert_eq!(staging.keys().await.unwrap().count().await, 0);
+
+        // chunks are written to the collection directly if the metadata comes first
+        let (mut tx, rx) = mpsc::channel(10);
+        tx.send(Ok(Part::Meta {
+            collection: collection.into(),
+            tags: HashMap::default(),
+            acl: None,
+        }))
+        .await
+        .unwrap();
+        tx.send(Ok(Part::Data("metadata first".into())))
+            .await
+            .unwrap();
+        drop(tx);
+
+        let key = db.set_stream(&ctx, rx).await.unwrap();
+        let obj = db.get(&ctx, key, collection).await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"metadata first".to_vec());
+        assert_eq!(data.keys().await.unwrap().count().await, 6 + 5);
+        assert_eq!(staging.keys().await.unwrap().count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn database_update_stream() {
+        let collection = "test";
+        let collections = MemoryCollections::new();
+        let mut db = BcdbDatabase::new(
+            Namespaces::new(collections.clone()).await.unwrap(),
+            MemoryIndex::new(),
+            ACLStorage::new(MemoryStorage::new()),
+        )
+        .with_chunk_size(4);
+
+        let ctx = Context::default().with_auth(Authorization::Owner);
+        let key = db
+            .set(&ctx, collection, "small".into(), HashMap::default(), None)
+            .await
+            .unwrap();
+
+        let (mut tx, rx) = mpsc::channel(10);
+        for part in &["a larger", " streamed", " update"] {
+            tx.send(Ok(part.as_bytes().into())).await.unwrap();
+        }
+        drop(tx);
+
+        db.update_stream(&ctx, key, collection, rx, HashMap::default(), None)
+            .await
+            .unwrap();
+
+        let obj = db.get(&ctx, key, collection).await.unwrap();
+        assert_eq!(obj.meta.size(), Some(24));
+        assert_eq!(obj.data.unwrap(), b"a larger streamed update".to_vec());
+
+        // an empty stream only updates the tags
+        let (tx, rx) = mpsc::channel(10);
+        drop(tx);
+
+        let mut tags = HashMap::default();
+        tags.insert("tag".into(), "value".into());
+        db.update_stream(&ctx, key, collection, rx, tags, None)
+            .await
+            .unwrap();
+
+        let versions = db.versions(&ctx, key, collection).await.unwrap();
+        let sizes: Vec<(u64, u64)> = versions.iter().map(|v| (v.version, v.size)).collect();
+        assert_eq!(sizes, vec![(1, 5), (2, 24)]);
+
+        let obj = db.get(&ctx, key, collection).await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"a larger streamed update".to_vec());
+
+        // a failed stream leaves the object as it was
+        let (mut tx, rx) = mpsc::channel(10);
+        tx.send(Ok("lost".into())).await.unwrap();
+        tx.send(Err(format_err!("broken body"))).await.unwrap();
+        drop(tx);
+
+        let result = db
+            .update_stream(&ctx, key, collection, rx, HashMap::default(), None)
+            .await;
+        assert_eq!(result.is_err(), true);
+
+        let versions = db.versions(&ctx, key, collection).await.unwrap();
+        assert_eq!(versions.len(), 2);
+        let obj = db.get(&ctx, key, collection).await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"a larger streamed update".to_vec());
+    }
+
+    #[tokio::test]
+    async fn database_migrate() {
+        let collections = MemoryCollections::new();
+        let index = MemoryIndex::new();
+
+        // an object written before collections were split
+        let shared = collections.collection("objects").unwrap();
+        let legacy = shared.set(None, b"legacy object").await.unwrap();
+        index
+            .set(legacy, Meta::default().with_collection("test"))
+            .await
+            .unwrap();
+
+        let mut db = BcdbDatabase::new(
+            Namespaces::new(collections.clone()).await.unwrap(),
+            index,
+            ACLStorage::new(MemoryStorage::new()),
+        );
+
+        let ctx = Context::default().with_auth(Authorization::Owner);
+        let key = db
+            .set(&ctx, "test", "new object".into(), HashMap::default(), None)
+            .await
+            .unwrap();
+        assert_ne!(key, legacy);
+
+        let obj = db.fetch(&ctx, legacy).await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"legacy object".to_vec());
+
+        assert_eq!(db.migrate().await.unwrap(), 1);
+        assert_eq!(db.migrate().await.unwrap(), 0);
+
+        // both objects are stored in the collection, and keep their keys
+        let data = collections.collection("objects-1").unwrap();
+        assert_eq!(data.keys().await.unwrap().count().await, 2);
+
+        let obj = db.fetch(&ctx, legacy).await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"legacy object".to_vec());
+        let obj = db.fetch(&ctx, key).await.unwrap();
+        assert_eq!(obj.data.unwrap(), b"new object".to_vec());
+
+        db.delete(&ctx, legacy, "test").await.unwrap();
+        assert_eq!(data.keys().await.unwrap().count().await, 1);
+        assert_eq!(shared.get(legacy).await.unwrap(), None);
+    }
+
     #[tokio::test]
     async fn database_insert_perf() {
         let collection = "test";
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
 
         let ctx = Context::default().with_auth(Authorization::Owner);
         let mut tags = HashMap::default();
@@ -645,7 +2067,7 @@ pub mod database_tests {
         let data: Vec<u8> = "hello world".into();
 
         let now = std::time::Instant::now();
-        for i in 0..10000u32 {
+        for _ in 0..10000 {
             db.set(&ctx, collection, data.clone(), tags.clone(), None)
                 .await
                 .expect("failed to do insert");
@@ -1,7 +1,9 @@
+use super::page::SortKey;
 use super::*;
-use crate::storage::Storage;
+use crate::storage::{AsyncStorage, Error as StorageError, Record};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use sqlx::prelude::*;
@@ -9,7 +11,6 @@ use sqlx::SqlitePool;
 use std::ops::Deref;
 use std::sync::Arc;
 use tokio::sync::{mpsc, RwLock};
-use tokio::task::spawn_blocking;
 
 pub struct SqliteIndexBuilder {
     root: String,
@@ -76,8 +77,20 @@ impl Index for SqliteIndex {
         self.schema.get(key).await
     }
 
-    async fn find(&self, meta: Meta) -> Result<mpsc::Receiver<Result<Key>>> {
-        self.schema.find(meta).await
+    async fn find(&self, query: Query) -> Result<mpsc::Receiver<Result<Key>>> {
+        self.schema.find(query).await
+    }
+
+    async fn count(&self, query: Query) -> Result<u64> {
+        self.schema.count(query).await
+    }
+
+    async fn aggregate(&self, tag: &str, query: Query) -> Result<Aggregate> {
+        self.schema.aggregate(tag, query).await
+    }
+
+    async fn facets(&self, tag: &str, query: Query, limit: Option<usize>) -> Result<Vec<Facet>> {
+        self.schema.facets(tag, query, limit).await
     }
 }
 
@@ -115,48 +128,161 @@ impl Schema {
         .execute(db.deref())
         .await?;
 
+        Self::migrate(db.deref()).await
+    }
+
+    /// migrate brings an index created by an older version up to date. The
+    /// schema version is tracked with the sqlite `user_version` pragma.
+    async fn migrate(db: &SqlitePool) -> Result<()> {
+        #[derive(sqlx::FromRow, Debug)]
+        struct Row {
+            user_version: i32,
+        }
+
+        let row = sqlx::query("PRAGMA user_version").fetch_one(db).await?;
+        let version = Row::from_row(&row)?.user_version;
+
+        if version < 1 {
+            // version 0 stored keys as floats, which can't represent 64 bit
+            // keys exactly. Make sure all keys are stored as integers.
+            info!("migrating index schema to version 1");
+            sqlx::query(
+                "
+                UPDATE metadata SET key = CAST(key AS INTEGER) WHERE typeof(key) = 'real';
+                PRAGMA user_version = 1;
+                ",
+            )
+            .execute(db)
+            .await
+            .context("failed to migrate index schema")?;
+        }
+
+        if version < 2 {
+            // version 2 also stores the numeric value of tags, so they can be
+            // compared as numbers. Tags had no types before, so only the system
+            // tags which hold numbers get a numeric value
+            info!("migrating index schema to version 2");
+            Self::migrate_numbers(db)
+                .await
+                .context("failed to migrate index schema")?;
+        }
+
+        if version < 3 {
+            // version 3 indexes the values of every tag, so prefix and glob
+            // queries on a tag only scan the matching range of its values
+            info!("migrating index schema to version 3");
+            sqlx::query(
+                "
+                CREATE INDEX IF NOT EXISTS metadata_tag_value ON metadata (tag, value);
+                PRAGMA user_version = 3;
+                ",
+            )
+            .execute(db)
+            .await
+            .context("failed to migrate index schema")?;
+        }
+
+        Ok(())
+    }
+
+    async fn migrate_numbers(db: &SqlitePool) -> Result<()> {
+        #[derive(sqlx::FromRow, Debug)]
+        struct Row {
+            id: i64,
+            tag: String,
+            value: String,
+        }
+
+        let mut tx = db.begin().await?;
+        sqlx::query(
+            "
+            ALTER TABLE metadata ADD COLUMN number NUMERIC;
+            CREATE INDEX IF NOT EXISTS metadata_number ON metadata (tag, number);
+            ",
+        )
+        .execute(&mut tx)
+        .await?;
+
+        let rows = sqlx::query("SELECT rowid AS id, tag, value FROM metadata")
+            .fetch_all(&mut tx)
+            .await?;
+        for row in rows {
+            let row = Row::from_row(&row)?;
+            if NUMBER_TAGS.contains(&row.tag.as_str()) && Number::parse(&row.value).is_some() {
+                sqlx::query("UPDATE metadata SET number = CAST(value AS NUMERIC) WHERE rowid = ?")
+                    .bind(row.id)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+
+        sqlx::query("PRAGMA user_version = 2")
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
         Ok(())
     }
 
     async fn insert(&self, key: Key, tags: Meta) -> Result<()> {
         let db = self.c.write().await;
+        // all tags are set at once, so a failure never leaves part of them
+        let mut tx = db.begin().await?;
         for (k, v) in tags {
             sqlx::query(
                 "
-                INSERT INTO metadata (key, tag, value) values
-                (?, ?, ?)
+                INSERT INTO metadata (key, tag, value, number) values
+                (?, ?, ?, CAST(? AS NUMERIC))
                 ON CONFLICT (key, tag)
-                DO UPDATE SET value = ?;
+                DO UPDATE SET value = excluded.value, number = excluded.number;
                 ",
             )
-            .bind(key as f64) // only f64 is supported by sqlite.
+            .bind(key as i64)
             .bind(&k)
-            .bind(&v)
-            .bind(&v)
-            .execute(db.deref())
+            .bind(v.as_str())
+            // only numbers are stored in the number column, text is never
+            // compared as a number
+            .bind(match &v {
+                Value::Number(number) => Some(number.clone()),
+                Value::Text(_) => None,
+            })
+            .execute(&mut tx)
             .await
             .context("failed to insert data to index")?;
         }
 
+        tx.commit()
+            .await
+            .context("failed to insert data to index")?;
+
         Ok(())
     }
 
     async fn get(&self, key: Key) -> Result<Meta> {
         let db = self.c.read().await;
-        let mut cur = sqlx::query("SELECT tag, value FROM metadata WHERE key = ?")
-            .bind(key as f64)
-            .fetch(db.deref());
+        // tags with a numeric value were set as numbers
+        let mut cur = sqlx::query(
+            "SELECT tag, value, number IS NOT NULL AS is_number FROM metadata WHERE key = ?",
+        )
+        .bind(key as i64)
+        .fetch(db.deref());
 
         #[derive(sqlx::FromRow, Debug)]
         struct Row {
             tag: String,
             value: String,
+            is_number: i64,
         }
 
         let mut meta = Meta::default();
         while let Some(row) = cur.next().await? {
             let row = Row::from_row(&row)?;
-            meta.insert(row.tag, row.value);
+            let value = if row.is_number != 0 {
+                Value::Number(row.value)
+            } else {
+                Value::Text(row.value)
+            };
+            meta.insert(row.tag, value);
         }
 
         Ok(meta)
@@ -172,31 +298,175 @@ impl Schema {
         Ok(())
     }
 
-    async fn find<'a>(&'a self, meta: Meta) -> Result<mpsc::Receiver<Result<Key>>> {
-        let q = "SELECT key FROM metadata WHERE tag = ? AND value = ?";
-        let mut query_str = String::new();
+    /// the sql condition on the value of a tag, and the values to bind. Numbers
+    /// are bound as text, and cast so they are compared as numbers. Prefix and
+    /// glob patterns are bounded by the range of values which start with their
+    /// literal prefix, so only that range of the tag values index is scanned.
+    fn condition(condition: &Condition) -> (String, Vec<String>) {
+        let (condition, values) = match condition {
+            Condition::Equal(value) => ("value = ?", vec![value.clone()]),
+            Condition::Less(n) => ("number < CAST(? AS NUMERIC)", vec![n.to_string()]),
+            Condition::LessEqual(n) => ("number <= CAST(? AS NUMERIC)", vec![n.to_string()]),
+            Condition::Greater(n) => ("number > CAST(? AS NUMERIC)", vec![n.to_string()]),
+            Condition::GreaterEqual(n) => ("number >= CAST(? AS NUMERIC)", vec![n.to_string()]),
+            Condition::Between(lower, upper) => (
+                "number BETWEEN CAST(? AS NUMERIC) AND CAST(? AS NUMERIC)",
+                vec![lower.to_string(), upper.to_string()],
+            ),
+            Condition::Prefix(prefix) => return Self::range(prefix),
+            Condition::Glob(pattern) => {
+                let (range, mut values) = Self::range(query::glob_prefix(pattern));
+                values.push(pattern.clone());
+                return (format!("{} AND value GLOB ?", range), values);
+            }
+        };
+
+        (condition.into(), values)
+    }
+
+    /// the sql condition on the values which start with a prefix
+    fn range(prefix: &str) -> (String, Vec<String>) {
+        match successor(prefix) {
+            Some(upper) => (
+                "value >= ? AND value < ?".into(),
+                vec![prefix.into(), upper],
+            ),
+            None => ("value >= ?".into(), vec![prefix.into()]),
+        }
+    }
+
+    /// the sql statement which selects the keys matching the terms of a query,
+    /// and the values to bind. The page of the query is ignored.
+    fn matching(query: &Query) -> (String, Vec<String>) {
+        let mut values = vec![];
+        let query_str = match query.terms() {
+            [] => "SELECT DISTINCT key FROM metadata".into(),
+            terms => Self::compound(terms, "INTERSECT", &mut values),
+        };
+
+        (query_str, values)
+    }
 
-        for _ in 0..meta.count() {
-            if query_str.len() > 0 {
-                query_str.push_str(" intersect ");
+    /// the sql statement which selects the keys matching a query, and the
+    /// values to bind
+    fn select(query: &Query) -> (String, Vec<String>) {
+        let (query_str, values) = Self::matching(query);
+        let page = query.page();
+        if *page == Page::default() {
+            return (query_str, values);
+        }
+
+        Self::paginate(query_str, values, page)
+    }
+
+    /// sorts the keys selected by a statement, and keeps the ones of the page.
+    /// Keys are sorted by the value of the sort tag like `SortKey`, so objects
+    /// which don't have the tag get an empty blob, which sorts after numbers and
+    /// text.
+    fn paginate(select: String, mut values: Vec<String>, page: &Page) -> (String, Vec<String>) {
+        let sorted = match page.sort_by() {
+            Some(tag) => {
+                values.push(tag.into());
+                format!(
+                    "SELECT m.key AS key, COALESCE(s.number, s.value, x'') AS sort
+                    FROM ({}) AS m LEFT JOIN metadata AS s ON s.key = m.key AND s.tag = ?",
+                    select
+                )
             }
-            query_str.push_str(q);
+            None => format!("SELECT key, x'' AS sort FROM ({})", select),
+        };
+
+        let (direction, after) = match page.order() {
+            Order::Ascending => ("ASC", ">"),
+            Order::Descending => ("DESC", "<"),
+        };
+
+        let mut query_str = format!("SELECT key FROM ({})", sorted);
+        if let Some(cursor) = page.after() {
+            let (sort, bound) = match &cursor.sort {
+                SortKey::Number(n) => ("CAST(? AS NUMERIC)", Some(n.to_string())),
+                SortKey::Text(text) => ("?", Some(text.clone())),
+                SortKey::Missing => ("x''", None),
+            };
+
+            query_str.push_str(&format!(
+                " WHERE sort {after} {sort} OR (sort = {sort} AND key {after} {key})",
+                after = after,
+                sort = sort,
+                key = cursor.key as i64,
+            ));
+            values.extend(bound.clone());
+            values.extend(bound);
         }
 
-        if query_str.len() == 0 {
-            //no tags where provided
-            query_str.push_str("SELECT DISTINCT key FROM metadata");
+        query_str.push_str(&format!(
+            " ORDER BY sort {direction}, key {direction}",
+            direction = direction
+        ));
+        if let Some(limit) = page.limit() {
+            query_str.push_str(&format!(" LIMIT {}", limit));
         }
+
+        (query_str, values)
+    }
+
+    /// compiles an expression to a select statement of the matching keys.
+    /// Every predicate selects its keys from the tag values index, and the
+    /// results are combined with set operations, so objects are never scanned.
+    fn compile(expr: &Expr, values: &mut Vec<String>) -> String {
+        match expr {
+            Expr::Filter(filter) => {
+                let (condition, bounds) = Self::condition(&filter.condition);
+                values.push(filter.tag.clone());
+                values.extend(bounds);
+                format!("SELECT key FROM metadata WHERE tag = ? AND {}", condition)
+            }
+            Expr::Exists(tag) => {
+                values.push(tag.clone());
+                "SELECT key FROM metadata WHERE tag = ?".into()
+            }
+            Expr::And(exprs) if exprs.is_empty() => "SELECT DISTINCT key FROM metadata".into(),
+            Expr::Or(exprs) if exprs.is_empty() => "SELECT key FROM metadata WHERE 0".into(),
+            Expr::And(exprs) => Self::compound(exprs, "INTERSECT", values),
+            Expr::Or(exprs) => Self::compound(exprs, "UNION", values),
+            Expr::Not(expr) => format!(
+                "SELECT key FROM metadata EXCEPT {}",
+                Self::member(expr, values)
+            ),
+        }
+    }
+
+    fn compound(exprs: &[Expr], operator: &str, values: &mut Vec<String>) -> String {
+        let members: Vec<String> = exprs
+            .iter()
+            .map(|expr| Self::member(expr, values))
+            .collect();
+
+        members.join(&format!(" {} ", operator))
+    }
+
+    /// compiles an expression to a simple select, which can be combined with
+    /// other selects. sqlite evaluates compound selects from left to right,
+    /// so compound selects are nested as subqueries.
+    fn member(expr: &Expr, values: &mut Vec<String>) -> String {
+        match expr {
+            Expr::Filter(_) | Expr::Exists(_) => Self::compile(expr, values),
+            _ => format!("SELECT key FROM ({})", Self::compile(expr, values)),
+        }
+    }
+
+    async fn find<'a>(&'a self, query: Query) -> Result<mpsc::Receiver<Result<Key>>> {
+        let (query_str, values) = Self::select(&query);
         #[derive(sqlx::FromRow, Debug)]
         struct Row {
-            key: f64,
+            key: i64,
         }
         let (mut tx, rx) = mpsc::channel(10);
         let pool = self.c.clone();
         tokio::spawn(async move {
             let mut query = sqlx::query(&query_str);
-            for (k, v) in meta {
-                query = query.bind(k).bind(v);
+            for value in values {
+                query = query.bind(value);
             }
             let db = pool.read().await;
             let mut cur = query.fetch(db.deref());
@@ -206,10 +476,10 @@ impl Schema {
                     Err(err) => Err(format_err!("{}", err)),
                     Ok(row) => match row {
                         None => break, // end of results
-                        Some(row) => {
-                            let row = Row::from_row(&row).unwrap();
-                            Ok(row.key as Key)
-                        }
+                        Some(row) => match Row::from_row(&row) {
+                            Ok(row) => Ok(row.key as Key),
+                            Err(err) => Err(format_err!("{}", err)),
+                        },
                     },
                 };
 
@@ -225,18 +495,134 @@ impl Schema {
 
         Ok(rx)
     }
+
+    async fn count(&self, query: Query) -> Result<u64> {
+        let (select, values) = Self::matching(&query);
+        let query_str = format!("SELECT COUNT(*) AS count FROM ({})", select);
+        #[derive(sqlx::FromRow, Debug)]
+        struct Row {
+            count: i64,
+        }
+
+        let mut query = sqlx::query(&query_str);
+        for value in values {
+            query = query.bind(value);
+        }
+
+        let db = self.c.read().await;
+        let row = query.fetch_one(db.deref()).await?;
+        let row = Row::from_row(&row)?;
+
+        Ok(row.count as u64)
+    }
+
+    /// aggregates the numbers of a tag over the matching keys. min and max are
+    /// returned as text, so integers and floats keep their type.
+    async fn aggregate(&self, tag: &str, query: Query) -> Result<Aggregate> {
+        let (select, mut values) = Self::matching(&query);
+        values.push(tag.into());
+        let query_str = format!(
+            "SELECT COUNT(s.number) AS count, TOTAL(s.number) AS sum,
+                CAST(MIN(s.number) AS TEXT) AS min, CAST(MAX(s.number) AS TEXT) AS max
+            FROM ({}) AS m JOIN metadata AS s ON s.key = m.key AND s.tag = ?",
+            select
+        );
+        #[derive(sqlx::FromRow, Debug)]
+        struct Row {
+            count: i64,
+            sum: f64,
+            min: Option<String>,
+            max: Option<String>,
+        }
+
+        let mut query = sqlx::query(&query_str);
+        for value in values {
+            query = query.bind(value);
+        }
+
+        let db = self.c.read().await;
+        let row = query.fetch_one(db.deref()).await?;
+        let row = Row::from_row(&row)?;
+
+        Ok(Aggregate {
+            count: row.count as u64,
+            sum: row.sum,
+            min: row.min.as_ref().and_then(|min| Number::parse(min)),
+            max: row.max.as_ref().and_then(|max| Number::parse(max)),
+        })
+    }
+
+    /// groups the matching keys by the value of a tag. Values which are equally
+    /// common are ordered by value, like `Facets`.
+    async fn facets(&self, tag: &str, query: Query, limit: Option<usize>) -> Result<Vec<Facet>> {
+        let (select, mut values) = Self::matching(&query);
+        values.push(tag.into());
+        let mut query_str = format!(
+            "SELECT s.value AS value, COUNT(*) AS count
+            FROM ({}) AS m JOIN metadata AS s ON s.key = m.key AND s.tag = ?
+            GROUP BY s.value ORDER BY count DESC, s.value ASC",
+            select
+        );
+        if let Some(limit) = limit {
+            query_str.push_str(&format!(" LIMIT {}", limit));
+        }
+
+        #[derive(sqlx::FromRow, Debug)]
+        struct Row {
+            value: String,
+            count: i64,
+        }
+
+        let mut query = sqlx::query(&query_str);
+        for value in values {
+            query = query.bind(value);
+        }
+
+        let db = self.c.read().await;
+        let rows = query.fetch_all(db.deref()).await?;
+        let mut facets = vec![];
+        for row in rows {
+            let row = Row::from_row(&row)?;
+            facets.push(Facet {
+                value: row.value,
+                count: row.count as u64,
+            });
+        }
+
+        Ok(facets)
+    }
 }
 
+/// the smallest string which is greater than all strings starting with the
+/// prefix, `None` if there is no such string
+fn successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // utf-8 preserves the order of code points, so the next code point
+        // sorts right after all strings starting with the prefix
+        let next = (last as u32 + 1..=std::char::MAX as u32).find_map(std::char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+
+    None
+}
+
+/// an entry of the metadata log, the tags are serialized with their types
 #[derive(Serialize)]
 struct ZdbMetaSer<'a> {
     key: Key,
-    tags: &'a HashMap<String, String>,
+    #[serde(flatten)]
+    meta: &'a Meta,
 }
 
 #[derive(Deserialize)]
 struct ZdbMetaDe {
     key: Key,
-    tags: HashMap<String, String>,
+    #[serde(flatten)]
+    meta: Meta,
 }
 
 /// An index interceptor that also stores the metadata in
@@ -245,7 +631,7 @@ struct ZdbMetaDe {
 pub struct MetaInterceptor<I, S>
 where
     I: Index,
-    S: Storage,
+    S: AsyncStorage,
 {
     inner: I,
     storage: S,
@@ -254,7 +640,7 @@ where
 impl<I, S> MetaInterceptor<I, S>
 where
     I: Index,
-    S: Storage,
+    S: AsyncStorage,
 {
     /// creates a new instance of the zdb interceptor
     pub fn new(index: I, storage: S) -> Self {
@@ -268,25 +654,34 @@ where
 impl<I, S> MetaInterceptor<I, S>
 where
     I: Index,
-    S: Storage,
+    S: AsyncStorage,
 {
     /// rebuild index by scanning the storage database for entries,
     /// and calling set on the index store. Optionally start scanning from
     /// from.
     pub async fn rebuild(&mut self, from: Option<u32>) -> Result<()> {
         match from {
-            None => self.rebuild_all().await,
+            None => self.rebuild_after(None).await,
             Some(ts) => self.rebuild_from(ts).await,
         }
     }
 
+    /// resume a rebuild that failed, by scanning the entries which follow
+    /// the given metadata key.
+    pub async fn resume(&mut self, after: Key) -> Result<()> {
+        self.rebuild_after(Some(after)).await
+    }
+
     async fn rebuild_from(&mut self, from: u32) -> Result<()> {
         let mut start = None;
+        let mut after = None;
         // we try to find the first KEY that is created after this timestamp
         // we iterate backwards, check each key insertion time, until we hit a key
-        // that was created before this time. Once we have found the one, we use the
-        // first key after this one, and continue scanning from there.
-        for r in self.storage.rev()? {
+        // that was created before this time. Once we have found the one, we
+        // continue scanning forward after this one.
+        let mut records = self.storage.rev().await?;
+        while let Some(r) = records.next().await {
+            let r = r.context("failed to scan metadata")?;
             let ts = match r.timestamp {
                 Some(ts) => ts,
                 None => {
@@ -295,6 +690,7 @@ where
             };
 
             if ts < from {
+                after = Some(r.key);
                 break;
             }
 
@@ -305,40 +701,49 @@ where
             return Ok(());
         }
 
-        let mut key = start.unwrap();
+        self.rebuild_after(after).await
+    }
 
-        loop {
-            let data = match self.storage.get(key)? {
-                Some(data) => data,
-                None => {
-                    break; // we hit the end
-                }
-            };
+    async fn rebuild_after(&mut self, after: Option<Key>) -> Result<()> {
+        let mut records = match after {
+            Some(key) => self.storage.keys_from(key).await?,
+            None => self.storage.keys().await?,
+        };
 
-            let obj = serde_json::from_slice::<ZdbMetaDe>(&data)?;
-            self.inner.set(obj.key, Meta::new(obj.tags)).await?;
-            key += 1;
+        let mut last = after;
+        while let Some(record) = records.next().await {
+            match self.rebuild_record(record).await {
+                Ok(key) => last = Some(key),
+                Err(err) => {
+                    return Err(match last {
+                        Some(key) => err.context(format!(
+                            "rebuild failed, it can be resumed after metadata key '{}'",
+                            key
+                        )),
+                        None => err.context("rebuild failed"),
+                    })
+                }
+            }
         }
 
         Ok(())
     }
 
-    async fn rebuild_all(&mut self) -> Result<()> {
-        for k in self.storage.keys()? {
-            let data = match self.storage.get(k.key)? {
-                Some(data) => data,
-                None => {
-                    warn!("metadata with key '{}' not found", k.key);
-                    continue;
-                }
-            };
-
-            let obj = serde_json::from_slice::<ZdbMetaDe>(&data)?;
+    /// index a single metadata entry, returns the key of the entry
+    async fn rebuild_record(&mut self, record: Result<Record, StorageError>) -> Result<Key> {
+        let key = record.context("failed to scan metadata")?.key;
+        let data = match self.storage.get(key).await? {
+            Some(data) => data,
+            None => {
+                warn!("metadata with key '{}' not found", key);
+                return Ok(key);
+            }
+        };
 
-            self.inner.set(obj.key, Meta::new(obj.tags)).await?;
-        }
+        let obj = serde_json::from_slice::<ZdbMetaDe>(&data)?;
+        self.inner.set(obj.key, obj.meta).await?;
 
-        Ok(())
+        Ok(key)
     }
 }
 
@@ -346,36 +751,59 @@ where
 impl<I, S> Index for MetaInterceptor<I, S>
 where
     I: Index,
-    S: Storage + Send + Sync + 'static,
+    S: AsyncStorage,
 {
     async fn set(&self, key: Key, meta: Meta) -> Result<()> {
         let m = ZdbMetaSer {
             key: key,
-            tags: &meta.0,
+            meta: &meta,
         };
 
         let bytes = serde_json::to_vec(&m)?;
-        let db = self.storage.clone();
-        spawn_blocking(move || db.set(None, &bytes))
+        let entry = self
+            .storage
+            .set(None, &bytes)
             .await
-            .context("failed to run blocking task")?
             .context("failed to set metadata")?;
 
-        self.inner.set(key, meta).await
+        // the entry is dropped again if the index is not updated, so a rebuild
+        // does not bring back metadata that was never set
+        if let Err(err) = self.inner.set(key, meta).await {
+            if let Err(err) = self.storage.delete(entry).await {
+                warn!("failed to delete metadata entry '{}': {}", entry, err);
+            }
+
+            return Err(err);
+        }
+
+        Ok(())
     }
 
     async fn get(&self, key: Key) -> Result<Meta> {
         self.inner.get(key).await
     }
 
-    async fn find(&self, meta: Meta) -> Result<mpsc::Receiver<Result<Key>>> {
-        self.inner.find(meta).await
+    async fn find(&self, query: Query) -> Result<mpsc::Receiver<Result<Key>>> {
+        self.inner.find(query).await
+    }
+
+    async fn count(&self, query: Query) -> Result<u64> {
+        self.inner.count(query).await
+    }
+
+    async fn aggregate(&self, tag: &str, query: Query) -> Result<Aggregate> {
+        self.inner.aggregate(tag, query).await
+    }
+
+    async fn facets(&self, tag: &str, query: Query, limit: Option<usize>) -> Result<Vec<Facet>> {
+        self.inner.facets(tag, query, limit).await
     }
 }
 
 #[cfg(test)]
 pub mod memory {
-    use crate::database::{Index, Meta};
+    use crate::database::page::SortKey;
+    use crate::database::{Cursor, Expr, Index, Meta, Query, Value};
     use crate::storage::Key;
     use anyhow::Result;
     use async_trait::async_trait;
@@ -386,7 +814,7 @@ pub mod memory {
 
     #[derive(Clone)]
     pub struct MemoryIndex {
-        data: Arc<Mutex<HashMap<(String, String), HashSet<u32>>>>,
+        data: Arc<Mutex<HashMap<(String, Value), HashSet<Key>>>>,
     }
 
     impl MemoryIndex {
@@ -395,24 +823,64 @@ pub mod memory {
                 data: Arc::new(Mutex::new(HashMap::default())),
             }
         }
+
+        /// the keys which match an expression, out of all keys
+        fn eval(
+            data: &HashMap<(String, Value), HashSet<Key>>,
+            all: &HashSet<Key>,
+            expr: &Expr,
+        ) -> HashSet<Key> {
+            let select = |matches: &dyn Fn(&str, &Value) -> bool| -> HashSet<Key> {
+                data.iter()
+                    .filter(|((tag, value), _)| matches(tag, value))
+                    .flat_map(|(_, keys)| keys.iter().copied())
+                    .collect()
+            };
+
+            match expr {
+                Expr::Filter(filter) => select(&|tag: &str, value: &Value| {
+                    tag == filter.tag && filter.condition.matches(value)
+                }),
+                Expr::Exists(name) => select(&|tag: &str, _: &Value| tag == name.as_str()),
+                Expr::And(exprs) => exprs.iter().fold(all.clone(), |keys, expr| {
+                    let matched = Self::eval(data, all, expr);
+                    keys.intersection(&matched).copied().collect()
+                }),
+                Expr::Or(exprs) => exprs
+                    .iter()
+                    .flat_map(|expr| Self::eval(data, all, expr))
+                    .collect(),
+                Expr::Not(expr) => all
+                    .difference(&Self::eval(data, all, expr))
+                    .copied()
+                    .collect(),
+            }
+        }
     }
 
     #[async_trait]
     impl Index for MemoryIndex {
         async fn set(&self, key: Key, meta: Meta) -> Result<()> {
             let mut data = self.data.lock().await;
-            for pair in meta {
-                let set = data.get_mut(&pair);
-                match set {
-                    Some(set) => {
-                        set.insert(key);
-                    }
-                    None => {
-                        let mut set = HashSet::default();
-                        set.insert(key);
-                        data.insert(pair, set);
+            if meta.deleted() {
+                for set in data.values_mut() {
+                    set.remove(&key);
+                }
+
+                return Ok(());
+            }
+
+            for (tag, value) in meta {
+                // a key has a single value per tag
+                for ((other, _), set) in data.iter_mut() {
+                    if *other == tag {
+                        set.remove(&key);
                     }
-                };
+                }
+
+                data.entry((tag, value))
+                    .or_insert_with(HashSet::default)
+                    .insert(key);
             }
             Ok(())
         }
@@ -422,41 +890,50 @@ pub mod memory {
             let mut meta = Meta::default();
             for ((k, v), s) in data.iter() {
                 if !s.get(&key).is_none() {
-                    meta.insert::<String, String>(k.into(), v.into())
+                    meta.insert(k.clone(), v.clone())
                 }
             }
 
             Ok(meta)
         }
 
-        async fn find(&self, meta: Meta) -> Result<mpsc::Receiver<Result<Key>>> {
+        async fn find(&self, query: Query) -> Result<mpsc::Receiver<Result<Key>>> {
             let data = self.data.lock().await;
-            let mut results: Option<HashSet<u32>> = None;
-            for pair in meta {
-                let set = data.get(&pair);
-                match set {
-                    None => break,
-                    Some(data) => {
-                        results = match results {
-                            None => Some(data.clone()),
-                            Some(results) => {
-                                results.intersection(data);
-                                Some(results)
-                            }
-                        }
+            // an empty query matches all keys
+            let all: HashSet<Key> = data.values().flatten().copied().collect();
+            let results = query.terms().iter().fold(all.clone(), |results, expr| {
+                let matched = Self::eval(&data, &all, expr);
+                results.intersection(&matched).copied().collect()
+            });
+
+            let page = query.page();
+            let cursors = results
+                .into_iter()
+                .map(|key| {
+                    let value = page.sort_by().and_then(|sort_by| {
+                        data.iter()
+                            .find(|((tag, _), keys)| tag == sort_by && keys.contains(&key))
+                            .map(|((_, value), _)| value)
+                    });
+
+                    Cursor {
+                        sort: SortKey::new(value),
+                        key: key,
                     }
-                }
-            }
+                })
+                .collect();
+            let results: Vec<Key> = page
+                .apply(cursors)
+                .into_iter()
+                .map(|cursor| cursor.key)
+                .collect();
 
             let (mut tx, rx) = mpsc::channel(10);
             tokio::spawn(async move {
-                let results = match results {
-                    None => return,
-                    Some(results) => results,
-                };
-
                 for result in results {
-                    tx.send(Ok(result)).await.unwrap();
+                    if tx.send(Ok(result)).await.is_err() {
+                        break;
+                    }
                 }
             });
             Ok(rx)
@@ -474,17 +951,93 @@ pub mod memory {
         async fn get(&self, key: Key) -> Result<Meta> {
             bail!("not supported");
         }
-        async fn find(&self, meta: Meta) -> Result<mpsc::Receiver<Result<Key>>> {
+        async fn find(&self, query: Query) -> Result<mpsc::Receiver<Result<Key>>> {
             bail!("not supported");
         }
     }
 }
 
+/// An index wrapper which injects faults, see `crate::storage::faulty`
+#[cfg(test)]
+pub mod faulty {
+    use crate::database::{Index, Meta, Query};
+    use crate::storage::faulty::{Fault, Script};
+    use crate::storage::Key;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use tokio::sync::mpsc;
+
+    /// An index operation
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum Op {
+        Set,
+        Get,
+        Find,
+    }
+
+    /// Wraps an index, faults are injected as with a `FaultyStorage`. A corrupted `Get` returns
+    /// no tags, as if the entry of the key was lost.
+    #[derive(Clone)]
+    pub struct FaultyIndex<I> {
+        inner: I,
+        script: Script<Op>,
+    }
+
+    impl<I> FaultyIndex<I>
+    where
+        I: Index,
+    {
+        pub fn new(inner: I) -> Self {
+            FaultyIndex {
+                inner: inner,
+                script: Script::new(),
+            }
+        }
+
+        pub fn script(&self) -> &Script<Op> {
+            &self.script
+        }
+    }
+
+    #[async_trait]
+    impl<I> Index for FaultyIndex<I>
+    where
+        I: Index,
+    {
+        async fn set(&self, key: Key, meta: Meta) -> Result<()> {
+            match self.script.next(Op::Set).await {
+                Some(Fault::Fail) => bail!("injected fault"),
+                Some(Fault::Lost) => {
+                    self.inner.set(key, meta).await?;
+                    bail!("injected fault")
+                }
+                _ => self.inner.set(key, meta).await,
+            }
+        }
+
+        async fn get(&self, key: Key) -> Result<Meta> {
+            match self.script.next(Op::Get).await {
+                Some(Fault::Fail) | Some(Fault::Lost) => bail!("injected fault"),
+                Some(Fault::Corrupt) => Ok(Meta::default()),
+                _ => self.inner.get(key).await,
+            }
+        }
+
+        async fn find(&self, query: Query) -> Result<mpsc::Receiver<Result<Key>>> {
+            match self.script.next(Op::Find).await {
+                Some(Fault::Fail) | Some(Fault::Lost) => bail!("injected fault"),
+                _ => self.inner.find(query).await,
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::memory::MemoryIndex;
     use super::*;
     use crate::database::Meta;
+    use crate::storage::memory::MemoryStorage;
 
     #[tokio::test]
     async fn memory_index() {
@@ -517,7 +1070,7 @@ mod tests {
         find.insert("age", "38");
 
         use tokio::stream::StreamExt;
-        let found = index.find(find).await.unwrap();
+        let found = index.find(find.into()).await.unwrap();
         let results: Vec<Result<Key>> = found.collect().await;
 
         assert_eq!(results.len(), 2);
@@ -525,12 +1078,43 @@ mod tests {
         let mut find = Meta::default();
         find.insert("name", "user1");
 
-        let found = index.find(find).await.unwrap();
+        let found = index.find(find.into()).await.unwrap();
         let results: Vec<Result<Key>> = found.collect().await;
 
         assert_eq!(results.len(), 1);
     }
 
+    #[tokio::test]
+    async fn rebuild_resume() {
+        let storage = MemoryStorage::new();
+        let index = MetaInterceptor::new(MemoryIndex::new(), storage.clone());
+        for key in 1..=3 {
+            let mut meta = Meta::default();
+            meta.insert("name", format!("user{}", key));
+            meta.insert("id", Value::number(key.to_string()).unwrap());
+            index.set(key, meta).await.unwrap();
+        }
+
+        let mut index = MetaInterceptor::new(MemoryIndex::new(), storage.clone());
+        index.rebuild(None).await.unwrap();
+        assert_eq!(index.get(1).await.unwrap().get("name").unwrap(), "user1");
+        // the types of the tags are kept in the metadata log
+        assert_eq!(
+            index.get(1).await.unwrap().value("id"),
+            Some(&Value::Number("1".into()))
+        );
+        assert_eq!(index.get(3).await.unwrap().get("name").unwrap(), "user3");
+
+        // resuming after the first metadata entry only indexes the entries that follow
+        let first = storage.keys().await.unwrap().next().await.unwrap().unwrap();
+        let mut index = MetaInterceptor::new(MemoryIndex::new(), storage);
+        index.resume(first.key).await.unwrap();
+        assert_eq!(index.get(1).await.unwrap().count(), 0);
+        assert_eq!(index.get(2).await.unwrap().count(), 2);
+        assert_eq!(index.get(2).await.unwrap().get("name").unwrap(), "user2");
+        assert_eq!(index.get(3).await.unwrap().get("name").unwrap(), "user3");
+    }
+
     #[tokio::test]
     async fn schema() {
         let db = "/tmp/testing.sqlite3";
@@ -555,7 +1139,7 @@ mod tests {
         let getter = schema.clone();
         let mut filter = Meta::default();
         filter.insert("name", "filename");
-        let mut cur = schema.find(filter).await.expect("failed to do fine");
+        let mut cur = schema.find(filter.into()).await.expect("failed to do fine");
         loop {
             let key = match cur.recv().await {
                 Some(key) => key,
@@ -563,7 +1147,7 @@ mod tests {
             };
             let tags = getter.get(key.unwrap()).await.expect("object not found");
             for (k, v) in tags {
-                println!("{}: {}", k, v);
+                println!("{}: {}", k, v.as_str());
             }
         }
     }
@@ -600,7 +1184,7 @@ mod tests {
             assert_eq!(handle.await.is_ok(), true);
         }
 
-        let mut results = schema.find(Meta::default()).await.expect("find failed");
+        let mut results = schema.find(Query::new()).await.expect("find failed");
 
         let mut keys = vec![];
         while let Some(item) = results.recv().await {
@@ -657,7 +1241,7 @@ mod tests {
         find.insert("age", "38");
 
         use tokio::stream::StreamExt;
-        let found = index.find(find).await.unwrap();
+        let found = index.find(find.into()).await.unwrap();
         let results: Vec<Result<Key>> = found.collect().await;
 
         assert_eq!(results.len(), 2);
@@ -665,7 +1249,7 @@ mod tests {
         let mut find = Meta::default();
         find.insert("name", "updated");
 
-        let found = index.find(find).await.unwrap();
+        let found = index.find(find.into()).await.unwrap();
         let results: Vec<Result<Key>> = found.collect().await;
 
         assert_eq!(results.len(), 1);
@@ -716,6 +1300,456 @@ mod tests {
         assert_eq!(loaded.get("age").unwrap(), "38");
     }
 
+    async fn find_keys<I: Index>(index: &I, query: Query) -> Vec<Key> {
+        use tokio::stream::StreamExt;
+        let found = index.find(query).await.unwrap();
+        let mut keys: Vec<Key> = found.map(|key| key.unwrap()).collect().await;
+        keys.sort();
+        keys
+    }
+
+    async fn range_queries<I: Index>(index: I) {
+        for (key, age, size) in &[
+            (1, Value::number("9").unwrap(), 100),
+            (2, Value::number("10").unwrap(), 2000),
+            (3, Value::number("38.5").unwrap(), 30),
+            (4, Value::Text("old".into()), 4),
+            (5, Value::Text("15".into()), 5),
+        ] {
+            let mut meta = Meta::default().with_size(*size);
+            meta.insert("age", age.clone());
+            index.set(*key, meta).await.unwrap();
+        }
+
+        // numbers are compared by value, not as strings
+        let query = Query::new()
+            .with_condition("age", Condition::Greater(Number::Integer(9)))
+            .unwrap();
+        assert_eq!(find_keys(&index, query).await, vec![2, 3]);
+
+        let query = Query::new()
+            .with_condition("age", Condition::parse("between", "9,10").unwrap())
+            .unwrap();
+        assert_eq!(find_keys(&index, query).await, vec![1, 2]);
+
+        let query = Query::new()
+            .with_condition(TAG_SIZE, Condition::LessEqual(Number::Integer(100)))
+            .unwrap()
+            .with_condition("age", Condition::Less(Number::Float(38.6)))
+            .unwrap();
+        assert_eq!(find_keys(&index, query).await, vec![1, 3]);
+
+        // text never matches a comparison, also if it looks like a number
+        let query = Query::new()
+            .with_condition("age", Condition::GreaterEqual(Number::Integer(0)))
+            .unwrap();
+        assert_eq!(find_keys(&index, query).await, vec![1, 2, 3]);
+        assert_eq!(
+            find_keys(&index, Query::new().with_tag("age", "old")).await,
+            vec![4]
+        );
+        assert_eq!(
+            find_keys(&index, Query::new().with_tag("age", "15")).await,
+            vec![5]
+        );
+        assert_eq!(
+            index.get(5).await.unwrap().value("age"),
+            Some(&Value::Text("15".into()))
+        );
+        assert_eq!(
+            index.get(1).await.unwrap().value("age"),
+            Some(&Value::Number("9".into()))
+        );
+
+        // an updated value is compared by its new value
+        let mut meta = Meta::default();
+        meta.insert("age", Value::number("8").unwrap());
+        index.set(2, meta).await.unwrap();
+        let query = Query::new()
+            .with_condition("age", Condition::Less(Number::Integer(10)))
+            .unwrap();
+        assert_eq!(find_keys(&index, query).await, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn memory_range() {
+        range_queries(MemoryIndex::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_range() {
+        const DIR: &str = "/tmp/sqlite-range.test";
+        let _ = std::fs::remove_dir_all(DIR);
+        let builder = SqliteIndexBuilder::new(DIR).unwrap();
+
+        range_queries(builder.build("metadata").await.unwrap()).await;
+    }
+
+    async fn pattern_queries<I: Index>(index: I) {
+        let paths = [
+            "/home/",
+            "/home/a.txt",
+            "/home/b.rs",
+            "/homework",
+            "/var/log",
+        ];
+        for (key, path) in paths.iter().enumerate() {
+            let mut meta = Meta::default();
+            meta.insert("path", *path);
+            index.set(key as Key, meta).await.unwrap();
+        }
+
+        let query = |condition| Query::new().with_condition("path", condition).unwrap();
+        assert_eq!(
+            find_keys(&index, query(Condition::Prefix("/home/".into()))).await,
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            find_keys(&index, query(Condition::Prefix("/home".into()))).await,
+            vec![0, 1, 2, 3]
+        );
+        assert_eq!(
+            find_keys(&index, query(Condition::Prefix("".into()))).await,
+            vec![0, 1, 2, 3, 4]
+        );
+        assert_eq!(
+            find_keys(&index, query(Condition::Glob("/home/*.txt".into()))).await,
+            vec![1]
+        );
+        assert_eq!(
+            find_keys(&index, query(Condition::Glob("*/[a-b].*".into()))).await,
+            vec![1, 2]
+        );
+        assert_eq!(
+            find_keys(&index, query(Condition::Glob("/HOME/*".into()))).await,
+            Vec::<Key>::new()
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_patterns() {
+        pattern_queries(MemoryIndex::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_patterns() {
+        const DIR: &str = "/tmp/sqlite-patterns.test";
+        let _ = std::fs::remove_dir_all(DIR);
+        let builder = SqliteIndexBuilder::new(DIR).unwrap();
+
+        pattern_queries(builder.build("metadata").await.unwrap()).await;
+    }
+
+    async fn boolean_queries<I: Index>(index: I) {
+        let objects = [
+            ("photo", Some("1999"), "europe.belgium"),
+            ("photo", Some("2012"), "europe.france"),
+            ("video", Some("2012"), "africa.egypt"),
+            ("photo", None, "asia.japan"),
+        ];
+        for (key, (kind, year, location)) in objects.iter().enumerate() {
+            let mut meta = Meta::default();
+            meta.insert("kind", *kind);
+            meta.insert("location", *location);
+            if let Some(year) = year {
+                meta.insert("year", Value::number(*year).unwrap());
+            }
+            index.set(key as Key, meta).await.unwrap();
+        }
+
+        let query = |expr: &str| Query::new().with_expr(Expr::parse(expr).unwrap());
+        let cases: Vec<(&str, Vec<Key>)> = vec![
+            ("kind = photo", vec![0, 1, 3]),
+            ("kind = photo or year = 2012", vec![0, 1, 2, 3]),
+            ("kind = photo and not year = 2012", vec![0, 3]),
+            ("kind != video", vec![0, 1, 3]),
+            ("not exists year", vec![3]),
+            ("exists year and year < 2000", vec![0]),
+            (
+                "(kind = video or location prefix europe.) and year >= 2000",
+                vec![1, 2],
+            ),
+            ("not (kind = photo or kind = video)", vec![]),
+            ("not not kind = video", vec![2]),
+            (
+                "location glob *.japan or (kind = photo and (year < 2000 or location prefix europe.f))",
+                vec![0, 1, 3],
+            ),
+        ];
+
+        for (expr, expected) in cases {
+            assert_eq!(find_keys(&index, query(expr)).await, expected, "{}", expr);
+        }
+
+        // terms of a query are combined with the expression
+        let query = query("kind = photo or kind = video").with_tag("year", "2012");
+        assert_eq!(find_keys(&index, query).await, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn memory_boolean() {
+        boolean_queries(MemoryIndex::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_boolean() {
+        const DIR: &str = "/tmp/sqlite-boolean.test";
+        let _ = std::fs::remove_dir_all(DIR);
+        let builder = SqliteIndexBuilder::new(DIR).unwrap();
+
+        boolean_queries(builder.build("metadata").await.unwrap()).await;
+    }
+
+    async fn find_page<I: Index>(index: &I, query: Query) -> Vec<Key> {
+        use tokio::stream::StreamExt;
+        let found = index.find(query).await.unwrap();
+        found.map(|key| key.unwrap()).collect().await
+    }
+
+    async fn paged_queries<I: Index>(index: I) {
+        let number = |value: &str| Some(Value::number(value).unwrap());
+        let ages = [
+            number("30"),
+            number("9"),
+            None,
+            Some(Value::Text("abc".into())),
+            number("9.5"),
+            number("9"),
+        ];
+        for (key, age) in ages.iter().enumerate() {
+            let mut meta = Meta::default();
+            meta.insert("kind", "person");
+            if let Some(age) = age {
+                meta.insert("age", age.clone());
+            }
+            index.set(key as Key, meta).await.unwrap();
+        }
+
+        let all = Query::new().with_tag("kind", "person");
+        let page = Page::new().with_sort_by("age");
+        assert_eq!(
+            find_page(&index, all.clone().with_page(page.clone())).await,
+            vec![1, 5, 4, 0, 3, 2]
+        );
+
+        // ordered by key without a sort tag
+        let descending = Page::new().with_order(Order::Descending).with_limit(4);
+        assert_eq!(
+            find_page(&index, all.clone().with_page(descending)).await,
+            vec![5, 4, 3, 2]
+        );
+
+        // walk all pages, while objects are added and removed
+        let page = page.with_order(Order::Descending).with_limit(2);
+        let mut keys = vec![];
+        let mut query = all.clone().with_page(page.clone());
+        loop {
+            let found = find_page(&index, query.clone()).await;
+            if found.is_empty() {
+                break;
+            }
+            keys.extend(found.iter().copied());
+
+            let last = *found.last().unwrap();
+            let cursor = page.cursor(last, &index.get(last).await.unwrap());
+            if keys.len() == 2 {
+                // a new object before the cursor, and one after it
+                let mut meta = Meta::default();
+                meta.insert("kind", "person");
+                index.set(10, meta).await.unwrap();
+                let mut meta = Meta::default();
+                meta.insert("kind", "person");
+                meta.insert("age", Value::number("1").unwrap());
+                index.set(11, meta).await.unwrap();
+                // the last result of the page is gone
+                index
+                    .set(3, Meta::default().with_deleted(true))
+                    .await
+                    .unwrap();
+            }
+
+            query = all.clone().with_page(page.clone().with_cursor(cursor));
+        }
+        assert_eq!(keys, vec![2, 3, 0, 4, 5, 1, 11]);
+    }
+
+    #[tokio::test]
+    async fn memory_paged() {
+        paged_queries(MemoryIndex::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_paged() {
+        const DIR: &str = "/tmp/sqlite-paged.test";
+        let _ = std::fs::remove_dir_all(DIR);
+        let builder = SqliteIndexBuilder::new(DIR).unwrap();
+
+        paged_queries(builder.build("metadata").await.unwrap()).await;
+    }
+
+    async fn aggregate_queries<I: Index>(index: I) {
+        let number = |value: &str| Some(Value::number(value).unwrap());
+        let files = [
+            ("pdf", number("10")),
+            ("txt", number("2.5")),
+            ("pdf", Some(Value::Text("big".into()))),
+            ("png", None),
+            ("pdf", number("-3")),
+            ("txt", number("10")),
+            // text is not aggregated, also if it looks like a number
+            ("doc", Some(Value::Text("007".into()))),
+        ];
+        for (key, (kind, size)) in files.iter().enumerate() {
+            let mut meta = Meta::default();
+            meta.insert("type", "file");
+            meta.insert("kind", *kind);
+            if let Some(size) = size {
+                meta.insert("size", size.clone());
+            }
+            index.set(key as Key, meta).await.unwrap();
+        }
+
+        // the page of the query is ignored
+        let all = Query::new()
+            .with_tag("type", "file")
+            .with_page(Page::new().with_limit(1));
+        assert_eq!(index.count(all.clone()).await.unwrap(), 7);
+        let query = Query::new().with_expr(Expr::parse("kind = pdf or kind = png").unwrap());
+        assert_eq!(index.count(query.clone()).await.unwrap(), 4);
+
+        let aggregate = index.aggregate("size", all.clone()).await.unwrap();
+        assert_eq!(aggregate.count, 4);
+        assert_eq!(aggregate.sum, 19.5);
+        assert_eq!(aggregate.min, Some(Number::Integer(-3)));
+        assert_eq!(aggregate.max, Some(Number::Integer(10)));
+        assert_eq!(aggregate.avg(), Some(4.875));
+
+        let aggregate = index.aggregate("size", query.clone()).await.unwrap();
+        assert_eq!(aggregate.count, 2);
+        assert_eq!(aggregate.avg(), Some(3.5));
+
+        let aggregate = index.aggregate("missing", all.clone()).await.unwrap();
+        assert_eq!(aggregate, Aggregate::default());
+
+        let facet = |value: &str, count| Facet {
+            value: value.into(),
+            count: count,
+        };
+        assert_eq!(
+            index.facets("kind", all.clone(), None).await.unwrap(),
+            vec![
+                facet("pdf", 3),
+                facet("txt", 2),
+                facet("doc", 1),
+                facet("png", 1)
+            ]
+        );
+        assert_eq!(
+            index.facets("kind", all.clone(), Some(2)).await.unwrap(),
+            vec![facet("pdf", 3), facet("txt", 2)]
+        );
+        assert_eq!(
+            index.facets("size", query, None).await.unwrap(),
+            vec![facet("-3", 1), facet("10", 1), facet("big", 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_aggregate() {
+        aggregate_queries(MemoryIndex::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_aggregate() {
+        const DIR: &str = "/tmp/sqlite-aggregate.test";
+        let _ = std::fs::remove_dir_all(DIR);
+        let builder = SqliteIndexBuilder::new(DIR).unwrap();
+
+        aggregate_queries(builder.build("metadata").await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_patterns_plan() {
+        const DIR: &str = "/tmp/sqlite-patterns-plan.test";
+        let _ = std::fs::remove_dir_all(DIR);
+        let builder = SqliteIndexBuilder::new(DIR).unwrap();
+        let index = builder.build("metadata").await.unwrap();
+
+        #[derive(sqlx::FromRow, Debug)]
+        struct Row {
+            detail: String,
+        }
+
+        for condition in vec![
+            Condition::Prefix("europe.".into()),
+            Condition::Glob("europe.*.gent".into()),
+        ] {
+            let query = Query::new().with_condition("location", condition).unwrap();
+            let (query_str, values) = Schema::select(&query);
+            let mut plan = sqlx::query(&format!("EXPLAIN QUERY PLAN {}", query_str));
+            for value in values {
+                plan = plan.bind(value);
+            }
+
+            let db = index.schema.c.read().await;
+            let rows = plan.fetch_all(db.deref()).await.unwrap();
+            let detail = Row::from_row(&rows[0]).unwrap().detail;
+            // the values of the tag are searched by range, not scanned
+            assert!(detail.contains("metadata_tag_value"), "{}", detail);
+            assert!(detail.contains("value>? AND value<?"), "{}", detail);
+        }
+
+        assert_eq!(successor("europe."), Some("europe/".into()));
+        assert_eq!(successor("a\u{10ffff}"), Some("b".into()));
+        assert_eq!(successor(""), None);
+    }
+
+    #[tokio::test]
+    async fn sqlite_migrate_numbers() {
+        const DIR: &str = "/tmp/sqlite-migrate.test";
+        let _ = std::fs::remove_dir_all(DIR);
+        std::fs::create_dir_all(DIR).unwrap();
+
+        // an index of schema version 1, without numbers
+        let pool = SqlitePool::new(&format!("sqlite://{}/metadata.sqlite", DIR))
+            .await
+            .unwrap();
+        sqlx::query(
+            "
+            CREATE TABLE metadata (key INT, tag TEXT, value TEXT);
+            CREATE UNIQUE INDEX metadata_unique ON metadata (key, tag);
+            INSERT INTO metadata (key, tag, value) VALUES (1, ':size', '9'), (2, ':size', '10'),
+                (3, ':size', 'old'), (1, 'age', '9');
+            PRAGMA user_version = 1;
+            ",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        drop(pool);
+
+        let index = SqliteIndexBuilder::new(DIR)
+            .unwrap()
+            .build("metadata")
+            .await
+            .unwrap();
+        let query = Query::new()
+            .with_condition(TAG_SIZE, Condition::Greater(Number::Integer(5)))
+            .unwrap();
+        assert_eq!(find_keys(&index, query).await, vec![1, 2]);
+        assert_eq!(index.get(3).await.unwrap().get(TAG_SIZE).unwrap(), "old");
+
+        // user tags had no types, so they stay text
+        let query = Query::new()
+            .with_condition("age", Condition::Greater(Number::Integer(5)))
+            .unwrap();
+        assert_eq!(find_keys(&index, query).await, Vec::<Key>::new());
+        assert_eq!(
+            index.get(1).await.unwrap().value("age"),
+            Some(&Value::Text("9".into()))
+        );
+    }
+
     #[tokio::test]
     async fn sqlite_perf() {
         // this should probably be replaced by a benchmark test
@@ -0,0 +1,403 @@
+//! Sorting, limits and continuation of query results. The results of a query are ordered by
+//! their sort key, the value of the tag they are sorted by, and then by their key. A page of
+//! results starts right after a cursor, the position of the last result of the previous page,
+//! so pages are stable while objects are added or removed: no result is skipped or repeated,
+//! unless its sort key changes.
+//!
+//! Sort keys follow the sqlite order of values: numbers sort before text, and objects which
+//! don't have the tag sort last.
+
+use super::{Context, Database, Key, Meta, Number, Query, Reason, Value};
+use anyhow::Result;
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use tokio::sync::mpsc;
+
+/// Version of the token format
+const TOKEN_FORMAT: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+impl Default for Order {
+    fn default() -> Self {
+        Order::Ascending
+    }
+}
+
+impl std::str::FromStr for Order {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Order> {
+        let order = match s.to_lowercase().as_ref() {
+            "asc" => Order::Ascending,
+            "desc" => Order::Descending,
+            _ => bail!(Reason::InvalidQuery(format!("unknown order '{}'", s))),
+        };
+
+        Ok(order)
+    }
+}
+
+/// The value of the tag results are sorted by
+#[derive(Debug, Clone, PartialEq)]
+pub enum SortKey {
+    Number(Number),
+    Text(String),
+    Missing,
+}
+
+impl SortKey {
+    /// the sort key of a tag value, numbers are sorted as numbers and text as text,
+    /// also if it looks like a number
+    pub fn new(value: Option<&Value>) -> SortKey {
+        match value {
+            Some(value) => match value.as_number() {
+                Some(number) => SortKey::Number(number),
+                None => SortKey::Text(value.as_str().into()),
+            },
+            None => SortKey::Missing,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            SortKey::Number(_) => 0,
+            SortKey::Text(_) => 1,
+            SortKey::Missing => 2,
+        }
+    }
+
+    fn compare(&self, other: &SortKey) -> Ordering {
+        match (self, other) {
+            (SortKey::Number(a), SortKey::Number(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+            (SortKey::Text(a), SortKey::Text(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+/// The position of a result in the order of a query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    pub sort: SortKey,
+    pub key: Key,
+}
+
+impl Cursor {
+    fn compare(&self, other: &Cursor) -> Ordering {
+        self.sort
+            .compare(&other.sort)
+            .then_with(|| self.key.cmp(&other.key))
+    }
+}
+
+/// How the results of a query are sorted, and which of them are returned
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Page {
+    sort_by: Option<String>,
+    order: Order,
+    limit: Option<usize>,
+    after: Option<Cursor>,
+}
+
+impl Page {
+    /// all results, ordered by key
+    pub fn new() -> Self {
+        Page::default()
+    }
+
+    /// sorts the results by the value of a tag
+    pub fn with_sort_by<K: Into<String>>(mut self, tag: K) -> Self {
+        self.sort_by = Some(tag.into());
+        self
+    }
+
+    pub fn with_order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// returns at most the given number of results
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// returns the results after the cursor
+    pub fn with_cursor(mut self, cursor: Cursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    /// returns the results after the cursor of a continuation token. The token must have
+    /// been returned for a page with the same sort tag and order.
+    pub fn with_token(self, token: &str) -> Result<Self> {
+        let invalid = || Reason::InvalidQuery("invalid continuation token".into());
+        let data = base64::decode_config(token, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        if data.len() < 12 || data[0] != TOKEN_FORMAT {
+            bail!(invalid());
+        }
+
+        let order = match data[1] {
+            0 => Order::Ascending,
+            1 => Order::Descending,
+            _ => bail!(invalid()),
+        };
+
+        let key = Key::from_le_bytes(data[2..10].try_into().unwrap());
+        let len = u16::from_le_bytes(data[10..12].try_into().unwrap()) as usize;
+        if data.len() < 13 + len {
+            bail!(invalid());
+        }
+
+        let sort_by = match len {
+            0 => None,
+            _ => Some(String::from_utf8(data[12..12 + len].to_vec()).map_err(|_| invalid())?),
+        };
+
+        let value = &data[13 + len..];
+        let sort = match (data[12 + len], value.len()) {
+            (0, 0) => SortKey::Missing,
+            (1, 8) => SortKey::Number(Number::Integer(i64::from_le_bytes(
+                value.try_into().unwrap(),
+            ))),
+            (2, 8) => SortKey::Number(Number::Float(f64::from_le_bytes(value.try_into().unwrap()))),
+            (3, _) => SortKey::Text(String::from_utf8(value.to_vec()).map_err(|_| invalid())?),
+            _ => bail!(invalid()),
+        };
+
+        if sort_by != self.sort_by || order != self.order {
+            bail!(Reason::InvalidQuery(
+                "continuation token is for another sort order".into()
+            ));
+        }
+
+        Ok(self.with_cursor(Cursor { sort, key }))
+    }
+
+    pub fn sort_by(&self) -> Option<&str> {
+        self.sort_by.as_ref().map(|tag| tag.as_str())
+    }
+
+    pub fn order(&self) -> Order {
+        self.order
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub fn after(&self) -> Option<&Cursor> {
+        self.after.as_ref()
+    }
+
+    /// the cursor of a result, given the tags of the object
+    pub fn cursor(&self, key: Key, meta: &Meta) -> Cursor {
+        let sort = match self.sort_by() {
+            Some(tag) => SortKey::new(meta.value(tag)),
+            None => SortKey::Missing,
+        };
+
+        Cursor {
+            sort: sort,
+            key: key,
+        }
+    }
+
+    /// the continuation token of a result, to get the results after it. The token holds the
+    /// position of the result, and the sort tag and order of the page.
+    pub fn token(&self, cursor: &Cursor) -> String {
+        let sort_by = self.sort_by().unwrap_or_default().as_bytes();
+        let mut data = vec![TOKEN_FORMAT];
+        data.push(match self.order {
+            Order::Ascending => 0,
+            Order::Descending => 1,
+        });
+        data.extend_from_slice(&cursor.key.to_le_bytes());
+        data.extend_from_slice(&(sort_by.len() as u16).to_le_bytes());
+        data.extend_from_slice(sort_by);
+        match &cursor.sort {
+            SortKey::Missing => data.push(0),
+            SortKey::Number(Number::Integer(n)) => {
+                data.push(1);
+                data.extend_from_slice(&n.to_le_bytes());
+            }
+            SortKey::Number(Number::Float(n)) => {
+                data.push(2);
+                data.extend_from_slice(&n.to_le_bytes());
+            }
+            SortKey::Text(text) => {
+                data.push(3);
+                data.extend_from_slice(text.as_bytes());
+            }
+        }
+
+        base64::encode_config(&data, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// checks if a result at the given position comes after the cursor of the page
+    pub fn follows(&self, cursor: &Cursor) -> bool {
+        let after = match &self.after {
+            Some(after) => after,
+            None => return true,
+        };
+
+        match self.order {
+            Order::Ascending => cursor.compare(after) == Ordering::Greater,
+            Order::Descending => cursor.compare(after) == Ordering::Less,
+        }
+    }
+
+    /// sorts the cursors of all results, and keeps the ones of the page
+    pub fn apply(&self, mut cursors: Vec<Cursor>) -> Vec<Cursor> {
+        cursors.retain(|cursor| self.follows(cursor));
+        cursors.sort_by(|a, b| match self.order {
+            Order::Ascending => a.compare(b),
+            Order::Descending => b.compare(a),
+        });
+
+        if let Some(limit) = self.limit {
+            cursors.truncate(limit);
+        }
+
+        cursors
+    }
+}
+
+/// lists the keys of the objects which match a query, with the cursor of every key. Objects are
+/// found instead when the results are sorted by a tag, since a cursor holds the value of the tag.
+pub async fn list_cursors<D: Database>(
+    db: &mut D,
+    ctx: &Context,
+    query: Query,
+    collection: Option<&str>,
+) -> Result<mpsc::Receiver<Result<(Key, Cursor)>>> {
+    let page = query.page().clone();
+    let (mut tx, rx) = mpsc::channel(10);
+    if page.sort_by().is_none() {
+        let mut keys = db.list(ctx, query, collection).await?;
+        tokio::spawn(async move {
+            while let Some(key) = keys.recv().await {
+                let result = key.map(|key| (key, page.cursor(key, &Meta::default())));
+                if tx.send(result).await.is_err() {
+                    debug!("failed to send result, broken stream");
+                    break;
+                }
+            }
+        });
+    } else {
+        let mut objects = db.find(ctx, query, collection).await?;
+        tokio::spawn(async move {
+            while let Some(object) = objects.recv().await {
+                let result = object.map(|object| {
+                    let cursor = page.cursor(object.key, &object.meta);
+                    (object.key, cursor)
+                });
+                if tx.send(result).await.is_err() {
+                    debug!("failed to send result, broken stream");
+                    break;
+                }
+            }
+        });
+    }
+
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(sort: SortKey, key: Key) -> Cursor {
+        Cursor {
+            sort: sort,
+            key: key,
+        }
+    }
+
+    #[test]
+    fn sort_order() {
+        let page = Page::new().with_sort_by("age");
+        let cursors = vec![
+            cursor(SortKey::Missing, 1),
+            cursor(SortKey::Text("abc".into()), 2),
+            cursor(SortKey::Number(Number::Float(10.5)), 3),
+            cursor(SortKey::Number(Number::Integer(9)), 4),
+            cursor(SortKey::Number(Number::Integer(9)), 0),
+            cursor(SortKey::Text("Abc".into()), 5),
+        ];
+
+        let keys: Vec<Key> = page
+            .apply(cursors.clone())
+            .into_iter()
+            .map(|c| c.key)
+            .collect();
+        assert_eq!(keys, vec![0, 4, 3, 5, 2, 1]);
+
+        let page = page.with_order(Order::Descending).with_limit(3);
+        let keys: Vec<Key> = page
+            .apply(cursors.clone())
+            .into_iter()
+            .map(|c| c.key)
+            .collect();
+        assert_eq!(keys, vec![1, 2, 5]);
+
+        // the next page starts after the last result, even if it is gone
+        let page = page.with_cursor(cursor(SortKey::Text("Abc".into()), 5));
+        let keys: Vec<Key> = page
+            .apply(cursors[..5].to_vec())
+            .into_iter()
+            .map(|c| c.key)
+            .collect();
+        assert_eq!(keys, vec![3, 4, 0]);
+    }
+
+    #[test]
+    fn sort_key() {
+        let number = Value::number("1e3").unwrap();
+        assert_eq!(
+            SortKey::new(Some(&number)),
+            SortKey::Number(Number::Float(1000.0))
+        );
+
+        // text is sorted as text, also if it looks like a number
+        let text = Value::Text("007".into());
+        assert_eq!(SortKey::new(Some(&text)), SortKey::Text("007".into()));
+        assert_eq!(SortKey::new(None), SortKey::Missing);
+    }
+
+    #[test]
+    fn token() {
+        let page = Page::new()
+            .with_sort_by(":created")
+            .with_order(Order::Descending);
+
+        for sort in vec![
+            SortKey::Missing,
+            SortKey::Number(Number::Integer(-42)),
+            SortKey::Number(Number::Float(2.5)),
+            SortKey::Text("some text".into()),
+        ] {
+            let cursor = cursor(sort, 1 << 40);
+            let token = page.token(&cursor);
+            let next = page.clone().with_token(&token).unwrap();
+            assert_eq!(next.after(), Some(&cursor));
+        }
+
+        let token = page.token(&cursor(SortKey::Missing, 1));
+        assert!(Page::new().with_token(&token).is_err());
+        assert!(Page::new()
+            .with_sort_by(":created")
+            .with_token(&token)
+            .is_err());
+        assert!(page.clone().with_token("invalid").is_err());
+        assert!(page.with_token(&token[..token.len() - 2]).is_err());
+
+        let token = Page::new().token(&cursor(SortKey::Missing, 7));
+        let page = Page::new().with_token(&token).unwrap();
+        assert_eq!(page.after(), Some(&cursor(SortKey::Missing, 7)));
+    }
+}
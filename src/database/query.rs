@@ -0,0 +1,866 @@
+//! Queries over the tags of objects. A query is a set of filters on tags, and matches the objects
+//! which match all filters. A filter either matches a tag value exactly, by prefix or by a glob
+//! pattern, or compares the numeric value of a tag. Tag values are text unless they are set as
+//! numbers, only numbers are compared by value, text never matches a comparison, also if it looks
+//! like a number.
+//!
+//! Of the system tags, only the numeric tags `:created`, `:updated` and `:size` can be compared.
+
+use super::{
+    is_reserved, Meta, Page, Reason, Value, TAG_COLLECTION, TAG_CREATED, TAG_SIZE, TAG_UPDATED,
+};
+use anyhow::Result;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// System tags which can be compared
+const NUMERIC_TAGS: &[&str] = &[TAG_CREATED, TAG_UPDATED, TAG_SIZE];
+
+/// The numeric value of a tag. Integers which fit in 64 signed bits are exact, other numbers
+/// are floating point.
+#[derive(Debug, Clone, Copy)]
+pub enum Number {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Number {
+    /// parses the numeric value of a tag, `None` if the value is not a number
+    pub fn parse(value: &str) -> Option<Number> {
+        if let Ok(n) = value.parse::<i64>() {
+            return Some(Number::Integer(n));
+        }
+
+        match value.parse::<f64>() {
+            Ok(n) if n.is_finite() => Some(Number::Float(n)),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        match *self {
+            Number::Integer(n) => n as f64,
+            Number::Float(n) => n,
+        }
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Integer(a), Number::Integer(b)) => Some(a.cmp(b)),
+            _ => self.as_f64().partial_cmp(&other.as_f64()),
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Number::Integer(n) => write!(f, "{}", n),
+            Number::Float(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// A condition on the value of a tag
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Equal(String),
+    Less(Number),
+    LessEqual(Number),
+    Greater(Number),
+    GreaterEqual(Number),
+    /// between two numbers, inclusive
+    Between(Number, Number),
+    /// values which start with the given prefix
+    Prefix(String),
+    /// values which match a glob pattern. `*` matches any sequence of characters, `?` matches
+    /// a single character and `[...]` matches one of a set of characters, like `[a-z]`, or
+    /// none of them, like `[^0-9]`. Matching is case sensitive.
+    Glob(String),
+}
+
+impl Condition {
+    /// parses a condition, the operator is one of `lt`, `lte`, `gt`, `gte`, `between`, `prefix`
+    /// or `glob`. The bounds of `between` are separated by a comma.
+    pub fn parse(operator: &str, value: &str) -> Result<Condition> {
+        let number = |value: &str| match Number::parse(value.trim()) {
+            Some(number) => Ok(number),
+            None => Err(Reason::InvalidQuery(format!(
+                "expecting a number, found '{}'",
+                value
+            ))),
+        };
+
+        let condition = match operator {
+            "lt" => Condition::Less(number(value)?),
+            "lte" => Condition::LessEqual(number(value)?),
+            "gt" => Condition::Greater(number(value)?),
+            "gte" => Condition::GreaterEqual(number(value)?),
+            "between" => {
+                let mut bounds = value.splitn(2, ',');
+                match (bounds.next(), bounds.next()) {
+                    (Some(lower), Some(upper)) => {
+                        Condition::Between(number(lower)?, number(upper)?)
+                    }
+                    _ => bail!(Reason::InvalidQuery(
+                        "expecting two numbers separated by a comma".into()
+                    )),
+                }
+            }
+            "prefix" => Condition::Prefix(value.into()),
+            "glob" if glob_valid(value) => Condition::Glob(value.into()),
+            "glob" => bail!(Reason::InvalidQuery(format!(
+                "unterminated '[' in pattern '{}'",
+                value
+            ))),
+            _ => bail!(Reason::InvalidQuery(format!(
+                "unknown operator '{}'",
+                operator
+            ))),
+        };
+
+        Ok(condition)
+    }
+
+    /// checks if a tag value matches the condition, only numbers can match a comparison
+    pub fn matches(&self, value: &Value) -> bool {
+        match (self, value.as_number()) {
+            (Condition::Equal(expected), _) => value.as_str() == expected,
+            (Condition::Prefix(prefix), _) => value.as_str().starts_with(prefix.as_str()),
+            (Condition::Glob(pattern), _) => glob_matches(pattern, value.as_str()),
+            (_, None) => false,
+            (Condition::Less(n), Some(v)) => v < *n,
+            (Condition::LessEqual(n), Some(v)) => v <= *n,
+            (Condition::Greater(n), Some(v)) => v > *n,
+            (Condition::GreaterEqual(n), Some(v)) => v >= *n,
+            (Condition::Between(lower, upper), Some(v)) => v >= *lower && v <= *upper,
+        }
+    }
+}
+
+/// A filter on a tag
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub tag: String,
+    pub condition: Condition,
+}
+
+impl Filter {
+    /// creates a filter on a tag. Of the system tags, only `:created`, `:updated` and `:size`
+    /// can be compared.
+    pub fn new<K: Into<String>>(tag: K, condition: Condition) -> Result<Self> {
+        let tag = tag.into();
+        let comparable = match condition {
+            Condition::Equal(_) | Condition::Prefix(_) | Condition::Glob(_) => true,
+            _ => !is_reserved(&tag) || NUMERIC_TAGS.contains(&tag.as_str()),
+        };
+
+        if !comparable {
+            bail!(Reason::InvalidQuery(format!(
+                "tag '{}' can't be compared",
+                tag
+            )));
+        }
+
+        Ok(Filter {
+            tag: tag,
+            condition: condition,
+        })
+    }
+
+    /// checks if the tags of an object match the filter, an object without the tag never
+    /// matches
+    pub fn matches(&self, meta: &Meta) -> bool {
+        match meta.value(&self.tag) {
+            Some(value) => self.condition.matches(value),
+            None => false,
+        }
+    }
+}
+
+/// A boolean expression over the tags of an object
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Filter(Filter),
+    /// the object has the tag, with any value
+    Exists(String),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+}
+
+impl Expr {
+    /// parses an expression of the query language, see `Parser`
+    pub fn parse(input: &str) -> Result<Expr> {
+        Parser::new(input)?.parse()
+    }
+
+    /// checks if the tags of an object match the expression
+    pub fn matches(&self, meta: &Meta) -> bool {
+        match self {
+            Expr::Filter(filter) => filter.matches(meta),
+            Expr::Exists(tag) => meta.get(tag).is_some(),
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.matches(meta)),
+            Expr::Or(exprs) => exprs.iter().any(|expr| expr.matches(meta)),
+            Expr::Not(expr) => !expr.matches(meta),
+        }
+    }
+}
+
+/// A query matches the objects which match all its terms, an empty query matches all objects.
+/// The page of a query sorts and limits the results.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    terms: Vec<Expr>,
+    page: Page,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Query::default()
+    }
+
+    /// matches the objects which have the tag with the given value
+    pub fn with_tag<K, V>(mut self, tag: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.terms.push(Expr::Filter(Filter {
+            tag: tag.into(),
+            condition: Condition::Equal(value.into()),
+        }));
+        self
+    }
+
+    /// matches the objects of the given collection
+    pub fn with_collection<V: Into<String>>(self, collection: V) -> Self {
+        self.with_tag(TAG_COLLECTION, collection)
+    }
+
+    /// matches the objects with a tag whose value satisfies the condition. Of the system
+    /// tags, only `:created`, `:updated` and `:size` can be compared.
+    pub fn with_condition<K: Into<String>>(self, tag: K, condition: Condition) -> Result<Self> {
+        Ok(self.with_expr(Expr::Filter(Filter::new(tag, condition)?)))
+    }
+
+    /// matches the objects which match the expression
+    pub fn with_expr(mut self, expr: Expr) -> Self {
+        self.terms.push(expr);
+        self
+    }
+
+    pub fn terms(&self) -> &[Expr] {
+        &self.terms
+    }
+
+    /// sorts and limits the results
+    pub fn with_page(mut self, page: Page) -> Self {
+        self.page = page;
+        self
+    }
+
+    pub fn page(&self) -> &Page {
+        &self.page
+    }
+
+    /// checks if the tags of an object match the query
+    pub fn matches(&self, meta: &Meta) -> bool {
+        self.terms.iter().all(|expr| expr.matches(meta))
+    }
+}
+
+impl From<Meta> for Query {
+    /// matches the objects which have all the given tags
+    fn from(meta: Meta) -> Self {
+        meta.into_iter().fold(Query::new(), |query, (tag, value)| {
+            query.with_tag(tag, String::from(value))
+        })
+    }
+}
+
+/// The maximum nesting of groups and negations in an expression
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    /// one of `=`, `!=`, `<`, `<=`, `>` or `>=`
+    Operator(&'static str),
+    /// an unquoted word, which may be a keyword
+    Word(String),
+    /// a quoted string, which is never a keyword
+    Quoted(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::Operator(op) => write!(f, "{}", op),
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Quoted(string) => write!(f, "\"{}\"", string),
+        }
+    }
+}
+
+/// Parses the query language. An expression combines predicates on tags with `and`, `or`, `not`
+/// and parentheses, where `not` binds strongest and `or` weakest. Predicates which follow each
+/// other without an operator are combined with `and`. The predicates are
+///
+/// - `tag = value` and `tag != value`, where `!=` also matches objects without the tag
+/// - `tag < n`, `tag <= n`, `tag > n`, `tag >= n` and `tag between n and m`
+/// - `tag prefix value` and `tag glob pattern`
+/// - `exists tag`
+///
+/// Keywords are case insensitive. Tags and values are single words, or quoted with `"` if they
+/// contain spaces, parentheses, quotes or operators, or are keywords. Within quotes `\` escapes
+/// the next character.
+///
+/// For example `type = photo and (year between 2010 and 2015 or not exists year)`.
+pub struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+    depth: usize,
+}
+
+impl Parser {
+    pub fn new(input: &str) -> Result<Parser> {
+        Ok(Parser {
+            tokens: Self::tokenize(input)?,
+            at: 0,
+            depth: 0,
+        })
+    }
+
+    fn tokenize(input: &str) -> Result<Vec<Token>> {
+        let mut tokens = vec![];
+        let mut chars = input.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                c if c.is_whitespace() => continue,
+                '(' => Token::Open,
+                ')' => Token::Close,
+                '=' => Token::Operator("="),
+                '!' if chars.peek() == Some(&'=') => {
+                    chars.next();
+                    Token::Operator("!=")
+                }
+                '<' | '>' if chars.peek() == Some(&'=') => {
+                    chars.next();
+                    Token::Operator(if c == '<' { "<=" } else { ">=" })
+                }
+                '<' => Token::Operator("<"),
+                '>' => Token::Operator(">"),
+                '!' => bail!(Reason::InvalidQuery("expecting '=' after '!'".into())),
+                '"' => {
+                    let mut string = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some(c) => string.push(c),
+                                None => bail!(Reason::InvalidQuery("unterminated string".into())),
+                            },
+                            Some(c) => string.push(c),
+                            None => bail!(Reason::InvalidQuery("unterminated string".into())),
+                        }
+                    }
+
+                    Token::Quoted(string)
+                }
+                c => {
+                    let mut word = c.to_string();
+                    while let Some(c) = chars.peek() {
+                        if c.is_whitespace() || "()\"=!<>".contains(*c) {
+                            break;
+                        }
+                        word.push(*c);
+                        chars.next();
+                    }
+
+                    Token::Word(word)
+                }
+            };
+
+            tokens.push(token);
+        }
+
+        Ok(tokens)
+    }
+
+    /// parses the whole input as a single expression
+    pub fn parse(mut self) -> Result<Expr> {
+        if self.tokens.is_empty() {
+            bail!(Reason::InvalidQuery("empty query".into()));
+        }
+
+        let expr = self.or()?;
+        match self.next() {
+            None => Ok(expr),
+            Some(token) => Err(Self::unexpected(Some(token))),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut exprs = vec![self.and()?];
+        while self.keyword("or") {
+            exprs.push(self.and()?);
+        }
+
+        Ok(Self::combine(exprs, Expr::Or))
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut exprs = vec![self.unary()?];
+        loop {
+            let implicit = match self.peek() {
+                Some(Token::Open) | Some(Token::Quoted(_)) => true,
+                Some(Token::Word(word)) => !word.eq_ignore_ascii_case("or"),
+                _ => false,
+            };
+
+            if !implicit {
+                break;
+            }
+
+            self.keyword("and");
+            exprs.push(self.unary()?);
+        }
+
+        Ok(Self::combine(exprs, Expr::And))
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.keyword("not") {
+            let expr = self.nested(|parser| parser.unary())?;
+            return Ok(Expr::Not(Box::new(expr)));
+        }
+
+        if self.keyword("exists") {
+            return Ok(Expr::Exists(self.value()?));
+        }
+
+        match self.next() {
+            Some(Token::Open) => {
+                let expr = self.nested(|parser| parser.or())?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    token => Err(Self::unexpected(token)),
+                }
+            }
+            Some(Token::Word(tag)) | Some(Token::Quoted(tag)) => self.predicate(tag),
+            token => Err(Self::unexpected(token)),
+        }
+    }
+
+    fn predicate(&mut self, tag: String) -> Result<Expr> {
+        let condition = match self.next() {
+            Some(Token::Operator("!=")) => {
+                let filter = Filter::new(tag, Condition::Equal(self.value()?))?;
+                return Ok(Expr::Not(Box::new(Expr::Filter(filter))));
+            }
+            Some(Token::Operator("=")) => Condition::Equal(self.value()?),
+            Some(Token::Operator("<")) => Condition::parse("lt", &self.value()?)?,
+            Some(Token::Operator("<=")) => Condition::parse("lte", &self.value()?)?,
+            Some(Token::Operator(">")) => Condition::parse("gt", &self.value()?)?,
+            Some(Token::Operator(">=")) => Condition::parse("gte", &self.value()?)?,
+            Some(Token::Word(ref word)) if word.eq_ignore_ascii_case("between") => {
+                let lower = self.value()?;
+                if !self.keyword("and") {
+                    return Err(Self::unexpected(self.next()));
+                }
+
+                let upper = self.value()?;
+                Condition::parse("between", &format!("{},{}", lower, upper))?
+            }
+            Some(Token::Word(ref word)) if word.eq_ignore_ascii_case("prefix") => {
+                Condition::Prefix(self.value()?)
+            }
+            Some(Token::Word(ref word)) if word.eq_ignore_ascii_case("glob") => {
+                Condition::parse("glob", &self.value()?)?
+            }
+            token => bail!(Reason::InvalidQuery(format!(
+                "expecting an operator after '{}', found {}",
+                tag,
+                Self::describe(token.as_ref())
+            ))),
+        };
+
+        Ok(Expr::Filter(Filter::new(tag, condition)?))
+    }
+
+    /// parses a tag or a value
+    fn value(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Word(value)) | Some(Token::Quoted(value)) => Ok(value),
+            token => Err(Self::unexpected(token)),
+        }
+    }
+
+    /// parses a nested expression, up to the maximum depth
+    fn nested<F>(&mut self, parse: F) -> Result<Expr>
+    where
+        F: FnOnce(&mut Self) -> Result<Expr>,
+    {
+        if self.depth == MAX_DEPTH {
+            bail!(Reason::InvalidQuery("query is nested too deep".into()));
+        }
+
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    /// consumes the next token if it is the given keyword
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.at += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn combine<F>(mut exprs: Vec<Expr>, combine: F) -> Expr
+    where
+        F: FnOnce(Vec<Expr>) -> Expr,
+    {
+        if exprs.len() == 1 {
+            exprs.pop().unwrap()
+        } else {
+            combine(exprs)
+        }
+    }
+
+    fn describe(token: Option<&Token>) -> String {
+        match token {
+            Some(token) => format!("'{}'", token),
+            None => "end of query".into(),
+        }
+    }
+
+    fn unexpected(token: Option<Token>) -> anyhow::Error {
+        Reason::InvalidQuery(format!("unexpected {}", Self::describe(token.as_ref()))).into()
+    }
+}
+
+/// the literal prefix of a glob pattern, all values which match the pattern start with it
+pub fn glob_prefix(pattern: &str) -> &str {
+    match pattern.find(|c| c == '*' || c == '?' || c == '[') {
+        Some(at) => &pattern[..at],
+        None => pattern,
+    }
+}
+
+/// checks that all sets of characters in a glob pattern are terminated
+fn glob_valid(pattern: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let mut at = 0;
+    while at < pattern.len() {
+        if pattern[at] != '[' {
+            at += 1;
+            continue;
+        }
+
+        match glob_set(&pattern[at..], '\0') {
+            Some((_, len)) => at += len,
+            None => return false,
+        }
+    }
+
+    true
+}
+
+/// matches a value against a glob pattern, with the semantics of the sqlite GLOB operator
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+
+    let (mut p, mut v) = (0, 0);
+    // the position of the last `*` in the pattern, and of the value it matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while v < value.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, v));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match glob_set(&pattern[p..], value[v]) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                None => return false,
+            },
+            Some(c) if *c == value[v] => Some(1),
+            _ => None,
+        };
+
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                v += 1;
+            }
+            // let the last `*` match one more character
+            (None, Some((sp, sv))) => {
+                star = Some((sp, sv + 1));
+                p = sp + 1;
+                v = sv + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// matches a character against the set of characters at the start of the pattern. Returns if
+/// the character matches, and the length of the set, or `None` if the set is not terminated.
+fn glob_set(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut at = 1;
+    let negate = pattern.get(at) == Some(&'^');
+    if negate {
+        at += 1;
+    }
+
+    let mut matched = false;
+    let start = at;
+    loop {
+        let first = *pattern.get(at)?;
+        // a `]` right after the opening bracket is part of the set
+        if first == ']' && at > start {
+            break;
+        }
+
+        match (pattern.get(at + 1), pattern.get(at + 2)) {
+            (Some('-'), Some(last)) if *last != ']' => {
+                matched = matched || (first <= c && c <= *last);
+                at += 3;
+            }
+            _ => {
+                matched = matched || first == c;
+                at += 1;
+            }
+        }
+    }
+
+    Some((matched != negate, at + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn number_parse() {
+        assert_eq!(Number::parse("42"), Some(Number::Integer(42)));
+        assert_eq!(Number::parse("-7"), Some(Number::Integer(-7)));
+        assert_eq!(Number::parse("2.5"), Some(Number::Float(2.5)));
+        assert_eq!(Number::parse("abc"), None);
+        assert_eq!(Number::parse("inf"), None);
+
+        assert!(Number::Integer(2) < Number::Float(2.5));
+        assert!(Number::Float(3.0) == Number::Integer(3));
+        assert!(Number::Integer(i64::max_value()) > Number::Integer(i64::max_value() - 1));
+    }
+
+    #[test]
+    fn condition_matches() {
+        let number = |value: &str| Value::number(value).unwrap();
+        let between = Condition::parse("between", "10, 20").unwrap();
+        assert!(between.matches(&number("10")));
+        assert!(between.matches(&number("15.5")));
+        assert!(between.matches(&number("20")));
+        assert!(!between.matches(&number("21")));
+        assert!(!between.matches(&"abc".into()));
+
+        // numbers are compared by value, not as strings
+        assert!(Condition::parse("gt", "9").unwrap().matches(&number("10")));
+        assert!(Condition::parse("lte", "-1")
+            .unwrap()
+            .matches(&number("-1")));
+        assert!(!Condition::parse("lt", "-1").unwrap().matches(&number("-1")));
+
+        // text never matches a comparison, also if it looks like a number
+        assert!(!between.matches(&"15".into()));
+        let prefix = Condition::parse("prefix", "00").unwrap();
+        assert!(prefix.matches(&"007".into()));
+
+        assert!(Condition::parse("gt", "abc").is_err());
+        assert!(Condition::parse("between", "10").is_err());
+        assert!(Condition::parse("like", "10").is_err());
+    }
+
+    #[test]
+    fn query_matches() {
+        let query = Query::new()
+            .with_collection("files")
+            .with_condition(TAG_SIZE, Condition::Greater(Number::Integer(100)))
+            .unwrap();
+
+        let meta = Meta::default().with_collection("files").with_size(200);
+        assert!(query.matches(&meta));
+        assert!(!query.matches(&Meta::default().with_collection("files").with_size(50)));
+        assert!(!query.matches(&Meta::default().with_collection("files")));
+        assert!(Query::new().matches(&meta));
+
+        let result = Query::new().with_condition(":acl", Condition::Less(Number::Integer(1)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse() {
+        let filter = |tag: &str, condition| Expr::Filter(Filter::new(tag, condition).unwrap());
+        let equal = |tag: &str, value: &str| filter(tag, Condition::Equal(value.into()));
+
+        assert_eq!(Expr::parse("name = test").unwrap(), equal("name", "test"));
+        assert_eq!(
+            Expr::parse("type = photo year >= 2010").unwrap(),
+            Expr::And(vec![
+                equal("type", "photo"),
+                filter("year", Condition::GreaterEqual(Number::Integer(2010)))
+            ])
+        );
+        // not binds strongest, then and, then or
+        assert_eq!(
+            Expr::parse("a = 1 OR b = 2 and NOT c = 3").unwrap(),
+            Expr::Or(vec![
+                equal("a", "1"),
+                Expr::And(vec![equal("b", "2"), Expr::Not(Box::new(equal("c", "3")))])
+            ])
+        );
+        assert_eq!(
+            Expr::parse("(a = 1 or b = 2) and (exists c or d != \"x y\")").unwrap(),
+            Expr::And(vec![
+                Expr::Or(vec![equal("a", "1"), equal("b", "2")]),
+                Expr::Or(vec![
+                    Expr::Exists("c".into()),
+                    Expr::Not(Box::new(equal("d", "x y")))
+                ])
+            ])
+        );
+        assert_eq!(
+            Expr::parse(":size between 1 and 1.5").unwrap(),
+            filter(
+                TAG_SIZE,
+                Condition::Between(Number::Integer(1), Number::Float(1.5))
+            )
+        );
+        assert_eq!(
+            Expr::parse("location prefix europe. path glob \"*.txt\"").unwrap(),
+            Expr::And(vec![
+                filter("location", Condition::Prefix("europe.".into())),
+                filter("path", Condition::Glob("*.txt".into()))
+            ])
+        );
+        assert_eq!(
+            Expr::parse("\"and\"=\"a \\\"quoted\\\" value\"").unwrap(),
+            equal("and", "a \"quoted\" value")
+        );
+
+        for invalid in &[
+            "",
+            "name",
+            "name =",
+            "name = test)",
+            "(name = test",
+            "name ! test",
+            "name = \"test",
+            "size > big",
+            "size between 1 or 2",
+            "path glob [a-",
+            ":acl < 10",
+            "a = 1 or",
+            "not",
+        ] {
+            let err = Expr::parse(invalid).unwrap_err();
+            match Reason::from(&err) {
+                Reason::InvalidQuery(_) => {}
+                reason => panic!("unexpected error for '{}': {:?}", invalid, reason),
+            }
+        }
+
+        let nested = format!("{}a = 1{}", "(".repeat(40), ")".repeat(40));
+        assert!(Expr::parse(&nested).is_err());
+        let nested = format!("{}a = 1{}", "(".repeat(10), ")".repeat(10));
+        assert_eq!(Expr::parse(&nested).unwrap(), equal("a", "1"));
+    }
+
+    #[test]
+    fn expr_matches() {
+        let expr = Expr::parse("kind = photo and (year < 2000 or not exists year)").unwrap();
+        let mut meta = Meta::default();
+        meta.insert("kind", "photo");
+        assert!(expr.matches(&meta));
+        meta.insert("year", Value::number("1999").unwrap());
+        assert!(expr.matches(&meta));
+        meta.insert("year", Value::number("2001").unwrap());
+        assert!(!expr.matches(&meta));
+
+        // a year set as text is never compared
+        meta.insert("year", "1999");
+        assert!(!expr.matches(&meta));
+
+        let query = Query::new().with_collection("files").with_expr(expr);
+        assert!(!query.matches(&meta.clone().with_collection("files")));
+        meta.insert("year", Value::number("1980").unwrap());
+        assert!(query.matches(&meta.clone().with_collection("files")));
+        assert!(!query.matches(&meta.with_collection("other")));
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_matches("europe.*", "europe.belgium.gent"));
+        assert!(glob_matches("europe.*", "europe."));
+        assert!(!glob_matches("europe.*", "europe"));
+        assert!(glob_matches("*.gent.*", "europe.belgium.gent.korenlei"));
+        assert!(glob_matches("a*b*c", "axxbyyc"));
+        assert!(!glob_matches("a*b*c", "axxbyy"));
+        assert!(glob_matches("file-?.txt", "file-1.txt"));
+        assert!(!glob_matches("file-?.txt", "file-10.txt"));
+        assert!(glob_matches("file-[0-9].txt", "file-7.txt"));
+        assert!(!glob_matches("file-[^0-9].txt", "file-7.txt"));
+        assert!(glob_matches("[]]", "]"));
+        assert!(glob_matches("[a-]", "-"));
+        assert!(!glob_matches("Europe.*", "europe.belgium"));
+        assert!(glob_matches("", ""));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("hé*", "héllo"));
+
+        assert_eq!(glob_prefix("europe.*"), "europe.");
+        assert_eq!(glob_prefix("file-[0-9]"), "file-");
+        assert_eq!(glob_prefix("plain"), "plain");
+
+        assert!(Condition::parse("glob", "a[bc").is_err());
+        assert!(Condition::parse("glob", "a[bc]*").is_ok());
+        assert!(Condition::parse("prefix", "/home/")
+            .unwrap()
+            .matches(&"/home/user".into()));
+        assert!(Query::new()
+            .with_condition(TAG_COLLECTION, Condition::Prefix("fi".into()))
+            .is_ok());
+    }
+}
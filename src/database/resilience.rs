@@ -0,0 +1,409 @@
+//! Tests of the database, the metadata interceptor and the router against a storage and an index
+//! which fail halfway through a request. A failed request must leave no trace: no data which
+//! can't be found, no metadata of objects which don't exist, and no stale metadata.
+
+use super::data::BcdbDatabase;
+use super::index::faulty::{self, FaultyIndex};
+use super::index::memory::MemoryIndex;
+use super::index::MetaInterceptor;
+use super::*;
+use crate::acl::ACLStorage;
+use crate::auth::Authenticator;
+use crate::identity::Identity;
+use crate::peer::{Peer, Router};
+use crate::rpc::{BcdbServer, BcdbService};
+use crate::storage::faulty::{Fault, FaultyCollections, FaultyStorage, Op, Rule, Script};
+use crate::storage::memory::{MemoryCollections, MemoryStorage};
+use crate::storage::namespaces::{Collections, Namespaces};
+use crate::storage::Storage;
+use futures::StreamExt;
+use std::time::Duration;
+use tonic::transport::Server;
+
+type FaultyDatabase =
+    BcdbDatabase<FaultyCollections<MemoryCollections>, FaultyIndex<MemoryIndex>, MemoryStorage>;
+
+struct Setup {
+    db: FaultyDatabase,
+    collections: MemoryCollections,
+    storage: Script<Op>,
+    index: Script<faulty::Op>,
+}
+
+impl Setup {
+    async fn new() -> Setup {
+        let collections = MemoryCollections::new();
+        let data = FaultyCollections::new(collections.clone());
+        let storage = data.script().clone();
+        let index = FaultyIndex::new(MemoryIndex::new());
+        let script = index.script().clone();
+        let data = Namespaces::new(data).await.unwrap();
+
+        Setup {
+            db: BcdbDatabase::new(data, index, ACLStorage::new(MemoryStorage::new())),
+            collections: collections,
+            storage: storage,
+            index: script,
+        }
+    }
+
+    /// number of forward records, and of records in the storage of the first collection
+    fn records(&self) -> (usize, usize) {
+        let count = |name: &str| {
+            let storage = self.collections.collection(name).unwrap();
+            Storage::keys(&storage).unwrap().count()
+        };
+
+        (count("objects"), count("objects-1"))
+    }
+
+    /// sets an object in the test collection, with its name as tag
+    async fn set(&mut self, name: &str, data: &[u8]) -> Result<Key> {
+        let mut tags = HashMap::new();
+        tags.insert("name".into(), name.into());
+        self.db
+            .set(&owner(), "test", data.to_vec(), tags, None)
+            .await
+    }
+
+    /// updates the data and the name of an object
+    async fn update(&mut self, key: Key, name: &str, data: &[u8]) -> Result<()> {
+        let mut tags = HashMap::new();
+        tags.insert("name".into(), name.into());
+        self.db
+            .update(&owner(), key, "test", Some(data.to_vec()), tags, None)
+            .await
+    }
+
+    /// keys of the objects with the given name
+    async fn find(&mut self, name: &str) -> Vec<Key> {
+        let query = Query::new().with_tag("name", name);
+        let found = self.db.list(&owner(), query, Some("test")).await.unwrap();
+        found.map(|key| key.unwrap()).collect().await
+    }
+}
+
+fn owner() -> Context {
+    Context::default().with_auth(Authorization::Owner)
+}
+
+fn reason(result: Result<Object>) -> Option<Reason> {
+    result.err().map(|err| Reason::from(&err))
+}
+
+/// serves a database over grpc on a free local port, as a peer does. Requests
+/// signed with the given identity are made by the owner.
+async fn serve(id: &Identity, db: FaultyDatabase) -> Peer {
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut peer = Peer::new(id);
+    peer.host = format!("http://{}", listener.local_addr().unwrap());
+
+    let authenticator = Authenticator::new(HashMap::<u32, Peer>::new(), id.clone());
+    tokio::spawn(async move {
+        let service = BcdbServer::with_interceptor(BcdbService::new(db), move |request| {
+            authenticator.authenticate_blocking(request)
+        });
+
+        Server::builder()
+            .add_service(service)
+            .serve_with_incoming(listener.incoming())
+            .await
+            .unwrap();
+    });
+
+    peer
+}
+
+#[tokio::test]
+async fn set_storage_failure() {
+    let mut setup = Setup::new().await;
+    setup.set("first", b"first").await.unwrap();
+    let records = setup.records();
+
+    // fail to write the data, and to link the written data
+    for after in 0..2 {
+        setup
+            .storage
+            .push(Rule::new(Op::Set, Fault::Fail).after(after));
+        assert!(setup.set("second", b"second").await.is_err());
+        assert_eq!(setup.records(), records);
+    }
+
+    assert_eq!(setup.find("second").await, vec![]);
+    assert_eq!(setup.find("first").await.len(), 1);
+}
+
+#[tokio::test]
+async fn set_index_failure() {
+    let mut setup = Setup::new().await;
+    setup.set("first", b"first").await.unwrap();
+    let records = setup.records();
+
+    // the data of an object without metadata is dropped again
+    setup.index.push(Rule::new(faulty::Op::Set, Fault::Fail));
+    assert!(setup.set("second", b"second").await.is_err());
+    assert_eq!(setup.records(), records);
+
+    // as is the metadata, if it was set after all
+    setup.index.push(Rule::new(faulty::Op::Set, Fault::Lost));
+    assert!(setup.set("third", b"third").await.is_err());
+    assert_eq!(setup.records(), records);
+    assert_eq!(setup.find("third").await, vec![]);
+
+    let key = setup.set("fourth", b"fourth").await.unwrap();
+    assert_eq!(setup.find("fourth").await, vec![key]);
+}
+
+#[tokio::test]
+async fn set_stream_index_failure() {
+    let mut setup = Setup::new().await;
+    setup.set("first", b"first").await.unwrap();
+    let records = setup.records();
+
+    setup.index.push(Rule::new(faulty::Op::Set, Fault::Fail));
+    let (mut tx, rx) = tokio::sync::mpsc::channel(2);
+    tx.send(Ok(Part::Data(b"second".to_vec()))).await.unwrap();
+    tx.send(Ok(Part::Meta {
+        collection: "test".into(),
+        tags: HashMap::new(),
+        acl: None,
+    }))
+    .await
+    .unwrap();
+    drop(tx);
+
+    assert!(setup.db.set_stream(&owner(), rx).await.is_err());
+    assert_eq!(setup.records(), records);
+}
+
+#[tokio::test]
+async fn update_failure() {
+    let mut setup = Setup::new().await;
+    let key = setup.set("object", b"first").await.unwrap();
+    let records = setup.records();
+
+    // fail to write the new data, the history, or to link them, and then
+    // to update the metadata
+    for after in 0..3 {
+        setup
+            .storage
+            .push(Rule::new(Op::Set, Fault::Fail).after(after));
+        assert!(setup.update(key, "updated", b"second").await.is_err());
+        assert_eq!(setup.records(), records);
+    }
+
+    setup.index.push(Rule::new(faulty::Op::Set, Fault::Fail));
+    assert!(setup.update(key, "updated", b"second").await.is_err());
+    assert_eq!(setup.records(), records);
+
+    // the object is left as it was
+    let object = setup.db.get(&owner(), key, "test").await.unwrap();
+    assert_eq!(object.data, Some(b"first".to_vec()));
+    assert_eq!(object.meta.size(), Some(5));
+    assert_eq!(object.meta.get("name").unwrap(), "object");
+    let versions = setup.db.versions(&owner(), key, "test").await.unwrap();
+    assert_eq!(versions.len(), 1);
+
+    setup.update(key, "updated", b"second").await.unwrap();
+    let object = setup.db.get(&owner(), key, "test").await.unwrap();
+    assert_eq!(object.data, Some(b"second".to_vec()));
+    let versions = setup.db.versions(&owner(), key, "test").await.unwrap();
+    assert_eq!(versions.len(), 2);
+}
+
+#[tokio::test]
+async fn delete_storage_failure() {
+    let mut setup = Setup::new().await;
+    let key = setup.set("object", b"data").await.unwrap();
+
+    // the object is deleted with its metadata, even if its data is left behind
+    setup
+        .storage
+        .push(Rule::new(Op::Delete, Fault::Fail).always());
+    setup.db.delete(&owner(), key, "test").await.unwrap();
+    let result = setup.db.get(&owner(), key, "test").await;
+    assert_eq!(reason(result), Some(Reason::NotFound));
+    assert_eq!(setup.find("object").await, vec![]);
+
+    // an object whose metadata can't be deleted stays
+    setup.storage.clear();
+    let key = setup.set("kept", b"data").await.unwrap();
+    setup.index.push(Rule::new(faulty::Op::Set, Fault::Fail));
+    assert!(setup.db.delete(&owner(), key, "test").await.is_err());
+    let object = setup.db.get(&owner(), key, "test").await.unwrap();
+    assert_eq!(object.data, Some(b"data".to_vec()));
+}
+
+#[tokio::test]
+async fn find_index_failure() {
+    let mut setup = Setup::new().await;
+    setup.set("first", b"first").await.unwrap();
+    setup.set("second", b"second").await.unwrap();
+
+    setup.index.push(Rule::new(faulty::Op::Find, Fault::Fail));
+    let results: Vec<_> = setup
+        .db
+        .find(&owner(), Query::new(), Some("test"))
+        .await
+        .unwrap()
+        .collect()
+        .await;
+    assert_eq!(results.len(), 1);
+    assert!(results[0].is_err());
+
+    // the stream ends with the first error
+    setup
+        .index
+        .push(Rule::new(faulty::Op::Get, Fault::Fail).after(1));
+    let results: Vec<_> = setup
+        .db
+        .find(&owner(), Query::new(), Some("test"))
+        .await
+        .unwrap()
+        .collect()
+        .await;
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+}
+
+#[tokio::test]
+async fn get_latency() {
+    let mut setup = Setup::new().await;
+    let key = setup.set("object", b"data").await.unwrap();
+
+    setup
+        .storage
+        .push(Rule::new(Op::Get, Fault::Delay(Duration::from_millis(20))).always());
+    let object = tokio::time::timeout(Duration::from_secs(5), setup.db.get(&owner(), key, "test"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(object.data, Some(b"data".to_vec()));
+}
+
+#[tokio::test]
+async fn interceptor_index_failure() {
+    let log = MemoryStorage::new();
+    let index = FaultyIndex::new(MemoryIndex::new());
+    let script = index.script().clone();
+    let interceptor = MetaInterceptor::new(index, log.clone());
+
+    let mut meta = Meta::default();
+    meta.insert("name", "object");
+    script.push(Rule::new(faulty::Op::Set, Fault::Fail));
+    assert!(interceptor.set(1, meta.clone()).await.is_err());
+    assert_eq!(Storage::keys(&log).unwrap().count(), 0);
+
+    // a rebuild does not bring back the metadata
+    let mut rebuilt = MetaInterceptor::new(MemoryIndex::new(), log.clone());
+    rebuilt.rebuild(None).await.unwrap();
+    assert_eq!(rebuilt.get(1).await.unwrap().count(), 0);
+
+    interceptor.set(1, meta).await.unwrap();
+    assert_eq!(Storage::keys(&log).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn interceptor_storage_failure() {
+    let log = FaultyStorage::new(MemoryStorage::new());
+    let index = MemoryIndex::new();
+    let interceptor = MetaInterceptor::new(index.clone(), log.clone());
+
+    let mut meta = Meta::default();
+    meta.insert("name", "object");
+    log.script().push(Rule::new(Op::Set, Fault::Fail));
+    assert!(interceptor.set(1, meta).await.is_err());
+    assert_eq!(index.get(1).await.unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn interceptor_rebuild_failure() {
+    let log = FaultyStorage::new(MemoryStorage::new());
+    let interceptor = MetaInterceptor::new(MemoryIndex::new(), log.clone());
+    for key in 1..=3 {
+        let mut meta = Meta::default();
+        meta.insert("name", format!("object{}", key));
+        interceptor.set(key, meta).await.unwrap();
+    }
+
+    // a corrupted entry stops the rebuild, it can be resumed after the last good entry
+    log.script()
+        .push(Rule::new(Op::Get, Fault::Corrupt).after(1));
+    let mut rebuilt = MetaInterceptor::new(MemoryIndex::new(), log.clone());
+    let err = rebuilt.rebuild(None).await.unwrap_err();
+    assert!(err.to_string().contains("resumed after metadata key '0'"));
+    rebuilt.resume(0).await.unwrap();
+    assert_eq!(
+        rebuilt.get(2).await.unwrap().get("name").unwrap(),
+        "object2"
+    );
+
+    // so does a scan which breaks off
+    log.script().push(Rule::new(Op::Keys, Fault::Lost));
+    let mut rebuilt = MetaInterceptor::new(MemoryIndex::new(), log.clone());
+    let err = rebuilt.rebuild(None).await.unwrap_err();
+    assert!(err.to_string().contains("resumed after metadata key '2'"));
+    assert_eq!(
+        rebuilt.get(3).await.unwrap().get("name").unwrap(),
+        "object3"
+    );
+}
+
+#[tokio::test]
+async fn router_failure() {
+    let id = Identity::from_mnemonic(1, "crunch depend lock agree lava include clown toss runway source better such never bonus divide trade squeeze type ride satoshi slender lottery rain cause").unwrap();
+    let mut setup = Setup::new().await;
+    let key = setup.set("object", b"data").await.unwrap();
+
+    // the peer serves a faulty database of its own
+    let mut remote = Setup::new().await;
+    let remote_key = remote.set("remote", b"remote data").await.unwrap();
+    let mut peers = HashMap::new();
+    peers.insert(2, serve(&id, remote.db.clone()).await);
+
+    // nothing listens on the port of this peer
+    let mut unreachable = Peer::new(&id);
+    unreachable.host = "http://127.0.0.1:1".into();
+    peers.insert(3, unreachable);
+
+    let mut router = Router::new(id, setup.db.clone(), peers);
+
+    // a head only reads the metadata of an object
+    setup.storage.push(Rule::new(Op::Get, Fault::Fail).always());
+    let object = router.head(&owner(), key, "test").await.unwrap();
+    assert_eq!(object.data, None);
+    assert_eq!(object.meta.get("name").unwrap(), "object");
+    let local = reason(router.get(&owner(), key, "test").await);
+    match local {
+        Some(Reason::Unknown(_)) => {}
+        ref reason => panic!("unexpected result: {:?}", reason),
+    }
+
+    let ctx = owner().with_route(Some(2));
+    let object = router.get(&ctx, remote_key, "test").await.unwrap();
+    assert_eq!(object.data, Some(b"remote data".to_vec()));
+    assert_eq!(object.meta.get("name").unwrap(), "remote");
+
+    // a failure of the peer is surfaced as the same failure of the local database
+    remote
+        .storage
+        .push(Rule::new(Op::Get, Fault::Fail).always());
+    let object = router.head(&ctx, remote_key, "test").await.unwrap();
+    assert_eq!(object.data, None);
+    assert_eq!(object.meta.get("name").unwrap(), "remote");
+    assert_eq!(reason(router.get(&ctx, remote_key, "test").await), local);
+    assert_eq!(
+        reason(router.get(&ctx, remote_key + 1, "test").await),
+        Some(Reason::NotFound)
+    );
+
+    // unknown peers and peers which don't answer can't be reached
+    for id in &[3, 4] {
+        let ctx = owner().with_route(Some(*id));
+        match reason(router.get(&ctx, key, "test").await) {
+            Some(Reason::CannotGetPeer(_)) => {}
+            reason => panic!("unexpected result: {:?}", reason),
+        }
+    }
+}
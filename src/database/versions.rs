@@ -0,0 +1,342 @@
+//! Version history of objects. Updating the data of an object writes the new data to a new record,
+//! the previous data is kept as a past version of the object. The past versions of an object are
+//! listed in its history record, which is stored in the collection of the object next to its
+//! data. The forward record of the object points to both its current data and its history.
+//!
+//! The metadata of an object is kept with every past version, so a past version is read with the
+//! metadata it had while it was current.
+//!
+//! The retention policy of a collection limits how many versions of an object are kept, once
+//! an object has more versions the oldest versions are dropped. The history record is rewritten
+//! on every update, so the number of versions is always limited, by default to
+//! `DEFAULT_VERSIONS`.
+
+use super::{Key, Meta, Reason};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+/// Magic prefix of a history record
+const MAGIC: &[u8; 8] = b"bcdb:his";
+
+/// Version of the history format
+const FORMAT: u8 = 1;
+
+/// Size of the history header: magic (8), format (1), current version (8), timestamp (8),
+/// version count (4)
+const HEADER_SIZE: usize = 29;
+
+/// Size of a past version: version (8), key (8), size (8), timestamp (8), followed by
+/// the size of its metadata (4) and the metadata itself
+const ENTRY_SIZE: usize = 32;
+
+/// Size of the length of the metadata of a past version
+const META_SIZE: usize = 4;
+
+/// A version of an object
+#[derive(Debug, Clone, PartialEq)]
+pub struct Version {
+    /// number of the version, the first version of an object is 1
+    pub version: u64,
+    /// size of the data of the version
+    pub size: u64,
+    /// time the data of the version was written, in seconds since the epoch
+    pub timestamp: u64,
+}
+
+/// The number of versions that are kept of an object, including its current
+/// version, if its collection has no retention policy
+pub const DEFAULT_VERSIONS: usize = 10;
+
+/// The number of versions that are kept of the objects of every collection
+#[derive(Debug, Clone)]
+pub struct Retention {
+    default: usize,
+    collections: HashMap<String, usize>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            default: DEFAULT_VERSIONS,
+            collections: HashMap::default(),
+        }
+    }
+}
+
+impl Retention {
+    /// creates a retention policy which keeps `DEFAULT_VERSIONS` versions of
+    /// all objects
+    pub fn new() -> Self {
+        Retention::default()
+    }
+
+    /// sets the number of versions that are kept of the objects of collections
+    /// which have no policy of their own
+    pub fn with_default(mut self, versions: usize) -> Self {
+        assert!(versions > 0);
+        self.default = versions;
+        self
+    }
+
+    /// sets the number of versions that are kept of the objects of a collection
+    pub fn with_collection<N: Into<String>>(mut self, collection: N, versions: usize) -> Self {
+        assert!(versions > 0);
+        self.collections.insert(collection.into(), versions);
+        self
+    }
+
+    /// the maximum number of versions that are kept of an object of the given
+    /// collection, including its current version
+    pub fn versions(&self, collection: &str) -> usize {
+        match self.collections.get(collection) {
+            Some(versions) => *versions,
+            None => self.default,
+        }
+    }
+}
+
+/// A past version of an object
+#[derive(Debug, Clone, PartialEq)]
+pub struct Past {
+    pub version: Version,
+    /// key of the data of the version in the storage collection of the object
+    pub key: Key,
+    /// metadata of the object while the version was current
+    pub meta: Meta,
+}
+
+/// The history of an object, the past versions of the object and the keys of
+/// their data in the storage collection of the object
+#[derive(Debug, Clone, PartialEq)]
+pub struct History {
+    /// number of the current version
+    pub current: u64,
+    /// time the data of the current version was written
+    pub timestamp: u64,
+    /// past versions, oldest first
+    pub past: Vec<Past>,
+}
+
+impl History {
+    /// creates the history of an object which was never updated
+    pub fn new(created: u64) -> Self {
+        History {
+            current: 1,
+            timestamp: created,
+            past: Vec::new(),
+        }
+    }
+
+    /// makes the current version, stored at the given key and with the given
+    /// metadata, a past version. The next version, written at the given time,
+    /// becomes current.
+    pub fn push(&mut self, key: Key, size: u64, meta: Meta, timestamp: u64) {
+        let version = Version {
+            version: self.current,
+            size: size,
+            timestamp: self.timestamp,
+        };
+
+        self.past.push(Past {
+            version: version,
+            key: key,
+            meta: meta,
+        });
+        self.current += 1;
+        self.timestamp = timestamp;
+    }
+
+    /// drops the oldest past versions, so at most the given number of versions
+    /// are left, including the current version. Returns the keys of the data of
+    /// the dropped versions.
+    pub fn retain(&mut self, versions: usize) -> Vec<Key> {
+        let keep = versions.saturating_sub(1);
+        if self.past.len() <= keep {
+            return Vec::new();
+        }
+
+        let count = self.past.len() - keep;
+        self.past.drain(..count).map(|past| past.key).collect()
+    }
+
+    /// gets a past version
+    pub fn get(&self, version: u64) -> Option<&Past> {
+        self.past
+            .iter()
+            .find(|past| past.version.version == version)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + ENTRY_SIZE * self.past.len());
+        buf.extend_from_slice(MAGIC);
+        buf.push(FORMAT);
+        buf.extend_from_slice(&self.current.to_le_bytes());
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        buf.extend_from_slice(&(self.past.len() as u32).to_le_bytes());
+        for past in &self.past {
+            buf.extend_from_slice(&past.version.version.to_le_bytes());
+            buf.extend_from_slice(&past.key.to_le_bytes());
+            buf.extend_from_slice(&past.version.size.to_le_bytes());
+            buf.extend_from_slice(&past.version.timestamp.to_le_bytes());
+
+            let meta = serde_json::to_vec(&past.meta)?;
+            buf.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&meta);
+        }
+
+        Ok(buf)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<History> {
+        if data.len() < HEADER_SIZE || !data.starts_with(MAGIC) {
+            bail!(Reason::Unknown("invalid history record".into()));
+        }
+
+        let format = data[8];
+        if format != FORMAT {
+            bail!(Reason::Unknown(format!(
+                "unsupported history format {}",
+                format
+            )));
+        }
+
+        let truncated = || Reason::Unknown("history record is truncated".into());
+        let u64_at = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+        let current = u64_at(9);
+        let timestamp = u64_at(17);
+        let count = u32::from_le_bytes(data[25..29].try_into().unwrap()) as usize;
+
+        // every entry is at least ENTRY_SIZE long, so a corrupt count can't
+        // allocate more than the record holds
+        if data.len() < HEADER_SIZE + count.saturating_mul(ENTRY_SIZE) {
+            bail!(truncated());
+        }
+
+        let mut past = Vec::with_capacity(count);
+        let mut at = HEADER_SIZE;
+        for _ in 0..count {
+            if data.len() < at + ENTRY_SIZE {
+                bail!(truncated());
+            }
+
+            let version = Version {
+                version: u64_at(at),
+                size: u64_at(at + 16),
+                timestamp: u64_at(at + 24),
+            };
+            let key = u64_at(at + 8);
+            at += ENTRY_SIZE;
+
+            if data.len() < at + META_SIZE {
+                bail!(truncated());
+            }
+
+            let size = u32::from_le_bytes(data[at..at + META_SIZE].try_into().unwrap()) as usize;
+            at += META_SIZE;
+            if data.len() < at + size {
+                bail!(truncated());
+            }
+
+            let meta = serde_json::from_slice(&data[at..at + size])?;
+            at += size;
+
+            past.push(Past {
+                version: version,
+                key: key,
+                meta: meta,
+            });
+        }
+
+        if at != data.len() {
+            bail!(Reason::Unknown("invalid history record".into()));
+        }
+
+        Ok(History {
+            current,
+            timestamp,
+            past,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_retain() {
+        let mut history = History::new(100);
+        assert_eq!(history.retain(1), Vec::<Key>::new());
+
+        history.push(10, 5, Meta::default(), 200);
+        history.push(11, 6, Meta::default(), 300);
+        history.push(12, 7, Meta::default(), 400);
+        assert_eq!(history.current, 4);
+        assert_eq!(history.timestamp, 400);
+        assert_eq!(
+            history.get(2),
+            Some(&Past {
+                version: Version {
+                    version: 2,
+                    size: 6,
+                    timestamp: 200
+                },
+                key: 11,
+                meta: Meta::default(),
+            })
+        );
+
+        assert_eq!(history.retain(4), Vec::<Key>::new());
+        assert_eq!(history.past.len(), 3);
+
+        assert_eq!(history.retain(2), vec![10, 11]);
+        assert_eq!(history.past.len(), 1);
+        assert_eq!(history.get(1), None);
+        assert_eq!(history.get(3).map(|past| past.key), Some(12));
+
+        assert_eq!(history.retain(1), vec![12]);
+        assert_eq!(history.past.len(), 0);
+        assert_eq!(history.current, 4);
+    }
+
+    #[test]
+    fn history_encode() {
+        let mut meta = Meta::default().with_size(5);
+        meta.insert("name", "first");
+
+        let mut history = History::new(100);
+        history.push(10, 5, meta, 200);
+        history.push(11, 6, Meta::default(), 300);
+
+        let encoded = history.encode().unwrap();
+        let decoded = History::decode(&encoded).unwrap();
+        assert_eq!(decoded, history);
+        let meta = &decoded.get(1).unwrap().meta;
+        assert_eq!(meta.get("name").unwrap(), "first");
+        assert_eq!(meta.size(), Some(5));
+
+        let encoded = History::new(100).encode().unwrap();
+        assert_eq!(encoded.len(), HEADER_SIZE);
+        assert_eq!(History::decode(&encoded).unwrap(), History::new(100));
+
+        assert!(History::decode(b"not a history").is_err());
+        let encoded = history.encode().unwrap();
+        assert!(History::decode(&encoded[..40]).is_err());
+        assert!(History::decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn retention_versions() {
+        let retention = Retention::new();
+        assert_eq!(retention.versions("test"), DEFAULT_VERSIONS);
+
+        let retention = retention.with_collection("test", 3);
+        assert_eq!(retention.versions("test"), 3);
+        assert_eq!(retention.versions("other"), DEFAULT_VERSIONS);
+
+        let retention = retention.with_default(1);
+        assert_eq!(retention.versions("test"), 3);
+        assert_eq!(retention.versions("other"), 1);
+    }
+}
@@ -4,12 +4,20 @@ use ed25519_dalek::{
     Keypair, PublicKey as PubKey, SecretKey, Signature as Sig, KEYPAIR_LENGTH, PUBLIC_KEY_LENGTH,
     SECRET_KEY_LENGTH, SIGNATURE_LENGTH,
 };
+use hkdf::Hkdf;
 use serde::de::{Error as SerdeError, Unexpected, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha256;
 use signature::{Signature as SignatureTrait, Signer, Verifier};
 use std::fmt;
 use std::path::Path;
 
+/// Length of a key derived from an identity
+pub const DERIVED_KEY_LENGTH: usize = 32;
+
+/// Salt used for key derivation, so derived keys are bound to this application
+const KDF_SALT: &[u8] = b"bcdb identity kdf v1";
+
 /// An identity representation on the threefold grid. This can be used to sign messages, and verify
 /// signatures of messages created by this identity. The public key can be exported and exchanged
 /// to allow others to verify messages signed with this identity.
@@ -115,6 +123,25 @@ impl Identity {
         self.kp.secret.as_bytes()
     }
 
+    /// Derive a subkey from the private key, with HKDF-SHA256. The label names the purpose of the
+    /// key (for example "storage"), and the context binds it to a specific use (for example a
+    /// collection name). Different labels or contexts yield independent keys, so the private key
+    /// itself is only ever used for signing.
+    pub fn derive_key(&self, label: &str, context: &[u8]) -> [u8; DERIVED_KEY_LENGTH] {
+        let kdf = Hkdf::<Sha256>::new(Some(KDF_SALT), self.kp.secret.as_bytes());
+
+        // the label is length prefixed, so label and context are unambiguous
+        let mut info = Vec::with_capacity(4 + label.len() + context.len());
+        info.extend_from_slice(&(label.len() as u32).to_le_bytes());
+        info.extend_from_slice(label.as_bytes());
+        info.extend_from_slice(context);
+
+        let mut key = [0; DERIVED_KEY_LENGTH];
+        // expanding can only fail if the requested key is too long
+        kdf.expand(&info, &mut key).unwrap();
+        key
+    }
+
     /// Get a copy of the public key of the keypair
     pub fn public_key(&self) -> PublicKey {
         PublicKey { pk: self.kp.public }
@@ -269,6 +296,24 @@ mod tests {
         id.verify(message, &sig).unwrap();
     }
 
+    #[test]
+    fn derive_key() {
+        let id = Identity::from_sk_bytes(0, &[1; 32]).unwrap();
+
+        let key = id.derive_key("storage", b"objects");
+        assert_eq!(key, id.derive_key("storage", b"objects"));
+        assert_ne!(&key, id.as_sk_bytes());
+        assert_ne!(key, id.derive_key("storage", b"acl"));
+        assert_ne!(key, id.derive_key("other", b"objects"));
+        assert_ne!(
+            id.derive_key("storage", b"\0objects"),
+            id.derive_key("storage\0", b"objects")
+        );
+
+        let other = Identity::from_sk_bytes(0, &[2; 32]).unwrap();
+        assert_ne!(key, other.derive_key("storage", b"objects"));
+    }
+
     #[test]
     fn decode_public_key_json() {
         let invalid_type = "35498";
@@ -1,9 +1,30 @@
 use anyhow::Context;
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::future::{BoxFuture, FutureExt};
 use identity::Identity;
 use log::debug;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use storage::{encrypted::EncryptedStorage, zdb::Zdb};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use storage::{
+    blocking::BlockingStorage,
+    compaction::{self, Compactor, DeadBytes, ZdbCompactor},
+    compressed::{Codec, CompressedStorage},
+    dedup::{self, DedupStorage},
+    encrypted::EncryptedStorage,
+    erasure::ErasureStorage,
+    file::FileStorage,
+    keyring::Keyring,
+    namespaces::{
+        Collections, Namespaces, REGISTRY_COLLECTION, SHARED_COLLECTION, STAGING_COLLECTION,
+    },
+    replicated::ReplicatedStorage,
+    zdb,
+    zdb::Zdb,
+    AsyncStorage, Storage,
+};
 use tokio::runtime::Builder;
 use tonic::transport::Server;
 
@@ -23,6 +44,14 @@ mod rpc;
 mod storage;
 
 const MEAT_DIR: &str = ".bcdb-meta";
+const DATA_DIR: &str = ".bcdb-data";
+
+/// Interval between repairs of unhealthy replicas
+const REPAIR_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Re-wraps the data keys of a collection whose key encryption key was rotated, resolves to the
+/// number of re-wrapped keys
+type Rewrap = BoxFuture<'static, Result<u64, storage::Error>>;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut runtime = Builder::default()
@@ -52,15 +81,129 @@ async fn entry() -> Result<(), Box<dyn std::error::Error>> {
         None => std::path::PathBuf::from(MEAT_DIR),
     };
 
+    let data_dir = match dirs::home_dir() {
+        Some(p) => p.join(DATA_DIR),
+        None => std::path::PathBuf::from(DATA_DIR),
+    };
+
     let matches = App::new("bcdb")
         .arg(
             Arg::with_name("zdb")
-                .help("local zdb port")
+                .help("zdb address: a local port, <host>:<port>, redis://<host>:<port> or unix://<socket path>")
                 .long("zdb")
                 .short("z")
                 .takes_value(true)
                 .default_value("9900"),
         )
+        .arg(
+            Arg::with_name("zdb-password")
+                .help("zdb admin password, used to authenticate all zdb connections")
+                .long("zdb-password")
+                .env("ZDB_PASSWORD")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("namespace-password")
+                .help("password of a zdb namespace (metadata, acl, objects or keys): <namespace>=<password>, the password is set when bcdb creates the namespace")
+                .long("namespace-password")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("pool-size")
+                .help("maximum number of connections to a zdb namespace")
+                .long("pool-size")
+                .takes_value(true)
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("pool-min-idle")
+                .help("minimum number of idle connections to a zdb namespace")
+                .long("pool-min-idle")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("pool-lifetime")
+                .help("close zdb connections after this number of seconds")
+                .long("pool-lifetime")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pool-idle-timeout")
+                .help("close idle zdb connections after this number of seconds")
+                .long("pool-idle-timeout")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("storage")
+                .help("storage backend: an external 0-db process, an embedded 0-db or plain files")
+                .long("storage")
+                .takes_value(true)
+                .possible_values(&["external", "embedded", "file"])
+                .default_value("external"),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .help("directory where the embedded 0-db or file storage stores its data")
+                .long("data-dir")
+                .takes_value(true)
+                .default_value(data_dir.to_str().unwrap_or(DATA_DIR)),
+        )
+        .arg(
+            Arg::with_name("sync")
+                .help("when the file storage syncs writes to disk: always, never, or once every <n> writes. Writes which are not synced are lost on power failure")
+                .long("sync")
+                .takes_value(true)
+                .default_value("always"),
+        )
+        .arg(
+            Arg::with_name("compression")
+                .help("compress a collection (metadata, acl or objects): <collection>=<none|zstd|lz4>")
+                .long("compression")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("versions")
+                .help("number of versions kept of every object, including the current version: <count> for all collections, or <collection>=<count>. The last 10 versions are kept by default")
+                .long("versions")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("replica")
+                .help("address of another zdb which holds a replica of all data (external storage only)")
+                .long("replica")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("write-quorum")
+                .help("number of replicas a write must succeed on, defaults to a majority of replicas")
+                .long("write-quorum")
+                .takes_value(true)
+                .requires("replica"),
+        )
+        .arg(
+            Arg::with_name("shard")
+                .help("address of a zdb which holds a shard of every object, objects are erasure coded over all shards (external storage only)")
+                .long("shard")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("parity-shards")
+                .help("number of shards which hold parity data, an object can be read as long as no more shards are lost")
+                .long("parity-shards")
+                .takes_value(true)
+                .default_value("1"),
+        )
         .arg(
             Arg::with_name("grpc")
                 .help("listen on address for grpc api")
@@ -144,6 +287,62 @@ async fn entry() -> Result<(), Box<dyn std::error::Error>> {
                         .help("only rebuild index with records after given timestamp")
                         .takes_value(true)
                         .required(false),
+                )
+                .arg(
+                    Arg::with_name("after")
+                        .long("after")
+                        .short("a")
+                        .help("resume a failed rebuild after the given metadata key")
+                        .takes_value(true)
+                        .required(false)
+                        .conflicts_with("from"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rotate-keys")
+                .about("rotate the key encryption keys of all collections, and re-wrap their data keys"),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("move objects from the shared objects collection to the storage of their collection"),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("reclaim the space of deleted and overwritten objects")
+                .arg(
+                    Arg::with_name("collection")
+                        .long("collection")
+                        .short("c")
+                        .help("storage collection to compact, all collections by default")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("threshold")
+                        .long("threshold")
+                        .short("t")
+                        .help("minimum fraction of dead space for a collection to be compacted")
+                        .takes_value(true)
+                        .default_value("0.3"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("only report the used and dead space of the collections"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("report the space saved by deduplication of objects")
+                .arg(
+                    Arg::with_name("collection")
+                        .long("collection")
+                        .short("c")
+                        .help("collection to report, all collections by default")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
                 ),
         )
         .get_matches();
@@ -171,20 +370,439 @@ async fn entry() -> Result<(), Box<dyn std::error::Error>> {
         identity.public_key()
     );
 
-    debug!("Using identity private key as symmetric encryption key for zdb data");
+    debug!("Using keys derived from the identity private key to seal the key encryption keys of zdb data");
+
+    match matches.value_of("storage").unwrap() {
+        "embedded" => {
+            let zdb = zdb::embedded::Zdb::new(matches.value_of("data-dir").unwrap())?;
+            info!(
+                "Using embedded zdb, data directory: {}",
+                matches.value_of("data-dir").unwrap()
+            );
+            let dead = dead_bytes(&matches, "embedded")?;
+            let compactor = ZdbCompactor::new(vec![(zdb.clone(), dead.clone())]);
+            recover(&compactor).await?;
+
+            let metadata =
+                BlockingStorage::new(dead.counted("metadata", zdb.collection("metadata")));
+            let acl = BlockingStorage::new(dead.counted("acl", zdb.collection("acl")));
+            let keys = dead.counted("keys", zdb.collection("keys"));
+            let objects = move |name: &str| -> Result<_, storage::Error> {
+                Ok(BlockingStorage::new(
+                    dead.counted(name, zdb.collection(name)),
+                ))
+            };
+            app(&matches, identity, metadata, acl, objects, keys, compactor).await
+        }
+        "file" => {
+            let storage = FileStorage::new(matches.value_of("data-dir").unwrap())?
+                .with_sync(matches.value_of("sync").unwrap().parse()?);
+            info!(
+                "Using file storage, data directory: {}",
+                matches.value_of("data-dir").unwrap()
+            );
+            let metadata = BlockingStorage::new(storage.collection("metadata")?);
+            let acl = BlockingStorage::new(storage.collection("acl")?);
+            let keys = storage.collection("keys")?;
+            let compactor = storage.clone();
+            let objects = move |name: &str| storage.collection(name).map(BlockingStorage::new);
+            app(&matches, identity, metadata, acl, objects, keys, compactor).await
+        }
+        _ => {
+            let address = matches.value_of("zdb").unwrap();
+            let zdb = connect(&matches, address)?;
+            let dead = dead_bytes(&matches, address)?;
+            let addresses = match matches.values_of("replica") {
+                Some(addresses) => addresses,
+                None => {
+                    let instances = vec![(zdb.clone(), dead.clone())];
+                    recover(&ZdbCompactor::new(instances.clone())).await?;
+
+                    // requests are multiplexed over a single connection per namespace, only
+                    // the keys are read through the (blocking) connection pool
+                    let multiplexed = zdb.multiplexed();
+                    let metadata = dead.counted("metadata", multiplexed.collection("metadata"));
+                    let acl = dead.counted("acl", multiplexed.collection("acl"));
+                    let keys = dead.counted("keys", zdb.collection("keys"));
+                    let objects = move |name: &str| -> Result<_, storage::Error> {
+                        Ok(dead.counted(name, multiplexed.collection(name)))
+                    };
+                    return external(&matches, identity, metadata, acl, objects, keys, instances)
+                        .await;
+                }
+            };
+
+            let mut zdbs = vec![(zdb, dead)];
+            for address in addresses {
+                zdbs.push((connect(&matches, address)?, dead_bytes(&matches, address)?));
+            }
+
+            let instances = zdbs.clone();
+            recover(&ZdbCompactor::new(instances.clone())).await?;
+
+            let quorum = match matches.value_of("write-quorum") {
+                Some(quorum) => quorum.parse()?,
+                None => zdbs.len() / 2 + 1,
+            };
+
+            if quorum == 0 || quorum > zdbs.len() {
+                return Err(format!(
+                    "invalid write quorum {}, expecting a value between 1 and {}",
+                    quorum,
+                    zdbs.len()
+                )
+                .into());
+            }
+
+            info!(
+                "Replicating data over {} zdb instances, with a write quorum of {}",
+                zdbs.len(),
+                quorum
+            );
+
+            // the generations of the replicas of every collection are kept with the metadata
+            let state = Path::new(matches.value_of("meta").unwrap()).join("replication");
+            std::fs::create_dir_all(&state)?;
+            let collection = move |name: &str| {
+                ReplicatedStorage::new(
+                    zdbs.iter()
+                        .map(|(zdb, dead)| dead.counted(name, zdb.collection(name)))
+                        .collect(),
+                )
+                .with_quorum(quorum)
+                .with_state(state.join(name))
+            };
+
+            // replicas are not repaired while their namespaces are compacted
+            let repairs = Repairs::new();
+            if matches.subcommand_name() != Some("compact") {
+                repairs.start();
+            }
+
+            // the replicas of the collections are checked on the blocking pool
+            let (metadata, acl, keys) = {
+                let repairs = repairs.clone();
+                let collection = collection.clone();
+                tokio::task::spawn_blocking(move || -> Result<_, storage::Error> {
+                    Ok((
+                        repairs.open("metadata", || collection("metadata"))?,
+                        repairs.open("acl", || collection("acl"))?,
+                        repairs.open("keys", || collection("keys"))?,
+                    ))
+                })
+                .await??
+            };
+
+            // collections of objects are opened on first use, and repaired with the others
+            let objects = move |name: &str| -> Result<_, storage::Error> {
+                let storage = repairs.open(name, || collection(name))?;
+                Ok(BlockingStorage::new(storage))
+            };
 
-    let zdb = Zdb::new(matches.value_of("zdb").unwrap().parse()?);
+            let metadata = BlockingStorage::new(metadata);
+            let acl = BlockingStorage::new(acl);
+            external(&matches, identity, metadata, acl, objects, keys, instances).await
+        }
+    }
+}
+
+/// Connects to the zdb at the given address, with the configured passwords and pool settings.
+fn connect(matches: &ArgMatches<'_>, address: &str) -> Result<Zdb, Box<dyn std::error::Error>> {
+    let seconds = |name: &str| -> Result<Option<Duration>, Box<dyn std::error::Error>> {
+        match matches.value_of(name) {
+            Some(value) => Ok(Some(Duration::from_secs(value.parse()?))),
+            None => Ok(None),
+        }
+    };
+
+    let pool = zdb::PoolConfig {
+        max_size: matches.value_of("pool-size").unwrap().parse()?,
+        min_idle: Some(matches.value_of("pool-min-idle").unwrap().parse()?),
+        max_lifetime: seconds("pool-lifetime")?,
+        idle_timeout: seconds("pool-idle-timeout")?,
+        ..Default::default()
+    };
+
+    let mut zdb = Zdb::open(address)?.with_pool(pool);
+    if let Some(password) = matches.value_of("zdb-password") {
+        zdb = zdb.with_password(password);
+    }
+
+    if let Some(values) = matches.values_of("namespace-password") {
+        for value in values {
+            let mut parts = value.splitn(2, '=');
+            let namespace = parts.next().unwrap_or_default();
+            let password = match parts.next() {
+                Some(password) => password,
+                None => {
+                    return Err(
+                        "invalid namespace password, expecting <namespace>=<password>".into(),
+                    )
+                }
+            };
+
+            zdb = zdb.with_namespace_password(namespace, password);
+        }
+    }
+
+    Ok(zdb)
+}
+
+/// Runs the bcdb services on top of external zdb collections. If shards are given, objects are
+/// erasure coded over the shard zdbs, instead of being stored on the zdb given with `--zdb`. The
+/// namespaces of the given zdb instances, and of the shards, are compacted offline.
+async fn external<S, K, O>(
+    matches: &ArgMatches<'_>,
+    identity: Identity,
+    metadata: S,
+    acl: S,
+    objects: O,
+    keys: K,
+    mut instances: Vec<(Zdb, DeadBytes)>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncStorage,
+    K: Storage + Send + Sync + 'static,
+    O: Collections,
+{
+    let addresses = match matches.values_of("shard") {
+        Some(addresses) => addresses,
+        None => {
+            let compactor = ZdbCompactor::new(instances);
+            return app(matches, identity, metadata, acl, objects, keys, compactor).await;
+        }
+    };
+
+    let mut shards = Vec::new();
+    for address in addresses {
+        shards.push((connect(matches, address)?, dead_bytes(matches, address)?));
+    }
+
+    recover(&ZdbCompactor::new(shards.clone())).await?;
+    instances.extend(shards.iter().cloned());
+
+    let count = shards.len();
+    let parity: usize = matches.value_of("parity-shards").unwrap().parse()?;
+    let objects = move |name: &str| {
+        ErasureStorage::new(
+            shards
+                .iter()
+                .map(|(zdb, dead)| dead.counted(name, zdb.collection(name)))
+                .collect(),
+            parity,
+        )
+        .map(BlockingStorage::new)
+    };
+    // check the shard configuration before any collection is opened
+    objects("objects")?;
+    info!(
+        "Erasure coding objects over {} zdb instances, with {} parity shards",
+        count, parity
+    );
+
+    let compactor = ZdbCompactor::new(instances);
+    app(matches, identity, metadata, acl, objects, keys, compactor).await
+}
+
+/// Opens the counters of the dead bytes of the namespaces of a zdb. The counters are kept with
+/// the metadata, in a file which is named after the address of the zdb.
+fn dead_bytes(
+    matches: &ArgMatches<'_>,
+    address: &str,
+) -> Result<DeadBytes, Box<dyn std::error::Error>> {
+    let dir = Path::new(matches.value_of("meta").unwrap()).join("dead-bytes");
+    std::fs::create_dir_all(&dir)?;
+    let name: String = address
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    Ok(DeadBytes::open(dir.join(name))?)
+}
+
+/// Finishes or rolls back the compactions which were interrupted, this must run before the
+/// collections are used.
+async fn recover<C: Compactor>(compactor: &C) -> Result<(), Box<dyn std::error::Error>> {
+    let compactor = compactor.clone();
+    tokio::task::spawn_blocking(move || compactor.recover()).await??;
+    Ok(())
+}
+
+/// The replicated collections which are repaired in the background. A single task repairs all
+/// registered collections, collections which are opened later are added to it.
+#[derive(Clone)]
+struct Repairs<S> {
+    collections: Arc<Mutex<HashMap<String, ReplicatedStorage<S>>>>,
+}
+
+impl<S> Repairs<S>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    fn new() -> Self {
+        Repairs {
+            collections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Starts the task which keeps repairing the unhealthy replicas of the registered collections
+    fn start(&self) {
+        let task = self.clone();
+        tokio::spawn(async move {
+            loop {
+                task.repair().await;
+                tokio::time::delay_for(REPAIR_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Gets the registered collection with the given name, or opens and registers it. The
+    /// replicas of a new collection which are behind are marked unhealthy. All users of a
+    /// collection share the same storage, and so the health of its replicas. Checking the
+    /// replicas blocks, so collections are opened on the blocking pool.
+    fn open<F>(&self, name: &str, open: F) -> Result<ReplicatedStorage<S>, storage::Error>
+    where
+        F: FnOnce() -> Result<ReplicatedStorage<S>, storage::Error>,
+    {
+        if let Some(storage) = self.collections.lock().unwrap().get(name) {
+            return Ok(storage.clone());
+        }
+
+        // the replicas are checked without holding the lock, so the repair task and the
+        // collections which are already open are not held up. If the collection is opened
+        // twice at the same time, the first one to be registered is used.
+        let storage = open()?;
+        storage.check()?;
+        Ok(self
+            .collections
+            .lock()
+            .unwrap()
+            .entry(name.into())
+            .or_insert(storage)
+            .clone())
+    }
+
+    async fn repair(&self) {
+        let collections: Vec<(String, ReplicatedStorage<S>)> = self
+            .collections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, storage)| (name.clone(), storage.clone()))
+            .collect();
+
+        for (name, storage) in collections {
+            match tokio::task::spawn_blocking(move || storage.repair()).await {
+                Ok(Ok(count)) if count > 0 => {
+                    info!("repaired collection {}, synced {} records", name, count)
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => error!("failed to repair collection {}: {}", name, err),
+                Err(err) => error!("failed to repair collection {}: {}", name, err),
+            }
+        }
+    }
+}
+
+/// Runs the bcdb services (or the selected subcommand) on top of the given storage collections.
+async fn app<S, K, O, C>(
+    matches: &ArgMatches<'_>,
+    identity: Identity,
+    metadata: S,
+    acl: S,
+    objects: O,
+    keys: K,
+    compactor: C,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncStorage,
+    K: Storage + Send + Sync + 'static,
+    O: Collections,
+    C: Compactor,
+{
+    if let Some(matches) = matches.subcommand_matches("compact") {
+        return compact(matches, compactor).await;
+    }
 
     // use sqlite meta data factory, to build a sqlite index
     let index = database::index::SqliteIndexBuilder::new(matches.value_of("meta").unwrap())?
         .build("metadata")
         .await?;
 
+    // every record is encrypted with its own data key, which is wrapped by a
+    // key encryption key from the keyring of its collection
+    let metadata = encrypted(&identity, "metadata", metadata, &keys)?;
+    let acl = encrypted(&identity, "acl", acl, &keys)?;
+
+    // the data keys of rotated collections are re-wrapped once all collections are rotated
+    let rotate_keys = matches.subcommand_matches("rotate-keys").is_some();
+    let rewraps = Arc::new(Mutex::new(Vec::new()));
+    if rotate_keys {
+        let mut rewraps = rewraps.lock().unwrap();
+        rewraps.push(rotate("metadata", metadata.clone(), &keys)?);
+        rewraps.push(rotate("acl", acl.clone(), &keys)?);
+    }
+
+    // data is compressed before it's encrypted, since ciphertext does not compress
+    let metadata = CompressedStorage::new(metadata, codec(matches, "metadata")?);
+    let acl = CompressedStorage::new(acl, codec(matches, "acl")?);
+
+    // every collection of objects is stored in its own storage collection, which
+    // has its own keyring. Records are deduplicated before they are compressed,
+    // the blobs of a collection are listed in a table next to it.
+    let objects = {
+        let identity = identity.clone();
+        let keys = keys.clone();
+        let codec = codec(matches, "objects")?;
+        let rewraps = rewraps.clone();
+        move |name: &str| -> Result<_, storage::Error> {
+            let storage = encrypted(&identity, name, objects.collection(name)?, &keys)?;
+            if rotate_keys {
+                let rewrap = rotate(name, storage.clone(), &keys)?;
+                rewraps.lock().unwrap().push(rewrap);
+            }
+
+            let storage = DedupStorage::new(CompressedStorage::new(storage, codec));
+            if name == SHARED_COLLECTION
+                || name == REGISTRY_COLLECTION
+                || name == STAGING_COLLECTION
+            {
+                return Ok(storage);
+            }
+
+            let blobs = format!("{}-blobs", name);
+            let table = encrypted(&identity, &blobs, objects.collection(&blobs)?, &keys)?;
+            if rotate_keys {
+                let rewrap = rotate(&blobs, table.clone(), &keys)?;
+                rewraps.lock().unwrap().push(rewrap);
+            }
+
+            Ok(storage.with_table(table))
+        }
+    };
+
+    let objects = Namespaces::new(objects).await?;
+    if rotate_keys {
+        // opening a collection rotates its keys
+        for (name, _) in objects.collections() {
+            objects.collection(&name).await?;
+        }
+
+        let rewraps: Vec<Rewrap> = rewraps.lock().unwrap().drain(..).collect();
+        let mut count = 0;
+        for rewrap in rewraps {
+            count += rewrap.await?;
+        }
+
+        info!("re-wrapped {} keys in total", count);
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("stats") {
+        return stats(matches, &objects).await;
+    }
+
     // intercept the index to also store the metadata in zdb as well
-    let index = database::index::MetaInterceptor::new(
-        index,
-        EncryptedStorage::new(identity.as_sk_bytes(), zdb.collection("metadata")),
-    );
+    let index = database::index::MetaInterceptor::new(index, metadata);
 
     if let Some(matches) = matches.subcommand_matches("rebuild") {
         let mut index = index;
@@ -195,21 +813,31 @@ async fn entry() -> Result<(), Box<dyn std::error::Error>> {
             ),
             None => None,
         };
-        index.rebuild(from).await?;
+        match matches.value_of("after") {
+            Some(s) => {
+                let after = s
+                    .parse()
+                    .context("failed to parse 'after' value expecting key")?;
+                index.resume(after).await?;
+            }
+            None => index.rebuild(from).await?,
+        }
         return Ok(());
     }
 
     // the acl_store
-    let acl_store = acl::ACLStorage::new(EncryptedStorage::new(
-        identity.as_sk_bytes(),
-        zdb.collection("acl"),
-    ));
-
-    let db = database::BcdbDatabase::new(
-        EncryptedStorage::new(identity.as_sk_bytes(), zdb.collection("objects")),
-        index,
-        acl_store.clone(),
-    );
+    let acl_store = acl::ACLStorage::new(acl);
+
+    // objects larger than a single zdb record are split in chunks by the
+    // database, every chunk is compressed and encrypted on its own.
+    let db = database::BcdbDatabase::new(objects.clone(), index, acl_store.clone())
+        .with_retention(retention(matches)?);
+
+    if matches.subcommand_matches("migrate").is_some() {
+        let count = db.migrate().await?;
+        info!("moved {} objects to the storage of their collection", count);
+        return Ok(());
+    }
 
     let peers = if matches.is_present("peers-file") {
         peer::Either::A(peer::PeersFile::new(
@@ -220,21 +848,30 @@ async fn entry() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     // tracker cache peers from the given source, and validate their identity
-    let tracker = peer::Tracker::new(std::time::Duration::from_secs(20 * 60), 1000, peers);
+    let tracker = peer::Tracker::new(Duration::from_secs(20 * 60), 1000, peers);
 
     let db = peer::Router::new(identity.clone(), db, tracker.clone());
 
     let interceptor = auth::Authenticator::new(tracker, identity.clone());
     let acl_interceptor = interceptor.clone();
+    let v1_interceptor = interceptor.clone();
+    let v1_acl_interceptor = interceptor.clone();
 
     let bcdb_service = rpc::BcdbService::new(db.clone());
+    // the v1 api only supports 32 bit keys, and is kept for older clients
+    let v1_bcdb_service = rpc::BcdbService::new(db.clone());
 
     //acl api
     let acl_service = rpc::AclService::new(acl_store.clone());
+    let v1_acl_service = rpc::AclService::new(acl_store.clone());
 
     //identity api
     let identity_service = rpc::IdentityService::new(identity.clone());
 
+    //admin api
+    let admin_interceptor = interceptor.clone();
+    let admin_service = rpc::AdminService::new(compactor, objects);
+
     let grpc_address: SocketAddr = matches.value_of("grpc").unwrap().parse()?;
 
     let rest_address: String = matches.value_of("rest").unwrap().into();
@@ -257,9 +894,201 @@ async fn entry() -> Result<(), Box<dyn std::error::Error>> {
             acl_service,
             move |request| acl_interceptor.authenticate_blocking(request),
         ))
+        .add_service(rpc::v1::BcdbServer::with_interceptor(
+            v1_bcdb_service,
+            move |request| v1_interceptor.authenticate_blocking(request),
+        ))
+        .add_service(rpc::v1::AclServer::with_interceptor(
+            v1_acl_service,
+            move |request| v1_acl_interceptor.authenticate_blocking(request),
+        ))
         .add_service(rpc::IdentityServer::new(identity_service))
+        .add_service(rpc::AdminServer::with_interceptor(
+            admin_service,
+            move |request| admin_interceptor.authenticate_blocking(request),
+        ))
         .serve(grpc_address)
         .await?;
 
     Ok(())
 }
+
+/// Compacts the storage collections, and reports the reclaimed space.
+async fn compact<C: Compactor>(
+    matches: &ArgMatches<'_>,
+    compactor: C,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let collections: Vec<String> = match matches.values_of("collection") {
+        Some(values) => values.map(String::from).collect(),
+        None => Vec::new(),
+    };
+
+    let threshold: f64 = matches
+        .value_of("threshold")
+        .unwrap()
+        .parse()
+        .context("failed to parse 'threshold' value expecting a fraction")?;
+    if threshold < 0.0 || threshold > 1.0 {
+        return Err("invalid threshold, expecting a value between 0 and 1".into());
+    }
+
+    let dry_run = matches.is_present("dry-run");
+    let reports = tokio::task::spawn_blocking(move || {
+        compaction::compact(&compactor, &collections, threshold, dry_run)
+    })
+    .await??;
+
+    for report in reports.iter() {
+        info!(
+            "collection {}: {} live bytes, {} dead bytes ({:.1}%), reclaimed {} bytes",
+            report.collection,
+            report.usage.live,
+            report.usage.dead,
+            report.usage.dead_ratio() * 100.0,
+            report.reclaimed
+        );
+    }
+
+    info!(
+        "reclaimed {} bytes in total",
+        reports.iter().map(|report| report.reclaimed).sum::<u64>()
+    );
+
+    Ok(())
+}
+
+/// Reports the space saved by deduplication in the collections of objects.
+async fn stats<T: dedup::StatsSource>(
+    matches: &ArgMatches<'_>,
+    source: &T,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let collections: Vec<String> = match matches.values_of("collection") {
+        Some(values) => values.map(String::from).collect(),
+        None => Vec::new(),
+    };
+
+    let stats = dedup::stats(source, &collections).await?;
+    for (name, stats) in stats.iter() {
+        info!(
+            "collection {}: {} blobs, {} references, {} bytes stored of {} bytes, saved {} bytes",
+            name,
+            stats.blobs,
+            stats.references,
+            stats.stored,
+            stats.logical,
+            stats.saved()
+        );
+    }
+
+    info!(
+        "saved {} bytes in total",
+        stats.iter().map(|(_, stats)| stats.saved()).sum::<u64>()
+    );
+
+    Ok(())
+}
+
+/// Wraps a collection in an encrypted storage, with a keyring which is sealed by a key derived
+/// from the identity for that collection. The identity secret key itself is only used to read
+/// data which was written before keys were derived per collection.
+fn encrypted<S: AsyncStorage, K: Storage>(
+    identity: &Identity,
+    collection: &str,
+    storage: S,
+    keys: &K,
+) -> Result<EncryptedStorage<S>, storage::Error> {
+    let root = identity.derive_key("storage", collection.as_bytes());
+    let keyring = Keyring::new(collection, &root)
+        .with_legacy(identity.as_sk_bytes())
+        .load(keys)?;
+
+    Ok(EncryptedStorage::new(keyring, storage))
+}
+
+/// Rotates the key encryption key of a collection, and returns the re-wrap of its data keys. Old
+/// records stay readable until their keys are re-wrapped, since the keyring keeps all previous key
+/// encryption keys.
+fn rotate<S, K>(
+    name: &str,
+    storage: EncryptedStorage<S>,
+    keys: &K,
+) -> Result<Rewrap, storage::Error>
+where
+    S: AsyncStorage,
+    K: Storage,
+{
+    let version = storage.keyring().rotate(keys)?;
+    info!(
+        "rotated key encryption key of collection {} to version {}",
+        name, version
+    );
+
+    let name = name.to_string();
+    Ok(async move {
+        let count = storage.rewrap_all().await?;
+        info!("re-wrapped {} keys of collection {}", count, name);
+        Ok(count)
+    }
+    .boxed())
+}
+
+/// Gets the number of versions kept of the objects of every collection
+fn retention(matches: &ArgMatches<'_>) -> Result<database::Retention, Box<dyn std::error::Error>> {
+    let mut retention = database::Retention::new();
+    let values = match matches.values_of("versions") {
+        Some(values) => values,
+        None => return Ok(retention),
+    };
+
+    for value in values {
+        let mut parts = value.rsplitn(2, '=');
+        let count: usize = match parts.next().unwrap_or_default().parse() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                return Err(format!(
+                    "invalid versions '{}', expecting <count> or <collection>=<count> with a count of at least 1",
+                    value
+                )
+                .into())
+            }
+        };
+
+        retention = match parts.next() {
+            Some(collection) => retention.with_collection(collection, count),
+            None => retention.with_default(count),
+        };
+    }
+
+    Ok(retention)
+}
+
+/// Gets the compression codec configured for a collection, collections are not
+/// compressed by default.
+fn codec(matches: &ArgMatches<'_>, collection: &str) -> Result<Codec, Box<dyn std::error::Error>> {
+    let values = match matches.values_of("compression") {
+        Some(values) => values,
+        None => return Ok(Codec::None),
+    };
+
+    let mut codec = Codec::None;
+    for value in values {
+        let mut parts = value.splitn(2, '=');
+        let name = parts.next().unwrap_or_default();
+        let value = match parts.next() {
+            Some(value) => value,
+            None => {
+                return Err(format!(
+                    "invalid compression '{}', expecting <collection>=<codec>",
+                    value
+                )
+                .into())
+            }
+        };
+
+        if name == collection {
+            codec = value.parse()?;
+        }
+    }
+
+    Ok(codec)
+}
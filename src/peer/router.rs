@@ -1,8 +1,12 @@
 use super::PeersList;
 use crate::database::*;
 use crate::identity::Identity;
-use crate::rpc::generated::bcdb_client::BcdbClient;
-use crate::rpc::generated::*;
+use crate::rpc::generated::v2::bcdb_client::BcdbClient;
+use crate::rpc::generated::v2::{
+    get_stream_response, update_request, BatchGetRequest, DeleteRequest, FetchRequest, GetRequest,
+    ListVersionsRequest, UpdateRequest,
+};
+use crate::rpc::generated::Metadata;
 use anyhow::Result;
 use async_trait::async_trait;
 use std::collections::HashMap;
@@ -53,12 +57,20 @@ where
         );
     }
 
+    /// the metadata of an object returned by a peer
+    fn meta(metadata: Option<Metadata>) -> Result<Meta> {
+        match metadata {
+            Some(metadata) => Ok(metadata.typed()?.into_iter().collect()),
+            None => Ok(Meta::default()),
+        }
+    }
+
     async fn remote_set(
         &self,
         _id: u32,
         _collection: &str,
         _data: Vec<u8>,
-        _tags: HashMap<String, String>,
+        _tags: HashMap<String, Value>,
         _acl: Option<u64>,
     ) -> Result<Key> {
         bail!(Reason::NotSupported)
@@ -68,6 +80,7 @@ where
         let request = GetRequest {
             id: key,
             collection: collection.into(),
+            version: 0,
         };
 
         let mut request = tonic::Request::new(request);
@@ -78,10 +91,7 @@ where
         let response = cl.head(request).await.map_err(|s| Reason::from(s))?;
 
         let response = response.into_inner();
-        let meta = match response.metadata {
-            Some(meta) => Meta::new(meta.tags),
-            None => Meta::default(),
-        };
+        let meta = Self::meta(response.metadata)?;
 
         Ok(Object {
             key: key,
@@ -90,10 +100,18 @@ where
         })
     }
 
-    async fn remote_get(&self, id: u32, key: Key, collection: &str) -> Result<Object> {
+    /// gets an object from a remote peer, version 0 gets the current version
+    async fn remote_get(
+        &self,
+        id: u32,
+        key: Key,
+        collection: &str,
+        version: u64,
+    ) -> Result<Object> {
         let request = GetRequest {
             id: key,
             collection: collection.into(),
+            version: version,
         };
 
         let mut request = tonic::Request::new(request);
@@ -104,10 +122,7 @@ where
         let response = cl.get(request).await.map_err(|s| Reason::from(s))?;
 
         let response = response.into_inner();
-        let meta = match response.metadata {
-            Some(meta) => Meta::new(meta.tags),
-            None => Meta::default(),
-        };
+        let meta = Self::meta(response.metadata)?;
 
         Ok(Object {
             key: key,
@@ -116,6 +131,129 @@ where
         })
     }
 
+    async fn remote_get_many(
+        &self,
+        id: u32,
+        keys: Vec<Key>,
+        collection: &str,
+    ) -> Result<Vec<Object>> {
+        let request = BatchGetRequest {
+            ids: keys,
+            collection: collection.into(),
+        };
+
+        let mut request = tonic::Request::new(request);
+        self.set_headers(&mut request);
+
+        let mut cl = self.get_peer(id).await?;
+
+        let response = cl.batch_get(request).await.map_err(|s| Reason::from(s))?;
+
+        response
+            .into_inner()
+            .objects
+            .into_iter()
+            .map(|object| {
+                Ok(Object {
+                    key: object.id,
+                    data: Some(object.data),
+                    meta: Self::meta(object.metadata)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn remote_get_stream(
+        &self,
+        id: u32,
+        key: Key,
+        collection: &str,
+    ) -> Result<(Object, Chunks)> {
+        let request = GetRequest {
+            id: key,
+            collection: collection.into(),
+            version: 0,
+        };
+
+        let mut request = tonic::Request::new(request);
+        self.set_headers(&mut request);
+
+        let mut cl = self.get_peer(id).await?;
+
+        let response = cl.get_stream(request).await.map_err(|s| Reason::from(s))?;
+        let mut stream = response.into_inner();
+
+        // the first message carries the object metadata
+        let meta = match stream.message().await.map_err(|s| Reason::from(s))? {
+            Some(response) => match response.part {
+                Some(get_stream_response::Part::Metadata(meta)) => Self::meta(Some(meta))?,
+                _ => bail!(Reason::Unknown("expecting object metadata".into())),
+            },
+            None => bail!(Reason::NotFound),
+        };
+
+        let (mut tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let chunk = match stream.message().await {
+                    Ok(None) => break, // end of stream
+                    Ok(Some(response)) => match response.part {
+                        Some(get_stream_response::Part::Data(data)) => Ok(data),
+                        _ => Err(format_err!("expecting object data")),
+                    },
+                    Err(status) => Err(Reason::from(status).into()),
+                };
+
+                let failed = chunk.is_err();
+                if let Err(err) = tx.send(chunk).await {
+                    debug!("failed to send chunk, broken stream: {}", err);
+                    break;
+                }
+
+                if failed {
+                    break;
+                }
+            }
+        });
+
+        Ok((
+            Object {
+                key: key,
+                data: None,
+                meta: meta,
+            },
+            rx,
+        ))
+    }
+
+    async fn remote_versions(&self, id: u32, key: Key, collection: &str) -> Result<Vec<Version>> {
+        let request = ListVersionsRequest {
+            id: key,
+            collection: collection.into(),
+        };
+
+        let mut request = tonic::Request::new(request);
+        self.set_headers(&mut request);
+
+        let mut cl = self.get_peer(id).await?;
+
+        let response = cl
+            .list_versions(request)
+            .await
+            .map_err(|s| Reason::from(s))?;
+
+        Ok(response
+            .into_inner()
+            .versions
+            .into_iter()
+            .map(|v| Version {
+                version: v.version,
+                size: v.size,
+                timestamp: v.timestamp,
+            })
+            .collect())
+    }
+
     async fn remote_fetch(&self, id: u32, key: Key) -> Result<Object> {
         let request = FetchRequest { id: key };
 
@@ -127,10 +265,7 @@ where
         let response = cl.fetch(request).await.map_err(|s| Reason::from(s))?;
 
         let response = response.into_inner();
-        let meta = match response.metadata {
-            Some(meta) => Meta::new(meta.tags),
-            None => Meta::default(),
-        };
+        let meta = Self::meta(response.metadata)?;
 
         Ok(Object {
             key: key,
@@ -161,16 +296,12 @@ where
         key: Key,
         collection: &str,
         data: Option<Vec<u8>>,
-        tags: HashMap<String, String>,
+        tags: HashMap<String, Value>,
         acl: Option<u64>,
     ) -> Result<()> {
         let request = UpdateRequest {
             id: key,
-            metadata: Some(Metadata {
-                tags: tags,
-                collection: collection.into(),
-                acl: acl.map(|acl| AclRef { acl }),
-            }),
+            metadata: Some(Metadata::new(tags, collection.into(), acl)),
             data: data.map(|data| update_request::UpdateData { data }),
         };
 
@@ -187,7 +318,7 @@ where
     async fn remote_list(
         &self,
         _id: u32,
-        _tags: HashMap<String, String>,
+        _query: Query,
         _collection: Option<&str>,
     ) -> Result<mpsc::Receiver<Result<Key>>> {
         bail!(Reason::NotSupported);
@@ -196,7 +327,7 @@ where
     async fn remote_find(
         &self,
         _id: u32,
-        _tags: HashMap<String, String>,
+        _query: Query,
         _collection: Option<&str>,
     ) -> Result<mpsc::Receiver<Result<Object>>> {
         bail!(Reason::NotSupported);
@@ -214,7 +345,7 @@ where
         ctx: &Context,
         collection: &str,
         data: Vec<u8>,
-        tags: HashMap<String, String>,
+        tags: HashMap<String, Value>,
         acl: Option<u64>,
     ) -> Result<Key> {
         match ctx.route {
@@ -223,6 +354,17 @@ where
         }
     }
 
+    async fn set_stream(
+        &mut self,
+        ctx: &Context,
+        parts: mpsc::Receiver<Result<Part>>,
+    ) -> Result<Key> {
+        match ctx.route {
+            Route::Local => self.local.set_stream(ctx, parts).await,
+            Route::Remote(_) => bail!(Reason::NotSupported),
+        }
+    }
+
     async fn fetch(&mut self, ctx: &Context, key: Key) -> Result<Object> {
         match ctx.route {
             Route::Local => self.local.fetch(ctx, key).await,
@@ -233,13 +375,62 @@ where
     async fn get(&mut self, ctx: &Context, key: Key, collection: &str) -> Result<Object> {
         match ctx.route {
             Route::Local => self.local.get(ctx, key, collection).await,
-            Route::Remote(id) => self.remote_get(id, key, collection).await,
+            Route::Remote(id) => self.remote_get(id, key, collection, 0).await,
+        }
+    }
+
+    async fn get_stream(
+        &mut self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+    ) -> Result<(Object, Chunks)> {
+        match ctx.route {
+            Route::Local => self.local.get_stream(ctx, key, collection).await,
+            Route::Remote(id) => self.remote_get_stream(id, key, collection).await,
+        }
+    }
+
+    async fn get_many(
+        &mut self,
+        ctx: &Context,
+        keys: Vec<Key>,
+        collection: &str,
+    ) -> Result<Vec<Object>> {
+        match ctx.route {
+            Route::Local => self.local.get_many(ctx, keys, collection).await,
+            Route::Remote(id) => self.remote_get_many(id, keys, collection).await,
+        }
+    }
+
+    async fn get_version(
+        &mut self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+        version: u64,
+    ) -> Result<Object> {
+        match ctx.route {
+            Route::Local => self.local.get_version(ctx, key, collection, version).await,
+            Route::Remote(id) => self.remote_get(id, key, collection, version).await,
+        }
+    }
+
+    async fn versions(
+        &mut self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+    ) -> Result<Vec<Version>> {
+        match ctx.route {
+            Route::Local => self.local.versions(ctx, key, collection).await,
+            Route::Remote(id) => self.remote_versions(id, key, collection).await,
         }
     }
 
     async fn head(&mut self, ctx: &Context, key: Key, collection: &str) -> Result<Object> {
         match ctx.route {
-            Route::Local => self.local.get(ctx, key, collection).await,
+            Route::Local => self.local.head(ctx, key, collection).await,
             Route::Remote(id) => self.remote_head(id, key, collection).await,
         }
     }
@@ -257,7 +448,7 @@ where
         key: Key,
         collection: &str,
         data: Option<Vec<u8>>,
-        tags: HashMap<String, String>,
+        tags: HashMap<String, Value>,
         acl: Option<u64>,
     ) -> Result<()> {
         match ctx.route {
@@ -273,27 +464,100 @@ where
         }
     }
 
+    async fn update_stream(
+        &mut self,
+        ctx: &Context,
+        key: Key,
+        collection: &str,
+        mut data: Chunks,
+        tags: HashMap<String, Value>,
+        acl: Option<u64>,
+    ) -> Result<()> {
+        match ctx.route {
+            Route::Local => {
+                self.local
+                    .update_stream(ctx, key, collection, data, tags, acl)
+                    .await
+            }
+            Route::Remote(id) => {
+                // peers can't be updated with a stream, so the data is sent at once
+                let mut buffer = Vec::new();
+                while let Some(chunk) = data.recv().await {
+                    buffer.extend_from_slice(&chunk?);
+                }
+
+                let data = if buffer.is_empty() {
+                    None
+                } else {
+                    Some(buffer)
+                };
+
+                self.remote_update(id, key, collection, data, tags, acl)
+                    .await
+            }
+        }
+    }
+
     async fn list(
         &mut self,
         ctx: &Context,
-        tags: HashMap<String, String>,
+        query: Query,
         collection: Option<&str>,
     ) -> Result<mpsc::Receiver<Result<Key>>> {
         match ctx.route {
-            Route::Local => self.local.list(ctx, tags, collection).await,
-            Route::Remote(id) => self.remote_list(id, tags, collection).await,
+            Route::Local => self.local.list(ctx, query, collection).await,
+            Route::Remote(id) => self.remote_list(id, query, collection).await,
         }
     }
 
     async fn find(
         &mut self,
         ctx: &Context,
-        tags: HashMap<String, String>,
+        query: Query,
         collection: Option<&str>,
     ) -> Result<mpsc::Receiver<Result<Object>>> {
         match ctx.route {
-            Route::Local => self.local.find(ctx, tags, collection).await,
-            Route::Remote(id) => self.remote_find(id, tags, collection).await,
+            Route::Local => self.local.find(ctx, query, collection).await,
+            Route::Remote(id) => self.remote_find(id, query, collection).await,
+        }
+    }
+
+    async fn count(
+        &mut self,
+        ctx: &Context,
+        query: Query,
+        collection: Option<&str>,
+    ) -> Result<u64> {
+        match ctx.route {
+            Route::Local => self.local.count(ctx, query, collection).await,
+            Route::Remote(_) => bail!(Reason::NotSupported),
+        }
+    }
+
+    async fn aggregate(
+        &mut self,
+        ctx: &Context,
+        tag: &str,
+        query: Query,
+        collection: Option<&str>,
+    ) -> Result<Aggregate> {
+        match ctx.route {
+            Route::Local => self.local.aggregate(ctx, tag, query, collection).await,
+            Route::Remote(_) => bail!(Reason::NotSupported),
+        }
+    }
+
+    async fn facets(
+        &mut self,
+        ctx: &Context,
+        tag: &str,
+        query: Query,
+        collection: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Facet>> {
+        match ctx.route {
+            Route::Local => self.local.facets(ctx, tag, query, collection, limit).await,
+            Route::Remote(_) => bail!(Reason::NotSupported),
         }
     }
 }
@@ -6,7 +6,7 @@ This might change in the future to directly access the data layer
 */
 use crate::acl::ACLStorage;
 use crate::database::{Database, Reason};
-use crate::storage::Storage;
+use crate::storage::AsyncStorage;
 use anyhow::Error;
 use serde::Serialize;
 use std::convert::Infallible;
@@ -42,6 +42,7 @@ fn error_to_code(err: &Error) -> (StatusCode, String) {
                 StatusCode::BAD_REQUEST,
                 "Use of invalid tag string (':' prefix is for internal use)".into(),
             ),
+            Reason::InvalidQuery(m) => (StatusCode::BAD_REQUEST, format!("Invalid query: {}", m)),
             Reason::CannotGetPeer(m) => (StatusCode::BAD_REQUEST, m.into()),
             Reason::Unknown(m) => (StatusCode::INTERNAL_SERVER_ERROR, m.into()),
         };
@@ -92,7 +93,7 @@ async fn handle_rejections(err: Rejection) -> Result<impl warp::Reply, Infallibl
 pub async fn run<D, S>(db: D, acl: ACLStorage<S>, unx: String) -> Result<(), Error>
 where
     D: Database + Clone,
-    S: Storage + Clone + Send + Sync + 'static,
+    S: AsyncStorage,
 {
     let bcdb_api = bcdb::router(db);
     let acl_api = acl::router(acl);
@@ -1,7 +1,8 @@
 use super::BcdbRejection;
 use crate::acl::{ACLStorage, Permissions, ACL as ACLObject};
-use crate::storage::Storage;
+use crate::storage::{AsyncStorage, Key};
 use anyhow::Error;
+use futures::StreamExt;
 use hyper::Body;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
@@ -38,7 +39,7 @@ async fn handle_create<S>(
     body: ACLCreateRequest,
 ) -> Result<impl warp::Reply, Rejection>
 where
-    S: Storage,
+    S: AsyncStorage,
 {
     let acl = ACLObject {
         perm: Permissions::from_str(body.perm.as_ref())
@@ -46,7 +47,10 @@ where
         users: body.users,
     };
 
-    let key = storage.create(&acl).map_err(|e| super::rejection(e))?;
+    let key = storage
+        .create(&acl)
+        .await
+        .map_err(|e| super::rejection(e))?;
 
     Ok(warp::reply::with_status(
         warp::reply::json(&key),
@@ -56,13 +60,13 @@ where
 
 async fn handle_set<S>(
     mut storage: ACLStorage<S>,
-    key: u32,
+    key: Key,
     body: ACLSetRequest,
 ) -> Result<impl warp::Reply, Rejection>
 where
-    S: Storage,
+    S: AsyncStorage,
 {
-    let acl = storage.get(key).map_err(|e| super::rejection(e))?;
+    let acl = storage.get(key).await.map_err(|e| super::rejection(e))?;
     let mut acl = match acl {
         None => return Err(warp::reject::not_found()),
         Some(acl) => acl,
@@ -73,16 +77,19 @@ where
         .parse()
         .map_err(|_| warp::reject::custom(BcdbRejection::InvalidACLPermission))?;
 
-    storage.update(key, &acl).map_err(|e| super::rejection(e))?;
+    storage
+        .update(key, &acl)
+        .await
+        .map_err(|e| super::rejection(e))?;
 
     Ok(warp::reply::reply())
 }
 
-async fn handle_get<S>(mut storage: ACLStorage<S>, key: u32) -> Result<impl warp::Reply, Rejection>
+async fn handle_get<S>(mut storage: ACLStorage<S>, key: Key) -> Result<impl warp::Reply, Rejection>
 where
-    S: Storage,
+    S: AsyncStorage,
 {
-    let acl = storage.get(key).map_err(|e| super::rejection(e))?;
+    let acl = storage.get(key).await.map_err(|e| super::rejection(e))?;
 
     let acl = match acl {
         None => return Err(warp::reject::not_found()),
@@ -99,13 +106,13 @@ where
 
 async fn handle_grant<S>(
     mut storage: ACLStorage<S>,
-    key: u32,
+    key: Key,
     body: ACLUsersRequest,
 ) -> Result<impl warp::Reply, Rejection>
 where
-    S: Storage,
+    S: AsyncStorage,
 {
-    let acl = storage.get(key).map_err(|e| super::rejection(e))?;
+    let acl = storage.get(key).await.map_err(|e| super::rejection(e))?;
     let mut acl = match acl {
         None => return Err(warp::reject::not_found()),
         Some(acl) => acl,
@@ -121,7 +128,10 @@ where
     if updated > 0 {
         acl.users = Vec::from_iter(set.into_iter());
 
-        storage.update(key, &acl).map_err(|e| super::rejection(e))?;
+        storage
+            .update(key, &acl)
+            .await
+            .map_err(|e| super::rejection(e))?;
     }
 
     Ok(warp::reply::json(&updated))
@@ -129,13 +139,13 @@ where
 
 async fn handle_revoke<S>(
     mut storage: ACLStorage<S>,
-    key: u32,
+    key: Key,
     body: ACLUsersRequest,
 ) -> Result<impl warp::Reply, Rejection>
 where
-    S: Storage,
+    S: AsyncStorage,
 {
-    let acl = storage.get(key).map_err(|e| super::rejection(e))?;
+    let acl = storage.get(key).await.map_err(|e| super::rejection(e))?;
     let mut acl = match acl {
         None => return Err(warp::reject::not_found()),
         Some(acl) => acl,
@@ -151,7 +161,10 @@ where
     if updated > 0 {
         acl.users = Vec::from_iter(set.into_iter());
 
-        storage.update(key, &acl).map_err(|e| super::rejection(e))?;
+        storage
+            .update(key, &acl)
+            .await
+            .map_err(|e| super::rejection(e))?;
     }
 
     Ok(warp::reply::json(&updated))
@@ -165,18 +178,16 @@ struct ACL {
 
 #[derive(Serialize)]
 struct ListResult {
-    key: u32,
+    key: Key,
     acl: ACL,
 }
 
 async fn handle_list<S>(mut storage: ACLStorage<S>) -> Result<impl warp::Reply, Rejection>
 where
-    S: Storage + Send + Clone,
+    S: AsyncStorage,
 {
-    //let mut storage = storage.clone();
-    let response = storage.list().map_err(|e| super::rejection(e))?;
-    let response: Vec<Result<String, Error>> = response
-        .map(|item| -> Result<String, Error> {
+    let response = storage.list().await.map_err(|e| super::rejection(e))?.map(
+        |item| -> Result<String, Error> {
             let (key, acl) = item?;
             let data = ListResult {
                 key: key,
@@ -186,15 +197,10 @@ where
                 },
             };
             Ok(serde_json::to_string(&data)?)
-        })
-        .collect();
+        },
+    );
 
-    //TODO: doing a collect here will load all the list from the db.
-    //we need to figure out another way to not have to do this and
-    //convert this to a stream
-
-    let stream = tokio::stream::iter(response);
-    let body = Body::wrap_stream(stream);
+    let body = Body::wrap_stream(response);
 
     Ok(warp::reply::Response::new(body))
 }
@@ -203,7 +209,7 @@ fn with_storage<S>(
     storage: ACLStorage<S>,
 ) -> impl Filter<Extract = (ACLStorage<S>,), Error = std::convert::Infallible> + Clone
 where
-    S: Storage + Clone + Send + Sync,
+    S: AsyncStorage,
 {
     warp::any().map(move || storage.clone())
 }
@@ -212,7 +218,7 @@ pub fn router<S>(
     storage: ACLStorage<S>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone
 where
-    S: Storage + Clone + Send + Sync,
+    S: AsyncStorage,
 {
     let base = warp::any().and(with_storage(storage.clone()));
 
@@ -227,13 +233,13 @@ where
 
     let get = base
         .clone()
-        .and(warp::path::param::<u32>()) // key
+        .and(warp::path::param::<Key>()) // key
         .and(warp::get())
         .and_then(handle_get);
 
     let set = base
         .clone()
-        .and(warp::path::param::<u32>()) // key
+        .and(warp::path::param::<Key>()) // key
         .and(warp::put())
         .and(warp::body::content_length_limit(4 * 1024 * 1024)) // setting a limit of 4MB
         .and(warp::body::json())
@@ -241,7 +247,7 @@ where
 
     let grant = base
         .clone()
-        .and(warp::path!(u32 / "grant"))
+        .and(warp::path!(Key / "grant"))
         .and(warp::post())
         .and(warp::body::content_length_limit(4 * 1024 * 1024)) // setting a limit of 4MB
         .and(warp::body::json())
@@ -249,7 +255,7 @@ where
 
     let revoke = base
         .clone()
-        .and(warp::path!(u32 / "revoke"))
+        .and(warp::path!(Key / "revoke"))
         .and(warp::post())
         .and(warp::body::content_length_limit(4 * 1024 * 1024)) // setting a limit of 4MB
         .and(warp::body::json())
@@ -1,9 +1,16 @@
-use crate::database::{Authorization, Context, Database};
+use crate::database::page::list_cursors;
+use crate::database::{
+    Authorization, Condition, Context, Cursor, Database, Expr, Key, Meta, Number, Order, Page,
+    Part, Query, Reason, Value,
+};
 use anyhow::Error;
+use bytes::Buf;
+use futures::Stream;
 use http::response::Builder as ResponseBuilder;
 use hyper::Body;
 use serde::Serialize;
 use std::collections::HashMap;
+use tokio::sync::mpsc;
 use warp::http::StatusCode;
 use warp::reject::Rejection;
 use warp::Filter;
@@ -12,6 +19,10 @@ const HEADER_ACL: &str = "x-acl";
 const HEADER_TAGS: &str = "x-tags";
 const HEADER_ROUTE: &str = "x-threebot-id";
 const HEADER_FIND_MODE: &str = "x-find-mode";
+const HEADER_LIMIT: &str = "x-limit";
+const HEADER_SORT_BY: &str = "x-sort-by";
+const HEADER_ORDER: &str = "x-order";
+const HEADER_TOKEN: &str = "x-continuation-token";
 
 #[derive(Debug)]
 enum FindMode {
@@ -33,31 +44,134 @@ impl std::str::FromStr for FindMode {
     }
 }
 
-fn tags_from_str(s: &str) -> Result<HashMap<String, String>, Rejection> {
-    let map: HashMap<String, String> = match serde_json::from_str(s) {
-        Ok(map) => map,
-        Err(_) => {
-            return Err(warp::reject::custom(
-                super::BcdbRejection::InvalidTagsString,
-            ))
+/// Sorting and pagination of find results, set with headers
+struct PageOptions {
+    limit: Option<usize>,
+    sort_by: Option<String>,
+    order: Option<Order>,
+    token: Option<String>,
+}
+
+impl PageOptions {
+    fn build(self) -> Result<Page, Error> {
+        let mut page = Page::new();
+        if let Some(sort_by) = self.sort_by {
+            page = page.with_sort_by(sort_by);
+        }
+
+        if let Some(order) = self.order {
+            page = page.with_order(order);
+        }
+
+        if let Some(limit) = self.limit {
+            page = page.with_limit(limit);
+        }
+
+        match self.token {
+            Some(token) => page.with_token(&token),
+            None => Ok(page),
         }
+    }
+}
+
+fn with_page_options() -> impl Filter<Extract = (PageOptions,), Error = Rejection> + Clone {
+    warp::header::optional::<usize>(HEADER_LIMIT)
+        .and(warp::header::optional::<String>(HEADER_SORT_BY))
+        .and(warp::header::optional::<Order>(HEADER_ORDER))
+        .and(warp::header::optional::<String>(HEADER_TOKEN))
+        .map(|limit, sort_by, order, token| PageOptions {
+            limit: limit,
+            sort_by: sort_by,
+            order: order,
+            token: token,
+        })
+}
+
+/// parses the tags header, a JSON object of tags. Tags with a JSON number as value
+/// are numeric, tags with a string as value are text
+fn tags_from_str(s: &str) -> Result<HashMap<String, Value>, Rejection> {
+    let invalid = || warp::reject::custom(super::BcdbRejection::InvalidTagsString);
+    let map: HashMap<String, serde_json::Value> = match serde_json::from_str(s) {
+        Ok(map) => map,
+        Err(_) => return Err(invalid()),
     };
 
-    Ok(map)
+    map.into_iter()
+        .map(|(k, v)| {
+            let value = match v {
+                serde_json::Value::String(v) => Value::Text(v),
+                serde_json::Value::Number(v) => Value::Number(v.to_string()),
+                _ => return Err(invalid()),
+            };
+
+            Ok((k, value))
+        })
+        .collect()
 }
 
-fn tags_to_str(tags: HashMap<String, String>) -> Result<String, Error> {
-    Ok(serde_json::to_string(&tags)?)
+/// the tags of an object as a JSON object, numeric tags have a JSON number as value
+fn tags_to_json(meta: Meta) -> HashMap<String, serde_json::Value> {
+    meta.into_iter()
+        .map(|(k, v)| {
+            let value = match v.as_number() {
+                Some(Number::Integer(n)) => n.into(),
+                Some(Number::Float(n)) => n.into(),
+                None => serde_json::Value::String(v.into()),
+            };
+
+            (k, value)
+        })
+        .collect()
 }
 
-async fn handle_set<D: Database>(
+fn tags_to_str(meta: Meta) -> Result<String, Error> {
+    Ok(serde_json::to_string(&tags_to_json(meta))?)
+}
+
+/// forwards the chunks of a request body to the database, returns false if the
+/// body could not be read completely
+async fn forward_body<S, B, T, F>(body: S, tx: &mut mpsc::Sender<Result<T, Error>>, part: F) -> bool
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+    F: Fn(Vec<u8>) -> T,
+{
+    use futures::stream::StreamExt;
+    futures::pin_mut!(body);
+
+    while let Some(buf) = body.next().await {
+        let item = match buf {
+            Ok(mut buf) => Ok(part(buf.to_bytes().to_vec())),
+            Err(err) => Err(format_err!("failed to read body: {}", err)),
+        };
+
+        let failed = item.is_err();
+        if let Err(err) = tx.send(item).await {
+            debug!("failed to send part, broken stream: {}", err);
+            return false;
+        }
+
+        if failed {
+            return false;
+        }
+    }
+
+    true
+}
+
+async fn handle_set<D, S, B>(
     mut db: D,
     route: Option<u32>,
     collection: String,
     acl: Option<u64>,
     tags: Option<String>,
-    data: bytes::Bytes,
-) -> Result<impl warp::Reply, Rejection> {
+    body: S,
+) -> Result<impl warp::Reply, Rejection>
+where
+    D: Database,
+    S: Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: Buf + Send,
+{
     let ctx = Context::default()
         .with_route(route)
         .with_auth(Authorization::Owner);
@@ -67,8 +181,25 @@ async fn handle_set<D: Database>(
         None => HashMap::default(),
     };
 
+    // the body is streamed to the database as it is received, so large
+    // objects are never held in memory
+    let (mut tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        if !forward_body(body, &mut tx, Part::Data).await {
+            return;
+        }
+
+        let _ = tx
+            .send(Ok(Part::Meta {
+                collection,
+                tags,
+                acl,
+            }))
+            .await;
+    });
+
     let key = db
-        .set(&ctx, &collection, Vec::from(data.as_ref()), tags, acl)
+        .set_stream(&ctx, rx)
         .await
         .map_err(|e| super::rejection(e))?;
 
@@ -82,14 +213,41 @@ async fn handle_get<D: Database>(
     mut db: D,
     route: Option<u32>,
     collection: String,
-    key: u32,
+    key: Key,
+) -> Result<impl warp::Reply, Rejection> {
+    let ctx = Context::default()
+        .with_route(route)
+        .with_auth(Authorization::Owner);
+
+    let (object, chunks) = db
+        .get_stream(&ctx, key, &collection)
+        .await
+        .map_err(|e| super::rejection(e))?;
+
+    let mut builder = ResponseBuilder::new().status(StatusCode::OK);
+
+    if let Some(acl) = object.meta.acl() {
+        builder = builder.header(HEADER_ACL, acl)
+    }
+
+    builder = builder.header(HEADER_TAGS, tags_to_str(object.meta).unwrap());
+
+    Ok(builder.body(Body::wrap_stream(chunks)))
+}
+
+async fn handle_get_version<D: Database>(
+    mut db: D,
+    route: Option<u32>,
+    collection: String,
+    key: Key,
+    version: u64,
 ) -> Result<impl warp::Reply, Rejection> {
     let ctx = Context::default()
         .with_route(route)
         .with_auth(Authorization::Owner);
 
     let object = db
-        .get(&ctx, key, &collection)
+        .get_version(&ctx, key, &collection, version)
         .await
         .map_err(|e| super::rejection(e))?;
 
@@ -99,7 +257,7 @@ async fn handle_get<D: Database>(
         builder = builder.header(HEADER_ACL, acl)
     }
 
-    builder = builder.header(HEADER_TAGS, tags_to_str(object.meta.into()).unwrap());
+    builder = builder.header(HEADER_TAGS, tags_to_str(object.meta).unwrap());
 
     match object.data {
         Some(data) => Ok(builder.body(data)),
@@ -107,11 +265,82 @@ async fn handle_get<D: Database>(
     }
 }
 
+#[derive(Serialize)]
+struct VersionResult {
+    version: u64,
+    size: u64,
+    timestamp: u64,
+}
+
+async fn handle_versions<D: Database>(
+    mut db: D,
+    route: Option<u32>,
+    collection: String,
+    key: Key,
+) -> Result<impl warp::Reply, Rejection> {
+    let ctx = Context::default()
+        .with_route(route)
+        .with_auth(Authorization::Owner);
+
+    let versions = db
+        .versions(&ctx, key, &collection)
+        .await
+        .map_err(|e| super::rejection(e))?;
+
+    let results: Vec<VersionResult> = versions
+        .into_iter()
+        .map(|v| VersionResult {
+            version: v.version,
+            size: v.size,
+            timestamp: v.timestamp,
+        })
+        .collect();
+
+    Ok(warp::reply::json(&results))
+}
+
+#[derive(Serialize)]
+struct BatchResult {
+    id: Key,
+    tags: HashMap<String, serde_json::Value>,
+    acl: Option<u64>,
+    /// base64 encoded object data
+    data: String,
+}
+
+async fn handle_batch_get<D: Database>(
+    mut db: D,
+    route: Option<u32>,
+    collection: String,
+    keys: Vec<Key>,
+) -> Result<impl warp::Reply, Rejection> {
+    let ctx = Context::default()
+        .with_route(route)
+        .with_auth(Authorization::Owner);
+
+    let objects = db
+        .get_many(&ctx, keys, &collection)
+        .await
+        .map_err(|e| super::rejection(e))?;
+
+    let results: Vec<BatchResult> = objects
+        .into_iter()
+        .map(|object| BatchResult {
+            id: object.key,
+            acl: object.meta.acl(),
+            data: base64::encode(object.data.unwrap_or_default()),
+            tags: tags_to_json(object.meta),
+        })
+        .collect();
+
+    Ok(warp::reply::json(&results))
+}
+
 async fn handle_head<D: Database>(
     mut db: D,
     route: Option<u32>,
     collection: String,
-    key: u32,
+    key: Key,
 ) -> Result<impl warp::Reply, Rejection> {
     let ctx = Context::default()
         .with_route(route)
@@ -128,7 +357,7 @@ async fn handle_head<D: Database>(
         builder = builder.header(HEADER_ACL, acl)
     }
 
-    builder = builder.header(HEADER_TAGS, tags_to_str(object.meta.into()).unwrap());
+    builder = builder.header(HEADER_TAGS, tags_to_str(object.meta).unwrap());
 
     match object.data {
         Some(data) => Ok(builder.body(data)),
@@ -139,7 +368,7 @@ async fn handle_head<D: Database>(
 async fn handle_fetch<D: Database>(
     mut db: D,
     route: Option<u32>,
-    key: u32,
+    key: Key,
 ) -> Result<impl warp::Reply, Rejection> {
     let ctx = Context::default()
         .with_route(route)
@@ -153,7 +382,7 @@ async fn handle_fetch<D: Database>(
         builder = builder.header(HEADER_ACL, acl)
     }
 
-    builder = builder.header(HEADER_TAGS, tags_to_str(object.meta.into()).unwrap());
+    builder = builder.header(HEADER_TAGS, tags_to_str(object.meta).unwrap());
 
     match object.data {
         Some(data) => Ok(builder.body(data)),
@@ -165,7 +394,7 @@ async fn handle_delete<D: Database>(
     mut db: D,
     route: Option<u32>,
     collection: String,
-    key: u32,
+    key: Key,
 ) -> Result<impl warp::Reply, Rejection> {
     let ctx = Context::default()
         .with_route(route)
@@ -178,15 +407,20 @@ async fn handle_delete<D: Database>(
     Ok(warp::reply())
 }
 
-async fn handle_update<D: Database>(
+async fn handle_update<D, S, B>(
     mut db: D,
     route: Option<u32>,
     collection: String,
-    key: u32,
+    key: Key,
     acl: Option<u64>,
     tags: Option<String>,
-    data: bytes::Bytes,
-) -> Result<impl warp::Reply, Rejection> {
+    body: S,
+) -> Result<impl warp::Reply, Rejection>
+where
+    D: Database,
+    S: Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: Buf + Send,
+{
     let ctx = Context::default()
         .with_route(route)
         .with_auth(Authorization::Owner);
@@ -196,13 +430,13 @@ async fn handle_update<D: Database>(
         None => HashMap::default(),
     };
 
-    let data = if data.len() > 0 {
-        Some(Vec::from(data.as_ref()))
-    } else {
-        None
-    };
+    // an empty body only updates the metadata of the object
+    let (mut tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        forward_body(body, &mut tx, |data| data).await;
+    });
 
-    db.update(&ctx, key, &collection, data, tags, acl)
+    db.update_stream(&ctx, key, &collection, rx, tags, acl)
         .await
         .map_err(|e| super::rejection(e))?;
 
@@ -211,24 +445,42 @@ async fn handle_update<D: Database>(
 
 #[derive(Serialize)]
 struct FindResult {
-    id: u32,
-    tags: HashMap<String, String>,
+    id: Key,
+    tags: HashMap<String, serde_json::Value>,
     acl: Option<u64>,
 }
 
-fn parse_query(query: &str) -> HashMap<String, String> {
-    let query = serde_urlencoded::from_str::<Vec<(String, String)>>(query).unwrap();
-    let mut meta = HashMap::default();
-    for (k, v) in query {
+/// parses the query string of a find request. A `tag=value` pair matches the tag
+/// exactly, a `tag[op]=value` pair compares the value of the tag, where op is one of
+/// `lt`, `lte`, `gt`, `gte`, `between` (with bounds `lower,upper`), `prefix` or `glob`
+/// The `q` parameter is a query expression, which is combined with the other pairs
+fn parse_query(query: &str) -> Result<Query, Error> {
+    let pairs = serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+        .map_err(|e| Reason::InvalidQuery(e.to_string()))?;
+
+    let mut query = Query::new();
+    for (k, v) in pairs {
         if k == "_" {
             // this is a hack because the query::raw()
             // filter does not work if query string is empty
             continue;
         }
-        meta.insert(k.into(), v.into());
+
+        if k == "q" {
+            query = query.with_expr(Expr::parse(&v)?);
+            continue;
+        }
+
+        query = match k.find('[') {
+            Some(at) if k.ends_with(']') => {
+                let condition = Condition::parse(&k[at + 1..k.len() - 1], &v)?;
+                query.with_condition(&k[..at], condition)?
+            }
+            _ => query.with_tag(k, v),
+        };
     }
 
-    meta
+    Ok(query)
 }
 
 async fn handle_find<D: Database>(
@@ -236,13 +488,17 @@ async fn handle_find<D: Database>(
     route: Option<u32>,
     collection: String,
     mode: Option<FindMode>,
+    page: PageOptions,
     query: String,
 ) -> Result<impl warp::Reply, Rejection> {
     let ctx = Context::default()
         .with_route(route)
         .with_auth(Authorization::Owner);
 
-    let meta = parse_query(&query);
+    let page = page.build().map_err(|e| super::rejection(e))?;
+    let query = parse_query(&query)
+        .map_err(|e| super::rejection(e))?
+        .with_page(page.clone());
 
     use tokio::stream::StreamExt;
     let mode = match mode {
@@ -251,42 +507,165 @@ async fn handle_find<D: Database>(
     };
 
     use tokio::stream::Stream;
-    let response: Box<dyn Stream<Item = Result<String, Error>> + Unpin + Send + Sync> = match mode {
-        FindMode::Find => {
-            let results = db
-                .find(&ctx, meta, Some(&collection))
-                .await
-                .map_err(|e| super::rejection(e))?;
-
-            Box::new(results.map(|entry| -> Result<String, Error> {
-                let entry = entry?;
-                //let meta = entry.metadata.unwrap();
-                let data = FindResult {
-                    id: entry.key,
-                    acl: entry.meta.acl(),
-                    tags: entry.meta.into(),
-                };
-
-                Ok(serde_json::to_string(&data)? + "\n")
-            }))
-        }
-        FindMode::List => {
-            let results = db
-                .list(&ctx, meta, Some(&collection))
-                .await
-                .map_err(|e| super::rejection(e))?;
-
-            Box::new(results.map(|entry| -> Result<String, Error> {
-                let entry = entry?;
+    let response: Box<dyn Stream<Item = Result<(String, Cursor), Error>> + Unpin + Send + Sync> =
+        match mode {
+            FindMode::Find => {
+                let results = db
+                    .find(&ctx, query, Some(&collection))
+                    .await
+                    .map_err(|e| super::rejection(e))?;
+
+                let page = page.clone();
+                Box::new(
+                    results.map(move |entry| -> Result<(String, Cursor), Error> {
+                        let entry = entry?;
+                        let cursor = page.cursor(entry.key, &entry.meta);
+                        let data = FindResult {
+                            id: entry.key,
+                            acl: entry.meta.acl(),
+                            tags: tags_to_json(entry.meta),
+                        };
+
+                        Ok((serde_json::to_string(&data)? + "\n", cursor))
+                    }),
+                )
+            }
+            FindMode::List => {
+                let results = list_cursors(&mut db, &ctx, query, Some(&collection))
+                    .await
+                    .map_err(|e| super::rejection(e))?;
+
+                Box::new(results.map(|entry| -> Result<(String, Cursor), Error> {
+                    let (key, cursor) = entry?;
+
+                    Ok((serde_json::to_string(&key)? + "\n", cursor))
+                }))
+            }
+        };
 
-                Ok(serde_json::to_string(&entry)? + "\n")
-            }))
+    let mut builder = ResponseBuilder::new().status(StatusCode::OK);
+    let body = match page.limit() {
+        Some(limit) => {
+            // a page is small, so it is collected to return the continuation
+            // token of its last result in a header
+            let results: Vec<Result<(String, Cursor), Error>> = response.collect().await;
+            let count = results.len();
+            let mut body = String::new();
+            let mut last = None;
+            for result in results {
+                let (line, cursor) = result.map_err(|e| super::rejection(e))?;
+                body.push_str(&line);
+                last = Some(cursor);
+            }
+
+            match last {
+                // a page which is not full is the last page
+                Some(cursor) if count == limit => {
+                    builder = builder.header(HEADER_TOKEN, page.token(&cursor));
+                }
+                _ => {}
+            }
+
+            Body::from(body)
         }
+        None => Body::wrap_stream(response.map(|result| result.map(|(line, _)| line))),
     };
 
-    let body = Body::wrap_stream(response);
+    Ok(builder.body(body))
+}
 
-    Ok(warp::reply::Response::new(body))
+#[derive(Serialize)]
+struct CountResult {
+    count: u64,
+}
+
+async fn handle_count<D: Database>(
+    mut db: D,
+    route: Option<u32>,
+    collection: String,
+    query: String,
+) -> Result<impl warp::Reply, Rejection> {
+    let ctx = Context::default()
+        .with_route(route)
+        .with_auth(Authorization::Owner);
+
+    let query = parse_query(&query).map_err(|e| super::rejection(e))?;
+    let count = db
+        .count(&ctx, query, Some(&collection))
+        .await
+        .map_err(|e| super::rejection(e))?;
+
+    Ok(warp::reply::json(&CountResult { count: count }))
+}
+
+#[derive(Serialize)]
+struct AggregateResult {
+    count: u64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    avg: Option<f64>,
+}
+
+async fn handle_aggregate<D: Database>(
+    mut db: D,
+    route: Option<u32>,
+    collection: String,
+    tag: String,
+    query: String,
+) -> Result<impl warp::Reply, Rejection> {
+    let ctx = Context::default()
+        .with_route(route)
+        .with_auth(Authorization::Owner);
+
+    let query = parse_query(&query).map_err(|e| super::rejection(e))?;
+    let aggregate = db
+        .aggregate(&ctx, &tag, query, Some(&collection))
+        .await
+        .map_err(|e| super::rejection(e))?;
+
+    Ok(warp::reply::json(&AggregateResult {
+        count: aggregate.count,
+        sum: aggregate.sum,
+        min: aggregate.min.map(|n| n.as_f64()),
+        max: aggregate.max.map(|n| n.as_f64()),
+        avg: aggregate.avg(),
+    }))
+}
+
+#[derive(Serialize)]
+struct FacetResult {
+    value: String,
+    count: u64,
+}
+
+async fn handle_facets<D: Database>(
+    mut db: D,
+    route: Option<u32>,
+    collection: String,
+    tag: String,
+    limit: Option<usize>,
+    query: String,
+) -> Result<impl warp::Reply, Rejection> {
+    let ctx = Context::default()
+        .with_route(route)
+        .with_auth(Authorization::Owner);
+
+    let query = parse_query(&query).map_err(|e| super::rejection(e))?;
+    let facets = db
+        .facets(&ctx, &tag, query, Some(&collection), limit)
+        .await
+        .map_err(|e| super::rejection(e))?;
+
+    let results: Vec<FacetResult> = facets
+        .into_iter()
+        .map(|f| FacetResult {
+            value: f.value,
+            count: f.count,
+        })
+        .collect();
+
+    Ok(warp::reply::json(&results))
 }
 
 async fn handle_delete_all<D: Database>(
@@ -299,14 +678,13 @@ async fn handle_delete_all<D: Database>(
         .with_route(route)
         .with_auth(Authorization::Owner);
 
-    let meta = parse_query(&query);
+    let query = parse_query(&query).map_err(|e| super::rejection(e))?;
 
     let results = db
-        .list(&ctx, meta, Some(&collection))
+        .list(&ctx, query, Some(&collection))
         .await
         .map_err(|e| super::rejection(e))?;
 
-    use crate::storage::Key;
     use tokio::stream::StreamExt;
     // We have to do collect here because the index implementation
     // does not allow "write" operations while doing a search.
@@ -340,56 +718,107 @@ where
 
     let fetch = base
         .clone()
-        .and(warp::path::param::<u32>())
+        .and(warp::path::param::<Key>())
         .and(warp::get())
         .and_then(handle_fetch);
 
     let collection = base.clone().and(warp::path::param::<String>()); // collection
 
+    let batch_get = collection
+        .clone()
+        .and(warp::path("batch"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024)) // setting a limit of 1MB
+        .and(warp::body::json())
+        .and_then(handle_batch_get);
+
     let set = collection
         .clone()
         .and(warp::post())
         .and(warp::header::optional::<u64>(HEADER_ACL))
         .and(warp::header::optional::<String>(HEADER_TAGS))
-        .and(warp::body::content_length_limit(4 * 1024 * 1024)) // setting a limit of 4MB
-        .and(warp::body::bytes())
+        .and(warp::body::stream())
         .and_then(handle_set);
 
     let get = collection
         .clone()
-        .and(warp::path::param::<u32>()) // key
+        .and(warp::path::param::<Key>()) // key
         .and(warp::get())
         .and_then(handle_get);
 
+    let versions = collection
+        .clone()
+        .and(warp::path::param::<Key>()) // key
+        .and(warp::path("versions"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(handle_versions);
+
+    let get_version = collection
+        .clone()
+        .and(warp::path::param::<Key>()) // key
+        .and(warp::path("versions"))
+        .and(warp::path::param::<u64>()) // version
+        .and(warp::get())
+        .and_then(handle_get_version);
+
     let head = collection
         .clone()
-        .and(warp::path::param::<u32>()) // key
+        .and(warp::path::param::<Key>()) // key
         .and(warp::head())
         .and_then(handle_head);
 
     let delete = collection
         .clone()
-        .and(warp::path::param::<u32>()) // key
+        .and(warp::path::param::<Key>()) // key
         .and(warp::delete())
         .and_then(handle_delete);
 
     let update = collection
         .clone()
-        .and(warp::path::param::<u32>()) // key
+        .and(warp::path::param::<Key>()) // key
         .and(warp::put())
         .and(warp::header::optional::<u64>(HEADER_ACL))
         .and(warp::header::optional::<String>(HEADER_TAGS))
-        .and(warp::body::content_length_limit(4 * 1024 * 1024)) // setting a limit of 4MB
-        .and(warp::body::bytes())
+        .and(warp::body::stream())
         .and_then(handle_update);
 
     let find = collection
         .clone()
         .and(warp::get())
         .and(warp::header::optional::<FindMode>(HEADER_FIND_MODE))
+        .and(with_page_options())
         .and(warp::query::raw()) // query
         .and_then(handle_find);
 
+    let count = collection
+        .clone()
+        .and(warp::path("count"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::raw()) // query
+        .and_then(handle_count);
+
+    let aggregate = collection
+        .clone()
+        .and(warp::path("aggregate"))
+        .and(warp::path::param::<String>()) // tag
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::raw()) // query
+        .and_then(handle_aggregate);
+
+    let facets = collection
+        .clone()
+        .and(warp::path("facets"))
+        .and(warp::path::param::<String>()) // tag
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<usize>(HEADER_LIMIT))
+        .and(warp::query::raw()) // query
+        .and_then(handle_facets);
+
     let delete_all = collection
         .clone()
         .and(warp::delete())
@@ -398,7 +827,15 @@ where
 
     warp::path("db").and(
         fetch
+            .or(batch_get)
             .or(set)
+            // aggregations go before find, which matches any path after the collection
+            .or(count)
+            .or(aggregate)
+            .or(facets)
+            // versions go before get, which matches any path after the key
+            .or(versions)
+            .or(get_version)
             .or(get)
             .or(head)
             .or(delete)
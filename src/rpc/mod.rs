@@ -1,25 +1,92 @@
 use crate::acl::*;
-use crate::database::{Database, Meta, Reason};
+use crate::database::page::list_cursors;
+use crate::database::{Condition, Database, Expr, Meta, Order, Page, Part, Query, Reason, Value};
 use crate::identity::Identity;
 use anyhow::Error;
-use generated::acl_server::Acl as AclServiceTrait;
-use generated::bcdb_server::Bcdb as BcdbServiceTrait;
+use futures::StreamExt;
 use generated::identity_server::Identity as IdentityTrait;
+use generated::v2::acl_server::Acl as AclServiceTrait;
+use generated::v2::admin_server::Admin as AdminServiceTrait;
+use generated::v2::bcdb_server::Bcdb as BcdbServiceTrait;
+use generated::v2::{
+    batch_get_response, compact_response, count_response, facets_response, get_stream_response,
+    list_versions_response, set_stream_request, stats_response, AclCreateResponse, AclGetRequest,
+    AclListResponse, AclSetRequest, AclUsersRequest, BatchGetRequest, BatchGetResponse,
+    CompactRequest, CompactResponse, CountRequest, CountResponse, DeleteRequest, FacetsRequest,
+    FacetsResponse, FetchRequest, FindResponse, GetRequest, GetStreamResponse, ListResponse,
+    ListVersionsRequest, ListVersionsResponse, SetResponse, SetStreamRequest, StatsRequest,
+    StatsResponse, UpdateRequest,
+};
 use generated::*;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use tokio::sync::mpsc;
 use tonic::{Code, Request, Response, Status};
 
 use crate::auth::MetadataMapExt;
-use crate::storage::{zdb::Collection, zdb::Zdb, Storage as ObjectStorage};
+use crate::storage::compaction::{self, Compactor};
+use crate::storage::dedup::{self, StatsSource};
+use crate::storage::{zdb::aio::Collection, zdb::Zdb, AsyncStorage as ObjectStorage};
 
-pub use generated::acl_server::AclServer;
-pub use generated::bcdb_server::BcdbServer;
 pub use generated::identity_server::IdentityServer;
+pub use generated::v2::acl_server::AclServer;
+pub use generated::v2::admin_server::AdminServer;
+pub use generated::v2::bcdb_server::BcdbServer;
+
+pub mod v1;
 
 pub mod generated {
     tonic::include_proto!("bcdb"); // The string specified here must match the proto package name
+
+    pub mod v2 {
+        tonic::include_proto!("bcdb.v2");
+    }
+}
+
+impl Metadata {
+    /// metadata with typed tags. All tags are set in `tags` as well, so clients
+    /// which don't know the types of tags still get all tags
+    pub fn new(tags: HashMap<String, Value>, collection: String, acl: Option<u64>) -> Self {
+        let typed_tags = tags
+            .iter()
+            .map(|(key, value)| Tag {
+                key: key.clone(),
+                value: value.as_str().into(),
+                r#type: match value {
+                    Value::Text(_) => tag::Type::Text as i32,
+                    Value::Number(_) => tag::Type::Number as i32,
+                },
+            })
+            .collect();
+
+        Metadata {
+            tags: tags
+                .into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect(),
+            collection: collection,
+            acl: acl.map(|acl| AclRef { acl }),
+            typed_tags: typed_tags,
+        }
+    }
+
+    /// the tags with their types. Tags which are only set in `tags` are text, except
+    /// for the numeric system tags, which peers without typed tags send this way
+    pub fn typed(&self) -> Result<HashMap<String, Value>, Error> {
+        let mut tags: HashMap<String, Value> = Meta::new(self.tags.clone()).into_iter().collect();
+
+        for tag in &self.typed_tags {
+            let value = match tag::Type::from_i32(tag.r#type) {
+                Some(tag::Type::Text) => Value::Text(tag.value.clone()),
+                Some(tag::Type::Number) => Value::number(tag.value.as_str())?,
+                None => bail!(Reason::InvalidTag),
+            };
+
+            tags.insert(tag.key.clone(), value);
+        }
+
+        Ok(tags)
+    }
 }
 
 trait FailureExt {
@@ -35,6 +102,7 @@ impl FailureExt for Error {
             Reason::InvalidTag => Status::invalid_argument(
                 "use of invalid tag string (':' prefix is for internal use)",
             ),
+            Reason::InvalidQuery(m) => Status::invalid_argument(format!("invalid query: {}", m)),
             Reason::CannotGetPeer(m) => Status::unavailable(m),
             Reason::Unknown(m) => Status::internal(m),
         }
@@ -43,6 +111,7 @@ impl FailureExt for Error {
 
 type ListStream = mpsc::Receiver<Result<ListResponse, Status>>;
 type FindStream = mpsc::Receiver<Result<FindResponse, Status>>;
+type GetStream = mpsc::Receiver<Result<GetStreamResponse, Status>>;
 
 //TODO: use generics for both object store type and meta factory type.
 pub struct BcdbService<D>
@@ -63,12 +132,69 @@ where
     fn build_meta(metadata: Meta) -> Metadata {
         //build metadata for storage
         let collection = metadata.collection().unwrap_or_default();
-        let acl = metadata.acl().map(|acl| AclRef { acl });
-        Metadata {
-            tags: metadata.into(),
-            collection: collection,
-            acl: acl,
+        let acl = metadata.acl();
+        Metadata::new(metadata.into_iter().collect(), collection, acl)
+    }
+
+    fn build_query(request: &QueryRequest) -> Result<Query, Error> {
+        let mut query = request
+            .tags
+            .iter()
+            .fold(Query::new(), |query, (tag, value)| {
+                query.with_tag(tag.as_str(), value.as_str())
+            });
+
+        if !request.query.trim().is_empty() {
+            query = query.with_expr(Expr::parse(&request.query)?);
+        }
+
+        let mut page = Page::new();
+        if !request.sort_by.is_empty() {
+            page = page.with_sort_by(request.sort_by.as_str());
+        }
+
+        match query_request::Order::from_i32(request.order) {
+            Some(query_request::Order::Asc) => {}
+            Some(query_request::Order::Desc) => page = page.with_order(Order::Descending),
+            None => bail!(Reason::InvalidQuery(format!(
+                "unknown order {}",
+                request.order
+            ))),
+        };
+
+        if request.limit > 0 {
+            page = page.with_limit(request.limit as usize);
+        }
+
+        if !request.token.is_empty() {
+            page = page.with_token(&request.token)?;
         }
+
+        let query = query.with_page(page);
+        request
+            .comparisons
+            .iter()
+            .try_fold(query, |query, comparison| -> Result<Query, Error> {
+                use comparison::Operator;
+                let condition = match Operator::from_i32(comparison.operator) {
+                    Some(Operator::Lt) => Condition::parse("lt", &comparison.value)?,
+                    Some(Operator::Lte) => Condition::parse("lte", &comparison.value)?,
+                    Some(Operator::Gt) => Condition::parse("gt", &comparison.value)?,
+                    Some(Operator::Gte) => Condition::parse("gte", &comparison.value)?,
+                    Some(Operator::Between) => Condition::parse(
+                        "between",
+                        &format!("{},{}", comparison.value, comparison.upper),
+                    )?,
+                    Some(Operator::Prefix) => Condition::parse("prefix", &comparison.value)?,
+                    Some(Operator::Glob) => Condition::parse("glob", &comparison.value)?,
+                    None => bail!(Reason::InvalidQuery(format!(
+                        "unknown operator {}",
+                        comparison.operator
+                    ))),
+                };
+
+                query.with_condition(comparison.tag.as_str(), condition)
+            })
     }
 }
 
@@ -88,27 +214,73 @@ where
             None => return Err(Status::invalid_argument("metadata is required")),
         };
 
-        let acl = metadata.acl.map(|a| a.acl);
+        let acl = metadata.acl.as_ref().map(|a| a.acl);
+        let tags = metadata.typed().map_err(|e| e.status())?;
 
         let mut db = self.db.clone();
         let id = db
-            .set(&ctx, &metadata.collection, data, metadata.tags, acl)
+            .set(&ctx, &metadata.collection, data, tags, acl)
             .await
             .map_err(|e| e.status())?;
 
         Ok(Response::new(SetResponse { id }))
     }
 
+    async fn set_stream(
+        &self,
+        request: Request<tonic::Streaming<SetStreamRequest>>,
+    ) -> Result<Response<SetResponse>, Status> {
+        let ctx = request.metadata().context();
+        let mut stream = request.into_inner();
+
+        let (mut tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let part = match stream.message().await {
+                    Ok(None) => break, // end of stream
+                    Ok(Some(request)) => match request.part {
+                        Some(set_stream_request::Part::Data(data)) => Ok(Part::Data(data)),
+                        Some(set_stream_request::Part::Metadata(metadata)) => {
+                            metadata.typed().map(|tags| Part::Meta {
+                                collection: metadata.collection,
+                                tags: tags,
+                                acl: metadata.acl.map(|a| a.acl),
+                            })
+                        }
+                        None => Err(format_err!("empty set stream request")),
+                    },
+                    Err(status) => Err(format_err!("failed to receive data: {}", status)),
+                };
+
+                let failed = part.is_err();
+                if let Err(err) = tx.send(part).await {
+                    debug!("failed to send part, broken stream: {}", err);
+                    break;
+                }
+
+                if failed {
+                    break;
+                }
+            }
+        });
+
+        let mut db = self.db.clone();
+        let id = db.set_stream(&ctx, rx).await.map_err(|e| e.status())?;
+
+        Ok(Response::new(SetResponse { id }))
+    }
+
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
         let ctx = request.metadata().context();
         let request = request.into_inner();
         let id = request.id;
 
         let mut db = self.db.clone();
-        let object = db
-            .get(&ctx, id, &request.collection)
-            .await
-            .map_err(|e| e.status())?;
+        let object = match request.version {
+            0 => db.get(&ctx, id, &request.collection).await,
+            version => db.get_version(&ctx, id, &request.collection, version).await,
+        }
+        .map_err(|e| e.status())?;
 
         Ok(Response::new(GetResponse {
             data: object.data.unwrap_or_default(), // This unwrap is safe as we checked the none case above
@@ -116,6 +288,78 @@ where
         }))
     }
 
+    type GetStreamStream = GetStream;
+
+    async fn get_stream(
+        &self,
+        request: Request<GetRequest>,
+    ) -> Result<Response<Self::GetStreamStream>, Status> {
+        let ctx = request.metadata().context();
+        let request = request.into_inner();
+        let id = request.id;
+
+        let mut db = self.db.clone();
+        let (object, mut chunks) = db
+            .get_stream(&ctx, id, &request.collection)
+            .await
+            .map_err(|e| e.status())?;
+
+        let (mut tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let metadata = get_stream_response::Part::Metadata(Self::build_meta(object.meta));
+            if tx
+                .send(Ok(GetStreamResponse {
+                    part: Some(metadata),
+                }))
+                .await
+                .is_err()
+            {
+                return;
+            }
+
+            while let Some(chunk) = chunks.recv().await {
+                let response = match chunk {
+                    Ok(data) => Ok(GetStreamResponse {
+                        part: Some(get_stream_response::Part::Data(data)),
+                    }),
+                    Err(err) => Err(err.status()),
+                };
+
+                if let Err(err) = tx.send(response).await {
+                    debug!("failed to send chunk, broken stream: {}", err);
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+
+    async fn batch_get(
+        &self,
+        request: Request<BatchGetRequest>,
+    ) -> Result<Response<BatchGetResponse>, Status> {
+        let ctx = request.metadata().context();
+        let request = request.into_inner();
+
+        let mut db = self.db.clone();
+        let objects = db
+            .get_many(&ctx, request.ids, &request.collection)
+            .await
+            .map_err(|e| e.status())?;
+
+        Ok(Response::new(BatchGetResponse {
+            objects: objects
+                .into_iter()
+                .map(|object| batch_get_response::Object {
+                    id: object.key,
+                    data: object.data.unwrap_or_default(),
+                    metadata: Some(Self::build_meta(object.meta)),
+                })
+                .collect(),
+        }))
+    }
+
     async fn head(&self, request: Request<GetRequest>) -> Result<Response<HeadResponse>, Status> {
         let ctx = request.metadata().context();
         let request = request.into_inner();
@@ -162,6 +406,31 @@ where
         Ok(Response::new(DeleteResponse {}))
     }
 
+    async fn list_versions(
+        &self,
+        request: Request<ListVersionsRequest>,
+    ) -> Result<Response<ListVersionsResponse>, Status> {
+        let ctx = request.metadata().context();
+        let request = request.into_inner();
+
+        let mut db = self.db.clone();
+        let versions = db
+            .versions(&ctx, request.id, &request.collection)
+            .await
+            .map_err(|e| e.status())?;
+
+        Ok(Response::new(ListVersionsResponse {
+            versions: versions
+                .into_iter()
+                .map(|v| list_versions_response::Version {
+                    version: v.version,
+                    size: v.size,
+                    timestamp: v.timestamp,
+                })
+                .collect(),
+        }))
+    }
+
     async fn update(
         &self,
         request: Request<UpdateRequest>,
@@ -176,7 +445,8 @@ where
             None => return Err(Status::invalid_argument("metadata is required")),
         };
 
-        let acl = metadata.acl.map(|a| a.acl);
+        let acl = metadata.acl.as_ref().map(|a| a.acl);
+        let tags = metadata.typed().map_err(|e| e.status())?;
 
         let mut db = self.db.clone();
         let _ = db
@@ -185,7 +455,7 @@ where
                 id,
                 &metadata.collection,
                 data.map(|d| d.data),
-                metadata.tags,
+                tags,
                 acl,
             )
             .await
@@ -202,16 +472,23 @@ where
 
         let mut db = self.db.clone();
 
-        let mut results = db
-            .list(&ctx, request.tags, Some(&request.collection))
+        let query = Self::build_query(&request).map_err(|e| e.status())?;
+        let page = query.page().clone();
+        let mut results = list_cursors(&mut db, &ctx, query, Some(&request.collection))
             .await
             .map_err(|e| e.status())?;
 
         let (mut tx, rx) = mpsc::channel(10);
         tokio::spawn(async move {
-            while let Some(id) = results.recv().await {
-                match id {
-                    Ok(id) => tx.send(Ok(ListResponse { id: id })).await.unwrap(),
+            while let Some(result) = results.recv().await {
+                match result {
+                    Ok((id, cursor)) => tx
+                        .send(Ok(ListResponse {
+                            id: id,
+                            token: page.token(&cursor),
+                        }))
+                        .await
+                        .unwrap(),
                     Err(err) => tx.send(Err(err.status())).await.unwrap(),
                 }
             }
@@ -231,8 +508,10 @@ where
 
         let mut db = self.db.clone();
 
+        let query = Self::build_query(&request).map_err(|e| e.status())?;
+        let page = query.page().clone();
         let mut results = db
-            .find(&ctx, request.tags, Some(&request.collection))
+            .find(&ctx, query, Some(&request.collection))
             .await
             .map_err(|e| e.status())?;
 
@@ -243,6 +522,7 @@ where
                     Ok(object) => tx
                         .send(Ok(FindResponse {
                             id: object.key,
+                            token: page.token(&page.cursor(object.key, &object.meta)),
                             metadata: Some(Self::build_meta(object.meta)),
                         }))
                         .await
@@ -254,6 +534,88 @@ where
 
         Ok(Response::new(rx))
     }
+
+    async fn count(
+        &self,
+        request: Request<CountRequest>,
+    ) -> Result<Response<CountResponse>, Status> {
+        let ctx = request.metadata().context();
+        let request = request.into_inner();
+        let query_request = request.query.unwrap_or_default();
+
+        let mut db = self.db.clone();
+
+        let query = Self::build_query(&query_request).map_err(|e| e.status())?;
+        let collection = Some(query_request.collection.as_str());
+        let count = db
+            .count(&ctx, query.clone(), collection)
+            .await
+            .map_err(|e| e.status())?;
+
+        let aggregate = match request.tag.as_str() {
+            "" => None,
+            tag => {
+                let aggregate = db
+                    .aggregate(&ctx, tag, query, collection)
+                    .await
+                    .map_err(|e| e.status())?;
+
+                Some(count_response::Aggregate {
+                    count: aggregate.count,
+                    sum: aggregate.sum,
+                    min: aggregate.min.map(|n| n.as_f64()).unwrap_or_default(),
+                    max: aggregate.max.map(|n| n.as_f64()).unwrap_or_default(),
+                    avg: aggregate.avg().unwrap_or_default(),
+                })
+            }
+        };
+
+        Ok(Response::new(CountResponse {
+            count: count,
+            aggregate: aggregate,
+        }))
+    }
+
+    async fn facets(
+        &self,
+        request: Request<FacetsRequest>,
+    ) -> Result<Response<FacetsResponse>, Status> {
+        let ctx = request.metadata().context();
+        let request = request.into_inner();
+        let query_request = request.query.unwrap_or_default();
+        if request.tag.is_empty() {
+            return Err(Status::invalid_argument("tag is required"));
+        }
+
+        let limit = match request.limit {
+            0 => None,
+            limit => Some(limit as usize),
+        };
+
+        let mut db = self.db.clone();
+
+        let query = Self::build_query(&query_request).map_err(|e| e.status())?;
+        let facets = db
+            .facets(
+                &ctx,
+                &request.tag,
+                query,
+                Some(&query_request.collection),
+                limit,
+            )
+            .await
+            .map_err(|e| e.status())?;
+
+        Ok(Response::new(FacetsResponse {
+            facets: facets
+                .into_iter()
+                .map(|f| facets_response::Facet {
+                    value: f.value,
+                    count: f.count,
+                })
+                .collect(),
+        }))
+    }
 }
 
 pub struct AclService<S>
@@ -266,7 +628,7 @@ where
 impl Default for AclService<Collection> {
     fn default() -> AclService<Collection> {
         AclService {
-            store: ACLStorage::new(Zdb::default().collection("acl")),
+            store: ACLStorage::new(Zdb::default().multiplexed().collection("acl")),
         }
     }
 }
@@ -283,7 +645,7 @@ where
 #[tonic::async_trait]
 impl<S> AclServiceTrait for AclService<S>
 where
-    S: ObjectStorage + Send + Sync + 'static,
+    S: ObjectStorage,
 {
     async fn get(
         &self,
@@ -298,7 +660,7 @@ where
         let request = request.into_inner();
         let mut store = self.store.clone();
 
-        let acl = match store.get(request.key) {
+        let acl = match store.get(request.key).await {
             Ok(acl) => match acl {
                 Some(acl) => acl,
                 None => return Err(Status::not_found("acl not found")),
@@ -344,7 +706,7 @@ where
         };
 
         let mut store = self.store.clone();
-        match store.create(&acl) {
+        match store.create(&acl).await {
             Ok(k) => Ok(Response::new(AclCreateResponse { key: k })),
             Err(err) => Err(err.status()),
         }
@@ -366,15 +728,15 @@ where
         let mut store = self.store.clone();
 
         tokio::spawn(async move {
-            let iter = match store.list() {
-                Ok(iter) => iter,
+            let mut acls = match store.list().await {
+                Ok(acls) => acls,
                 Err(err) => {
                     tx.send(Err(err.status())).await.unwrap();
                     return;
                 }
             };
 
-            for item in iter {
+            while let Some(item) = acls.next().await {
                 let (key, acl) = match item {
                     Ok(item) => item,
                     Err(err) => {
@@ -420,7 +782,7 @@ where
         };
 
         let mut store = self.store.clone();
-        let mut acl = match store.get(request.key) {
+        let mut acl = match store.get(request.key).await {
             Ok(acl) => match acl {
                 Some(acl) => acl,
                 None => return Err(Status::not_found("no acl found with key")),
@@ -430,7 +792,7 @@ where
 
         acl.perm = perm;
 
-        match store.update(request.key, &acl) {
+        match store.update(request.key, &acl).await {
             Ok(_) => Ok(Response::new(AclSetResponse {})),
             Err(err) => Err(err.status()),
         }
@@ -449,7 +811,7 @@ where
         let request = request.into_inner();
 
         let mut store = self.store.clone();
-        let mut acl = match store.get(request.key) {
+        let mut acl = match store.get(request.key).await {
             Ok(acl) => match acl {
                 Some(acl) => acl,
                 None => return Err(Status::not_found("no acl found with key")),
@@ -467,7 +829,7 @@ where
         if updated > 0 {
             acl.users = Vec::from_iter(set.into_iter());
 
-            match store.update(request.key, &acl) {
+            match store.update(request.key, &acl).await {
                 Ok(_) => Ok(Response::new(AclUsersResponse {
                     updated: updated as u64,
                 })),
@@ -490,7 +852,7 @@ where
         let request = request.into_inner();
 
         let mut store = self.store.clone();
-        let mut acl = match store.get(request.key) {
+        let mut acl = match store.get(request.key).await {
             Ok(acl) => match acl {
                 Some(acl) => acl,
                 None => return Err(Status::not_found("no acl found with key")),
@@ -508,7 +870,7 @@ where
         if updated > 0 {
             acl.users = Vec::from_iter(set.into_iter());
 
-            match store.update(request.key, &acl) {
+            match store.update(request.key, &acl).await {
                 Ok(_) => Ok(Response::new(AclUsersResponse {
                     updated: updated as u64,
                 })),
@@ -558,20 +920,131 @@ impl IdentityTrait for IdentityService {
     }
 }
 
+pub struct AdminService<C, T>
+where
+    C: Compactor,
+    T: StatsSource,
+{
+    compactor: C,
+    stats: T,
+}
+
+impl<C, T> AdminService<C, T>
+where
+    C: Compactor,
+    T: StatsSource,
+{
+    pub fn new(compactor: C, stats: T) -> AdminService<C, T> {
+        AdminService { compactor, stats }
+    }
+}
+
+#[tonic::async_trait]
+impl<C, T> AdminServiceTrait for AdminService<C, T>
+where
+    C: Compactor,
+    T: StatsSource,
+{
+    async fn compact(
+        &self,
+        request: Request<CompactRequest>,
+    ) -> Result<Response<CompactResponse>, Status> {
+        let ctx = request.metadata().context();
+
+        if !ctx.is_owner() {
+            return Err(Status::unauthenticated("not authorized"));
+        }
+
+        let request = request.into_inner();
+        if !(0.0..=1.0).contains(&request.threshold) {
+            return Err(Status::invalid_argument(
+                "threshold must be between 0 and 1",
+            ));
+        }
+
+        // 0-db namespaces are replaced by a compaction, which can't happen while
+        // they are used, so on 0-db the compaction is only reported
+        if !request.dry_run && !self.compactor.online() {
+            return Err(Status::failed_precondition(
+                "the storage can only be compacted offline, with `bcdb compact`",
+            ));
+        }
+
+        // compaction reads and writes whole collections, so it runs on the blocking pool
+        let compactor = self.compactor.clone();
+        let reports = tokio::task::spawn_blocking(move || {
+            compaction::compact(
+                &compactor,
+                &request.collections,
+                request.threshold,
+                request.dry_run,
+            )
+        })
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?
+        .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(CompactResponse {
+            reclaimed: reports.iter().map(|report| report.reclaimed).sum(),
+            collections: reports
+                .into_iter()
+                .map(|report| compact_response::Collection {
+                    name: report.collection,
+                    live: report.usage.live,
+                    dead: report.usage.dead,
+                    reclaimed: report.reclaimed,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn stats(
+        &self,
+        request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        let ctx = request.metadata().context();
+
+        if !ctx.is_owner() {
+            return Err(Status::unauthenticated("not authorized"));
+        }
+
+        let request = request.into_inner();
+        let stats = dedup::stats(&self.stats, &request.collections)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(StatsResponse {
+            saved: stats.iter().map(|(_, stats)| stats.saved()).sum(),
+            collections: stats
+                .into_iter()
+                .map(|(name, stats)| stats_response::Collection {
+                    name: name,
+                    blobs: stats.blobs,
+                    references: stats.references,
+                    stored: stats.stored,
+                    logical: stats.logical,
+                    saved: stats.saved(),
+                })
+                .collect(),
+        }))
+    }
+}
+
 #[cfg(test)]
 mod rpc_tests {
-    use super::generated::*;
+    use super::generated::v2::bcdb_server::Bcdb;
+    use super::generated::v2::*;
+    use super::generated::{tag, AclRef, Metadata, QueryRequest, SetRequest, Tag};
     use super::BcdbService;
     use crate::database::data::database_tests::get_in_memory_db;
     use crate::database::Database;
-    use crate::database::{Authorization, Context};
-    use crate::rpc::generated::bcdb_server::Bcdb;
+    use crate::database::{Authorization, Context, Value};
     use std::collections::HashMap;
     use tonic::Request;
 
     #[tokio::test]
     async fn rpc_set_owner() {
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
         let rpc = BcdbService::new(db.clone());
         let mut tags = HashMap::default();
         tags.insert("tag".into(), "value".into());
@@ -583,6 +1056,7 @@ mod rpc_tests {
                 acl: Some(AclRef { acl: 3 }),
                 collection: "test".into(),
                 tags: tags,
+                typed_tags: vec![],
             }),
         });
 
@@ -606,9 +1080,79 @@ mod rpc_tests {
         assert_eq!(object.meta.get("tag").unwrap(), "value");
     }
 
+    #[tokio::test]
+    async fn rpc_typed_tags() {
+        let mut db = get_in_memory_db().await;
+        let rpc = BcdbService::new(db.clone());
+        let owner = Context::default().with_auth(Authorization::Owner);
+        let typed = |key: &str, value: &str, kind: tag::Type| Tag {
+            key: key.into(),
+            value: value.into(),
+            r#type: kind as i32,
+        };
+
+        let mut tags = HashMap::default();
+        tags.insert("code".into(), "007".into());
+        tags.insert("year".into(), "text".into());
+        let set = |typed_tags| {
+            let mut request = Request::new(SetRequest {
+                data: "hello world".into(),
+                metadata: Some(Metadata {
+                    acl: None,
+                    collection: "test".into(),
+                    tags: tags.clone(),
+                    typed_tags: typed_tags,
+                }),
+            });
+            owner.clone().into_metadata(request.metadata_mut());
+            request
+        };
+
+        // a typed tag overrides the tag of the same name
+        let request = set(vec![
+            typed("year", "2010", tag::Type::Number),
+            typed("name", "test", tag::Type::Text),
+        ]);
+        let id = rpc.set(request).await.unwrap().into_inner().id;
+
+        let object = db.fetch(&owner, id).await.unwrap();
+        assert_eq!(object.meta.value("code"), Some(&Value::Text("007".into())));
+        assert_eq!(
+            object.meta.value("year"),
+            Some(&Value::Number("2010".into()))
+        );
+        assert_eq!(object.meta.value("name"), Some(&Value::Text("test".into())));
+
+        // returned metadata has all tags, with and without types
+        let mut request = Request::new(GetRequest {
+            id: id,
+            collection: "test".into(),
+            version: 0,
+        });
+        owner.clone().into_metadata(request.metadata_mut());
+        let metadata = rpc
+            .get(request)
+            .await
+            .unwrap()
+            .into_inner()
+            .metadata
+            .unwrap();
+        assert_eq!(metadata.tags.get("year").unwrap(), "2010");
+        assert_eq!(metadata.tags.get("code").unwrap(), "007");
+        let tags = metadata.typed().unwrap();
+        assert_eq!(tags.get("year"), Some(&Value::Number("2010".into())));
+        assert_eq!(tags.get("code"), Some(&Value::Text("007".into())));
+        assert_eq!(tags.get(":size"), Some(&Value::Number("11".into())));
+
+        // a value typed as a number must be a number
+        let request = set(vec![typed("year", "2010s", tag::Type::Number)]);
+        let status = rpc.set(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
     #[tokio::test]
     async fn rpc_set_no_owner() {
-        let db = get_in_memory_db();
+        let db = get_in_memory_db().await;
         let rpc = BcdbService::new(db);
         let mut tags = HashMap::default();
         tags.insert("tag".into(), "value".into());
@@ -620,6 +1164,7 @@ mod rpc_tests {
                 acl: None,
                 collection: "test".into(),
                 tags: tags,
+                typed_tags: vec![],
             }),
         });
 
@@ -636,7 +1181,7 @@ mod rpc_tests {
 
     #[tokio::test]
     async fn rpc_get() {
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
         let data: Vec<u8> = "hello world".into();
         let mut tags = HashMap::default();
         tags.insert("tag".into(), "value".into());
@@ -657,6 +1202,7 @@ mod rpc_tests {
         let mut request = Request::new(GetRequest {
             id: id,
             collection: "test".into(),
+            version: 0,
         });
 
         // set required context on request
@@ -675,9 +1221,58 @@ mod rpc_tests {
         assert_eq!(metadata.tags.get("tag").unwrap(), "value");
     }
 
+    #[tokio::test]
+    async fn rpc_get_stream() {
+        let mut db = get_in_memory_db().await;
+        let data: Vec<u8> = "hello world".into();
+
+        let id = db
+            .set(
+                &Context::default().with_auth(Authorization::Owner),
+                "test".into(),
+                data.clone(),
+                HashMap::default(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let rpc = BcdbService::new(db);
+
+        let mut request = Request::new(GetRequest {
+            id: id,
+            collection: "test".into(),
+            version: 0,
+        });
+
+        // set required context on request
+        Context::default()
+            .with_auth(Authorization::Owner)
+            .into_metadata(request.metadata_mut());
+
+        let mut stream = rpc.get_stream(request).await.unwrap().into_inner();
+
+        match stream.recv().await.unwrap().unwrap().part {
+            Some(get_stream_response::Part::Metadata(metadata)) => {
+                assert_eq!(metadata.collection, "test");
+            }
+            _ => panic!("expecting metadata"),
+        };
+
+        let mut received = vec![];
+        while let Some(response) = stream.recv().await {
+            match response.unwrap().part {
+                Some(get_stream_response::Part::Data(chunk)) => received.extend(chunk),
+                _ => panic!("expecting data"),
+            }
+        }
+
+        assert_eq!(received, data);
+    }
+
     #[tokio::test]
     async fn rpc_fetch() {
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
         let data: Vec<u8> = "hello world".into();
         let mut tags = HashMap::default();
         tags.insert("tag".into(), "value".into());
@@ -698,6 +1293,7 @@ mod rpc_tests {
         let mut request = Request::new(GetRequest {
             id: id,
             collection: "wrong".into(),
+            version: 0,
         });
 
         // set required context on request
@@ -730,7 +1326,7 @@ mod rpc_tests {
 
     #[tokio::test]
     async fn rpc_delete() {
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
         let data: Vec<u8> = "hello world".into();
         let mut tags = HashMap::default();
         tags.insert("tag".into(), "value".into());
@@ -777,7 +1373,7 @@ mod rpc_tests {
 
     #[tokio::test]
     async fn rpc_update() {
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
         let data: Vec<u8> = "hello world".into();
         let mut tags = HashMap::default();
         tags.insert("tag".into(), "value".into());
@@ -802,6 +1398,7 @@ mod rpc_tests {
                 collection: "test".into(),
                 tags: HashMap::default(),
                 acl: Some(AclRef { acl: 3 }),
+                typed_tags: vec![],
             }),
             data: Some(update_request::UpdateData {
                 data: new_data.clone(),
@@ -834,9 +1431,79 @@ mod rpc_tests {
         assert_eq!(metadata.tags.get("tag").unwrap(), "value");
     }
 
+    #[tokio::test]
+    async fn rpc_versions() {
+        let mut db = get_in_memory_db().await;
+        let ctx = Context::default().with_auth(Authorization::Owner);
+        let id = db
+            .set(&ctx, "test", "first".into(), HashMap::default(), None)
+            .await
+            .unwrap();
+        db.update(
+            &ctx,
+            id,
+            "test",
+            Some("second version".into()),
+            HashMap::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let rpc = BcdbService::new(db);
+
+        let mut request = Request::new(ListVersionsRequest {
+            id: id,
+            collection: "test".into(),
+        });
+
+        // set required context on request
+        Context::default()
+            .with_auth(Authorization::Owner)
+            .into_metadata(request.metadata_mut());
+
+        let versions = rpc.list_versions(request).await.unwrap().into_inner();
+        let versions: Vec<(u64, u64)> = versions
+            .versions
+            .into_iter()
+            .map(|v| (v.version, v.size))
+            .collect();
+        assert_eq!(versions, vec![(1, 5), (2, 14)]);
+
+        let mut request = Request::new(GetRequest {
+            id: id,
+            collection: "test".into(),
+            version: 1,
+        });
+
+        // set required context on request
+        Context::default()
+            .with_auth(Authorization::Owner)
+            .into_metadata(request.metadata_mut());
+
+        let object = rpc.get(request).await.unwrap().into_inner();
+        assert_eq!(object.data, b"first".to_vec());
+        let metadata = object.metadata.unwrap();
+        assert_eq!(metadata.tags.get(":version").unwrap(), "1");
+
+        let mut request = Request::new(GetRequest {
+            id: id,
+            collection: "test".into(),
+            version: 3,
+        });
+
+        // set required context on request
+        Context::default()
+            .with_auth(Authorization::Owner)
+            .into_metadata(request.metadata_mut());
+
+        let status = rpc.get(request).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
     #[tokio::test]
     async fn rpc_find() {
-        let mut db = get_in_memory_db();
+        let mut db = get_in_memory_db().await;
         let data: Vec<u8> = "hello world".into();
         let mut tags = HashMap::default();
         tags.insert("common".into(), "value".into());
@@ -875,6 +1542,7 @@ mod rpc_tests {
         let mut request = Request::new(QueryRequest {
             collection: "test".into(),
             tags: query,
+            ..Default::default()
         });
 
         // set required context on request
@@ -887,7 +1555,7 @@ mod rpc_tests {
 
         let mut stream = result.unwrap().into_inner();
 
-        let mut results: HashMap<u32, FindResponse> = HashMap::new();
+        let mut results: HashMap<u64, FindResponse> = HashMap::new();
         while let Some(result) = stream.recv().await {
             let result = result.unwrap();
             results.insert(result.id, result);
@@ -908,4 +1576,423 @@ mod rpc_tests {
         assert_eq!(metadata.tags.get("common").unwrap(), "value");
         assert_eq!(metadata.tags.get("name").unwrap(), "object-2");
     }
+
+    #[tokio::test]
+    async fn rpc_list_range() {
+        use super::generated::{comparison::Operator, Comparison};
+
+        let mut db = get_in_memory_db().await;
+        let mut ids = vec![];
+        let ages = [
+            Value::number("9").unwrap(),
+            Value::number("10").unwrap(),
+            "old".into(),
+        ];
+        for age in &ages {
+            let mut tags = HashMap::default();
+            tags.insert("age".into(), age.clone());
+            let id = db
+                .set(
+                    &Context::default().with_auth(Authorization::Owner),
+                    "test",
+                    b"data".to_vec(),
+                    tags,
+                    None,
+                )
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+
+        let rpc = BcdbService::new(db);
+        let request = |tag: &str, operator: Operator, value: &str, upper: &str| {
+            let mut request = Request::new(QueryRequest {
+                collection: "test".into(),
+                tags: HashMap::new(),
+                comparisons: vec![Comparison {
+                    tag: tag.into(),
+                    operator: operator as i32,
+                    value: value.into(),
+                    upper: upper.into(),
+                }],
+                ..Default::default()
+            });
+
+            Context::default()
+                .with_auth(Authorization::Owner)
+                .into_metadata(request.metadata_mut());
+            request
+        };
+
+        let mut stream = rpc
+            .list(request("age", Operator::Gt, "9", ""))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut results = vec![];
+        while let Some(result) = stream.recv().await {
+            results.push(result.unwrap().id);
+        }
+        assert_eq!(results, vec![ids[1]]);
+
+        let mut stream = rpc
+            .list(request("age", Operator::Between, "1", "100"))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut results = vec![];
+        while let Some(result) = stream.recv().await {
+            results.push(result.unwrap().id);
+        }
+        results.sort();
+        assert_eq!(results, vec![ids[0], ids[1]]);
+
+        let result = rpc.list(request("age", Operator::Lt, "young", "")).await;
+        assert_eq!(result.err().unwrap().code(), tonic::Code::InvalidArgument);
+
+        let result = rpc.list(request(":acl", Operator::Lt, "1", "")).await;
+        assert_eq!(result.err().unwrap().code(), tonic::Code::InvalidArgument);
+
+        let mut stream = rpc
+            .list(request("age", Operator::Glob, "[0-9]", ""))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut results = vec![];
+        while let Some(result) = stream.recv().await {
+            results.push(result.unwrap().id);
+        }
+        assert_eq!(results, vec![ids[0]]);
+
+        let result = rpc.list(request("age", Operator::Glob, "[0-9", "")).await;
+        assert_eq!(result.err().unwrap().code(), tonic::Code::InvalidArgument);
+
+        let mut with_query = request("age", Operator::Gte, "9", "");
+        with_query.get_mut().query = "age = 10 or age = old".into();
+        let mut stream = rpc.list(with_query).await.unwrap().into_inner();
+        let mut results = vec![];
+        while let Some(result) = stream.recv().await {
+            results.push(result.unwrap().id);
+        }
+        assert_eq!(results, vec![ids[1]]);
+
+        let mut with_query = request("age", Operator::Gte, "9", "");
+        with_query.get_mut().query = "age = (10".into();
+        let result = rpc.list(with_query).await;
+        assert_eq!(result.err().unwrap().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn rpc_list_pages() {
+        use super::generated::query_request::Order;
+
+        let mut db = get_in_memory_db().await;
+        let mut ids = vec![];
+        for n in &["3", "1", "4", "1", "5"] {
+            let mut tags = HashMap::default();
+            tags.insert("n".into(), Value::number(*n).unwrap());
+            let id = db
+                .set(
+                    &Context::default().with_auth(Authorization::Owner),
+                    "test",
+                    b"data".to_vec(),
+                    tags,
+                    None,
+                )
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+
+        let rpc = BcdbService::new(db);
+        let request = |sort_by: &str, order: Order, token: &str| {
+            let mut request = Request::new(QueryRequest {
+                collection: "test".into(),
+                limit: 2,
+                sort_by: sort_by.into(),
+                order: order as i32,
+                token: token.into(),
+                ..Default::default()
+            });
+
+            Context::default()
+                .with_auth(Authorization::Owner)
+                .into_metadata(request.metadata_mut());
+            request
+        };
+
+        let mut results = vec![];
+        let mut token = String::new();
+        loop {
+            let mut stream = rpc
+                .list(request("n", Order::Desc, &token))
+                .await
+                .unwrap()
+                .into_inner();
+            let mut page = vec![];
+            while let Some(result) = stream.recv().await {
+                let result = result.unwrap();
+                page.push(result.id);
+                token = result.token;
+            }
+
+            if page.is_empty() {
+                break;
+            }
+            assert!(page.len() <= 2);
+            results.extend(page);
+        }
+        assert_eq!(results, vec![ids[4], ids[2], ids[0], ids[3], ids[1]]);
+
+        // find continues from a token just like list
+        let mut stream = rpc
+            .find(request("", Order::Asc, ""))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut token = String::new();
+        while let Some(result) = stream.recv().await {
+            token = result.unwrap().token;
+        }
+
+        let mut stream = rpc
+            .find(request("", Order::Asc, &token))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut results = vec![];
+        while let Some(result) = stream.recv().await {
+            results.push(result.unwrap().id);
+        }
+        assert_eq!(results, vec![ids[2], ids[3]]);
+
+        // a token only continues the order it was returned for
+        let result = rpc.list(request("n", Order::Asc, &token)).await;
+        assert_eq!(result.err().unwrap().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn rpc_count_facets() {
+        let mut db = get_in_memory_db().await;
+        let number = |value: &str| Value::number(value).unwrap();
+        let files = [
+            ("pdf", number("10")),
+            ("txt", number("2.5")),
+            ("pdf", "big".into()),
+            ("pdf", number("-3")),
+        ];
+        for (kind, size) in &files {
+            let mut tags = HashMap::default();
+            tags.insert("kind".into(), Value::from(*kind));
+            tags.insert("size".into(), size.clone());
+            db.set(
+                &Context::default().with_auth(Authorization::Owner),
+                "test",
+                b"data".to_vec(),
+                tags,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+
+        let rpc = BcdbService::new(db);
+        let query = |query: &str| {
+            Some(QueryRequest {
+                collection: "test".into(),
+                query: query.into(),
+                limit: 1,
+                ..Default::default()
+            })
+        };
+        fn with_auth<T>(mut request: Request<T>) -> Request<T> {
+            Context::default()
+                .with_auth(Authorization::Owner)
+                .into_metadata(request.metadata_mut());
+            request
+        }
+
+        let response = rpc
+            .count(with_auth(Request::new(CountRequest {
+                query: query("kind = pdf"),
+                tag: "size".into(),
+            })))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.count, 3);
+        assert_eq!(
+            response.aggregate,
+            Some(count_response::Aggregate {
+                count: 2,
+                sum: 7.0,
+                min: -3.0,
+                max: 10.0,
+                avg: 3.5,
+            })
+        );
+
+        let response = rpc
+            .count(with_auth(Request::new(CountRequest {
+                query: query(""),
+                tag: "".into(),
+            })))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.count, 4);
+        assert_eq!(response.aggregate, None);
+
+        let response = rpc
+            .facets(with_auth(Request::new(FacetsRequest {
+                query: query(""),
+                tag: "kind".into(),
+                limit: 0,
+            })))
+            .await
+            .unwrap()
+            .into_inner();
+        let facets: Vec<(String, u64)> = response
+            .facets
+            .into_iter()
+            .map(|f| (f.value, f.count))
+            .collect();
+        assert_eq!(facets, vec![("pdf".into(), 3), ("txt".into(), 1)]);
+
+        let result = rpc
+            .facets(with_auth(Request::new(FacetsRequest {
+                query: query("kind = ("),
+                tag: "kind".into(),
+                limit: 0,
+            })))
+            .await;
+        assert_eq!(result.err().unwrap().code(), tonic::Code::InvalidArgument);
+
+        // only the owner can count
+        let result = rpc
+            .count(Request::new(CountRequest {
+                query: query(""),
+                tag: "".into(),
+            }))
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn rpc_admin_compact() {
+        use super::generated::v2::admin_server::Admin;
+        use super::AdminService;
+        use crate::storage::compaction::{DeadBytes, ZdbCompactor};
+        use crate::storage::file::FileStorage;
+        use crate::storage::memory::MemoryCollections;
+        use crate::storage::Storage;
+
+        const DIR: &str = "/tmp/bcdb-rpc-compact.test";
+        let _ = std::fs::remove_dir_all(DIR);
+        let storage = FileStorage::new(DIR).unwrap();
+        let collection = storage.collection("test").unwrap();
+        let key = collection.set(None, b"first version").unwrap();
+        collection.set(Some(key), b"second version").unwrap();
+
+        let rpc = AdminService::new(storage, dedup_namespaces().await);
+        let compact = |auth: Authorization| {
+            let mut request = Request::new(CompactRequest {
+                collections: vec![],
+                threshold: 0.0,
+                dry_run: false,
+            });
+            Context::default()
+                .with_auth(auth)
+                .into_metadata(request.metadata_mut());
+            request
+        };
+
+        let result = rpc.compact(compact(Authorization::User(5))).await;
+        assert_eq!(result.err().unwrap().code(), tonic::Code::Unauthenticated);
+
+        let response = rpc
+            .compact(compact(Authorization::Owner))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.collections.len(), 1);
+        assert_eq!(response.collections[0].name, "test");
+        assert!(response.collections[0].dead > 0);
+        assert!(response.reclaimed > 0);
+        assert_eq!(
+            collection.get(key).unwrap(),
+            Some(b"second version".to_vec())
+        );
+
+        // 0-db namespaces are only compacted offline, a dry run still reports their usage
+        let zdb = ZdbCompactor::new(vec![(MemoryCollections::new(), DeadBytes::default())]);
+        let rpc = AdminService::new(zdb, dedup_namespaces().await);
+        let result = rpc.compact(compact(Authorization::Owner)).await;
+        assert_eq!(
+            result.err().unwrap().code(),
+            tonic::Code::FailedPrecondition
+        );
+
+        let mut request = compact(Authorization::Owner);
+        request.get_mut().dry_run = true;
+        assert!(rpc.compact(request).await.is_ok());
+    }
+
+    async fn dedup_namespaces() -> impl crate::storage::dedup::StatsSource {
+        use crate::storage::dedup::DedupStorage;
+        use crate::storage::memory::MemoryStorage;
+        use crate::storage::namespaces::Namespaces;
+
+        let collections = |_: &str| -> Result<_, crate::storage::Error> {
+            Ok(DedupStorage::new(MemoryStorage::new()).with_table(MemoryStorage::new()))
+        };
+        let namespaces = Namespaces::new(collections).await.unwrap();
+        let (_, storage) = namespaces.collection("files").await.unwrap();
+        let content = vec![7; crate::storage::dedup::MIN_SIZE];
+        for _ in 0..3 {
+            crate::storage::AsyncStorage::set(&storage, None, &content)
+                .await
+                .unwrap();
+        }
+
+        namespaces
+    }
+
+    #[tokio::test]
+    async fn rpc_admin_stats() {
+        use super::generated::v2::admin_server::Admin;
+        use super::AdminService;
+        use crate::storage::compaction::Unsupported;
+
+        let rpc = AdminService::new(Unsupported("test"), dedup_namespaces().await);
+        let stats = |auth: Authorization, collections: Vec<String>| {
+            let mut request = Request::new(StatsRequest { collections });
+            Context::default()
+                .with_auth(auth)
+                .into_metadata(request.metadata_mut());
+            request
+        };
+
+        let result = rpc.stats(stats(Authorization::User(5), vec![])).await;
+        assert_eq!(result.err().unwrap().code(), tonic::Code::Unauthenticated);
+
+        let response = rpc
+            .stats(stats(Authorization::Owner, vec![]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.collections.len(), 1);
+        let files = &response.collections[0];
+        assert_eq!(files.name, "files");
+        assert_eq!(files.blobs, 1);
+        assert_eq!(files.references, 3);
+        assert_eq!(files.logical, 3 * crate::storage::dedup::MIN_SIZE as u64);
+        assert_eq!(files.saved, 2 * crate::storage::dedup::MIN_SIZE as u64);
+        assert_eq!(response.saved, files.saved);
+
+        let result = rpc
+            .stats(stats(Authorization::Owner, vec!["missing".into()]))
+            .await;
+        assert!(result.is_err());
+    }
 }
@@ -0,0 +1,321 @@
+//! Version 1 of the grpc api. It only supports 32 bit keys and is kept for
+//! older clients. Every call is converted to its v2 equivalent and handled
+//! by the v2 implementation. Keys that do not fit in 32 bits are reported
+//! with an `OutOfRange` status, an object which is set with such a key is
+//! deleted again.
+
+use super::generated::acl_server::Acl as AclV1;
+use super::generated::bcdb_server::Bcdb as BcdbV1;
+use super::generated::v2;
+use super::generated::v2::acl_server::Acl as AclV2;
+use super::generated::v2::bcdb_server::Bcdb as BcdbV2;
+use super::generated::*;
+use super::{AclService, BcdbService};
+use crate::auth::MetadataMapExt;
+use crate::database::Database;
+use crate::storage::AsyncStorage as ObjectStorage;
+use std::convert::TryFrom;
+use tokio::sync::mpsc;
+use tonic::{Request, Response, Status};
+
+pub use super::generated::acl_server::AclServer;
+pub use super::generated::bcdb_server::BcdbServer;
+
+/// forward builds a v2 request out of a v1 request. The request metadata,
+/// which carries the call context, is preserved.
+fn forward<T, U, F>(request: Request<T>, f: F) -> Request<U>
+where
+    F: FnOnce(T) -> U,
+{
+    let metadata = request.metadata().clone();
+    let mut forwarded = Request::new(f(request.into_inner()));
+    *forwarded.metadata_mut() = metadata;
+    forwarded
+}
+
+/// narrow converts a key to a v1 key.
+fn narrow(key: u64) -> Result<u32, Status> {
+    u32::try_from(key).map_err(|_| {
+        Status::out_of_range(format!(
+            "key '{}' does not fit in 32 bits, use the v2 api",
+            key
+        ))
+    })
+}
+
+#[tonic::async_trait]
+impl<D> BcdbV1 for BcdbService<D>
+where
+    D: Database + Clone,
+{
+    async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetResponse>, Status> {
+        let ctx = request.metadata().context();
+        let collection = match request.get_ref().metadata {
+            Some(ref metadata) => metadata.collection.clone(),
+            None => String::default(),
+        };
+
+        let response = BcdbV2::set(self, request).await?.into_inner();
+        match narrow(response.id) {
+            Ok(id) => Ok(Response::new(SetResponse { id })),
+            Err(status) => {
+                // the key of the object can't be returned, so the object is not kept
+                let mut db = self.db.clone();
+                if let Err(err) = db.delete(&ctx, response.id, &collection).await {
+                    error!("failed to delete object '{}': {}", response.id, err);
+                }
+
+                Err(status)
+            }
+        }
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let request = forward(request, |r| v2::GetRequest {
+            id: r.id.into(),
+            collection: r.collection,
+            version: 0,
+        });
+
+        BcdbV2::get(self, request).await
+    }
+
+    async fn head(&self, request: Request<GetRequest>) -> Result<Response<HeadResponse>, Status> {
+        let request = forward(request, |r| v2::GetRequest {
+            id: r.id.into(),
+            collection: r.collection,
+            version: 0,
+        });
+
+        BcdbV2::head(self, request).await
+    }
+
+    async fn fetch(&self, request: Request<FetchRequest>) -> Result<Response<GetResponse>, Status> {
+        let request = forward(request, |r| v2::FetchRequest { id: r.id.into() });
+
+        BcdbV2::fetch(self, request).await
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let request = forward(request, |r| v2::DeleteRequest {
+            id: r.id.into(),
+            collection: r.collection,
+        });
+
+        BcdbV2::delete(self, request).await
+    }
+
+    async fn update(
+        &self,
+        request: Request<UpdateRequest>,
+    ) -> Result<Response<UpdateResponse>, Status> {
+        let request = forward(request, |r| v2::UpdateRequest {
+            id: r.id.into(),
+            metadata: r.metadata,
+            data: r
+                .data
+                .map(|d| v2::update_request::UpdateData { data: d.data }),
+        });
+
+        BcdbV2::update(self, request).await
+    }
+
+    type ListStream = mpsc::Receiver<Result<ListResponse, Status>>;
+
+    async fn list(
+        &self,
+        request: Request<QueryRequest>,
+    ) -> Result<Response<Self::ListStream>, Status> {
+        let mut results = BcdbV2::list(self, request).await?.into_inner();
+
+        let (mut tx, rx) = mpsc::channel(10);
+        tokio::spawn(async move {
+            while let Some(result) = results.recv().await {
+                let result = result.and_then(|r| {
+                    Ok(ListResponse {
+                        id: narrow(r.id)?,
+                        token: r.token,
+                    })
+                });
+                if tx.send(result).await.is_err() {
+                    debug!("failed to send result, broken stream");
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+
+    type FindStream = mpsc::Receiver<Result<FindResponse, Status>>;
+
+    async fn find(
+        &self,
+        request: Request<QueryRequest>,
+    ) -> Result<Response<Self::FindStream>, Status> {
+        let mut results = BcdbV2::find(self, request).await?.into_inner();
+
+        let (mut tx, rx) = mpsc::channel(10);
+        tokio::spawn(async move {
+            while let Some(result) = results.recv().await {
+                let result = result.and_then(|r| {
+                    Ok(FindResponse {
+                        id: narrow(r.id)?,
+                        metadata: r.metadata,
+                        token: r.token,
+                    })
+                });
+                if tx.send(result).await.is_err() {
+                    debug!("failed to send result, broken stream");
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+}
+
+#[tonic::async_trait]
+impl<S> AclV1 for AclService<S>
+where
+    S: ObjectStorage,
+{
+    async fn get(
+        &self,
+        request: Request<AclGetRequest>,
+    ) -> Result<Response<AclGetResponse>, Status> {
+        let request = forward(request, |r| v2::AclGetRequest { key: r.key.into() });
+
+        AclV2::get(self, request).await
+    }
+
+    async fn create(
+        &self,
+        request: Request<AclCreateRequest>,
+    ) -> Result<Response<AclCreateResponse>, Status> {
+        let response = AclV2::create(self, request).await?.into_inner();
+
+        Ok(Response::new(AclCreateResponse {
+            key: narrow(response.key)?,
+        }))
+    }
+
+    type ListStream = mpsc::Receiver<Result<AclListResponse, Status>>;
+
+    async fn list(
+        &self,
+        request: Request<AclListRequest>,
+    ) -> Result<Response<Self::ListStream>, Status> {
+        let mut results = AclV2::list(self, request).await?.into_inner();
+
+        let (mut tx, rx) = mpsc::channel(10);
+        tokio::spawn(async move {
+            while let Some(result) = results.recv().await {
+                let result = result.and_then(|r| {
+                    Ok(AclListResponse {
+                        key: narrow(r.key)?,
+                        acl: r.acl,
+                    })
+                });
+                if tx.send(result).await.is_err() {
+                    debug!("failed to send result, broken stream");
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+
+    async fn set(
+        &self,
+        request: Request<AclSetRequest>,
+    ) -> Result<Response<AclSetResponse>, Status> {
+        let request = forward(request, |r| v2::AclSetRequest {
+            key: r.key.into(),
+            perm: r.perm,
+        });
+
+        AclV2::set(self, request).await
+    }
+
+    async fn grant(
+        &self,
+        request: Request<AclUsersRequest>,
+    ) -> Result<Response<AclUsersResponse>, Status> {
+        let request = forward(request, |r| v2::AclUsersRequest {
+            key: r.key.into(),
+            users: r.users,
+        });
+
+        AclV2::grant(self, request).await
+    }
+
+    async fn revoke(
+        &self,
+        request: Request<AclUsersRequest>,
+    ) -> Result<Response<AclUsersResponse>, Status> {
+        let request = forward(request, |r| v2::AclUsersRequest {
+            key: r.key.into(),
+            users: r.users,
+        });
+
+        AclV2::revoke(self, request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::data::database_tests::get_in_memory_db;
+    use crate::database::{Authorization, Context};
+    use std::collections::HashMap;
+
+    #[test]
+    fn narrow_key() {
+        assert_eq!(narrow(10).unwrap(), 10);
+        assert_eq!(narrow(u32::MAX as u64).unwrap(), u32::MAX);
+        let status = narrow(u32::MAX as u64 + 1).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::OutOfRange);
+    }
+
+    #[tokio::test]
+    async fn v1_set_fetch() {
+        let rpc = BcdbService::new(get_in_memory_db().await);
+        let mut tags = HashMap::default();
+        tags.insert("tag".into(), "value".into());
+
+        let data: Vec<u8> = "hello world".into();
+        let mut request = Request::new(SetRequest {
+            data: data.clone(),
+            metadata: Some(Metadata {
+                acl: None,
+                collection: "test".into(),
+                tags: tags,
+                typed_tags: vec![],
+            }),
+        });
+
+        Context::default()
+            .with_auth(Authorization::Owner)
+            .into_metadata(request.metadata_mut());
+
+        let id = BcdbV1::set(&rpc, request).await.unwrap().into_inner().id;
+
+        let mut request = Request::new(FetchRequest { id: id });
+
+        Context::default()
+            .with_auth(Authorization::Owner)
+            .into_metadata(request.metadata_mut());
+
+        let object = BcdbV1::fetch(&rpc, request).await.unwrap().into_inner();
+        assert_eq!(object.data, data);
+        let metadata = object.metadata.unwrap();
+        assert_eq!(metadata.collection, "test");
+        assert_eq!(metadata.tags.get("tag").unwrap(), "value");
+    }
+}
@@ -1,14 +1,30 @@
+pub mod blocking;
+pub mod chunked;
+pub mod compaction;
+pub mod compressed;
+pub mod dedup;
 pub mod encrypted;
+pub mod erasure;
+pub mod file;
+pub mod keyring;
+pub mod namespaces;
+pub mod replicated;
 pub mod zdb;
 
+#[cfg(test)]
+pub mod faulty;
 #[cfg(test)]
 pub mod memory;
 
+use async_trait::async_trait;
+use futures::future;
+use futures::stream::{BoxStream, StreamExt};
 use std::cmp::{Ord, Ordering, PartialEq, PartialOrd};
 use std::{fmt, io};
 
-/// Key as they are expected by the Storage interface. For now, the key is expect to be 4 bytes
-pub type Key = u32;
+/// Key as they are expected by the Storage interface. Keys are 8 bytes wide, storage
+/// implementations which use narrower keys internally must reject keys they can't represent.
+pub type Key = u64;
 
 /// Iteration record
 #[derive(Eq)]
@@ -46,10 +62,160 @@ pub trait Storage: Clone {
     fn delete(&self, key: Key) -> Result<(), Error>;
     /// Get data which has been set previously.
     fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error>;
-    /// Get an iterator over all keys in a collection
-    fn keys(&self) -> Result<Box<dyn Iterator<Item = Record> + Send>, Error>;
+    /// Get an iterator over all keys in a collection. If reading the keys fails, the error is
+    /// the last item of the iterator.
+    fn keys(&self) -> Result<KeyIter, Error>;
     /// Get an iterator over all keys in a collection, in reverse order
-    fn rev(&self) -> Result<Box<dyn Iterator<Item = Record> + Send>, Error>;
+    fn rev(&self) -> Result<KeyIter, Error>;
+
+    /// Get an iterator over the keys which follow the given key, so a scan which failed can be
+    /// resumed after the last key it returned. Nothing is returned if the key does not exist.
+    ///
+    /// The default implementation skips over the keys up to the given one, implementations
+    /// which can start a scan at a key should override it.
+    fn keys_from(&self, key: Key) -> Result<KeyIter, Error> {
+        let mut found = false;
+        Ok(Box::new(self.keys()?.skip_while(move |record| {
+            skip_through(&mut found, key, record)
+        })))
+    }
+
+    /// Get an iterator over the keys which precede the given key, in reverse order, see
+    /// `keys_from`.
+    fn rev_from(&self, key: Key) -> Result<KeyIter, Error> {
+        let mut found = false;
+        Ok(Box::new(self.rev()?.skip_while(move |record| {
+            skip_through(&mut found, key, record)
+        })))
+    }
+
+    /// Set multiple records at once, returning their keys in the order of the records. As with
+    /// `set`, a record with a key replaces the data previously attached to that key. If an error
+    /// is returned, some of the records might have been written.
+    ///
+    /// The default implementation sets the records one by one, implementations which can do
+    /// better (for example by pipelining the requests) should override it.
+    fn set_many(&self, records: &[(Option<Key>, &[u8])]) -> Result<Vec<Key>, Error> {
+        records
+            .iter()
+            .map(|(key, data)| self.set(*key, data))
+            .collect()
+    }
+
+    /// Delete multiple records at once. If an error is returned, some of the records might have
+    /// been deleted.
+    fn delete_many(&self, keys: &[Key]) -> Result<(), Error> {
+        for key in keys {
+            self.delete(*key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get multiple records at once, the data is returned in the order of the keys.
+    fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        keys.iter().map(|key| self.get(*key)).collect()
+    }
+
+    /// Get the size of the data attached to a key, or None if the key does not exist.
+    ///
+    /// The default implementation reads the data, implementations which can look the size up
+    /// without reading the data (for example in their index) should override it.
+    fn size(&self, key: Key) -> Result<Option<u64>, Error> {
+        Ok(self.get(key)?.map(|data| data.len() as u64))
+    }
+}
+
+/// An iterator over the keys of a collection
+pub type KeyIter = Box<dyn Iterator<Item = Result<Record, Error>> + Send>;
+
+/// A stream over the keys of a collection
+pub type Keys = BoxStream<'static, Result<Record, Error>>;
+
+/// The asynchronous version of `Storage`, for storage implementations which can serve requests
+/// without blocking a thread. Blocking storage implementations can be used through a
+/// `blocking::BlockingStorage`.
+#[async_trait]
+pub trait AsyncStorage: Clone + Send + Sync + 'static {
+    /// Set some data, returning a generated key which can later be used to retrieve the data
+    /// The caller can optionally provide a previously returned key. If such a key is provided,
+    /// the data previously attached to this key will be replaced by the new data.
+    async fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error>;
+    /// Delete data from stroage
+    async fn delete(&self, key: Key) -> Result<(), Error>;
+    /// Get data which has been set previously.
+    async fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error>;
+    /// Get a stream over all keys in a collection
+    async fn keys(&self) -> Result<Keys, Error>;
+    /// Get a stream over all keys in a collection, in reverse order
+    async fn rev(&self) -> Result<Keys, Error>;
+
+    /// Get a stream over the keys which follow the given key, see `Storage::keys_from`.
+    async fn keys_from(&self, key: Key) -> Result<Keys, Error> {
+        let mut found = false;
+        Ok(self
+            .keys()
+            .await?
+            .skip_while(move |record| future::ready(skip_through(&mut found, key, record)))
+            .boxed())
+    }
+
+    /// Get a stream over the keys which precede the given key, in reverse order, see
+    /// `Storage::keys_from`.
+    async fn rev_from(&self, key: Key) -> Result<Keys, Error> {
+        let mut found = false;
+        Ok(self
+            .rev()
+            .await?
+            .skip_while(move |record| future::ready(skip_through(&mut found, key, record)))
+            .boxed())
+    }
+
+    /// Set multiple records at once, see `Storage::set_many`.
+    async fn set_many(&self, records: &[(Option<Key>, &[u8])]) -> Result<Vec<Key>, Error> {
+        let mut keys = Vec::with_capacity(records.len());
+        for (key, data) in records {
+            keys.push(self.set(*key, data).await?);
+        }
+
+        Ok(keys)
+    }
+
+    /// Delete multiple records at once, see `Storage::delete_many`.
+    async fn delete_many(&self, keys: &[Key]) -> Result<(), Error> {
+        for key in keys {
+            self.delete(*key).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get multiple records at once, see `Storage::get_many`.
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        let mut records = Vec::with_capacity(keys.len());
+        for key in keys {
+            records.push(self.get(*key).await?);
+        }
+
+        Ok(records)
+    }
+
+    /// Get the size of the data attached to a key, see `Storage::size`.
+    async fn size(&self, key: Key) -> Result<Option<u64>, Error> {
+        Ok(self.get(key).await?.map(|data| data.len() as u64))
+    }
+}
+
+/// Predicate to skip the records of a scan up to and including the record with the given key.
+/// Errors are never skipped.
+fn skip_through(found: &mut bool, key: Key, record: &Result<Record, Error>) -> bool {
+    match record {
+        Ok(record) if !*found => {
+            *found = record.key == key;
+            true
+        }
+        _ => false,
+    }
 }
 
 #[derive(Debug)]
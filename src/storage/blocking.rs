@@ -0,0 +1,211 @@
+//! An adapter which serves a blocking `Storage` as an `AsyncStorage`. Every call to the backend
+//! runs on the blocking thread pool of the runtime, so backends like the embedded 0-db or the
+//! file storage never block the executor. Scans hold a blocking thread per batch of keys, not for
+//! the whole scan, so the replicated and erasure coded storages, which are served through this
+//! adapter as well, can't exhaust the blocking thread pool with slow consumers of long scans.
+
+use super::{AsyncStorage, Error, Key, KeyIter, Keys, Storage};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use tokio::task::spawn_blocking;
+
+/// Number of keys that are read from the backend at once while streaming the keys of a collection
+const KEYS_BATCH: usize = 100;
+
+#[derive(Clone)]
+pub struct BlockingStorage<S> {
+    backend: S,
+}
+
+impl<S> BlockingStorage<S>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    /// Serve the given blocking storage as an asynchronous storage.
+    pub fn new(backend: S) -> Self {
+        BlockingStorage { backend: backend }
+    }
+
+    /// Run an operation on the backend, on the blocking thread pool.
+    async fn run<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(S) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let backend = self.backend.clone();
+        match spawn_blocking(move || f(backend)).await {
+            Ok(result) => result,
+            Err(err) => {
+                error!("failed to run blocking storage task: {}", err);
+                Err(Error::Other)
+            }
+        }
+    }
+
+    /// Stream the records of a key iterator. The iterator is driven on the blocking thread pool,
+    /// one batch of records at a time, so a blocking thread is only used while a batch is read,
+    /// and not while the stream waits for its consumer.
+    async fn stream<F>(&self, f: F) -> Result<Keys, Error>
+    where
+        F: FnOnce(S) -> Result<KeyIter, Error> + Send + 'static,
+    {
+        let records = self.run(f).await?;
+        let batches = stream::unfold(Some(records), |records| async move {
+            let mut records = records?;
+            let result = spawn_blocking(move || {
+                let batch: Vec<_> = records.by_ref().take(KEYS_BATCH).collect();
+                (records, batch)
+            })
+            .await;
+
+            match result {
+                Ok((_, batch)) if batch.is_empty() => None,
+                Ok((records, batch)) => {
+                    // a short batch means the iterator is exhausted
+                    let next = match batch.len() {
+                        KEYS_BATCH => Some(records),
+                        _ => None,
+                    };
+                    Some((stream::iter(batch), next))
+                }
+                Err(err) => {
+                    error!("failed to run blocking storage task: {}", err);
+                    Some((stream::iter(vec![Err(Error::Other)]), None))
+                }
+            }
+        });
+
+        Ok(batches.flatten().boxed())
+    }
+}
+
+#[async_trait]
+impl<S> AsyncStorage for BlockingStorage<S>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    async fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+        let data = data.to_vec();
+        self.run(move |backend| backend.set(key, &data)).await
+    }
+
+    async fn delete(&self, key: Key) -> Result<(), Error> {
+        self.run(move |backend| backend.delete(key)).await
+    }
+
+    async fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+        self.run(move |backend| backend.get(key)).await
+    }
+
+    async fn keys(&self) -> Result<Keys, Error> {
+        self.stream(|backend| backend.keys()).await
+    }
+
+    async fn rev(&self) -> Result<Keys, Error> {
+        self.stream(|backend| backend.rev()).await
+    }
+
+    async fn keys_from(&self, key: Key) -> Result<Keys, Error> {
+        self.stream(move |backend| backend.keys_from(key)).await
+    }
+
+    async fn rev_from(&self, key: Key) -> Result<Keys, Error> {
+        self.stream(move |backend| backend.rev_from(key)).await
+    }
+
+    async fn set_many(&self, records: &[(Option<Key>, &[u8])]) -> Result<Vec<Key>, Error> {
+        let records: Vec<(Option<Key>, Vec<u8>)> = records
+            .iter()
+            .map(|(key, data)| (*key, data.to_vec()))
+            .collect();
+
+        self.run(move |backend| {
+            let records: Vec<(Option<Key>, &[u8])> = records
+                .iter()
+                .map(|(key, data)| (*key, data.as_slice()))
+                .collect();
+            backend.set_many(&records)
+        })
+        .await
+    }
+
+    async fn delete_many(&self, keys: &[Key]) -> Result<(), Error> {
+        let keys = keys.to_vec();
+        self.run(move |backend| backend.delete_many(&keys)).await
+    }
+
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        let keys = keys.to_vec();
+        self.run(move |backend| backend.get_many(&keys)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    #[tokio::test]
+    async fn blocking_storage() {
+        let storage = BlockingStorage::new(MemoryStorage::new());
+
+        let key1 = storage.set(None, b"first").await.unwrap();
+        let key2 = storage.set(None, b"second").await.unwrap();
+        assert_eq!(storage.get(key1).await.unwrap(), Some(b"first".to_vec()));
+
+        let keys = storage
+            .set_many(&[(Some(key1), &b"updated"[..]), (None, &b"third"[..])])
+            .await
+            .unwrap();
+        assert_eq!(keys[0], key1);
+        assert_eq!(
+            storage.get_many(&[key1, key2, keys[1]]).await.unwrap(),
+            vec![
+                Some(b"updated".to_vec()),
+                Some(b"second".to_vec()),
+                Some(b"third".to_vec())
+            ]
+        );
+
+        storage.delete(key2).await.unwrap();
+        let mut found: Vec<Key> = storage
+            .keys()
+            .await
+            .unwrap()
+            .map(|record| record.unwrap().key)
+            .collect()
+            .await;
+        found.sort();
+        assert_eq!(found, vec![key1, keys[1]]);
+    }
+
+    #[tokio::test]
+    async fn blocking_storage_keys() {
+        let storage = BlockingStorage::new(MemoryStorage::new());
+        let count = KEYS_BATCH * 2 + 10;
+        for _ in 0..count {
+            storage.set(None, b"record").await.unwrap();
+        }
+
+        // keys are streamed over multiple batches
+        let keys: Vec<Key> = storage
+            .keys()
+            .await
+            .unwrap()
+            .map(|record| record.unwrap().key)
+            .collect()
+            .await;
+        assert_eq!(keys.len(), count);
+
+        // a stream can be dropped before it is exhausted
+        let first: Vec<Key> = storage
+            .rev()
+            .await
+            .unwrap()
+            .take(5)
+            .map(|record| record.unwrap().key)
+            .collect()
+            .await;
+        assert_eq!(first.len(), 5);
+    }
+}
@@ -0,0 +1,452 @@
+//! A storage wrapper which splits large objects in chunks. Backends like 0-db limit the size of
+//! a single record, so an object larger than the chunk size is written as several chunk records,
+//! followed by a manifest record which lists the keys of the chunks in order. The key of the
+//! object is the key of its manifest.
+//!
+//! Objects which fit in a single chunk are written to the backend as is, so existing data can be
+//! read without migration. To tell a manifest from a plain record, manifests start with a magic
+//! prefix. A small object which happens to start with the same prefix is written as a manifest
+//! with a single chunk, so a plain record never starts with the magic prefix.
+//!
+//! Since chunks are regular records in the backend, iterating over the keys of a chunked storage
+//! yields the keys of the chunks as well.
+
+use super::{AsyncStorage, Error, Key, Keys};
+use async_trait::async_trait;
+use std::convert::TryInto;
+
+/// Default maximum size of a single chunk, 4MB
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Magic prefix of a manifest record
+const MAGIC: &[u8; 8] = b"bcdb:chk";
+
+/// Version of the manifest format
+const VERSION: u8 = 1;
+
+/// Size of the manifest header: magic (8), version (1), size (8), chunk count (4)
+const MANIFEST_HEADER_SIZE: usize = 21;
+
+#[derive(Clone)]
+pub struct ChunkedStorage<S> {
+    chunk_size: usize,
+    backend: S,
+}
+
+/// Manifest of a chunked object
+#[derive(Debug, PartialEq, Default)]
+pub struct Manifest {
+    /// logical size of the object
+    pub size: u64,
+    /// keys of the chunks, in order
+    pub chunks: Vec<Key>,
+}
+
+/// Content of an object as it is stored in the backend
+#[derive(Debug, PartialEq)]
+pub enum Content {
+    /// the object is small enough to be stored in a single record
+    Inline(Vec<u8>),
+    /// the object is stored in chunks
+    Chunked(Manifest),
+}
+
+impl<S> ChunkedStorage<S>
+where
+    S: AsyncStorage,
+{
+    /// Create a new chunked storage on top of the given storage, with the default chunk size.
+    pub fn new(backend: S) -> Self {
+        ChunkedStorage {
+            chunk_size: CHUNK_SIZE,
+            backend: backend,
+        }
+    }
+
+    /// Set the maximum size of a single chunk.
+    ///
+    /// # panics
+    ///
+    /// This function will panic if the chunk size is 0.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0);
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Get the maximum size of a single chunk.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Get the manifest of the object with the given key. Returns `None` if the object does not
+    /// exist, or if it is not chunked.
+    pub async fn manifest(&self, key: Key) -> Result<Option<Manifest>, Error> {
+        match self.open(key).await? {
+            Some(Content::Chunked(manifest)) => Ok(Some(manifest)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Get the content of the object with the given key, without reading its chunks. This allows
+    /// reading a large object one chunk at a time with `get_chunk`.
+    pub async fn open(&self, key: Key) -> Result<Option<Content>, Error> {
+        let data = match self.backend.get(key).await? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        match Manifest::decode(&data)? {
+            Some(manifest) => Ok(Some(Content::Chunked(manifest))),
+            None => Ok(Some(Content::Inline(data))),
+        }
+    }
+
+    /// Get a single chunk of an object.
+    pub async fn get_chunk(&self, key: Key) -> Result<Vec<u8>, Error> {
+        match self.backend.get(key).await? {
+            Some(data) => Ok(data),
+            None => Err(Error::Protocol(format!("chunk '{}' is missing", key))),
+        }
+    }
+
+    /// Write a single chunk of an object, and return its key. Chunks are not visible as objects
+    /// until a manifest which refers to them is written with `set_manifest`.
+    pub async fn set_chunk(&self, data: &[u8]) -> Result<Key, Error> {
+        self.backend.set(None, data).await
+    }
+
+    /// Write the manifest of an object, whose chunks were written with `set_chunk`. If a key is
+    /// provided, the object with this key is replaced.
+    pub async fn set_manifest(&self, key: Option<Key>, manifest: &Manifest) -> Result<Key, Error> {
+        let previous = match key {
+            Some(key) => self.manifest(key).await?,
+            None => None,
+        };
+
+        let key = self.backend.set(key, &manifest.encode()).await?;
+        if let Some(previous) = previous {
+            self.delete_chunks(&previous.chunks).await;
+        }
+
+        Ok(key)
+    }
+
+    /// Delete the given chunks. Failures are only logged, since a dangling chunk only wastes
+    /// space.
+    pub async fn delete_chunks(&self, chunks: &[Key]) {
+        for key in chunks {
+            if let Err(err) = self.backend.delete(*key).await {
+                warn!("failed to delete chunk '{}': {}", key, err);
+            }
+        }
+    }
+
+    /// Write all chunks of data, and return the manifest. If writing a chunk fails, the chunks
+    /// which were already written are deleted again.
+    async fn write_chunks(&self, data: &[u8]) -> Result<Manifest, Error> {
+        let mut chunks = Vec::with_capacity(data.len() / self.chunk_size + 1);
+        for chunk in data.chunks(self.chunk_size) {
+            match self.backend.set(None, chunk).await {
+                Ok(key) => chunks.push(key),
+                Err(err) => {
+                    self.delete_chunks(&chunks).await;
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(Manifest {
+            size: data.len() as u64,
+            chunks: chunks,
+        })
+    }
+
+    /// Read all chunks of an object, the chunks are read in a single batch.
+    async fn assemble(&self, key: Key, manifest: Manifest) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(manifest.size as usize);
+        let chunks = self.backend.get_many(&manifest.chunks).await?;
+        for (chunk, part) in manifest.chunks.iter().zip(chunks) {
+            match part {
+                Some(part) => data.extend_from_slice(&part),
+                None => return Err(Error::Protocol(format!("chunk '{}' is missing", chunk))),
+            }
+        }
+
+        if data.len() as u64 != manifest.size {
+            return Err(Error::Protocol(format!(
+                "object '{}' has size {}, expected {}",
+                key,
+                data.len(),
+                manifest.size
+            )));
+        }
+
+        Ok(data)
+    }
+
+    /// Whether data must be written as a manifest
+    fn is_chunked(&self, data: &[u8]) -> bool {
+        data.len() > self.chunk_size || data.starts_with(MAGIC)
+    }
+}
+
+#[async_trait]
+impl<S> AsyncStorage for ChunkedStorage<S>
+where
+    S: AsyncStorage,
+{
+    async fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+        // chunks of the previous version of the object, which must be deleted once the object
+        // is replaced
+        let previous = match key {
+            Some(key) => self.manifest(key).await?,
+            None => None,
+        };
+
+        let key = if self.is_chunked(data) {
+            let manifest = self.write_chunks(data).await?;
+            match self.backend.set(key, &manifest.encode()).await {
+                Ok(key) => key,
+                Err(err) => {
+                    self.delete_chunks(&manifest.chunks).await;
+                    return Err(err);
+                }
+            }
+        } else {
+            self.backend.set(key, data).await?
+        };
+
+        if let Some(previous) = previous {
+            self.delete_chunks(&previous.chunks).await;
+        }
+
+        Ok(key)
+    }
+
+    async fn delete(&self, key: Key) -> Result<(), Error> {
+        let manifest = self.manifest(key).await?;
+        self.backend.delete(key).await?;
+        if let Some(manifest) = manifest {
+            self.delete_chunks(&manifest.chunks).await;
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+        match self.open(key).await? {
+            Some(Content::Chunked(manifest)) => self.assemble(key, manifest).await.map(Some),
+            Some(Content::Inline(data)) => Ok(Some(data)),
+            None => Ok(None),
+        }
+    }
+
+    async fn keys(&self) -> Result<Keys, Error> {
+        self.backend.keys().await
+    }
+
+    async fn rev(&self) -> Result<Keys, Error> {
+        self.backend.rev().await
+    }
+
+    async fn keys_from(&self, key: Key) -> Result<Keys, Error> {
+        self.backend.keys_from(key).await
+    }
+
+    async fn rev_from(&self, key: Key) -> Result<Keys, Error> {
+        self.backend.rev_from(key).await
+    }
+
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        let mut objects = Vec::with_capacity(keys.len());
+        for (key, data) in keys.iter().zip(self.backend.get_many(keys).await?) {
+            let data = match data {
+                Some(data) => data,
+                None => {
+                    objects.push(None);
+                    continue;
+                }
+            };
+
+            match Manifest::decode(&data)? {
+                Some(manifest) => objects.push(Some(self.assemble(*key, manifest).await?)),
+                None => objects.push(Some(data)),
+            }
+        }
+
+        Ok(objects)
+    }
+}
+
+impl Manifest {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MANIFEST_HEADER_SIZE + 8 * self.chunks.len());
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        buf.extend_from_slice(&(self.chunks.len() as u32).to_le_bytes());
+        for key in &self.chunks {
+            buf.extend_from_slice(&key.to_le_bytes());
+        }
+
+        buf
+    }
+
+    /// Decode a manifest. Returns `None` if the data is not a manifest.
+    fn decode(data: &[u8]) -> Result<Option<Manifest>, Error> {
+        if !data.starts_with(MAGIC) {
+            return Ok(None);
+        }
+
+        if data.len() < MANIFEST_HEADER_SIZE {
+            return Err(Error::Protocol("manifest is truncated".into()));
+        }
+
+        if data[8] != VERSION {
+            return Err(Error::Protocol(format!(
+                "unsupported manifest version {}",
+                data[8]
+            )));
+        }
+
+        let size = u64::from_le_bytes(data[9..17].try_into().unwrap());
+        let count = u32::from_le_bytes(data[17..21].try_into().unwrap()) as usize;
+        let keys = &data[MANIFEST_HEADER_SIZE..];
+        if keys.len() != count * 8 {
+            return Err(Error::Protocol("manifest is truncated".into()));
+        }
+
+        let chunks = keys
+            .chunks(8)
+            .map(|key| Key::from_le_bytes(key.try_into().unwrap()))
+            .collect();
+
+        Ok(Some(Manifest { size, chunks }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+    use futures::StreamExt;
+
+    async fn count(storage: &MemoryStorage) -> usize {
+        storage.keys().await.unwrap().count().await
+    }
+
+    #[tokio::test]
+    async fn chunked_storage() {
+        let backend = MemoryStorage::new();
+        let storage = ChunkedStorage::new(backend.clone()).with_chunk_size(4);
+
+        // small objects are written as is
+        let small = storage.set(None, b"abc").await.unwrap();
+        assert_eq!(backend.get(small).await.unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(storage.manifest(small).await.unwrap(), None);
+
+        let data: Vec<u8> = (0..10).collect();
+        let large = storage.set(None, &data).await.unwrap();
+        assert_eq!(storage.get(large).await.unwrap(), Some(data.clone()));
+
+        let manifest = storage.manifest(large).await.unwrap().unwrap();
+        assert_eq!(manifest.size, 10);
+        assert_eq!(manifest.chunks.len(), 3);
+        assert_eq!(count(&backend).await, 5);
+
+        // data which looks like a manifest is chunked as well
+        let tricky = storage.set(None, MAGIC).await.unwrap();
+        assert_eq!(storage.get(tricky).await.unwrap(), Some(MAGIC.to_vec()));
+        assert_eq!(count(&backend).await, 8);
+
+        assert_eq!(storage.get(100).await.unwrap(), None);
+
+        assert_eq!(
+            storage
+                .get_many(&[large, 100, small, tricky])
+                .await
+                .unwrap(),
+            vec![
+                Some(data),
+                None,
+                Some(b"abc".to_vec()),
+                Some(MAGIC.to_vec())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn chunked_storage_update() {
+        let backend = MemoryStorage::new();
+        let storage = ChunkedStorage::new(backend.clone()).with_chunk_size(4);
+
+        let data: Vec<u8> = (0..10).collect();
+        let key = storage.set(None, &data).await.unwrap();
+        assert_eq!(count(&backend).await, 4);
+
+        // replacing a large object with another one drops the old chunks
+        let data: Vec<u8> = (0..6).collect();
+        assert_eq!(storage.set(Some(key), &data).await.unwrap(), key);
+        assert_eq!(storage.get(key).await.unwrap(), Some(data));
+        assert_eq!(count(&backend).await, 3);
+
+        // replacing it with a small object drops all chunks
+        assert_eq!(storage.set(Some(key), b"abc").await.unwrap(), key);
+        assert_eq!(storage.get(key).await.unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(count(&backend).await, 1);
+    }
+
+    #[tokio::test]
+    async fn chunked_storage_manual() {
+        let backend = MemoryStorage::new();
+        let storage = ChunkedStorage::new(backend.clone()).with_chunk_size(4);
+
+        let mut manifest = Manifest::default();
+        for chunk in &[b"hell", b"o wo", b"rld!"] {
+            manifest
+                .chunks
+                .push(storage.set_chunk(*chunk).await.unwrap());
+            manifest.size += chunk.len() as u64;
+        }
+
+        let key = storage.set_manifest(None, &manifest).await.unwrap();
+        assert_eq!(
+            storage.get(key).await.unwrap(),
+            Some(b"hello world!".to_vec())
+        );
+
+        let chunks = match storage.open(key).await.unwrap() {
+            Some(Content::Chunked(manifest)) => manifest.chunks,
+            _ => panic!("expected a chunked object"),
+        };
+        assert_eq!(
+            storage.get_chunk(chunks[1]).await.unwrap(),
+            b"o wo".to_vec()
+        );
+
+        let small = storage.set(None, b"abc").await.unwrap();
+        assert_eq!(
+            storage.open(small).await.unwrap(),
+            Some(Content::Inline(b"abc".to_vec()))
+        );
+    }
+
+    #[tokio::test]
+    async fn chunked_storage_delete() {
+        let backend = MemoryStorage::new();
+        let storage = ChunkedStorage::new(backend.clone()).with_chunk_size(4);
+
+        let data: Vec<u8> = (0..10).collect();
+        let key = storage.set(None, &data).await.unwrap();
+        let small = storage.set(None, b"abc").await.unwrap();
+        assert_eq!(count(&backend).await, 5);
+
+        storage.delete(key).await.unwrap();
+        assert_eq!(storage.get(key).await.unwrap(), None);
+        assert_eq!(count(&backend).await, 1);
+
+        storage.delete(small).await.unwrap();
+        assert_eq!(count(&backend).await, 0);
+    }
+}
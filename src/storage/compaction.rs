@@ -0,0 +1,808 @@
+//! Reclaiming the space of deleted and overwritten records. The storage backends are append-only:
+//! an update writes a new version of a record and a delete writes a tombstone, the space of the
+//! old versions is only reclaimed when the collection is compacted.
+//!
+//! The file storage compacts a collection by rewriting its live records to a new segment. 0-db
+//! has no online compaction, a 0-db namespace is compacted by copying its live records into a
+//! fresh namespace, which then replaces it. This can't run while the namespace is used, so 0-db
+//! namespaces are only compacted offline. 0-db does not report the space of old versions of its
+//! records either, so the dead bytes of every namespace are counted by bcdb, see `DeadBytes`.
+
+use super::{AsyncStorage, Error, Key, KeyIter, Keys, Storage};
+use async_trait::async_trait;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Suffix of the name of the namespace a 0-db namespace is copied to while it is compacted
+const COMPACTING: &str = ".compacting";
+/// Data of the last record of a complete copy of a namespace
+const COMPLETE: &[u8] = b"bcdb: compaction complete";
+/// Data of the records which take the keys of deleted records in a copy of a namespace
+const PLACEHOLDER: &[u8] = b"-";
+/// Minimum interval between two saves of the dead byte counters
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Space used by a storage collection, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// space used by the latest version of all records
+    pub live: u64,
+    /// space used by old versions of records, deleted records and tombstones
+    pub dead: u64,
+}
+
+impl Usage {
+    /// The fraction of the used space which is dead
+    pub fn dead_ratio(&self) -> f64 {
+        let total = self.live + self.dead;
+        if total == 0 {
+            return 0.0;
+        }
+
+        self.dead as f64 / total as f64
+    }
+}
+
+/// Outcome of the compaction of a collection
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// name of the storage collection
+    pub collection: String,
+    /// space used by the collection before it was compacted
+    pub usage: Usage,
+    /// number of reclaimed bytes, 0 if the collection was not compacted
+    pub reclaimed: u64,
+}
+
+/// A storage backend whose collections can be compacted. Compacting a collection never changes
+/// the keys or the data of its live records.
+pub trait Compactor: Clone + Send + Sync + 'static {
+    /// Get the names of all collections of the backend
+    fn collections(&self) -> Result<Vec<String>, Error>;
+    /// Get the space used by a collection
+    fn usage(&self, collection: &str) -> Result<Usage, Error>;
+    /// Compact a collection, returns the number of reclaimed bytes
+    fn compact(&self, collection: &str) -> Result<u64, Error>;
+
+    /// Finish or roll back the compactions which were interrupted, must be called before the
+    /// collections are used.
+    fn recover(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Whether collections can be compacted while they are used
+    fn online(&self) -> bool {
+        true
+    }
+}
+
+/// A compactor for storage backends which don't support compaction, all operations fail.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct Unsupported(pub &'static str);
+
+#[cfg(test)]
+impl Compactor for Unsupported {
+    fn collections(&self) -> Result<Vec<String>, Error> {
+        Err(self.error())
+    }
+
+    fn usage(&self, _collection: &str) -> Result<Usage, Error> {
+        Err(self.error())
+    }
+
+    fn compact(&self, _collection: &str) -> Result<u64, Error> {
+        Err(self.error())
+    }
+}
+
+#[cfg(test)]
+impl Unsupported {
+    fn error(&self) -> Error {
+        Error::Protocol(format!(
+            "compaction is not supported by the {} storage",
+            self.0
+        ))
+    }
+}
+
+/// A 0-db whose namespaces can be listed and removed, which is all that is needed to compact its
+/// namespaces. 0-db must run in sequential mode.
+pub trait Namespaced: Clone + Send + Sync + 'static {
+    type Namespace: Storage;
+
+    /// Get the names of all namespaces, except the default namespace
+    fn namespaces(&self) -> Result<Vec<String>, Error>;
+    /// Get a namespace, the namespace is created on first use
+    fn namespace(&self, name: &str) -> Self::Namespace;
+    /// Remove a namespace with all its records, nothing happens if the namespace does not exist
+    fn remove(&self, name: &str) -> Result<(), Error>;
+}
+
+/// Get the name of the namespace which is compacted through the given copy
+pub fn compacted(name: &str) -> Option<&str> {
+    if name.len() > COMPACTING.len() && name.ends_with(COMPACTING) {
+        return Some(&name[..name.len() - COMPACTING.len()]);
+    }
+
+    None
+}
+
+/// Compacts the namespaces of one or more 0-db instances, which hold replicas or shards of the
+/// same collections. A namespace is compacted by copying its live records into a fresh namespace,
+/// and the namespace is then replaced by the copy: it is removed, and the records are copied
+/// back. 0-db assigns keys sequentially, the keys of deleted records are used and deleted again
+/// in the copy, so live records keep their keys, and keys are never reused.
+///
+/// A complete copy ends with a marker record, an interrupted compaction is finished by `recover`
+/// if the copy is complete, and is rolled back otherwise.
+#[derive(Clone)]
+pub struct ZdbCompactor<Z> {
+    instances: Vec<(Z, DeadBytes)>,
+}
+
+impl<Z> ZdbCompactor<Z>
+where
+    Z: Namespaced,
+{
+    /// Compact the namespaces of the given 0-db instances, every instance comes with the counters
+    /// of the dead bytes of its namespaces.
+    pub fn new(instances: Vec<(Z, DeadBytes)>) -> Self {
+        ZdbCompactor {
+            instances: instances,
+        }
+    }
+
+    /// Get the instances which have a namespace with the given name
+    fn instances(&self, name: &str) -> Result<Vec<&(Z, DeadBytes)>, Error> {
+        let mut instances = Vec::with_capacity(self.instances.len());
+        for instance in self.instances.iter() {
+            let (zdb, _) = instance;
+            if zdb.namespaces()?.iter().any(|namespace| namespace == name) {
+                instances.push(instance);
+            }
+        }
+
+        if instances.is_empty() {
+            return Err(Error::Protocol(format!(
+                "collection '{}' does not exist",
+                name
+            )));
+        }
+
+        Ok(instances)
+    }
+}
+
+impl<Z> Compactor for ZdbCompactor<Z>
+where
+    Z: Namespaced,
+{
+    fn collections(&self) -> Result<Vec<String>, Error> {
+        let mut names = BTreeSet::new();
+        for (zdb, _) in self.instances.iter() {
+            for name in zdb.namespaces()? {
+                if compacted(&name).is_none() {
+                    names.insert(name);
+                }
+            }
+        }
+
+        Ok(names.into_iter().collect())
+    }
+
+    fn usage(&self, collection: &str) -> Result<Usage, Error> {
+        let mut usage = Usage::default();
+        for (zdb, dead) in self.instances(collection)? {
+            for record in zdb.namespace(collection).keys()? {
+                usage.live += record?.size.unwrap_or_default() as u64;
+            }
+
+            usage.dead += dead.get(collection);
+        }
+
+        Ok(usage)
+    }
+
+    fn compact(&self, collection: &str) -> Result<u64, Error> {
+        let mut reclaimed = 0;
+        for (zdb, dead) in self.instances(collection)? {
+            let bytes = dead.get(collection);
+            compact_namespace(zdb, collection)?;
+            dead.reset(collection)?;
+            reclaimed += bytes;
+        }
+
+        Ok(reclaimed)
+    }
+
+    fn recover(&self) -> Result<(), Error> {
+        for (zdb, dead) in self.instances.iter() {
+            for name in zdb.namespaces()? {
+                if let Some(original) = compacted(&name) {
+                    recover_namespace(zdb, original)?;
+                    dead.reset(original)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn online(&self) -> bool {
+        false
+    }
+}
+
+/// Compact a namespace of a 0-db, by copying it, and replacing it with the copy
+fn compact_namespace<Z: Namespaced>(zdb: &Z, name: &str) -> Result<(), Error> {
+    let source = zdb.namespace(name);
+    let copy_name = format!("{}{}", name, COMPACTING);
+    zdb.remove(&copy_name)?;
+    let copy = zdb.namespace(&copy_name);
+
+    // the key of the next record of the namespace, all keys before it are used in the copy
+    let end = source.set(None, PLACEHOLDER)?;
+    source.delete(end)?;
+
+    copy_records(&source, &copy, end)?;
+    expect_key(copy.set(None, COMPLETE)?, end)?;
+    replace(zdb, name, &copy_name, end)
+}
+
+/// Finish the compaction of a namespace if its copy is complete, or drop the copy otherwise
+fn recover_namespace<Z: Namespaced>(zdb: &Z, name: &str) -> Result<(), Error> {
+    let copy_name = format!("{}{}", name, COMPACTING);
+    let copy = zdb.namespace(&copy_name);
+    let last = match copy.rev()?.next() {
+        Some(record) => Some(record?.key),
+        None => None,
+    };
+
+    match last {
+        Some(key) if copy.get(key)? == Some(COMPLETE.to_vec()) => {
+            info!("finishing the interrupted compaction of namespace {}", name);
+            replace(zdb, name, &copy_name, key)
+        }
+        _ => {
+            info!(
+                "rolling back the interrupted compaction of namespace {}",
+                name
+            );
+            zdb.remove(&copy_name)
+        }
+    }
+}
+
+/// Replace a namespace with its complete copy, whose marker record has the key `end`
+fn replace<Z: Namespaced>(zdb: &Z, name: &str, copy_name: &str, end: Key) -> Result<(), Error> {
+    zdb.remove(name)?;
+    copy_records(&zdb.namespace(copy_name), &zdb.namespace(name), end)?;
+    zdb.remove(copy_name)
+}
+
+/// Copy the live records with a key lower than `end` into an empty namespace, with the same keys.
+/// Keys of deleted records are taken by a placeholder which is deleted again, so the next key
+/// which is assigned by the copy is `end`.
+fn copy_records<S: Storage>(source: &S, copy: &S, end: Key) -> Result<(), Error> {
+    let mut next = 0;
+    for record in source.keys()? {
+        let key = record?.key;
+        if key >= end {
+            break;
+        }
+
+        let data = match source.get(key)? {
+            Some(data) => data,
+            None => continue,
+        };
+
+        fill(copy, next, key)?;
+        expect_key(copy.set(None, &data)?, key)?;
+        next = key + 1;
+    }
+
+    fill(copy, next, end)
+}
+
+/// Use and delete the keys from `next` up to `end` in a namespace
+fn fill<S: Storage>(storage: &S, next: Key, end: Key) -> Result<(), Error> {
+    for key in next..end {
+        storage.delete(expect_key(storage.set(None, PLACEHOLDER)?, key)?)?;
+    }
+
+    Ok(())
+}
+
+fn expect_key(assigned: Key, expected: Key) -> Result<Key, Error> {
+    if assigned != expected {
+        return Err(Error::Protocol(format!(
+            "namespace assigned key {} instead of {}, 0-db must run in sequential mode",
+            assigned, expected
+        )));
+    }
+
+    Ok(assigned)
+}
+
+/// Counters of the dead bytes of the namespaces of a 0-db, since 0-db does not report them. An
+/// overwrite or delete of a record through a `Counted` namespace adds the size of the old record
+/// to the counter of the namespace, the old record is read to know its size.
+///
+/// The counters are saved in a state file at most once a second, and when the last handle is
+/// dropped, so the counts of the last second before a crash can be lost. The counters only decide
+/// if a namespace is compacted, so this can only delay a compaction.
+#[derive(Clone)]
+pub struct DeadBytes {
+    inner: Arc<Mutex<Counters>>,
+}
+
+struct Counters {
+    path: Option<PathBuf>,
+    counts: HashMap<String, u64>,
+    saved: Instant,
+    dirty: bool,
+}
+
+impl Default for DeadBytes {
+    /// Counters which are not saved
+    fn default() -> Self {
+        DeadBytes::with_counters(None, HashMap::new())
+    }
+}
+
+impl DeadBytes {
+    /// Load the counters from the given state file, which is created if it does not exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let counts = match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|err| {
+                Error::Protocol(format!(
+                    "invalid dead bytes file '{}': {}",
+                    path.display(),
+                    err
+                ))
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(DeadBytes::with_counters(Some(path.into()), counts))
+    }
+
+    fn with_counters(path: Option<PathBuf>, counts: HashMap<String, u64>) -> Self {
+        DeadBytes {
+            inner: Arc::new(Mutex::new(Counters {
+                path: path,
+                counts: counts,
+                saved: Instant::now(),
+                dirty: false,
+            })),
+        }
+    }
+
+    /// Count the dead bytes of the given namespace
+    pub fn counted<S>(&self, name: &str, storage: S) -> Counted<S> {
+        Counted {
+            storage: storage,
+            name: Arc::new(name.into()),
+            dead: self.clone(),
+        }
+    }
+
+    /// Get the dead bytes of a namespace
+    pub fn get(&self, name: &str) -> u64 {
+        match self.inner.lock() {
+            Ok(counters) => counters.counts.get(name).copied().unwrap_or_default(),
+            Err(_) => 0,
+        }
+    }
+
+    /// Reset the counter of a namespace after it was compacted
+    pub fn reset(&self, name: &str) -> Result<(), Error> {
+        let mut counters = self.inner.lock().map_err(|_| Error::Other)?;
+        counters.counts.remove(name);
+        counters.save()
+    }
+
+    fn add(&self, name: &str, bytes: u64) {
+        let mut counters = match self.inner.lock() {
+            Ok(counters) => counters,
+            Err(_) => return,
+        };
+
+        *counters.counts.entry(name.into()).or_default() += bytes;
+        counters.dirty = true;
+        if counters.saved.elapsed() >= SAVE_INTERVAL {
+            if let Err(err) = counters.save() {
+                error!("failed to save dead bytes: {}", err);
+            }
+        }
+    }
+}
+
+impl Counters {
+    /// Write the counters to the state file, the file is replaced atomically
+    fn save(&mut self) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let data = serde_json::to_vec(&self.counts).map_err(|_| Error::Other)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)?;
+        self.saved = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for Counters {
+    fn drop(&mut self) {
+        if self.dirty {
+            if let Err(err) = self.save() {
+                error!("failed to save dead bytes: {}", err);
+            }
+        }
+    }
+}
+
+/// A namespace whose dead bytes are counted, see `DeadBytes`
+#[derive(Clone)]
+pub struct Counted<S> {
+    storage: S,
+    name: Arc<String>,
+    dead: DeadBytes,
+}
+
+impl<S> Counted<S> {
+    /// Count the sizes of the records which were overwritten or deleted
+    fn count<I>(&self, old: I)
+    where
+        I: IntoIterator<Item = Option<u64>>,
+    {
+        let bytes: u64 = old.into_iter().flatten().sum();
+        if bytes > 0 {
+            self.dead.add(&self.name, bytes);
+        }
+    }
+}
+
+impl<S> Counted<S>
+where
+    S: AsyncStorage,
+{
+    /// Get the sizes of the records with the given keys, see `AsyncStorage::size`
+    async fn sizes(&self, keys: &[Key]) -> Result<Vec<Option<u64>>, Error> {
+        let mut sizes = Vec::with_capacity(keys.len());
+        for key in keys {
+            sizes.push(self.storage.size(*key).await?);
+        }
+
+        Ok(sizes)
+    }
+}
+
+/// Get the keys of the records of a batch which overwrite existing records
+fn overwritten(records: &[(Option<Key>, &[u8])]) -> Vec<Key> {
+    records.iter().filter_map(|(key, _)| *key).collect()
+}
+
+impl<S> Storage for Counted<S>
+where
+    S: Storage,
+{
+    fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+        let old = match key {
+            Some(key) => self.storage.size(key)?,
+            None => None,
+        };
+
+        let key = self.storage.set(key, data)?;
+        self.count(old);
+        Ok(key)
+    }
+
+    fn delete(&self, key: Key) -> Result<(), Error> {
+        let old = self.storage.size(key)?;
+        self.storage.delete(key)?;
+        self.count(old);
+        Ok(())
+    }
+
+    fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+        self.storage.get(key)
+    }
+
+    fn keys(&self) -> Result<KeyIter, Error> {
+        self.storage.keys()
+    }
+
+    fn rev(&self) -> Result<KeyIter, Error> {
+        self.storage.rev()
+    }
+
+    fn keys_from(&self, key: Key) -> Result<KeyIter, Error> {
+        self.storage.keys_from(key)
+    }
+
+    fn rev_from(&self, key: Key) -> Result<KeyIter, Error> {
+        self.storage.rev_from(key)
+    }
+
+    fn set_many(&self, records: &[(Option<Key>, &[u8])]) -> Result<Vec<Key>, Error> {
+        let old = overwritten(records)
+            .into_iter()
+            .map(|key| self.storage.size(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let keys = self.storage.set_many(records)?;
+        self.count(old);
+        Ok(keys)
+    }
+
+    fn delete_many(&self, keys: &[Key]) -> Result<(), Error> {
+        let old = keys
+            .iter()
+            .map(|key| self.storage.size(*key))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.storage.delete_many(keys)?;
+        self.count(old);
+        Ok(())
+    }
+
+    fn size(&self, key: Key) -> Result<Option<u64>, Error> {
+        self.storage.size(key)
+    }
+
+    fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        self.storage.get_many(keys)
+    }
+}
+
+#[async_trait]
+impl<S> AsyncStorage for Counted<S>
+where
+    S: AsyncStorage,
+{
+    async fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+        let old = match key {
+            Some(key) => self.storage.size(key).await?,
+            None => None,
+        };
+
+        let key = self.storage.set(key, data).await?;
+        self.count(old);
+        Ok(key)
+    }
+
+    async fn delete(&self, key: Key) -> Result<(), Error> {
+        let old = self.storage.size(key).await?;
+        self.storage.delete(key).await?;
+        self.count(old);
+        Ok(())
+    }
+
+    async fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+        self.storage.get(key).await
+    }
+
+    async fn keys(&self) -> Result<Keys, Error> {
+        self.storage.keys().await
+    }
+
+    async fn rev(&self) -> Result<Keys, Error> {
+        self.storage.rev().await
+    }
+
+    async fn keys_from(&self, key: Key) -> Result<Keys, Error> {
+        self.storage.keys_from(key).await
+    }
+
+    async fn rev_from(&self, key: Key) -> Result<Keys, Error> {
+        self.storage.rev_from(key).await
+    }
+
+    async fn set_many(&self, records: &[(Option<Key>, &[u8])]) -> Result<Vec<Key>, Error> {
+        let old = self.sizes(&overwritten(records)).await?;
+        let keys = self.storage.set_many(records).await?;
+        self.count(old);
+        Ok(keys)
+    }
+
+    async fn delete_many(&self, keys: &[Key]) -> Result<(), Error> {
+        let old = self.sizes(keys).await?;
+        self.storage.delete_many(keys).await?;
+        self.count(old);
+        Ok(())
+    }
+
+    async fn size(&self, key: Key) -> Result<Option<u64>, Error> {
+        self.storage.size(key).await
+    }
+
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        self.storage.get_many(keys).await
+    }
+}
+
+/// Compact the given collections, or all collections if none are given. A collection is only
+/// compacted if it has dead space, and at least the `threshold` fraction of its space is dead.
+/// With `dry_run`, the usage of the collections is reported without compacting them.
+///
+/// Compacting a collection blocks, and may block writes to the collection while it runs.
+pub fn compact<C: Compactor>(
+    compactor: &C,
+    collections: &[String],
+    threshold: f64,
+    dry_run: bool,
+) -> Result<Vec<Report>, Error> {
+    let collections = if collections.is_empty() {
+        compactor.collections()?
+    } else {
+        collections.to_vec()
+    };
+
+    let mut reports = Vec::with_capacity(collections.len());
+    for collection in collections {
+        let usage = compactor.usage(&collection)?;
+        let reclaimed = if !dry_run && usage.dead > 0 && usage.dead_ratio() >= threshold {
+            let reclaimed = compactor.compact(&collection)?;
+            info!(
+                "compacted collection {}, reclaimed {} bytes",
+                collection, reclaimed
+            );
+            reclaimed
+        } else {
+            0
+        };
+
+        reports.push(Report {
+            collection,
+            usage,
+            reclaimed,
+        });
+    }
+
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compact, copy_records, Compactor, DeadBytes, Namespaced, Unsupported, ZdbCompactor,
+        COMPLETE,
+    };
+    use crate::storage::file::FileStorage;
+    use crate::storage::memory::MemoryCollections;
+    use crate::storage::namespaces::Collections;
+    use crate::storage::Storage;
+
+    #[test]
+    fn compact_file_storage() {
+        const DIR: &str = "/tmp/bcdb-compaction.test";
+        let _ = std::fs::remove_dir_all(DIR);
+        let storage = FileStorage::new(DIR)
+            .expect("failed to create file storage")
+            .with_segment_size(64);
+
+        let collection = storage.collection("test").unwrap();
+        let kept = collection.set(None, b"kept record").unwrap();
+        let updated = collection.set(None, b"first version").unwrap();
+        let deleted = collection.set(None, b"deleted record").unwrap();
+        let clean = storage.collection("clean").unwrap();
+        clean.set(None, b"never changed").unwrap();
+
+        collection.set(Some(updated), b"second version").unwrap();
+        collection.delete(deleted).unwrap();
+
+        let usage = storage.usage("test").unwrap();
+        assert!(usage.dead > 0);
+        assert_eq!(storage.usage("clean").unwrap().dead, 0);
+
+        // a dry run only reports the usage
+        let reports = compact(&storage, &[], 0.0, true).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|report| report.reclaimed == 0));
+
+        let reports = compact(&storage, &["test".into()], 0.1, false).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].usage, usage);
+        assert!(reports[0].reclaimed > 0);
+        assert_eq!(storage.usage("test").unwrap().live, usage.live);
+
+        // live records keep their key and data, deleted keys are not reused
+        assert_eq!(collection.get(kept).unwrap(), Some(b"kept record".to_vec()));
+        assert_eq!(
+            collection.get(updated).unwrap(),
+            Some(b"second version".to_vec())
+        );
+        assert_eq!(collection.get(deleted).unwrap(), None);
+        assert_eq!(collection.set(None, b"new").unwrap(), deleted + 1);
+
+        // and so after the collection is opened again
+        let storage = FileStorage::new(DIR).unwrap();
+        let collection = storage.collection("test").unwrap();
+        assert_eq!(collection.keys().unwrap().count(), 3);
+        assert_eq!(
+            collection.get(updated).unwrap(),
+            Some(b"second version".to_vec())
+        );
+        assert_eq!(collection.get(deleted).unwrap(), None);
+        assert_eq!(collection.set(None, b"newer").unwrap(), deleted + 2);
+
+        assert!(storage.usage("missing").is_err());
+        assert!(compact(&Unsupported("test"), &[], 0.0, true).is_err());
+    }
+
+    #[test]
+    fn compact_zdb_namespaces() {
+        let collections = MemoryCollections::new();
+        let dead = DeadBytes::default();
+        let compactor = ZdbCompactor::new(vec![(collections.clone(), dead.clone())]);
+
+        let namespace = dead.counted("test", collections.collection("test").unwrap());
+        let kept = namespace.set(None, b"kept record").unwrap();
+        let updated = namespace.set(None, b"first version").unwrap();
+        let deleted = namespace.set(None, b"deleted record").unwrap();
+        namespace.set(Some(updated), b"second version").unwrap();
+        namespace.delete(deleted).unwrap();
+        assert_eq!(dead.get("test"), 27);
+        assert_eq!(compactor.usage("test").unwrap().dead, 27);
+        assert!(compactor.usage("missing").is_err());
+
+        let reports = compact(&compactor, &[], 0.0, false).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].reclaimed, 27);
+        assert_eq!(dead.get("test"), 0);
+        assert_eq!(compactor.collections().unwrap(), vec!["test".to_string()]);
+
+        // live records keep their key and data, deleted keys are not reused
+        let namespace = collections.collection("test").unwrap();
+        assert_eq!(namespace.keys().unwrap().count(), 2);
+        assert_eq!(namespace.get(kept).unwrap(), Some(b"kept record".to_vec()));
+        assert_eq!(
+            namespace.get(updated).unwrap(),
+            Some(b"second version".to_vec())
+        );
+        assert_eq!(namespace.get(deleted).unwrap(), None);
+        assert_eq!(namespace.set(None, b"new").unwrap(), deleted + 1);
+    }
+
+    #[test]
+    fn recover_zdb_namespaces() {
+        let collections = MemoryCollections::new();
+        let compactor = ZdbCompactor::new(vec![(collections.clone(), DeadBytes::default())]);
+
+        // the compaction of the first namespace was interrupted after it was removed
+        let first = collections.collection("first").unwrap();
+        let key = first.set(None, b"first record").unwrap();
+        first
+            .delete(first.set(None, b"deleted record").unwrap())
+            .unwrap();
+        let copy = collections.collection("first.compacting").unwrap();
+        copy_records(&first, &copy, key + 2).unwrap();
+        copy.set(None, COMPLETE).unwrap();
+        collections.remove("first").unwrap();
+
+        // the compaction of the second namespace was interrupted while it was copied
+        let second = collections.collection("second").unwrap();
+        second.set(None, b"second record").unwrap();
+        collections.collection("second.compacting").unwrap();
+
+        compactor.recover().unwrap();
+        assert_eq!(
+            compactor.collections().unwrap(),
+            vec!["first".to_string(), "second".to_string()]
+        );
+
+        let first = collections.collection("first").unwrap();
+        assert_eq!(first.get(key).unwrap(), Some(b"first record".to_vec()));
+        assert_eq!(first.set(None, b"new").unwrap(), key + 2);
+        assert_eq!(second.keys().unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,286 @@
+//! A storage wrapper which compresses records. Compression must be applied before encryption,
+//! since ciphertext does not compress, so a compressed storage is stacked on top of an encrypted
+//! storage: `CompressedStorage<EncryptedStorage<S>>`.
+//!
+//! A compressed record starts with a small header: a magic prefix, followed by the codec which
+//! was used to compress the record. Records without the header are returned as is, so records
+//! which were written before compression was enabled stay readable, and compression can be
+//! enabled, changed or disabled at any time.
+
+use super::{AsyncStorage, Error, Key, Keys};
+use async_trait::async_trait;
+
+/// Magic prefix of a compressed record
+const MAGIC: &[u8; 4] = b"bcz\x00";
+
+/// Size of the record header: magic (4), codec (1)
+const HEADER_SIZE: usize = 5;
+
+/// Compression level used for zstd
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression algorithm of a record
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+            Codec::Lz4 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Codec, Error> {
+        match id {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zstd),
+            2 => Ok(Codec::Lz4),
+            _ => Err(Error::Protocol(format!("unknown compression codec {}", id))),
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let compressed = match self {
+            Codec::None => data.to_vec(),
+            Codec::Zstd => zstd::stream::encode_all(data, ZSTD_LEVEL)?,
+            Codec::Lz4 => lz4::block::compress(data, None, true)?,
+        };
+
+        Ok(compressed)
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let decompressed = match self {
+            Codec::None => data.to_vec(),
+            Codec::Zstd => zstd::stream::decode_all(data)?,
+            Codec::Lz4 => lz4::block::decompress(data, None)?,
+        };
+
+        Ok(decompressed)
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Codec, Error> {
+        match s.to_lowercase().as_ref() {
+            "none" => Ok(Codec::None),
+            "zstd" => Ok(Codec::Zstd),
+            "lz4" => Ok(Codec::Lz4),
+            _ => Err(Error::Protocol(format!(
+                "unknown compression codec '{}'",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CompressedStorage<S> {
+    codec: Codec,
+    backend: S,
+}
+
+impl<S> CompressedStorage<S>
+where
+    S: AsyncStorage,
+{
+    /// Create a new compressed storage on top of the given storage. New records are compressed
+    /// with the given codec, existing records are decompressed with the codec they were written
+    /// with.
+    pub fn new(backend: S, codec: Codec) -> Self {
+        CompressedStorage {
+            codec: codec,
+            backend: backend,
+        }
+    }
+
+    /// Encode a record. Data which does not get smaller when compressed is written as is, unless
+    /// it starts with the magic prefix, in which case a header is needed to tell it apart from a
+    /// compressed record.
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.codec != Codec::None {
+            let compressed = self.codec.compress(data)?;
+            if compressed.len() + HEADER_SIZE < data.len() {
+                return Ok(with_header(self.codec, &compressed));
+            }
+        }
+
+        if data.starts_with(MAGIC) {
+            return Ok(with_header(Codec::None, data));
+        }
+
+        Ok(data.to_vec())
+    }
+}
+
+/// Decode a record, records without the magic prefix were written without compression.
+fn decode(data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if !data.starts_with(MAGIC) {
+        return Ok(data);
+    }
+
+    if data.len() < HEADER_SIZE {
+        return Err(Error::Protocol("compressed record is truncated".into()));
+    }
+
+    let codec = Codec::from_id(data[4])?;
+    codec.decompress(&data[HEADER_SIZE..])
+}
+
+#[async_trait]
+impl<S> AsyncStorage for CompressedStorage<S>
+where
+    S: AsyncStorage,
+{
+    async fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+        let data = self.encode(data)?;
+        self.backend.set(key, &data).await
+    }
+
+    async fn delete(&self, key: Key) -> Result<(), Error> {
+        self.backend.delete(key).await
+    }
+
+    async fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+        self.backend.get(key).await?.map(decode).transpose()
+    }
+
+    async fn keys(&self) -> Result<Keys, Error> {
+        self.backend.keys().await
+    }
+
+    async fn rev(&self) -> Result<Keys, Error> {
+        self.backend.rev().await
+    }
+
+    async fn keys_from(&self, key: Key) -> Result<Keys, Error> {
+        self.backend.keys_from(key).await
+    }
+
+    async fn rev_from(&self, key: Key) -> Result<Keys, Error> {
+        self.backend.rev_from(key).await
+    }
+
+    async fn set_many(&self, records: &[(Option<Key>, &[u8])]) -> Result<Vec<Key>, Error> {
+        let encoded = records
+            .iter()
+            .map(|(_, data)| self.encode(data))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let batch: Vec<(Option<Key>, &[u8])> = records
+            .iter()
+            .zip(encoded.iter())
+            .map(|((key, _), data)| (*key, data.as_slice()))
+            .collect();
+
+        self.backend.set_many(&batch).await
+    }
+
+    async fn delete_many(&self, keys: &[Key]) -> Result<(), Error> {
+        self.backend.delete_many(keys).await
+    }
+
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        self.backend
+            .get_many(keys)
+            .await?
+            .into_iter()
+            .map(|data| data.map(decode).transpose())
+            .collect()
+    }
+}
+
+fn with_header(codec: Codec, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_SIZE + data.len());
+    buf.extend_from_slice(MAGIC);
+    buf.push(codec.id());
+    buf.extend_from_slice(data);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    async fn roundtrip(codec: Codec) {
+        let backend = MemoryStorage::new();
+        let storage = CompressedStorage::new(backend.clone(), codec);
+
+        let json = br#"{"name": "some name", "value": "some value"}"#.repeat(100);
+        let key = storage.set(None, &json).await.unwrap();
+        assert_eq!(storage.get(key).await.unwrap(), Some(json.clone()));
+
+        let stored = backend.get(key).await.unwrap().unwrap();
+        if codec == Codec::None {
+            assert_eq!(stored, json);
+        } else {
+            assert_eq!(&stored[..4], MAGIC);
+            assert_eq!(stored[4], codec.id());
+            assert!(stored.len() < json.len());
+        }
+
+        // data that does not compress is stored as is
+        let key = storage.set(None, b"abc").await.unwrap();
+        assert_eq!(backend.get(key).await.unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(storage.get(key).await.unwrap(), Some(b"abc".to_vec()));
+
+        // data that looks like a compressed record
+        let mut tricky = MAGIC.to_vec();
+        tricky.push(1);
+        let key = storage.set(None, &tricky).await.unwrap();
+        assert_eq!(storage.get(key).await.unwrap(), Some(tricky));
+
+        assert_eq!(storage.get(100).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn compressed_storage() {
+        roundtrip(Codec::None).await;
+        roundtrip(Codec::Zstd).await;
+        roundtrip(Codec::Lz4).await;
+    }
+
+    #[tokio::test]
+    async fn compressed_storage_mixed() {
+        let backend = MemoryStorage::new();
+        let plain = backend
+            .set(None, b"written before compression")
+            .await
+            .unwrap();
+
+        let data = b"0123456789".repeat(100);
+        let zstd = CompressedStorage::new(backend.clone(), Codec::Zstd)
+            .set(None, &data)
+            .await
+            .unwrap();
+
+        // records written with any codec are readable, whatever the configured codec is
+        let storage = CompressedStorage::new(backend.clone(), Codec::Lz4);
+        let lz4 = storage.set(None, &data).await.unwrap();
+
+        assert_eq!(
+            storage.get(plain).await.unwrap(),
+            Some(b"written before compression".to_vec())
+        );
+        assert_eq!(storage.get(zstd).await.unwrap(), Some(data.clone()));
+        assert_eq!(storage.get(lz4).await.unwrap(), Some(data.clone()));
+
+        let batch = storage
+            .set_many(&[(None, &data[..]), (Some(plain), &b"updated"[..])])
+            .await
+            .unwrap();
+        assert_eq!(batch[1], plain);
+        assert_eq!(
+            storage.get_many(&[batch[0], 100, plain]).await.unwrap(),
+            vec![Some(data), None, Some(b"updated".to_vec())]
+        );
+    }
+}
@@ -0,0 +1,594 @@
+//! A storage wrapper which deduplicates records by their content. Records are hashed before they
+//! are compressed and encrypted, every distinct content is stored once as a blob, and a record
+//! with the same content is written as a small reference to that blob. Since the database splits
+//! objects in chunks before they reach the storage, identical chunks of different objects are
+//! stored once as well.
+//!
+//! The blobs of a storage collection are listed in a table, a separate storage collection which
+//! holds the hash, key, size and reference count of every blob. The table is loaded on first use,
+//! and kept in memory. A blob is deleted when its last reference is deleted. The table is written
+//! before a reference is added, and after a reference is deleted, so a failure can leak a blob
+//! but never drops a blob which is still referenced.
+//!
+//! Records smaller than `MIN_SIZE` are written as they are, since a reference is not much smaller.
+//! A reference starts with a magic prefix, a small record which happens to start with the same
+//! prefix is deduplicated as well, so a plain record never starts with the magic prefix. Records
+//! which were written before deduplication was enabled stay readable.
+//!
+//! Since blobs and references are regular records in the backend, iterating over the keys of a
+//! deduplicated storage yields the keys of the blobs as well.
+
+use super::namespaces::{Collections, Namespaces};
+use super::{AsyncStorage, Error, Key, Keys};
+use async_trait::async_trait;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Minimum size of a deduplicated record, 1KB
+pub const MIN_SIZE: usize = 1024;
+
+/// Magic prefix of a reference record
+const MAGIC: &[u8; 8] = b"bcdb:ref";
+
+/// Size of a reference record: magic (8), blob key (8), hash (32)
+const REFERENCE_SIZE: usize = 48;
+
+/// Size of a blob entry in the table: hash (32), blob key (8), reference count (8), size (8)
+const ENTRY_SIZE: usize = 56;
+
+/// SHA-256 hash of the content of a blob
+type Hash = [u8; 32];
+
+/// The blobs of a collection, by the hash of their content
+type Blobs = HashMap<Hash, Blob>;
+
+#[derive(Clone)]
+pub struct DedupStorage<S, T> {
+    backend: S,
+    table: Option<T>,
+    // loaded on first use, the lock serializes all changes to reference counts
+    blobs: Arc<Mutex<Option<Blobs>>>,
+}
+
+/// A stored blob
+#[derive(Debug, Clone, Copy)]
+struct Blob {
+    /// key of the entry of the blob in the table
+    entry: Key,
+    /// key of the blob in the backend
+    key: Key,
+    /// number of records which refer to the blob
+    refs: u64,
+    /// size of the content of the blob
+    size: u64,
+}
+
+/// Deduplication stats of a storage collection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// number of stored blobs
+    pub blobs: u64,
+    /// number of records which refer to a blob
+    pub references: u64,
+    /// bytes of stored blob content
+    pub stored: u64,
+    /// bytes of content written, as if every record stored its own copy
+    pub logical: u64,
+}
+
+impl Stats {
+    /// The number of bytes which are not stored thanks to deduplication
+    pub fn saved(&self) -> u64 {
+        self.logical.saturating_sub(self.stored)
+    }
+}
+
+impl<S, T> DedupStorage<S, T>
+where
+    S: AsyncStorage,
+    T: AsyncStorage,
+{
+    /// Create a storage which writes all records to the backend as they are. Records are only
+    /// deduplicated once a table is set.
+    pub fn new(backend: S) -> Self {
+        DedupStorage {
+            backend: backend,
+            table: None,
+            blobs: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Deduplicate records, the blobs are listed in the given table.
+    pub fn with_table(mut self, table: T) -> Self {
+        self.table = Some(table);
+        self
+    }
+
+    /// Get the deduplication stats of the storage
+    pub async fn stats(&self) -> Result<Stats, Error> {
+        let table = match self.table {
+            Some(ref table) => table,
+            None => return Ok(Stats::default()),
+        };
+
+        let mut blobs = self.blobs.lock().await;
+        let blobs = Self::load(table, &mut blobs).await?;
+
+        Ok(blobs.values().fold(Stats::default(), |stats, blob| Stats {
+            blobs: stats.blobs + 1,
+            references: stats.references + blob.refs,
+            stored: stats.stored + blob.size,
+            logical: stats.logical + blob.size * blob.refs,
+        }))
+    }
+
+    /// Get the blobs, the table is loaded on first use.
+    async fn load<'a>(table: &T, blobs: &'a mut Option<Blobs>) -> Result<&'a mut Blobs, Error> {
+        if blobs.is_none() {
+            let mut loaded = HashMap::new();
+            let mut records = table.keys().await?;
+            while let Some(record) = records.next().await {
+                let record = record?;
+                let data = match table.get(record.key).await? {
+                    Some(data) => data,
+                    None => continue,
+                };
+
+                let (hash, blob) = Blob::decode(record.key, &data)?;
+                if loaded.insert(hash, blob).is_some() {
+                    warn!("duplicate blob entry '{}' in table", record.key);
+                }
+            }
+
+            debug!("loaded {} blobs", loaded.len());
+            *blobs = Some(loaded);
+        }
+
+        Ok(blobs.as_mut().expect("blobs are loaded"))
+    }
+
+    /// Add a reference to the blob with the given content, the blob is written if there is none
+    /// yet. Returns the reference record and the hash of the content.
+    async fn acquire(&self, table: &T, data: &[u8]) -> Result<(Vec<u8>, Hash), Error> {
+        let hash = hash(data);
+        let mut blobs = self.blobs.lock().await;
+        let blobs = Self::load(table, &mut blobs).await?;
+
+        if let Some(blob) = blobs.get_mut(&hash) {
+            let updated = Blob {
+                refs: blob.refs + 1,
+                ..*blob
+            };
+            table.set(Some(blob.entry), &updated.encode(&hash)).await?;
+            *blob = updated;
+            return Ok((reference(blob.key, &hash), hash));
+        }
+
+        let key = self.backend.set(None, data).await?;
+        let mut blob = Blob {
+            entry: 0,
+            key: key,
+            refs: 1,
+            size: data.len() as u64,
+        };
+
+        blob.entry = match table.set(None, &blob.encode(&hash)).await {
+            Ok(entry) => entry,
+            Err(err) => {
+                if let Err(err) = self.backend.delete(key).await {
+                    warn!("failed to delete unlisted blob '{}': {}", key, err);
+                }
+                return Err(err);
+            }
+        };
+
+        blobs.insert(hash, blob);
+        Ok((reference(key, &hash), hash))
+    }
+
+    /// Drop a reference to a blob, the blob is deleted with its last reference.
+    async fn release(&self, table: &T, hash: &Hash) -> Result<(), Error> {
+        let mut blobs = self.blobs.lock().await;
+        let blobs = Self::load(table, &mut blobs).await?;
+
+        let blob = match blobs.get(hash) {
+            Some(blob) => *blob,
+            None => {
+                warn!("released a reference to an unknown blob");
+                return Ok(());
+            }
+        };
+
+        if blob.refs > 1 {
+            let updated = Blob {
+                refs: blob.refs - 1,
+                ..blob
+            };
+            table.set(Some(blob.entry), &updated.encode(hash)).await?;
+            blobs.insert(*hash, updated);
+            return Ok(());
+        }
+
+        // once the entry is gone the blob can't be referenced anymore
+        table.delete(blob.entry).await?;
+        blobs.remove(hash);
+        if let Err(err) = self.backend.delete(blob.key).await {
+            warn!("failed to delete unreferenced blob '{}': {}", blob.key, err);
+        }
+
+        Ok(())
+    }
+
+    /// Get the blob and hash a record refers to, `None` if the record is not a reference.
+    async fn reference(&self, key: Key) -> Result<Option<(Key, Hash)>, Error> {
+        match self.backend.get(key).await? {
+            Some(data) => Ok(referenced(&data)),
+            None => Ok(None),
+        }
+    }
+
+    async fn blob(&self, key: Key) -> Result<Vec<u8>, Error> {
+        match self.backend.get(key).await? {
+            Some(data) => Ok(data),
+            None => Err(missing(key)),
+        }
+    }
+}
+
+#[async_trait]
+impl<S, T> AsyncStorage for DedupStorage<S, T>
+where
+    S: AsyncStorage,
+    T: AsyncStorage,
+{
+    async fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+        let table = match self.table {
+            Some(ref table) => table,
+            None => return self.backend.set(key, data).await,
+        };
+
+        // the blob of a replaced record is released once the record is written
+        let previous = match key {
+            Some(key) => self.reference(key).await?,
+            None => None,
+        };
+
+        let key = if data.len() < MIN_SIZE && !data.starts_with(MAGIC) {
+            self.backend.set(key, data).await?
+        } else {
+            let (record, hash) = self.acquire(table, data).await?;
+            match self.backend.set(key, &record).await {
+                Ok(key) => key,
+                Err(err) => {
+                    self.release(table, &hash).await?;
+                    return Err(err);
+                }
+            }
+        };
+
+        if let Some((_, hash)) = previous {
+            self.release(table, &hash).await?;
+        }
+
+        Ok(key)
+    }
+
+    async fn delete(&self, key: Key) -> Result<(), Error> {
+        let table = match self.table {
+            Some(ref table) => table,
+            None => return self.backend.delete(key).await,
+        };
+
+        let reference = self.reference(key).await?;
+        self.backend.delete(key).await?;
+        if let Some((_, hash)) = reference {
+            self.release(table, &hash).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+        let data = match self.backend.get(key).await? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        if self.table.is_none() {
+            return Ok(Some(data));
+        }
+
+        match referenced(&data) {
+            Some((blob, _)) => self.blob(blob).await.map(Some),
+            None => Ok(Some(data)),
+        }
+    }
+
+    async fn keys(&self) -> Result<Keys, Error> {
+        self.backend.keys().await
+    }
+
+    async fn rev(&self) -> Result<Keys, Error> {
+        self.backend.rev().await
+    }
+
+    async fn keys_from(&self, key: Key) -> Result<Keys, Error> {
+        self.backend.keys_from(key).await
+    }
+
+    async fn rev_from(&self, key: Key) -> Result<Keys, Error> {
+        self.backend.rev_from(key).await
+    }
+
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        let mut records = self.backend.get_many(keys).await?;
+        if self.table.is_none() {
+            return Ok(records);
+        }
+
+        // positions of the references, and the keys of their blobs
+        let (positions, blobs): (Vec<usize>, Vec<Key>) = records
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, data)| {
+                data.as_ref()
+                    .and_then(|data| referenced(data))
+                    .map(|(blob, _)| (idx, blob))
+            })
+            .unzip();
+
+        if blobs.is_empty() {
+            return Ok(records);
+        }
+
+        let contents = self.backend.get_many(&blobs).await?;
+        for ((idx, blob), data) in positions.into_iter().zip(blobs).zip(contents) {
+            match data {
+                Some(data) => records[idx] = Some(data),
+                None => return Err(missing(blob)),
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+/// A source of the deduplication stats of bcdb collections
+#[async_trait]
+pub trait StatsSource: Clone + Send + Sync + 'static {
+    /// Get the names of all collections
+    fn names(&self) -> Vec<String>;
+    /// Get the stats of a collection, `None` if the collection does not exist
+    async fn stats(&self, collection: &str) -> Result<Option<Stats>, Error>;
+}
+
+#[async_trait]
+impl<C, S, T> StatsSource for Namespaces<C>
+where
+    C: Collections<Storage = DedupStorage<S, T>>,
+    S: AsyncStorage,
+    T: AsyncStorage,
+{
+    fn names(&self) -> Vec<String> {
+        self.collections()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    async fn stats(&self, collection: &str) -> Result<Option<Stats>, Error> {
+        match self.lookup(collection).await? {
+            Some((_, storage)) => storage.stats().await.map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Get the stats of the given collections, or of all collections if none are given. The stats
+/// are sorted by collection name.
+pub async fn stats<T: StatsSource>(
+    source: &T,
+    collections: &[String],
+) -> Result<Vec<(String, Stats)>, Error> {
+    let mut collections = if collections.is_empty() {
+        source.names()
+    } else {
+        collections.to_vec()
+    };
+    collections.sort();
+
+    let mut results = Vec::with_capacity(collections.len());
+    for collection in collections {
+        match source.stats(&collection).await? {
+            Some(stats) => results.push((collection, stats)),
+            None => {
+                return Err(Error::Protocol(format!(
+                    "unknown collection '{}'",
+                    collection
+                )))
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+impl Blob {
+    fn encode(&self, hash: &Hash) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(ENTRY_SIZE);
+        buf.extend_from_slice(hash);
+        buf.extend_from_slice(&self.key.to_le_bytes());
+        buf.extend_from_slice(&self.refs.to_le_bytes());
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        buf
+    }
+
+    fn decode(entry: Key, data: &[u8]) -> Result<(Hash, Blob), Error> {
+        if data.len() != ENTRY_SIZE {
+            return Err(Error::Protocol(format!("invalid blob entry '{}'", entry)));
+        }
+
+        let u64_at = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+        let blob = Blob {
+            entry: entry,
+            key: u64_at(32),
+            refs: u64_at(40),
+            size: u64_at(48),
+        };
+
+        Ok((data[..32].try_into().unwrap(), blob))
+    }
+}
+
+fn hash(data: &[u8]) -> Hash {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Sha256::digest(data));
+    hash
+}
+
+fn reference(key: Key, hash: &Hash) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(REFERENCE_SIZE);
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&key.to_le_bytes());
+    buf.extend_from_slice(hash);
+    buf
+}
+
+/// Decode a reference record, returns `None` if the data is not a reference.
+fn referenced(data: &[u8]) -> Option<(Key, Hash)> {
+    if data.len() != REFERENCE_SIZE || !data.starts_with(MAGIC) {
+        return None;
+    }
+
+    let key = Key::from_le_bytes(data[8..16].try_into().unwrap());
+    Some((key, data[16..48].try_into().unwrap()))
+}
+
+fn missing(key: Key) -> Error {
+    Error::Protocol(format!("blob '{}' of reference is missing", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    async fn count(storage: &MemoryStorage) -> usize {
+        storage.keys().await.unwrap().count().await
+    }
+
+    #[tokio::test]
+    async fn dedup_storage() {
+        let backend = MemoryStorage::new();
+        let table = MemoryStorage::new();
+        let storage = DedupStorage::new(backend.clone()).with_table(table.clone());
+
+        let content = vec![7; 2 * MIN_SIZE];
+        let first = storage.set(None, &content).await.unwrap();
+        let second = storage.set(None, &content).await.unwrap();
+        assert_ne!(first, second);
+
+        // a single blob, and two references
+        assert_eq!(count(&backend).await, 3);
+        assert_eq!(count(&table).await, 1);
+        assert_eq!(storage.get(first).await.unwrap(), Some(content.clone()));
+        assert_eq!(storage.get(second).await.unwrap(), Some(content.clone()));
+
+        // small records are written as they are
+        let small = storage.set(None, b"small record").await.unwrap();
+        assert_eq!(
+            backend.get(small).await.unwrap(),
+            Some(b"small record".to_vec())
+        );
+
+        // unless they look like a reference
+        let tricky = storage.set(None, b"bcdb:ref").await.unwrap();
+        assert_eq!(
+            storage.get(tricky).await.unwrap(),
+            Some(b"bcdb:ref".to_vec())
+        );
+
+        let stats = storage.stats().await.unwrap();
+        assert_eq!(stats.blobs, 2);
+        assert_eq!(stats.references, 3);
+        assert_eq!(stats.saved(), content.len() as u64);
+
+        assert_eq!(
+            storage
+                .get_many(&[first, small, 100, second])
+                .await
+                .unwrap(),
+            vec![
+                Some(content.clone()),
+                Some(b"small record".to_vec()),
+                None,
+                Some(content.clone())
+            ]
+        );
+
+        // the blob is kept as long as it is referenced
+        storage.delete(first).await.unwrap();
+        assert_eq!(storage.get(first).await.unwrap(), None);
+        assert_eq!(storage.get(second).await.unwrap(), Some(content.clone()));
+
+        // the table is loaded again
+        let storage = DedupStorage::new(backend.clone()).with_table(table.clone());
+        let stats = storage.stats().await.unwrap();
+        assert_eq!(stats.blobs, 2);
+        assert_eq!(stats.references, 2);
+        assert_eq!(stats.saved(), 0);
+
+        // replacing a record releases its blob
+        let other = vec![8; 2 * MIN_SIZE];
+        storage.set(Some(second), &other).await.unwrap();
+        assert_eq!(storage.get(second).await.unwrap(), Some(other));
+        assert_eq!(storage.stats().await.unwrap().blobs, 2);
+
+        storage.delete(second).await.unwrap();
+        storage.delete(tricky).await.unwrap();
+        assert_eq!(storage.stats().await.unwrap(), Stats::default());
+        assert_eq!(count(&table).await, 0);
+        assert_eq!(count(&backend).await, 1);
+    }
+
+    #[tokio::test]
+    async fn dedup_storage_disabled() {
+        let backend = MemoryStorage::new();
+        let storage: DedupStorage<_, MemoryStorage> = DedupStorage::new(backend.clone());
+
+        let content = vec![7; 2 * MIN_SIZE];
+        let first = storage.set(None, &content).await.unwrap();
+        storage.set(None, &content).await.unwrap();
+
+        assert_eq!(count(&backend).await, 2);
+        assert_eq!(backend.get(first).await.unwrap(), Some(content));
+        assert_eq!(storage.stats().await.unwrap(), Stats::default());
+    }
+
+    #[tokio::test]
+    async fn dedup_stats() {
+        let collections = |_: &str| -> Result<_, Error> {
+            Ok(DedupStorage::new(MemoryStorage::new()).with_table(MemoryStorage::new()))
+        };
+        let namespaces = Namespaces::new(collections).await.unwrap();
+        let (_, storage) = namespaces.collection("files").await.unwrap();
+        namespaces.collection("empty").await.unwrap();
+
+        let content = vec![7; MIN_SIZE];
+        storage.set(None, &content).await.unwrap();
+        storage.set(None, &content).await.unwrap();
+
+        let results = stats(&namespaces, &[]).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, "empty");
+        assert_eq!(results[0].1, Stats::default());
+        assert_eq!(results[1].0, "files");
+        assert_eq!(results[1].1.saved(), MIN_SIZE as u64);
+
+        assert!(stats(&namespaces, &["missing".into()]).await.is_err());
+    }
+}
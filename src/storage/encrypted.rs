@@ -1,82 +1,398 @@
-use super::{Error as StorageError, Key, Record, Storage};
-use aead::{generic_array::GenericArray, Aead, NewAead};
+//! A storage wrapper which encrypts records with envelope encryption. Every record is encrypted
+//! with its own random data key, the data key is wrapped with a key-encryption key (KEK) from a
+//! `Keyring`, and stored in the record header next to the version of the KEK.
+//!
+//! Rotating the KEK only requires re-wrapping the data keys, the encrypted data itself is never
+//! touched. Records which were written before envelope encryption (without a header) are
+//! encrypted directly with the legacy key of the keyring, and stay readable.
+//!
+//! Since format version 2, the collection name and the record key are authenticated as associated
+//! data, so a record can't be moved to another key or collection without failing to decrypt. New
+//! records get their key in two phases: a pending record is inserted to reserve a key, which is
+//! then overwritten with the encrypted record. Records of format version 1, and records written
+//! before envelope encryption, are still readable, and are upgraded to format version 2 when their
+//! keys are re-wrapped. Only records without a header are decrypted with the legacy key, a record
+//! with a header which fails to decrypt is never read in another way.
+
+use super::keyring::{self, Keyring, KEY_SIZE, NONCE_SIZE};
+use super::{AsyncStorage, Error as StorageError, Key, Keys};
+use aead::{generic_array::GenericArray, NewAead};
 use aes_gcm::{aead, Aes256Gcm};
-use rand::prelude::*;
-use rand::rngs::OsRng;
-use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex as SyncMutex};
+use tokio::sync::Mutex;
+
+/// Magic prefix of an envelope encrypted record
+const MAGIC: &[u8; 3] = b"bce";
+
+/// Record format without associated data
+const FORMAT_V1: u8 = 1;
+
+/// Record format which authenticates the collection name and record key
+const FORMAT_V2: u8 = 2;
 
-const ENCRYPTION_KEY_SIZE: usize = 32;
-const NONCE_SIZE: usize = 12;
+/// A record which reserved a key, but was not written (yet)
+const PENDING: &[u8; 4] = b"bce\x00";
+
+/// Size of a wrapped data key: nonce (12), sealed key (32 + 16 bytes tag)
+const WRAPPED_KEY_SIZE: usize = NONCE_SIZE + KEY_SIZE + 16;
+
+/// Size of the record header: magic (3), format version (1), KEK version (4), wrapped key (60)
+const HEADER_SIZE: usize = 8 + WRAPPED_KEY_SIZE;
 
 #[derive(Clone)]
 pub struct EncryptedStorage<S> {
-    cipher: Aes256Gcm,
-    nonce_source: Arc<Mutex<OsRng>>,
+    keyring: Keyring,
+    // serializes writes of a record with re-wrapping it, so a record that is
+    // updated while its key is re-wrapped is never overwritten with the old data
+    locks: Locks,
     backend: S,
 }
 
+/// Locks of the records which are written. Only writes of the same record wait for each other,
+/// the locks of records which are not written anymore are dropped.
+#[derive(Clone, Default)]
+struct Locks(Arc<SyncMutex<HashMap<Key, Arc<Mutex<()>>>>>);
+
+impl Locks {
+    /// Get the locks of the given records, in the order of their keys, so writes of several
+    /// records always take their locks in the same order.
+    fn get(&self, keys: &[Key]) -> Vec<Arc<Mutex<()>>> {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+
+        let mut locks = self.0.lock().unwrap();
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        keys.into_iter()
+            .map(|key| locks.entry(key).or_default().clone())
+            .collect()
+    }
+}
+
+/// A parsed record header, and the encrypted data following it
+struct Envelope<'a> {
+    format: u8,
+    version: u32,
+    wrapped: &'a [u8],
+    body: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    fn parse(data: &'a [u8]) -> Option<Envelope<'a>> {
+        if data.len() < HEADER_SIZE + NONCE_SIZE
+            || !data.starts_with(MAGIC)
+            || (data[3] != FORMAT_V1 && data[3] != FORMAT_V2)
+        {
+            return None;
+        }
+
+        Some(Envelope {
+            format: data[3],
+            version: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            wrapped: &data[8..HEADER_SIZE],
+            body: &data[HEADER_SIZE..],
+        })
+    }
+
+    fn encode(format: u8, version: u32, wrapped: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + body.len());
+        buf.extend_from_slice(MAGIC);
+        buf.push(format);
+        buf.extend_from_slice(&version.to_le_bytes());
+        buf.extend_from_slice(wrapped);
+        buf.extend_from_slice(body);
+        buf
+    }
+}
+
 impl<S> EncryptedStorage<S>
 where
-    S: Storage,
+    S: AsyncStorage,
 {
     /// Create a new encrypted storage instance from an existing storage instance, with the given
-    /// key. All data written to the storage backend will be encrypted with the provided key.
-    /// Likewise, all data comming from the storage backend will be decrypted with the provided
-    /// key. The cryptographic algorithm used is AES in GCM mode, with a 256 bit key.
-    ///
-    /// # panics
-    ///
-    /// This function will panic if the provided key is not 32 bytes long.
-    pub fn new(key: &[u8], backend: S) -> Self {
-        assert_eq!(key.len(), ENCRYPTION_KEY_SIZE);
-        let key = GenericArray::clone_from_slice(key);
-        let cipher = Aes256Gcm::new(key);
-        let nonce_source = OsRng;
+    /// keyring. All data written to the storage backend will be encrypted with a fresh data key,
+    /// which is wrapped with the current KEK of the keyring. Likewise, all data comming from the
+    /// storage backend will be decrypted with the data key from its header. The cryptographic
+    /// algorithm used is AES in GCM mode, with a 256 bit key.
+    pub fn new(keyring: Keyring, backend: S) -> Self {
         EncryptedStorage {
-            cipher: cipher,
-            nonce_source: Arc::new(Mutex::new(nonce_source)),
+            keyring: keyring,
+            locks: Locks::default(),
             backend: backend,
         }
     }
+
+    /// The keyring used to wrap the data keys
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    /// Re-wrap the data key of a record with the current KEK of the keyring. Records written
+    /// before envelope encryption, or without associated data, are encrypted again. Returns true
+    /// if the record was rewritten.
+    pub async fn rewrap(&self, key: Key) -> Result<bool, StorageError> {
+        let lock = self.locks.get(&[key]).remove(0);
+        let _guard = lock.lock().await;
+        let data = match self.backend.get(key).await? {
+            Some(data) => data,
+            None => return Ok(false),
+        };
+
+        let record = match self.rewrapped(key, &data)? {
+            Some(record) => record,
+            None => return Ok(false),
+        };
+
+        self.backend.set(Some(key), &record).await?;
+        Ok(true)
+    }
+
+    /// Re-wrap the data keys of all records in the storage, see `rewrap`. Records that fail to be
+    /// re-wrapped are logged and skipped, so they can be retried by another rotation. Returns the
+    /// number of rewritten records.
+    pub async fn rewrap_all(&self) -> Result<u64, StorageError> {
+        // keys are collected first, since rewritten records may show up again in a running scan
+        let keys: Vec<Key> = self
+            .backend
+            .keys()
+            .await?
+            .map_ok(|record| record.key)
+            .try_collect()
+            .await?;
+        let mut count = 0;
+        for key in keys {
+            match self.rewrap(key).await {
+                Ok(true) => count += 1,
+                Ok(false) => {}
+                Err(err) => error!("failed to re-wrap key of record {}: {}", key, err),
+            }
+        }
+
+        Ok(count)
+    }
 }
 
-impl<S> Storage for EncryptedStorage<S>
+impl<S> EncryptedStorage<S> {
+    /// The associated data of a record: the format, the collection name and the record key
+    fn aad(&self, key: Key) -> Vec<u8> {
+        let collection = self.keyring.collection().as_bytes();
+        let mut aad = Vec::with_capacity(2 + collection.len() + 8);
+        aad.push(FORMAT_V2);
+        aad.push(collection.len() as u8);
+        aad.extend_from_slice(collection);
+        aad.extend_from_slice(&key.to_le_bytes());
+        aad
+    }
+
+    fn seal(&self, key: Key, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let data_key = keyring::random(KEY_SIZE);
+        let (version, wrapped) = self.keyring.wrap(&data_key)?;
+        let cipher = Aes256Gcm::new(GenericArray::clone_from_slice(&data_key));
+        let body = keyring::encrypt(&cipher, data, &self.aad(key))?;
+
+        Ok(Envelope::encode(FORMAT_V2, version, &wrapped, &body))
+    }
+
+    /// Decrypt a record. The format of the record decides how it is decrypted, a record with an
+    /// envelope header never falls back to the legacy key, so a record that fails to
+    /// authenticate its key and collection can't be read in another way.
+    fn open(&self, key: Key, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let envelope = match Envelope::parse(data) {
+            Some(envelope) => envelope,
+            None => return self.open_legacy(data),
+        };
+
+        let aad = match envelope.format {
+            FORMAT_V1 => Vec::default(),
+            _ => self.aad(key),
+        };
+
+        let data_key = self.keyring.unwrap(envelope.version, envelope.wrapped)?;
+        let cipher = Aes256Gcm::new(GenericArray::clone_from_slice(&data_key));
+        keyring::decrypt(&cipher, envelope.body, &aad)
+    }
+
+    fn open_legacy(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        match self.keyring.legacy() {
+            Some(cipher) => keyring::decrypt(cipher, data, &[]),
+            None => Err(StorageError::Crypto),
+        }
+    }
+
+    /// Get the record with its data key wrapped by the current KEK, or None if it is already
+    /// wrapped with the current KEK.
+    fn rewrapped(&self, key: Key, data: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        if data == PENDING {
+            return Ok(None);
+        }
+
+        if let Some(envelope) = Envelope::parse(data) {
+            if envelope.format == FORMAT_V2 {
+                if envelope.version == self.keyring.version() {
+                    return Ok(None);
+                }
+
+                if let Ok(data_key) = self.keyring.unwrap(envelope.version, envelope.wrapped) {
+                    let (version, wrapped) = self.keyring.wrap(&data_key)?;
+                    return Ok(Some(Envelope::encode(
+                        FORMAT_V2,
+                        version,
+                        &wrapped,
+                        envelope.body,
+                    )));
+                }
+            }
+        }
+
+        // older formats are encrypted again, to authenticate the key and collection
+        let plaintext = self.open(key, data)?;
+        self.seal(key, &plaintext).map(Some)
+    }
+}
+
+#[async_trait]
+impl<S> AsyncStorage for EncryptedStorage<S>
 where
-    S: Storage,
+    S: AsyncStorage,
 {
-    fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, StorageError> {
-        let mut nonce_slice = vec![0u8; NONCE_SIZE];
-        self.nonce_source
-            .lock()
-            .map_err(|_| StorageError::Other)?
-            .fill_bytes(&mut nonce_slice);
-        let nonce = GenericArray::clone_from_slice(&nonce_slice);
-        let mut ciphertext = self.cipher.encrypt(&nonce, data)?;
-        let mut final_data = nonce.to_vec();
-        final_data.append(&mut ciphertext);
-        self.backend.set(key, &final_data)
+    async fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, StorageError> {
+        // the key is part of the associated data, so a new record first
+        // reserves a key, and is then written to that key
+        let (key, pending) = match key {
+            Some(key) => (key, false),
+            None => (self.backend.set(None, PENDING).await?, true),
+        };
+
+        let result = match self.seal(key, data) {
+            Ok(record) => {
+                let lock = self.locks.get(&[key]).remove(0);
+                let _guard = lock.lock().await;
+                self.backend.set(Some(key), &record).await
+            }
+            Err(err) => Err(err),
+        };
+
+        if result.is_err() && pending {
+            if let Err(err) = self.backend.delete(key).await {
+                error!("failed to delete pending record {}: {}", key, err);
+            }
+        }
+
+        result
     }
 
-    fn delete(&self, key: Key) -> Result<(), StorageError> {
-        self.backend.delete(key)
+    async fn delete(&self, key: Key) -> Result<(), StorageError> {
+        let lock = self.locks.get(&[key]).remove(0);
+        let _guard = lock.lock().await;
+        self.backend.delete(key).await
     }
 
-    fn get(&self, key: Key) -> Result<Option<Vec<u8>>, StorageError> {
-        let data = match self.backend.get(key)? {
+    async fn get(&self, key: Key) -> Result<Option<Vec<u8>>, StorageError> {
+        let data = match self.backend.get(key).await? {
             Some(data) => data,
             None => return Ok(None),
         };
-        let nonce = GenericArray::clone_from_slice(&data[..NONCE_SIZE]);
-        let plaintext = self.cipher.decrypt(&nonce, &data[NONCE_SIZE..])?;
-        Ok(Some(plaintext))
+
+        // a record which was never completely written
+        if data == PENDING {
+            return Ok(None);
+        }
+
+        self.open(key, &data).map(Some)
+    }
+
+    async fn keys(&self) -> Result<Keys, StorageError> {
+        self.backend.keys().await
     }
 
-    fn keys(&self) -> Result<Box<dyn Iterator<Item = Record> + Send>, StorageError> {
-        self.backend.keys()
+    async fn rev(&self) -> Result<Keys, StorageError> {
+        self.backend.rev().await
     }
 
-    fn rev(&self) -> Result<Box<dyn Iterator<Item = Record> + Send>, StorageError> {
-        self.backend.rev()
+    async fn keys_from(&self, key: Key) -> Result<Keys, StorageError> {
+        self.backend.keys_from(key).await
+    }
+
+    async fn rev_from(&self, key: Key) -> Result<Keys, StorageError> {
+        self.backend.rev_from(key).await
+    }
+
+    async fn set_many(&self, records: &[(Option<Key>, &[u8])]) -> Result<Vec<Key>, StorageError> {
+        // keys of all new records are reserved in a single batch
+        let reserve: Vec<(Option<Key>, &[u8])> = records
+            .iter()
+            .filter(|(key, _)| key.is_none())
+            .map(|_| (None, &PENDING[..]))
+            .collect();
+        let pending = self.backend.set_many(&reserve).await?;
+
+        let mut reserved = pending.iter();
+        let mut keys = Vec::with_capacity(records.len());
+        for (key, _) in records {
+            match key.or_else(|| reserved.next().copied()) {
+                Some(key) => keys.push(key),
+                None => return Err(StorageError::Protocol("missing reserved key".into())),
+            }
+        }
+
+        let sealed = records
+            .iter()
+            .zip(keys.iter())
+            .map(|((_, data), key)| self.seal(*key, data))
+            .collect::<Result<Vec<_>, _>>();
+
+        let result = match sealed {
+            Ok(sealed) => {
+                let batch: Vec<(Option<Key>, &[u8])> = keys
+                    .iter()
+                    .zip(sealed.iter())
+                    .map(|(key, record)| (Some(*key), record.as_slice()))
+                    .collect();
+
+                let locks = self.locks.get(&keys);
+                let mut guards = Vec::with_capacity(locks.len());
+                for lock in &locks {
+                    guards.push(lock.lock().await);
+                }
+
+                self.backend.set_many(&batch).await
+            }
+            Err(err) => Err(err),
+        };
+
+        if result.is_err() {
+            if let Err(err) = self.backend.delete_many(&pending).await {
+                error!("failed to delete pending records {:?}: {}", pending, err);
+            }
+        }
+
+        result.map(|_| keys)
+    }
+
+    async fn delete_many(&self, keys: &[Key]) -> Result<(), StorageError> {
+        let locks = self.locks.get(keys);
+        let mut guards = Vec::with_capacity(locks.len());
+        for lock in &locks {
+            guards.push(lock.lock().await);
+        }
+
+        self.backend.delete_many(keys).await
+    }
+
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        keys.iter()
+            .zip(self.backend.get_many(keys).await?)
+            .map(|(key, data)| match data {
+                // a record which was never completely written
+                Some(ref data) if data == PENDING => Ok(None),
+                Some(data) => self.open(*key, &data).map(Some),
+                None => Ok(None),
+            })
+            .collect()
     }
 }
 
@@ -89,34 +405,194 @@ impl From<aead::Error> for StorageError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::memory::MemoryStorage;
 
-    #[test]
-    fn roundtrip() {
-        let storage = crate::storage::memory::MemoryStorage::new();
+    #[tokio::test]
+    async fn roundtrip() {
+        let storage = MemoryStorage::new();
 
-        let mut encryption_key = vec![0; 32];
-        rand::thread_rng().fill_bytes(&mut encryption_key);
+        let encryption_key = keyring::random(32);
+        let keyring = Keyring::new("test", &encryption_key)
+            .load(&MemoryStorage::new())
+            .unwrap();
 
-        let mut crypt = EncryptedStorage::new(&encryption_key, storage);
+        let crypt = EncryptedStorage::new(keyring, storage);
 
         let data1 = b"First piece of data";
         let data2 = b"Second piece of data";
         let data3 = b"Some super secret data nobody should read";
 
-        let key1 = crypt.set(None, data1).unwrap();
-        let key2 = crypt.set(None, data2).unwrap();
-        let key3 = crypt.set(None, data3).unwrap();
+        let key1 = crypt.set(None, data1).await.unwrap();
+        let key2 = crypt.set(None, data2).await.unwrap();
+        let key3 = crypt.set(None, data3).await.unwrap();
 
-        let recovered_data_1 = crypt.get(key1).unwrap();
-        let recovered_data_2 = crypt.get(key2).unwrap();
-        let recovered_data_3 = crypt.get(key3).unwrap();
+        let recovered_data_1 = crypt.get(key1).await.unwrap();
+        let recovered_data_2 = crypt.get(key2).await.unwrap();
+        let recovered_data_3 = crypt.get(key3).await.unwrap();
 
         assert_eq!(Some(Vec::from(&data1[..])), recovered_data_1);
         assert_eq!(Some(Vec::from(&data2[..])), recovered_data_2);
         assert_eq!(Some(Vec::from(&data3[..])), recovered_data_3);
 
-        assert_eq!(None, crypt.get(3).unwrap());
-        assert_eq!(None, crypt.get(17).unwrap());
-        assert_eq!(None, crypt.get(17_343_525).unwrap());
+        assert_eq!(None, crypt.get(3).await.unwrap());
+        assert_eq!(None, crypt.get(17).await.unwrap());
+        assert_eq!(None, crypt.get(17_343_525).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn batch() {
+        let storage = MemoryStorage::new();
+        let keyring = Keyring::new("test", &keyring::random(32))
+            .load(&MemoryStorage::new())
+            .unwrap();
+
+        let crypt = EncryptedStorage::new(keyring, storage.clone());
+        let key1 = crypt.set(None, b"existing record").await.unwrap();
+
+        let keys = crypt
+            .set_many(&[
+                (None, &b"first new record"[..]),
+                (Some(key1), &b"updated record"[..]),
+                (None, &b"second new record"[..]),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(keys[1], key1);
+
+        // every record is sealed with its own key as associated data
+        let pending = storage.set(None, PENDING).await.unwrap();
+        assert_eq!(
+            crypt
+                .get_many(&[keys[2], key1, pending, 100, keys[0]])
+                .await
+                .unwrap(),
+            vec![
+                Some(b"second new record".to_vec()),
+                Some(b"updated record".to_vec()),
+                None,
+                None,
+                Some(b"first new record".to_vec()),
+            ]
+        );
+
+        crypt.delete_many(&[keys[0], key1]).await.unwrap();
+        assert_eq!(
+            crypt.get_many(&keys).await.unwrap(),
+            vec![None, None, Some(b"second new record".to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn rotate_keys() {
+        let backend = MemoryStorage::new();
+        let keys = MemoryStorage::new();
+        let root = keyring::random(32);
+        let legacy = keyring::random(32);
+
+        // a record written before envelope encryption
+        let cipher = Aes256Gcm::new(GenericArray::clone_from_slice(&legacy));
+        let record = keyring::encrypt(&cipher, b"legacy data", &[]).unwrap();
+        let key1 = backend.set(None, &record).await.unwrap();
+
+        let load = || {
+            Keyring::new("test", &root)
+                .with_legacy(&legacy)
+                .load(&keys)
+                .unwrap()
+        };
+
+        let keyring = load();
+        let crypt = EncryptedStorage::new(keyring.clone(), backend.clone());
+        let key2 = crypt.set(None, b"some data").await.unwrap();
+
+        async fn header(backend: &MemoryStorage, key: Key) -> Option<u32> {
+            let data = backend.get(key).await.unwrap().unwrap();
+            Envelope::parse(&data).map(|envelope| envelope.version)
+        }
+
+        assert_eq!(header(&backend, key1).await, None);
+        assert_eq!(header(&backend, key2).await, Some(1));
+
+        keyring.rotate(&keys).unwrap();
+        let key3 = crypt.set(None, b"more data").await.unwrap();
+        assert_eq!(header(&backend, key3).await, Some(2));
+
+        // all records stay readable before they are re-wrapped
+        assert_eq!(
+            crypt.get(key1).await.unwrap(),
+            Some(b"legacy data".to_vec())
+        );
+        assert_eq!(crypt.get(key2).await.unwrap(), Some(b"some data".to_vec()));
+
+        let body = backend.get(key2).await.unwrap().unwrap()[HEADER_SIZE..].to_vec();
+        assert_eq!(crypt.rewrap_all().await.unwrap(), 2);
+        assert_eq!(crypt.rewrap_all().await.unwrap(), 0);
+
+        assert_eq!(header(&backend, key1).await, Some(2));
+        assert_eq!(header(&backend, key2).await, Some(2));
+        // only the data key is re-wrapped, the data is not encrypted again
+        assert_eq!(
+            &backend.get(key2).await.unwrap().unwrap()[HEADER_SIZE..],
+            &body[..]
+        );
+
+        // the legacy record is upgraded, and bound to its key like any other record
+        let upgraded = backend.get(key1).await.unwrap().unwrap();
+        assert_eq!(upgraded[3], FORMAT_V2);
+        let moved = backend.set(None, &upgraded).await.unwrap();
+        assert_eq!(crypt.get(moved).await.is_err(), true);
+        backend.delete(moved).await.unwrap();
+
+        // a reloaded keyring reads everything
+        let crypt = EncryptedStorage::new(load(), backend);
+        assert_eq!(
+            crypt.get(key1).await.unwrap(),
+            Some(b"legacy data".to_vec())
+        );
+        assert_eq!(crypt.get(key2).await.unwrap(), Some(b"some data".to_vec()));
+        assert_eq!(crypt.get(key3).await.unwrap(), Some(b"more data".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn associated_data() {
+        let backend = MemoryStorage::new();
+        let keys = MemoryStorage::new();
+        let root = keyring::random(32);
+        let keyring = Keyring::new("objects", &root).load(&keys).unwrap();
+        let crypt = EncryptedStorage::new(keyring.clone(), backend.clone());
+
+        let key1 = crypt.set(None, b"first").await.unwrap();
+        let key2 = crypt.set(None, b"second").await.unwrap();
+        let record1 = backend.get(key1).await.unwrap().unwrap();
+        assert_eq!(record1[3], FORMAT_V2);
+
+        // a record moved to another key does not decrypt
+        backend.set(Some(key2), &record1).await.unwrap();
+        assert_eq!(crypt.get(key2).await.is_err(), true);
+
+        // nor does a record moved to another collection, even with the same keys
+        let other = Keyring::new("acl", &root).load(&keys).unwrap();
+        let acl = EncryptedStorage::new(other, backend.clone());
+        assert_eq!(acl.get(key1).await.is_err(), true);
+
+        // a record of format version 1 is still readable, and upgraded on re-wrap
+        let data_key = keyring::random(KEY_SIZE);
+        let (version, wrapped) = keyring.wrap(&data_key).unwrap();
+        let cipher = Aes256Gcm::new(GenericArray::clone_from_slice(&data_key));
+        let body = keyring::encrypt(&cipher, b"version 1", &[]).unwrap();
+        let key3 = backend
+            .set(None, &Envelope::encode(FORMAT_V1, version, &wrapped, &body))
+            .await
+            .unwrap();
+
+        assert_eq!(crypt.get(key3).await.unwrap(), Some(b"version 1".to_vec()));
+        assert_eq!(crypt.rewrap(key3).await.unwrap(), true);
+        assert_eq!(backend.get(key3).await.unwrap().unwrap()[3], FORMAT_V2);
+        assert_eq!(crypt.get(key3).await.unwrap(), Some(b"version 1".to_vec()));
+
+        // a pending record, of which the second phase never happened
+        let key4 = backend.set(None, PENDING).await.unwrap();
+        assert_eq!(crypt.get(key4).await.unwrap(), None);
+        assert_eq!(crypt.rewrap(key4).await.unwrap(), false);
     }
 }
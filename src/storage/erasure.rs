@@ -0,0 +1,507 @@
+//! A storage which erasure codes records over multiple storages, usually collections of separate
+//! 0-db instances. Every record is split in k data shards, and m parity shards are computed from
+//! them, one shard is stored on every backend. A record can be read as long as any k of its
+//! shards are available.
+//!
+//! Like the replicated storage, the backends must agree on the keys of the records. Inserts are
+//! serialized, the first available backend assigns the key and the shards are appended to the
+//! other backends with the same key. A backend which is behind gets the shards it missed rebuilt
+//! from the other backends first.
+//!
+//! Every shard has a small header: a magic prefix with the format version, the generation of the
+//! record (the time it was written) and the length of the record. A backend which missed an update
+//! holds a shard of an older generation, when reading, the newest generation which has at least k
+//! shards is used.
+
+use super::{Error, Key, KeyIter, Record, Storage};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Magic prefix of a shard, the last byte is the format version
+const MAGIC: &[u8; 4] = b"bes\x01";
+
+/// Size of the shard header: magic (4), generation (8), record length (8)
+const HEADER_SIZE: usize = 20;
+
+#[derive(Clone)]
+pub struct ErasureStorage<S> {
+    codec: Arc<ReedSolomon>,
+    data_shards: usize,
+    quorum: usize,
+    backends: Vec<S>,
+    // writes are serialized, so all backends assign the same keys
+    writes: Arc<Mutex<()>>,
+}
+
+/// A shard as read from a backend
+struct Shard {
+    generation: u64,
+    length: u64,
+    data: Vec<u8>,
+}
+
+impl Shard {
+    fn parse(data: &[u8]) -> Option<Shard> {
+        if data.len() < HEADER_SIZE || !data.starts_with(MAGIC) {
+            return None;
+        }
+
+        Some(Shard {
+            generation: u64::from_le_bytes(data[4..12].try_into().unwrap()),
+            length: u64::from_le_bytes(data[12..20].try_into().unwrap()),
+            data: data[HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    fn encode(generation: u64, length: u64, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + data.len());
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&generation.to_le_bytes());
+        buf.extend_from_slice(&length.to_le_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+}
+
+/// The shards of the newest generation of a record, missing shards are None
+struct Stripe {
+    generation: u64,
+    length: u64,
+    shards: Vec<Option<Vec<u8>>>,
+}
+
+impl<S> ErasureStorage<S>
+where
+    S: Storage,
+{
+    /// Create a new erasure coded storage over the given backends, the last `parity_shards`
+    /// backends hold parity shards, the others hold data shards. The write quorum defaults to one
+    /// more shard than needed to read a record.
+    pub fn new(backends: Vec<S>, parity_shards: usize) -> Result<Self, Error> {
+        if parity_shards == 0 || parity_shards >= backends.len() {
+            return Err(Error::Protocol(format!(
+                "invalid number of parity shards {} for {} backends",
+                parity_shards,
+                backends.len()
+            )));
+        }
+
+        let data_shards = backends.len() - parity_shards;
+        let codec = ReedSolomon::new(data_shards, parity_shards)
+            .map_err(|err| Error::Protocol(format!("{:?}", err)))?;
+
+        Ok(ErasureStorage {
+            codec: Arc::new(codec),
+            data_shards: data_shards,
+            quorum: std::cmp::min(data_shards + 1, backends.len()),
+            backends: backends,
+            writes: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Set the number of shards a write must succeed on.
+    ///
+    /// # panics
+    ///
+    /// This function will panic if the quorum is lower than the number of data shards, or larger
+    /// than the number of backends.
+    pub fn with_quorum(mut self, quorum: usize) -> Self {
+        assert!(quorum >= self.data_shards && quorum <= self.backends.len());
+        self.quorum = quorum;
+        self
+    }
+
+    /// Split a record in shards, and compute the parity shards.
+    fn encode(&self, data: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        // shards can't be empty, so an empty record still gets 1 byte shards
+        let size = std::cmp::max(1, (data.len() + self.data_shards - 1) / self.data_shards);
+        let mut shards: Vec<Vec<u8>> = (0..self.backends.len())
+            .map(|index| {
+                let start = std::cmp::min(index * size, data.len());
+                let end = std::cmp::min(start + size, data.len());
+                let mut shard = if index < self.data_shards {
+                    data[start..end].to_vec()
+                } else {
+                    Vec::with_capacity(size)
+                };
+                shard.resize(size, 0);
+                shard
+            })
+            .collect();
+
+        self.codec.encode(&mut shards).map_err(codec_error)?;
+
+        let generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+
+        Ok(shards
+            .iter()
+            .map(|shard| Shard::encode(generation, data.len() as u64, shard))
+            .collect())
+    }
+
+    /// Read the shards of a record from all backends (except the excluded one). Returns None if
+    /// the record does not exist.
+    fn stripe(&self, key: Key, exclude: Option<usize>) -> Result<Option<Stripe>, Error> {
+        let mut shards: Vec<Option<Shard>> = Vec::with_capacity(self.backends.len());
+        let mut absent = 0;
+        for (index, backend) in self.backends.iter().enumerate() {
+            if Some(index) == exclude {
+                shards.push(None);
+                continue;
+            }
+
+            let shard = match backend.get(key) {
+                Ok(Some(data)) => Shard::parse(&data),
+                Ok(None) => {
+                    absent += 1;
+                    None
+                }
+                Err(err) => {
+                    debug!("failed to get shard {} of record {}: {}", index, key, err);
+                    None
+                }
+            };
+
+            shards.push(shard);
+        }
+
+        let mut generations: Vec<u64> = shards
+            .iter()
+            .filter_map(|shard| shard.as_ref().map(|shard| shard.generation))
+            .collect();
+        generations.sort();
+        generations.dedup();
+
+        // the newest generation of which enough shards are available
+        for generation in generations.into_iter().rev() {
+            let count = shards
+                .iter()
+                .filter(|shard| matches!(shard, Some(shard) if shard.generation == generation))
+                .count();
+            if count < self.data_shards {
+                continue;
+            }
+
+            let mut length = 0;
+            let shards = shards
+                .into_iter()
+                .map(|shard| match shard {
+                    Some(shard) if shard.generation == generation => {
+                        length = shard.length;
+                        Some(shard.data)
+                    }
+                    _ => None,
+                })
+                .collect();
+
+            return Ok(Some(Stripe {
+                generation,
+                length,
+                shards,
+            }));
+        }
+
+        // too few backends can hold a shard of the record, so it does not exist
+        if absent + self.data_shards > self.backends.len() {
+            return Ok(None);
+        }
+
+        Err(Error::Protocol(format!(
+            "not enough shards available to read record {}",
+            key
+        )))
+    }
+
+    /// Rebuild the shard of a record for the given backend, from the other backends.
+    fn rebuild(&self, key: Key, index: usize) -> Result<Option<Vec<u8>>, Error> {
+        let mut stripe = match self.stripe(key, Some(index))? {
+            Some(stripe) => stripe,
+            None => return Ok(None),
+        };
+
+        self.codec
+            .reconstruct(&mut stripe.shards)
+            .map_err(codec_error)?;
+
+        let shard = stripe.shards[index].take().unwrap_or_default();
+        Ok(Some(Shard::encode(
+            stripe.generation,
+            stripe.length,
+            &shard,
+        )))
+    }
+
+    /// Append a shard to a backend, with the given key. If the backend assigns a lower key, it
+    /// missed records, of which the shards are rebuilt until the backend assigns the expected key.
+    fn append(&self, index: usize, key: Key, shard: &[u8]) -> Result<(), Error> {
+        let backend = &self.backends[index];
+        loop {
+            let assigned = backend.set(None, shard)?;
+            if assigned == key {
+                return Ok(());
+            }
+
+            if assigned > key {
+                // the key was used on the backend, but not on the others
+                backend.delete(assigned)?;
+                return Err(Error::Protocol(format!(
+                    "backend assigned key {} instead of {}, backends diverged",
+                    assigned, key
+                )));
+            }
+
+            match self.rebuild(assigned, index) {
+                Ok(Some(missing)) => {
+                    backend.set(Some(assigned), &missing)?;
+                }
+                Ok(None) => backend.delete(assigned)?,
+                Err(err) => {
+                    warn!(
+                        "failed to rebuild shard {} of record {}: {}",
+                        index, assigned, err
+                    );
+                    backend.delete(assigned)?;
+                }
+            };
+        }
+    }
+
+    /// Insert a new record, the first available backend assigns the key.
+    fn insert(&self, data: &[u8]) -> Result<Key, Error> {
+        let shards = self.encode(data)?;
+        let mut key: Option<Key> = None;
+        let mut written = Vec::with_capacity(self.backends.len());
+        for (index, backend) in self.backends.iter().enumerate() {
+            let result = match key {
+                None => backend.set(None, &shards[index]),
+                Some(key) => self.append(index, key, &shards[index]).map(|_| key),
+            };
+
+            match result {
+                Ok(assigned) => {
+                    key.get_or_insert(assigned);
+                    written.push(index);
+                }
+                Err(err) => warn!("failed to write shard {}: {}", index, err),
+            }
+        }
+
+        let key = match key {
+            Some(key) => key,
+            None => return Err(Error::Protocol("no backend available".into())),
+        };
+
+        if let Err(err) = self.quorum(written.len()) {
+            // the key stays used on all backends, so they still agree on the next key
+            for index in written {
+                if let Err(err) = self.backends[index].delete(key) {
+                    warn!(
+                        "failed to delete shard {} of record {}: {}",
+                        index, key, err
+                    );
+                }
+            }
+
+            return Err(err);
+        }
+
+        Ok(key)
+    }
+
+    fn quorum(&self, written: usize) -> Result<(), Error> {
+        if written < self.quorum {
+            return Err(Error::Protocol(format!(
+                "write quorum not reached, written {} of {} required shards",
+                written, self.quorum
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Scan the keys of the first backend which can be scanned, all backends hold a shard of
+    /// every record.
+    fn scan<F>(&self, f: F) -> Result<KeyIter, Error>
+    where
+        F: Fn(&S) -> Result<KeyIter, Error>,
+    {
+        let mut last = None;
+        for backend in self.backends.iter() {
+            match f(backend) {
+                Ok(keys) => return Ok(Box::new(keys.map(|record| record.map(without_size)))),
+                Err(err) => last = Some(err),
+            }
+        }
+
+        Err(last.unwrap_or(Error::Other))
+    }
+}
+
+impl<S> Storage for ErasureStorage<S>
+where
+    S: Storage,
+{
+    fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+        let _guard = self.writes.lock().map_err(|_| Error::Other)?;
+        let key = match key {
+            Some(key) => key,
+            None => return self.insert(data),
+        };
+
+        let shards = self.encode(data)?;
+        let mut written = 0;
+        for (index, backend) in self.backends.iter().enumerate() {
+            match backend.set(Some(key), &shards[index]) {
+                Ok(_) => written += 1,
+                Err(err) => warn!("failed to write shard {} of record {}: {}", index, key, err),
+            }
+        }
+
+        self.quorum(written).map(|_| key)
+    }
+
+    fn delete(&self, key: Key) -> Result<(), Error> {
+        let _guard = self.writes.lock().map_err(|_| Error::Other)?;
+        let mut deleted = 0;
+        for (index, backend) in self.backends.iter().enumerate() {
+            match backend.delete(key) {
+                Ok(_) => deleted += 1,
+                Err(err) => warn!(
+                    "failed to delete shard {} of record {}: {}",
+                    index, key, err
+                ),
+            }
+        }
+
+        self.quorum(deleted)
+    }
+
+    fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+        let mut stripe = match self.stripe(key, None)? {
+            Some(stripe) => stripe,
+            None => return Ok(None),
+        };
+
+        self.codec
+            .reconstruct_data(&mut stripe.shards)
+            .map_err(codec_error)?;
+
+        let mut data = Vec::with_capacity(stripe.length as usize);
+        for shard in stripe.shards.iter().take(self.data_shards) {
+            if let Some(shard) = shard {
+                data.extend_from_slice(shard);
+            }
+        }
+
+        data.truncate(stripe.length as usize);
+        Ok(Some(data))
+    }
+
+    fn keys(&self) -> Result<KeyIter, Error> {
+        self.scan(|backend| backend.keys())
+    }
+
+    fn rev(&self) -> Result<KeyIter, Error> {
+        self.scan(|backend| backend.rev())
+    }
+
+    fn keys_from(&self, key: Key) -> Result<KeyIter, Error> {
+        self.scan(|backend| backend.keys_from(key))
+    }
+
+    fn rev_from(&self, key: Key) -> Result<KeyIter, Error> {
+        self.scan(|backend| backend.rev_from(key))
+    }
+}
+
+/// The size of a record on a backend is the size of a shard, not of the record
+fn without_size(record: Record) -> Record {
+    Record {
+        key: record.key,
+        timestamp: record.timestamp,
+        size: None,
+    }
+}
+
+fn codec_error(err: reed_solomon_erasure::Error) -> Error {
+    Error::Protocol(format!("erasure coding failed: {:?}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    fn backends(count: usize) -> Vec<MemoryStorage> {
+        (0..count).map(|_| MemoryStorage::new()).collect()
+    }
+
+    #[test]
+    fn erasure_storage() {
+        let backends = backends(4);
+        let storage = ErasureStorage::new(backends.clone(), 2).unwrap();
+
+        let large = b"0123456789".repeat(1001);
+        for data in vec![Vec::new(), b"a".to_vec(), b"abc".to_vec(), large.clone()] {
+            let key = storage.set(None, &data).unwrap();
+            assert_eq!(storage.get(key).unwrap(), Some(data));
+        }
+
+        let key = storage.set(None, &large).unwrap();
+        // a shard holds half of the data, plus the header
+        let shard = backends[0].get(key).unwrap().unwrap();
+        assert_eq!(shard.len(), HEADER_SIZE + (large.len() + 1) / 2);
+
+        // any 2 shards are enough to read the record
+        backends[0].delete(key).unwrap();
+        backends[2].set(Some(key), b"corrupted").unwrap();
+        assert_eq!(storage.get(key).unwrap(), Some(large.clone()));
+
+        backends[3].set(Some(key), b"corrupted").unwrap();
+        assert_eq!(storage.get(key).is_err(), true);
+
+        storage.delete(key).unwrap();
+        assert_eq!(storage.get(key).unwrap(), None);
+        assert_eq!(storage.get(1000).unwrap(), None);
+    }
+
+    #[test]
+    fn erasure_storage_update() {
+        let backends = backends(3);
+        let storage = ErasureStorage::new(backends.clone(), 1).unwrap();
+
+        let key = storage.set(None, b"first version").unwrap();
+        let stale = backends[1].get(key).unwrap().unwrap();
+
+        storage.set(Some(key), b"second version").unwrap();
+        assert_eq!(storage.get(key).unwrap(), Some(b"second version".to_vec()));
+
+        // a backend which missed the update still holds the old shard
+        backends[1].set(Some(key), &stale).unwrap();
+        assert_eq!(storage.get(key).unwrap(), Some(b"second version".to_vec()));
+    }
+
+    #[test]
+    fn erasure_storage_behind() {
+        let mut backends = backends(4);
+        let storage = ErasureStorage::new(backends.clone(), 1).unwrap();
+        let key = storage.set(None, b"first record").unwrap();
+        let shard = backends[3].get(key).unwrap().unwrap();
+
+        // the last backend is replaced by an empty one, which missed the first record
+        backends[3] = MemoryStorage::new();
+        let storage = ErasureStorage::new(backends.clone(), 1).unwrap();
+        let next = storage.set(None, b"second record").unwrap();
+        assert_eq!(next, key + 1);
+
+        // the missing shard was rebuilt before the new shard was appended
+        assert_eq!(backends[3].get(key).unwrap(), Some(shard));
+        assert_eq!(storage.get(key).unwrap(), Some(b"first record".to_vec()));
+        assert_eq!(storage.get(next).unwrap(), Some(b"second record".to_vec()));
+    }
+}
@@ -0,0 +1,339 @@
+//! A storage wrapper which injects faults, to test how the layers above a storage behave when
+//! it fails. Faults are scripted per operation: a rule injects a fault into some of the calls of
+//! an operation, optionally after skipping a number of calls, so a test can fail for example the
+//! second write of a request. A script can be shared by multiple storages, calls are counted
+//! over all of them.
+
+use super::namespaces::Collections;
+use super::{AsyncStorage, Error, Key, Keys};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A storage operation. Batch operations are run as single operations, so a fault can hit a
+/// batch halfway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    Set,
+    Get,
+    Delete,
+    /// any scan over the keys of a collection
+    Keys,
+}
+
+/// A fault which is injected into an operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// the operation fails without being applied
+    Fail,
+    /// the operation is applied, but fails as if its reply was lost. A scan returns its keys
+    /// followed by an error.
+    Lost,
+    /// the operation succeeds after the given delay
+    Delay(Duration),
+    /// the data which is written or read is corrupted. Only applies to `Set` and `Get`.
+    Corrupt,
+}
+
+/// Injects a fault into some of the calls of an operation
+#[derive(Debug, Clone)]
+pub struct Rule<O> {
+    op: O,
+    fault: Fault,
+    skip: usize,
+    times: Option<usize>,
+}
+
+impl<O> Rule<O> {
+    /// creates a rule which injects the fault into the next call of the operation
+    pub fn new(op: O, fault: Fault) -> Self {
+        Rule {
+            op: op,
+            fault: fault,
+            skip: 0,
+            times: Some(1),
+        }
+    }
+
+    /// lets the given number of calls pass before the fault is injected
+    pub fn after(mut self, calls: usize) -> Self {
+        self.skip = calls;
+        self
+    }
+
+    /// injects the fault into the given number of calls
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = Some(times);
+        self
+    }
+
+    /// injects the fault into all calls
+    pub fn always(mut self) -> Self {
+        self.times = None;
+        self
+    }
+}
+
+/// The faults which are injected into the operations of one or more storages
+#[derive(Debug, Clone)]
+pub struct Script<O> {
+    rules: Arc<Mutex<Vec<Rule<O>>>>,
+    calls: Arc<Mutex<HashMap<O, usize>>>,
+}
+
+impl<O> Script<O>
+where
+    O: Copy + Eq + Hash,
+{
+    pub fn new() -> Self {
+        Script {
+            rules: Arc::new(Mutex::new(Vec::new())),
+            calls: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// adds a rule, rules are applied in the order they are added
+    pub fn push(&self, rule: Rule<O>) {
+        self.rules.lock().unwrap().push(rule);
+    }
+
+    /// drops all rules which are not exhausted yet
+    pub fn clear(&self) {
+        self.rules.lock().unwrap().clear();
+    }
+
+    /// the number of calls of an operation so far
+    pub fn calls(&self, op: O) -> usize {
+        self.calls
+            .lock()
+            .unwrap()
+            .get(&op)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// gets the fault to inject into a call of the operation, if any. Delays are waited out
+    /// before returning.
+    pub async fn next(&self, op: O) -> Option<Fault> {
+        *self.calls.lock().unwrap().entry(op).or_default() += 1;
+
+        let fault = {
+            let mut rules = self.rules.lock().unwrap();
+            let mut fault = None;
+            // every rule of the operation sees the call, the first rule which is due wins
+            for rule in rules.iter_mut().filter(|rule| rule.op == op) {
+                if rule.skip > 0 {
+                    rule.skip -= 1;
+                } else if fault.is_none() {
+                    fault = Some(rule.fault);
+                    rule.times = rule.times.map(|times| times - 1);
+                }
+            }
+
+            rules.retain(|rule| rule.times != Some(0));
+            fault
+        };
+
+        match fault {
+            Some(Fault::Delay(delay)) => {
+                tokio::time::delay_for(delay).await;
+                None
+            }
+            fault => fault,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FaultyStorage<S> {
+    inner: S,
+    script: Script<Op>,
+}
+
+impl<S> FaultyStorage<S>
+where
+    S: AsyncStorage,
+{
+    /// wraps a storage with a script of its own
+    pub fn new(inner: S) -> Self {
+        Self::with_script(inner, Script::new())
+    }
+
+    /// wraps a storage with a script which may be shared with other storages
+    pub fn with_script(inner: S, script: Script<Op>) -> Self {
+        FaultyStorage {
+            inner: inner,
+            script: script,
+        }
+    }
+
+    pub fn script(&self) -> &Script<Op> {
+        &self.script
+    }
+
+    async fn scan<F>(&self, keys: F) -> Result<Keys, Error>
+    where
+        F: std::future::Future<Output = Result<Keys, Error>>,
+    {
+        match self.script.next(Op::Keys).await {
+            Some(Fault::Fail) => Err(injected()),
+            Some(Fault::Lost) => Ok(keys
+                .await?
+                .chain(stream::once(async { Err(injected()) }))
+                .boxed()),
+            _ => keys.await,
+        }
+    }
+}
+
+#[async_trait]
+impl<S> AsyncStorage for FaultyStorage<S>
+where
+    S: AsyncStorage,
+{
+    async fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+        match self.script.next(Op::Set).await {
+            Some(Fault::Fail) => Err(injected()),
+            Some(Fault::Lost) => {
+                self.inner.set(key, data).await?;
+                Err(injected())
+            }
+            Some(Fault::Corrupt) => self.inner.set(key, &corrupt(data)).await,
+            _ => self.inner.set(key, data).await,
+        }
+    }
+
+    async fn delete(&self, key: Key) -> Result<(), Error> {
+        match self.script.next(Op::Delete).await {
+            Some(Fault::Fail) => Err(injected()),
+            Some(Fault::Lost) => {
+                self.inner.delete(key).await?;
+                Err(injected())
+            }
+            _ => self.inner.delete(key).await,
+        }
+    }
+
+    async fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+        match self.script.next(Op::Get).await {
+            Some(Fault::Fail) | Some(Fault::Lost) => Err(injected()),
+            Some(Fault::Corrupt) => Ok(self.inner.get(key).await?.map(|data| corrupt(&data))),
+            _ => self.inner.get(key).await,
+        }
+    }
+
+    async fn keys(&self) -> Result<Keys, Error> {
+        self.scan(self.inner.keys()).await
+    }
+
+    async fn rev(&self) -> Result<Keys, Error> {
+        self.scan(self.inner.rev()).await
+    }
+}
+
+/// Wraps every collection of a collection source in a `FaultyStorage`, all collections share
+/// a single script
+#[derive(Clone)]
+pub struct FaultyCollections<C> {
+    inner: C,
+    script: Script<Op>,
+}
+
+impl<C> FaultyCollections<C>
+where
+    C: Collections,
+{
+    pub fn new(inner: C) -> Self {
+        FaultyCollections {
+            inner: inner,
+            script: Script::new(),
+        }
+    }
+
+    pub fn script(&self) -> &Script<Op> {
+        &self.script
+    }
+}
+
+impl<C> Collections for FaultyCollections<C>
+where
+    C: Collections,
+{
+    type Storage = FaultyStorage<C::Storage>;
+
+    fn collection(&self, name: &str) -> Result<Self::Storage, Error> {
+        let storage = self.inner.collection(name)?;
+        Ok(FaultyStorage::with_script(storage, self.script.clone()))
+    }
+}
+
+/// the error returned by an injected fault
+fn injected() -> Error {
+    Error::Protocol("injected fault".into())
+}
+
+/// flips the bits of the first and the last byte of the data
+fn corrupt(data: &[u8]) -> Vec<u8> {
+    let mut data = data.to_vec();
+    if let Some(first) = data.first_mut() {
+        *first = !*first;
+    }
+
+    if let Some(last) = data.last_mut() {
+        *last = !*last;
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    #[tokio::test]
+    async fn faulty_storage() {
+        let inner = MemoryStorage::new();
+        let storage = FaultyStorage::new(inner.clone());
+        let script = storage.script().clone();
+
+        script.push(Rule::new(Op::Set, Fault::Fail).after(1));
+        let key = storage.set(None, b"first").await.unwrap();
+        assert!(storage.set(None, b"second").await.is_err());
+        assert_eq!(storage.set(None, b"third").await.unwrap(), key + 1);
+        assert_eq!(script.calls(Op::Set), 3);
+
+        // a lost reply hides an applied write
+        script.push(Rule::new(Op::Set, Fault::Lost));
+        assert!(storage.set(Some(key), b"updated").await.is_err());
+        assert_eq!(
+            AsyncStorage::get(&inner, key).await.unwrap(),
+            Some(b"updated".to_vec())
+        );
+
+        script.push(Rule::new(Op::Get, Fault::Corrupt));
+        let corrupted = storage.get(key).await.unwrap().unwrap();
+        assert_ne!(corrupted, b"updated".to_vec());
+        assert_eq!(corrupted.len(), b"updated".len());
+        assert_eq!(storage.get(key).await.unwrap(), Some(b"updated".to_vec()));
+
+        script.push(Rule::new(Op::Delete, Fault::Fail).always());
+        assert!(storage.delete(key).await.is_err());
+        assert!(storage.delete_many(&[key, key + 1]).await.is_err());
+        script.clear();
+        storage.delete(key).await.unwrap();
+
+        script.push(Rule::new(Op::Keys, Fault::Lost));
+        let records: Vec<_> = storage.keys().await.unwrap().collect().await;
+        assert_eq!(records.len(), 2);
+        assert!(records[0].is_ok());
+        assert!(records[1].is_err());
+
+        script.push(Rule::new(Op::Get, Fault::Delay(Duration::from_millis(10))).times(2));
+        let results = storage.get_many(&[key, key + 1]).await.unwrap();
+        assert_eq!(results, vec![None, Some(b"third".to_vec())]);
+    }
+}
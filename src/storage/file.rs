@@ -0,0 +1,948 @@
+//! An append-only file storage backend, written in pure rust. It follows the semantics of a 0-db
+//! running in sequential mode, and can be used wherever a zdb `Collection` is used.
+//!
+//! Every collection is a directory with one or more segment files. Records are only ever appended
+//! to the last segment: an update appends a new version of the record, and a delete appends a
+//! tombstone. The location of the latest version of every key is kept in memory, and is rebuilt by
+//! scanning the segments when the collection is opened.
+//!
+//! Every record is protected by a checksum. If the process crashes in the middle of a write, the
+//! partially written record at the end of the last segment is discarded on the next open.
+//!
+//! By default every write is synced to disk before it is acknowledged. With `SyncPolicy::Every`
+//! writes are synced in batches, and with `SyncPolicy::Never` syncing is left to the operating
+//! system. In both cases, writes which were acknowledged but not synced yet are lost if the
+//! machine loses power.
+//!
+//! Old versions and tombstones stay in the segments until the collection is compacted, which
+//! rewrites the live records to a new segment and removes the old segments.
+//!
+//! Every segment starts with a segment header, which holds the version of the record format.
+//! Segments without a segment header, or of another format version, are never read or changed.
+
+use super::compaction::{Compactor, Usage};
+use super::{Error, Key, KeyIter, Record, Storage};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Default maximum size of a segment file before a new segment is started.
+const SEGMENT_MAX_SIZE: u64 = 256 * 1024 * 1024;
+/// Size of the record header: crc (4), key (8), timestamp (4), flags (1), length (4)
+const HEADER_SIZE: usize = 21;
+/// Size of the segment header: magic (4), format version (4)
+const SEGMENT_HEADER_SIZE: usize = 8;
+const SEGMENT_MAGIC: &[u8; 4] = b"BCSG";
+/// Version of the record format
+const FORMAT_VERSION: u32 = 1;
+const SEGMENT_PREFIX: &str = "data-";
+const FLAG_DELETED: u8 = 0x1;
+
+/// When written records are synced to disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// every write is synced before it is acknowledged
+    Always,
+    /// writes are synced once the given number of writes is not synced yet, and when a segment is
+    /// full or the collection is closed
+    Every(usize),
+    /// writes are only synced when a segment is full or the collection is closed
+    Never,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy::Always
+    }
+}
+
+impl std::str::FromStr for SyncPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<SyncPolicy, Error> {
+        let policy = match s {
+            "always" => SyncPolicy::Always,
+            "never" => SyncPolicy::Never,
+            n => match n.parse() {
+                Ok(n) if n > 0 => SyncPolicy::Every(n),
+                _ => {
+                    return Err(Error::Protocol(format!(
+                        "invalid sync policy '{}', expecting always, never or a number of writes",
+                        s
+                    )))
+                }
+            },
+        };
+
+        Ok(policy)
+    }
+}
+
+/// A directory holding one sub directory per collection.
+#[derive(Clone)]
+pub struct FileStorage {
+    root: PathBuf,
+    segment_size: u64,
+    sync: SyncPolicy,
+    collections: Arc<Mutex<HashMap<String, Collection>>>,
+}
+
+/// A single collection. Clones of a collection share the same underlying files.
+#[derive(Clone)]
+pub struct Collection {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    segment_size: u64,
+    sync: SyncPolicy,
+    /// number of writes which are not synced yet
+    unsynced: usize,
+    /// location of the latest version of every key, `None` if the key is deleted. Since keys are
+    /// sequential, the next key to assign is the length of the index.
+    index: Vec<Option<Location>>,
+    /// the last segment, opened for appending
+    writer: File,
+    segment: u32,
+    /// size of the last segment
+    offset: u64,
+    /// size of all segments, live records and dead records
+    size: u64,
+    /// cached read handles for segments
+    readers: HashMap<u32, File>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    segment: u32,
+    offset: u64,
+    length: u32,
+    timestamp: u32,
+}
+
+/// The outcome of the scan of a segment
+struct Scan {
+    /// whether the segment header is complete
+    header: bool,
+    /// length of the valid part of the segment
+    valid: u64,
+    /// actual size of the segment
+    size: u64,
+}
+
+struct Header {
+    crc: u32,
+    key: Key,
+    timestamp: u32,
+    flags: u8,
+    length: u32,
+}
+
+impl FileStorage {
+    /// Create a new file storage in the given directory. The directory is created if it does
+    /// not exist yet.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Result<FileStorage, Error> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(FileStorage {
+            root,
+            segment_size: SEGMENT_MAX_SIZE,
+            sync: SyncPolicy::default(),
+            collections: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Set the maximum size of a segment file. This only affects collections opened after this
+    /// call.
+    pub fn with_segment_size(mut self, size: u64) -> Self {
+        self.segment_size = size;
+        self
+    }
+
+    /// Set when writes are synced to disk. This only affects collections opened after this call.
+    pub fn with_sync(mut self, sync: SyncPolicy) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Get a reference to a `Collection`, opening it if needed.
+    pub fn collection(&self, name: &str) -> Result<Collection, Error> {
+        self.open(name, true)
+    }
+
+    /// Get a reference to a collection. If the collection does not exist yet, it is only created
+    /// if `create` is set.
+    fn open(&self, name: &str, create: bool) -> Result<Collection, Error> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(Error::Protocol(format!(
+                "invalid collection name '{}'",
+                name
+            )));
+        }
+
+        let mut collections = self.collections.lock().map_err(|_| Error::Other)?;
+        if let Some(collection) = collections.get(name) {
+            return Ok(collection.clone());
+        }
+
+        let dir = self.root.join(name);
+        if !create && !dir.is_dir() {
+            return Err(Error::Protocol(format!(
+                "collection '{}' does not exist",
+                name
+            )));
+        }
+
+        let inner = Inner::open(dir, self.segment_size, self.sync)?;
+        let collection = Collection {
+            inner: Arc::new(Mutex::new(inner)),
+        };
+        collections.insert(name.into(), collection.clone());
+
+        Ok(collection)
+    }
+}
+
+impl Compactor for FileStorage {
+    fn collections(&self) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_owned());
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    fn usage(&self, collection: &str) -> Result<Usage, Error> {
+        Ok(self.open(collection, false)?.lock()?.usage())
+    }
+
+    fn compact(&self, collection: &str) -> Result<u64, Error> {
+        self.open(collection, false)?.lock()?.compact()
+    }
+}
+
+impl Collection {
+    fn lock(&self) -> Result<MutexGuard<Inner>, Error> {
+        self.inner.lock().map_err(|_| Error::Other)
+    }
+}
+
+impl Storage for Collection {
+    fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+        let mut inner = self.lock()?;
+        let key = match key {
+            Some(key) if inner.location(key).is_some() => key,
+            Some(_) => {
+                return Err(Error::Protocol(
+                    "can't update a key which does not exist".into(),
+                ))
+            }
+            None => inner.next_key()?,
+        };
+
+        inner.append(key, 0, data)?;
+        Ok(key)
+    }
+
+    fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+        let mut inner = self.lock()?;
+        let location = match inner.location(key) {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+
+        inner.read(key, location).map(Some)
+    }
+
+    fn delete(&self, key: Key) -> Result<(), Error> {
+        let mut inner = self.lock()?;
+        if inner.location(key).is_none() {
+            return Ok(());
+        }
+
+        inner.append(key, FLAG_DELETED, &[])
+    }
+
+    fn keys(&self) -> Result<KeyIter, Error> {
+        let inner = self.lock()?;
+        Ok(Box::new(inner.records().into_iter().map(Ok)))
+    }
+
+    fn rev(&self) -> Result<KeyIter, Error> {
+        let inner = self.lock()?;
+        Ok(Box::new(inner.records().into_iter().rev().map(Ok)))
+    }
+}
+
+impl Inner {
+    /// Open a collection directory, rebuilding the in memory index from the segments.
+    fn open(dir: PathBuf, segment_size: u64, sync: SyncPolicy) -> Result<Inner, Error> {
+        fs::create_dir_all(&dir)?;
+
+        let segments = segments(&dir)?;
+        let last = segments.last().copied().unwrap_or(0);
+        let mut index = Vec::new();
+        let mut total = 0;
+        let mut append = false;
+        for &segment in segments.iter() {
+            let path = segment_path(&dir, segment);
+            let scan = scan(&path, segment, &mut index)?;
+            if !scan.header {
+                // the segment was created, but its header was not completely written
+                if segment == last {
+                    continue;
+                }
+
+                return Err(corrupted(&path, 0));
+            }
+
+            total += scan.valid - SEGMENT_HEADER_SIZE as u64;
+            append = segment == last;
+            if scan.valid == scan.size {
+                continue;
+            }
+
+            // only the last segment can hold an interrupted write
+            if segment != last {
+                return Err(corrupted(&path, scan.valid));
+            }
+
+            // a write was interrupted, drop the partial record so we can append after the last
+            // complete one
+            warn!(
+                "discarding {} bytes of incomplete data at the end of '{:?}'",
+                scan.size - scan.valid,
+                path
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(scan.valid)?;
+        }
+
+        let writer = if append {
+            OpenOptions::new()
+                .append(true)
+                .open(segment_path(&dir, last))?
+        } else {
+            // a new collection, or a segment without a complete header, which holds no records
+            create_segment(&dir, last)?
+        };
+        let offset = writer.metadata()?.len();
+
+        Ok(Inner {
+            dir,
+            segment_size,
+            sync,
+            unsynced: 0,
+            index,
+            writer,
+            segment: last,
+            offset,
+            size: total,
+            readers: HashMap::new(),
+        })
+    }
+
+    fn next_key(&self) -> Result<Key, Error> {
+        self.index
+            .len()
+            .try_into()
+            .map_err(|_| Error::Protocol("no more keys available in this collection".into()))
+    }
+
+    fn location(&self, key: Key) -> Option<Location> {
+        self.index.get(key as usize).copied().flatten()
+    }
+
+    fn records(&self) -> Vec<Record> {
+        self.index
+            .iter()
+            .enumerate()
+            .filter_map(|(key, location)| {
+                location.map(|location| Record {
+                    key: key as Key,
+                    timestamp: Some(location.timestamp),
+                    size: Some(location.length),
+                })
+            })
+            .collect()
+    }
+
+    /// Append a record to the last segment, and point the key to it.
+    fn append(&mut self, key: Key, flags: u8, data: &[u8]) -> Result<(), Error> {
+        self.write(key, flags, now(), data)
+    }
+
+    /// Append a record with the given timestamp to the last segment, and point the key to it.
+    fn write(&mut self, key: Key, flags: u8, timestamp: u32, data: &[u8]) -> Result<(), Error> {
+        let length: u32 = data
+            .len()
+            .try_into()
+            .map_err(|_| Error::Protocol("data too large".into()))?;
+        let record_size = (HEADER_SIZE + data.len()) as u64;
+        let empty = self.offset <= SEGMENT_HEADER_SIZE as u64;
+        if !empty && self.offset + record_size > self.segment_size {
+            self.rotate()?;
+        }
+
+        let header = Header {
+            crc: 0,
+            key,
+            timestamp,
+            flags,
+            length,
+        };
+        let buf = header.encode(data);
+
+        if let Err(err) = self.writer.write_all(&buf) {
+            // make sure a partial write does not end up in the middle of the segment
+            self.writer.set_len(self.offset)?;
+            return Err(err.into());
+        }
+
+        let location = Location {
+            segment: self.segment,
+            offset: self.offset,
+            length,
+            timestamp: header.timestamp,
+        };
+        self.offset += record_size;
+        self.size += record_size;
+        self.unsynced += 1;
+        let due = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Every(n) => self.unsynced >= n,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+
+        let idx = key as usize;
+        if self.index.len() <= idx {
+            self.index.resize(idx + 1, None);
+        }
+        self.index[idx] = if flags & FLAG_DELETED > 0 {
+            None
+        } else {
+            Some(location)
+        };
+
+        Ok(())
+    }
+
+    /// Sync the written records of the last segment to disk.
+    fn sync(&mut self) -> Result<(), Error> {
+        self.writer.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Start a new segment, all future writes go to the new segment. The last segment is synced
+    /// first, so only writes to the last segment can be lost.
+    fn rotate(&mut self) -> Result<(), Error> {
+        if self.unsynced > 0 {
+            self.sync()?;
+        }
+
+        let segment = self.segment + 1;
+        self.writer = create_segment(&self.dir, segment)?;
+        self.segment = segment;
+        self.offset = SEGMENT_HEADER_SIZE as u64;
+        Ok(())
+    }
+
+    /// Space used by the live records, and by old versions and tombstones.
+    fn usage(&self) -> Usage {
+        let live = self
+            .index
+            .iter()
+            .flatten()
+            .map(|location| (HEADER_SIZE + location.length as usize) as u64)
+            .sum();
+
+        Usage {
+            live,
+            dead: self.size.saturating_sub(live),
+        }
+    }
+
+    /// Rewrite the live records to a new segment, and remove all older segments. Records keep
+    /// their key and timestamp. Returns the number of reclaimed bytes.
+    ///
+    /// Old segments are only removed once all live records are written, and in the order they
+    /// were written. If the compaction is interrupted, the records are found twice on the next
+    /// open, and the latest copy is used.
+    fn compact(&mut self) -> Result<u64, Error> {
+        let before = self.size;
+        if self.usage().dead == 0 {
+            return Ok(0);
+        }
+
+        let old = segments(&self.dir)?;
+        self.rotate()?;
+        let first = self.segment;
+
+        for idx in 0..self.index.len() {
+            let location = match self.index[idx] {
+                Some(location) if location.segment < first => location,
+                _ => continue,
+            };
+
+            let key = idx as Key;
+            let data = self.read(key, location)?;
+            self.write(key, 0, location.timestamp, &data)?;
+        }
+
+        // keys are never reused, so the last key is kept if it was deleted, since the next key
+        // is derived from it when the collection is opened
+        if let Some(None) = self.index.last() {
+            let key = (self.index.len() - 1) as Key;
+            self.append(key, FLAG_DELETED, &[])?;
+        }
+
+        self.sync()?;
+        self.readers.clear();
+        for segment in old {
+            fs::remove_file(segment_path(&self.dir, segment))?;
+        }
+
+        // only the rewritten records are left
+        let written = self.size - before;
+        self.size = written;
+        Ok(before.saturating_sub(written))
+    }
+
+    fn read(&mut self, key: Key, location: Location) -> Result<Vec<u8>, Error> {
+        if !self.readers.contains_key(&location.segment) {
+            let file = File::open(segment_path(&self.dir, location.segment))?;
+            self.readers.insert(location.segment, file);
+        }
+        let file = &self.readers[&location.segment];
+
+        let mut buf = vec![0; HEADER_SIZE + location.length as usize];
+        file.read_exact_at(&mut buf, location.offset)?;
+
+        let header = Header::decode(&buf[..HEADER_SIZE]);
+        if header.key != key || header.crc != checksum(&buf[4..]) {
+            return Err(Error::IO(Some(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record with key '{}' is corrupted", key),
+            ))));
+        }
+
+        Ok(buf.split_off(HEADER_SIZE))
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if self.unsynced == 0 {
+            return;
+        }
+
+        if let Err(err) = self.sync() {
+            error!("failed to sync segment '{}': {}", self.segment, err);
+        }
+    }
+}
+
+impl Header {
+    /// Encode the header followed by the data, and fill in the checksum.
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + data.len());
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&self.key.to_le_bytes());
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        buf.push(self.flags);
+        buf.extend_from_slice(&self.length.to_le_bytes());
+        buf.extend_from_slice(data);
+
+        let crc = checksum(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Decode a header, the input must be at least as long as the header.
+    fn decode(buf: &[u8]) -> Header {
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        Header {
+            crc: u32_at(0),
+            key: Key::from_le_bytes(buf[4..12].try_into().unwrap()),
+            timestamp: u32_at(12),
+            flags: buf[16],
+            length: u32_at(17),
+        }
+    }
+}
+
+/// Scan a segment, and update the index with all the records in it.
+fn scan(path: &Path, segment: u32, index: &mut Vec<Option<Location>>) -> Result<Scan, Error> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    if !read_segment_header(&mut reader)? {
+        return Ok(Scan {
+            header: false,
+            valid: 0,
+            size,
+        });
+    }
+
+    let mut offset = SEGMENT_HEADER_SIZE as u64;
+    let mut header_buf = [0; HEADER_SIZE];
+
+    while offset < size {
+        if read_full(&mut reader, &mut header_buf)? < HEADER_SIZE {
+            break;
+        }
+        let header = Header::decode(&header_buf);
+        // the length is not verified yet, a record can't be larger than the rest of the segment
+        if header.length as u64 > size - offset - HEADER_SIZE as u64 {
+            break;
+        }
+
+        let mut data = vec![0; header.length as usize];
+        if read_full(&mut reader, &mut data)? < data.len() {
+            break;
+        }
+
+        let mut crc_input = header_buf[4..].to_vec();
+        crc_input.extend_from_slice(&data);
+        if header.crc != checksum(&crc_input) {
+            break;
+        }
+
+        let idx = header.key as usize;
+        if index.len() <= idx {
+            index.resize(idx + 1, None);
+        }
+        index[idx] = if header.flags & FLAG_DELETED > 0 {
+            None
+        } else {
+            Some(Location {
+                segment,
+                offset,
+                length: header.length,
+                timestamp: header.timestamp,
+            })
+        };
+
+        offset += (HEADER_SIZE + data.len()) as u64;
+    }
+
+    Ok(Scan {
+        header: true,
+        valid: offset,
+        size,
+    })
+}
+
+/// Read and verify the segment header. Returns false if the segment header is incomplete, which
+/// only happens if the segment holds no records.
+fn read_segment_header<R: Read>(reader: &mut R) -> Result<bool, Error> {
+    let mut buf = [0; SEGMENT_HEADER_SIZE];
+    let read = read_full(reader, &mut buf)?;
+    let magic = std::cmp::min(read, SEGMENT_MAGIC.len());
+    if buf[..magic] != SEGMENT_MAGIC[..magic] {
+        return Err(Error::IO(Some(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "segment has no segment header",
+        ))));
+    }
+
+    if read < SEGMENT_HEADER_SIZE {
+        return Ok(false);
+    }
+
+    match u32::from_le_bytes(buf[SEGMENT_MAGIC.len()..].try_into().unwrap()) {
+        FORMAT_VERSION => Ok(true),
+        version => Err(Error::IO(Some(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported segment format version {}", version),
+        )))),
+    }
+}
+
+/// Create an empty segment, which only holds the segment header, and open it for appending. An
+/// existing segment is overwritten.
+fn create_segment(dir: &Path, segment: u32) -> Result<File, Error> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, segment))?;
+    file.set_len(0)?;
+
+    let mut header = SEGMENT_MAGIC.to_vec();
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    file.write_all(&header)?;
+    file.sync_all()?;
+
+    Ok(file)
+}
+
+fn corrupted(path: &Path, offset: u64) -> Error {
+    Error::IO(Some(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("segment '{:?}' is corrupted at offset {}", path, offset),
+    )))
+}
+
+/// Read until the buffer is full or the end of the input is reached, returns the amount of bytes
+/// read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(read)
+}
+
+/// List the ids of the segments in a collection directory, in order.
+fn segments(dir: &Path) -> Result<Vec<u32>, Error> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let id = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(SEGMENT_PREFIX))
+            .and_then(|id| id.parse::<u32>().ok());
+        if let Some(id) = id {
+            segments.push(id);
+        }
+    }
+
+    segments.sort();
+    Ok(segments)
+}
+
+fn segment_path(dir: &Path, segment: u32) -> PathBuf {
+    dir.join(format!("{}{:05}", SEGMENT_PREFIX, segment))
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fresh(dir: &str) -> FileStorage {
+        let _ = fs::remove_dir_all(dir);
+        FileStorage::new(dir).expect("failed to create file storage")
+    }
+
+    #[test]
+    fn file_storage() {
+        let storage = fresh("/tmp/bcdb-file-storage.test")
+            .collection("test")
+            .unwrap();
+
+        let key1 = storage.set(None, &[0, 1, 2, 3]).unwrap();
+        let key2 = storage.set(None, &[1, 2, 3, 4]).unwrap();
+        let key3 = storage.set(None, &[2, 3, 4, 5]).unwrap();
+
+        assert_eq!(key1, 0);
+        assert_eq!(key2, 1);
+        assert_eq!(key3, 2);
+
+        assert_eq!(Some(vec![0, 1, 2, 3]), storage.get(key1).unwrap());
+        assert_eq!(Some(vec![1, 2, 3, 4]), storage.get(key2).unwrap());
+        assert_eq!(Some(vec![2, 3, 4, 5]), storage.get(key3).unwrap());
+
+        // overwrite key
+        let key4 = storage.set(Some(key1), &[3, 4, 5, 6]).unwrap();
+        assert_eq!(key1, key4);
+        assert_eq!(Some(vec![3, 4, 5, 6]), storage.get(key1).unwrap());
+
+        // can't set a key which was never assigned
+        assert_eq!(storage.set(Some(17), &[1]).is_err(), true);
+
+        // check for nonexisting keys
+        assert_eq!(None, storage.get(3).unwrap());
+        assert_eq!(None, storage.get(32_413_214).unwrap());
+
+        storage.delete(key2).unwrap();
+        assert_eq!(None, storage.get(key2).unwrap());
+
+        let keys = storage
+            .keys()
+            .unwrap()
+            .map(|r| r.unwrap().key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![key1, key3]);
+        let keys = storage
+            .rev()
+            .unwrap()
+            .map(|r| r.unwrap().key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![key3, key1]);
+
+        let record = storage.keys().unwrap().next().unwrap().unwrap();
+        assert_eq!(record.size, Some(4));
+        assert_eq!(record.timestamp.is_some(), true);
+
+        // deleted keys are not reused
+        assert_eq!(storage.set(None, &[1]).unwrap(), 3);
+    }
+
+    #[test]
+    fn file_storage_reopen() {
+        const DIR: &str = "/tmp/bcdb-file-storage-reopen.test";
+        let storage = fresh(DIR).with_segment_size(64);
+        let collection = storage.collection("test").unwrap();
+
+        let mut keys = vec![];
+        for i in 0..10u8 {
+            keys.push(collection.set(None, &[i; 20]).unwrap());
+        }
+        collection.set(Some(keys[2]), b"updated").unwrap();
+        collection.delete(keys[5]).unwrap();
+        drop(collection);
+        drop(storage);
+
+        // multiple segments must have been created
+        assert_eq!(
+            fs::read_dir(format!("{}/test", DIR)).unwrap().count() > 1,
+            true
+        );
+
+        let collection = FileStorage::new(DIR).unwrap().collection("test").unwrap();
+        assert_eq!(collection.get(keys[0]).unwrap(), Some(vec![0; 20]));
+        assert_eq!(collection.get(keys[2]).unwrap(), Some(b"updated".to_vec()));
+        assert_eq!(collection.get(keys[5]).unwrap(), None);
+        assert_eq!(collection.get(keys[9]).unwrap(), Some(vec![9; 20]));
+        assert_eq!(collection.keys().unwrap().count(), 9);
+        assert_eq!(collection.set(None, &[1]).unwrap(), 10);
+    }
+
+    #[test]
+    fn file_storage_torn_write() {
+        const DIR: &str = "/tmp/bcdb-file-storage-torn.test";
+        let collection = fresh(DIR).collection("test").unwrap();
+        let key = collection.set(None, b"complete record").unwrap();
+        drop(collection);
+
+        // simulate a crash in the middle of a write, by appending half a record
+        let header = Header {
+            crc: 0,
+            key: key + 1,
+            timestamp: now(),
+            flags: 0,
+            length: 100,
+        };
+        let record = header.encode(&[7; 100]);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(segment_path(Path::new(&format!("{}/test", DIR)), 0))
+            .unwrap();
+        file.write_all(&record[..50]).unwrap();
+        drop(file);
+
+        let collection = FileStorage::new(DIR).unwrap().collection("test").unwrap();
+        assert_eq!(
+            collection.get(key).unwrap(),
+            Some(b"complete record".to_vec())
+        );
+        assert_eq!(collection.get(key + 1).unwrap(), None);
+        assert_eq!(collection.keys().unwrap().count(), 1);
+
+        // new writes go after the last complete record
+        let next = collection.set(None, b"after crash").unwrap();
+        assert_eq!(next, key + 1);
+        assert_eq!(collection.get(next).unwrap(), Some(b"after crash".to_vec()));
+    }
+
+    #[test]
+    fn file_storage_corrupt_length() {
+        const DIR: &str = "/tmp/bcdb-file-storage-corrupt-length.test";
+        let storage = fresh(DIR).with_sync(SyncPolicy::Every(2));
+        let collection = storage.collection("test").unwrap();
+        let key = collection.set(None, b"complete record").unwrap();
+        drop(collection);
+        drop(storage);
+
+        // a header which claims a record far larger than the segment is never allocated
+        let header = Header {
+            crc: 0,
+            key: key + 1,
+            timestamp: now(),
+            flags: 0,
+            length: u32::max_value(),
+        };
+        let record = header.encode(&[]);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(segment_path(Path::new(&format!("{}/test", DIR)), 0))
+            .unwrap();
+        file.write_all(&record).unwrap();
+        drop(file);
+
+        let collection = FileStorage::new(DIR).unwrap().collection("test").unwrap();
+        assert_eq!(
+            collection.get(key).unwrap(),
+            Some(b"complete record".to_vec())
+        );
+        assert_eq!(collection.keys().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn file_storage_unreadable_segment() {
+        const DIR: &str = "/tmp/bcdb-file-storage-unreadable.test";
+        let _ = fs::remove_dir_all(DIR);
+        let dir = PathBuf::from(format!("{}/test", DIR));
+        fs::create_dir_all(&dir).unwrap();
+
+        // a segment without a segment header is not truncated
+        let data = vec![0xab; 100];
+        fs::write(segment_path(&dir, 0), &data).unwrap();
+        assert!(FileStorage::new(DIR).unwrap().collection("test").is_err());
+        assert_eq!(fs::read(segment_path(&dir, 0)).unwrap(), data);
+
+        // nor is a segment of an unknown format version
+        let mut data = SEGMENT_MAGIC.to_vec();
+        data.extend_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        data.extend_from_slice(&[0; 50]);
+        fs::write(segment_path(&dir, 0), &data).unwrap();
+        assert!(FileStorage::new(DIR).unwrap().collection("test").is_err());
+        assert_eq!(fs::read(segment_path(&dir, 0)).unwrap(), data);
+    }
+
+    #[test]
+    fn sync_policy() {
+        assert_eq!("always".parse::<SyncPolicy>().unwrap(), SyncPolicy::Always);
+        assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+        assert_eq!("100".parse::<SyncPolicy>().unwrap(), SyncPolicy::Every(100));
+        assert!("0".parse::<SyncPolicy>().is_err());
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+    }
+}
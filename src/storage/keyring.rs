@@ -0,0 +1,323 @@
+//! A keyring of versioned key-encryption keys (KEKs). KEKs are random keys which are used to wrap
+//! the per-record data keys of an `EncryptedStorage`. Every collection has its own keyring, the
+//! keyrings are persisted in a storage collection, where every KEK is sealed with the root key of
+//! its collection (a key derived from the identity).
+//!
+//! Rotating the keyring adds a new KEK version which is used to wrap all new data keys. Older KEKs
+//! are kept, so records wrapped with them stay readable until they are re-wrapped.
+//!
+//! Before keys were derived per collection, the identity secret key was used as root key of a
+//! single keyring shared by all collections, and before envelope encryption it was used to encrypt
+//! the records directly. A keyring can be given this legacy key to read data written under these
+//! schemes.
+
+use super::{Error, Storage};
+use aead::{generic_array::GenericArray, Aead, NewAead, Payload};
+use aes_gcm::{aead, Aes256Gcm};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::sync::{Arc, RwLock};
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+
+/// Size of a sealed key: nonce (12), sealed key (32 + 16 bytes tag)
+const SEALED_KEY_SIZE: usize = NONCE_SIZE + KEY_SIZE + 16;
+
+/// Size of a key record of the shared keyring: version (4), sealed key (60). Key records of a
+/// collection keyring are prefixed with the collection name, so they are always larger.
+const LEGACY_RECORD_SIZE: usize = 4 + SEALED_KEY_SIZE;
+
+#[derive(Clone)]
+pub struct Keyring {
+    collection: String,
+    root: Aes256Gcm,
+    legacy: Option<Aes256Gcm>,
+    inner: Arc<RwLock<Inner>>,
+}
+
+struct Inner {
+    current: u32,
+    keys: BTreeMap<u32, Aes256Gcm>,
+}
+
+impl Keyring {
+    /// Create an empty keyring for a collection, KEKs of the collection are sealed with the given
+    /// root key. Use `load` to load the KEKs of the collection.
+    ///
+    /// # panics
+    ///
+    /// This function will panic if the root key is not 32 bytes long, or if the collection name
+    /// is longer than 255 bytes.
+    pub fn new(collection: &str, root: &[u8]) -> Keyring {
+        assert_eq!(root.len(), KEY_SIZE);
+        assert!(collection.len() <= u8::max_value() as usize);
+        Keyring {
+            collection: collection.into(),
+            root: Aes256Gcm::new(GenericArray::clone_from_slice(root)),
+            legacy: None,
+            inner: Arc::new(RwLock::new(Inner {
+                current: 0,
+                keys: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Set the legacy key, which is used to read KEKs of the shared keyring, and records which
+    /// were encrypted without a data key.
+    ///
+    /// # panics
+    ///
+    /// This function will panic if the legacy key is not 32 bytes long.
+    pub fn with_legacy(mut self, key: &[u8]) -> Keyring {
+        assert_eq!(key.len(), KEY_SIZE);
+        self.legacy = Some(Aes256Gcm::new(GenericArray::clone_from_slice(key)));
+        self
+    }
+
+    /// Load the KEKs of the collection from the given storage. If the storage holds no keys for
+    /// the collection yet, a first KEK is created and stored. KEKs of the shared keyring are
+    /// loaded as well if a legacy key is set, but new keys are always wrapped with a KEK of the
+    /// collection.
+    pub fn load<S: Storage>(self, storage: &S) -> Result<Keyring, Error> {
+        let mut owned = false;
+        for record in storage.keys()? {
+            let record = record?;
+            let data = match storage.get(record.key)? {
+                Some(data) => data,
+                None => continue,
+            };
+
+            if data.len() == LEGACY_RECORD_SIZE {
+                let legacy = match self.legacy {
+                    Some(ref legacy) => legacy,
+                    None => continue,
+                };
+
+                let (version, key) = unseal(legacy, &data, 4)?;
+                self.add(version, &key);
+                continue;
+            }
+
+            let prefix = self.prefix(&[]);
+            if !data.starts_with(&prefix) {
+                // a key of another collection
+                continue;
+            }
+
+            let (version, key) = unseal(&self.root, &data, prefix.len() + 4)?;
+            self.add(version, &key);
+            owned = true;
+        }
+
+        if !owned {
+            self.rotate(storage)?;
+        }
+
+        Ok(self)
+    }
+
+    /// Add a new KEK version and store it in the given storage. The new KEK is used for all keys
+    /// wrapped after this call. Returns the new version.
+    pub fn rotate<S: Storage>(&self, storage: &S) -> Result<u32, Error> {
+        let version = self.version() + 1;
+        let key = random(KEY_SIZE);
+
+        // the collection and version are authenticated as well
+        let mut record = self.prefix(&version.to_le_bytes());
+        let sealed = encrypt(&self.root, &key, &record)?;
+        record.extend(sealed);
+
+        storage.set(None, &record)?;
+        self.add(version, &key);
+
+        Ok(version)
+    }
+
+    /// The collection of the keyring
+    pub fn collection(&self) -> &str {
+        &self.collection
+    }
+
+    /// The current KEK version
+    pub fn version(&self) -> u32 {
+        self.inner.read().unwrap().current
+    }
+
+    /// The legacy cipher, which was used to encrypt records before envelope encryption.
+    pub fn legacy(&self) -> Option<&Aes256Gcm> {
+        self.legacy.as_ref()
+    }
+
+    /// Wrap a data key with the current KEK. Returns the KEK version, and the wrapped key
+    /// (nonce followed by the sealed key).
+    pub fn wrap(&self, key: &[u8]) -> Result<(u32, Vec<u8>), Error> {
+        let inner = self.inner.read().unwrap();
+        let kek = match inner.keys.get(&inner.current) {
+            Some(kek) => kek,
+            None => return Err(Error::Crypto),
+        };
+
+        Ok((inner.current, encrypt(kek, key, &[])?))
+    }
+
+    /// Unwrap a data key which was wrapped with the given KEK version.
+    pub fn unwrap(&self, version: u32, wrapped: &[u8]) -> Result<Vec<u8>, Error> {
+        let inner = self.inner.read().unwrap();
+        let kek = match inner.keys.get(&version) {
+            Some(kek) => kek,
+            None => {
+                return Err(Error::Protocol(format!(
+                    "unknown key encryption key version {}",
+                    version
+                )))
+            }
+        };
+
+        decrypt(kek, wrapped, &[])
+    }
+
+    fn add(&self, version: u32, key: &[u8]) {
+        let mut inner = self.inner.write().unwrap();
+        inner
+            .keys
+            .insert(version, Aes256Gcm::new(GenericArray::clone_from_slice(key)));
+        if version > inner.current {
+            inner.current = version;
+        }
+    }
+
+    /// The prefix of a key record of this collection: the length of the collection name, the
+    /// name itself, followed by the given bytes.
+    fn prefix(&self, suffix: &[u8]) -> Vec<u8> {
+        let mut prefix = Vec::with_capacity(1 + self.collection.len() + suffix.len());
+        prefix.push(self.collection.len() as u8);
+        prefix.extend_from_slice(self.collection.as_bytes());
+        prefix.extend_from_slice(suffix);
+        prefix
+    }
+}
+
+/// Unseal a key record, the version is found right before the given offset, and everything
+/// up to the offset is authenticated.
+fn unseal(cipher: &Aes256Gcm, record: &[u8], offset: usize) -> Result<(u32, Vec<u8>), Error> {
+    if record.len() != offset + SEALED_KEY_SIZE {
+        return Err(Error::Protocol("invalid key record".into()));
+    }
+
+    let version = u32::from_le_bytes(record[offset - 4..offset].try_into().unwrap());
+    let key = decrypt(cipher, &record[offset..], &record[..offset])?;
+    Ok((version, key))
+}
+
+/// Fill a buffer of the given length with random bytes
+pub fn random(len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    OsRng.fill_bytes(&mut buf);
+    buf
+}
+
+/// Encrypt data with a random nonce. The returned buffer is the nonce followed by the ciphertext.
+pub fn encrypt(cipher: &Aes256Gcm, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut buf = random(NONCE_SIZE);
+    let nonce = GenericArray::clone_from_slice(&buf);
+    buf.extend(cipher.encrypt(&nonce, Payload { msg: data, aad })?);
+    Ok(buf)
+}
+
+/// Decrypt data that was encrypted with `encrypt`.
+pub fn decrypt(cipher: &Aes256Gcm, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.len() < NONCE_SIZE {
+        return Err(Error::Crypto);
+    }
+
+    let nonce = GenericArray::clone_from_slice(&data[..NONCE_SIZE]);
+    let plaintext = cipher.decrypt(
+        &nonce,
+        Payload {
+            msg: &data[NONCE_SIZE..],
+            aad,
+        },
+    )?;
+
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    #[test]
+    fn keyring_load() {
+        let storage = MemoryStorage::new();
+        let root = random(KEY_SIZE);
+
+        let keyring = Keyring::new("objects", &root).load(&storage).unwrap();
+        assert_eq!(keyring.version(), 1);
+
+        let (version, wrapped) = keyring.wrap(b"some data key").unwrap();
+        assert_eq!(version, 1);
+
+        assert_eq!(keyring.rotate(&storage).unwrap(), 2);
+        let (version, rewrapped) = keyring.wrap(b"some data key").unwrap();
+        assert_eq!(version, 2);
+
+        // a reloaded keyring knows all versions
+        let keyring = Keyring::new("objects", &root).load(&storage).unwrap();
+        assert_eq!(keyring.version(), 2);
+        assert_eq!(keyring.unwrap(1, &wrapped).unwrap(), b"some data key");
+        assert_eq!(keyring.unwrap(2, &rewrapped).unwrap(), b"some data key");
+        assert_eq!(keyring.unwrap(2, &wrapped).is_err(), true);
+        assert_eq!(keyring.unwrap(3, &wrapped).is_err(), true);
+
+        // the keyring can't be loaded with another root key
+        assert_eq!(
+            Keyring::new("objects", &random(KEY_SIZE))
+                .load(&storage)
+                .is_err(),
+            true
+        );
+
+        // other collections have their own keys
+        let acl = Keyring::new("acl", &random(KEY_SIZE))
+            .load(&storage)
+            .unwrap();
+        assert_eq!(acl.version(), 1);
+        assert_eq!(acl.unwrap(1, &wrapped).is_err(), true);
+    }
+
+    #[test]
+    fn keyring_legacy() {
+        let storage = MemoryStorage::new();
+        let legacy = random(KEY_SIZE);
+
+        // a key of the shared keyring, sealed with the legacy key
+        let kek = random(KEY_SIZE);
+        let cipher = Aes256Gcm::new(GenericArray::clone_from_slice(&legacy));
+        let mut record = 1u32.to_le_bytes().to_vec();
+        record.extend(encrypt(&cipher, &kek, &record).unwrap());
+        storage.set(None, &record).unwrap();
+
+        let kek = Aes256Gcm::new(GenericArray::clone_from_slice(&kek));
+        let wrapped = encrypt(&kek, b"some data key", &[]).unwrap();
+
+        let keyring = Keyring::new("objects", &random(KEY_SIZE))
+            .with_legacy(&legacy)
+            .load(&storage)
+            .unwrap();
+        assert_eq!(keyring.unwrap(1, &wrapped).unwrap(), b"some data key");
+
+        // the collection got its own key, which is stored for the collection only
+        assert_eq!(keyring.version(), 2);
+        let acl = Keyring::new("acl", &random(KEY_SIZE))
+            .with_legacy(&legacy)
+            .load(&storage)
+            .unwrap();
+        assert_eq!(acl.version(), 2);
+        assert_eq!(acl.unwrap(1, &wrapped).unwrap(), b"some data key");
+        assert_eq!(acl.rotate(&storage).unwrap(), 3);
+    }
+}
@@ -1,7 +1,10 @@
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
 
-use super::{Error, Key, Record, Storage};
+use super::compaction::Namespaced;
+use super::namespaces::Collections;
+use super::{AsyncStorage, Error, Key, KeyIter, Keys, Record, Storage};
+use async_trait::async_trait;
 
 #[derive(Debug, Clone)]
 pub struct MemoryStorage {
@@ -11,13 +14,14 @@ pub struct MemoryStorage {
 #[derive(Debug)]
 struct Internal {
     key_counter: Key,
-    backend: HashMap<Key, Vec<u8>>,
+    // ordered, so keys are scanned in the order they were assigned, as with zdb
+    backend: BTreeMap<Key, Vec<u8>>,
 }
 
 impl MemoryStorage {
     pub fn new() -> MemoryStorage {
         let key_counter = 0;
-        let backend = HashMap::new();
+        let backend = BTreeMap::new();
         MemoryStorage {
             internal: Arc::new(RwLock::new(Internal {
                 key_counter,
@@ -56,7 +60,7 @@ impl Storage for MemoryStorage {
         Ok(())
     }
 
-    fn keys(&self) -> Result<Box<dyn Iterator<Item = Record> + Send>, Error> {
+    fn keys(&self) -> Result<KeyIter, Error> {
         let handle = self.internal.read().unwrap();
         Ok(Box::new(
             handle
@@ -65,15 +69,17 @@ impl Storage for MemoryStorage {
                 .copied()
                 .collect::<Vec<Key>>()
                 .into_iter()
-                .map(|v| Record {
-                    key: v,
-                    timestamp: None,
-                    size: None,
+                .map(|v| {
+                    Ok(Record {
+                        key: v,
+                        timestamp: None,
+                        size: None,
+                    })
                 }),
         ))
     }
 
-    fn rev(&self) -> Result<Box<dyn Iterator<Item = Record> + Send>, Error> {
+    fn rev(&self) -> Result<KeyIter, Error> {
         let handle = self.internal.read().unwrap();
         Ok(Box::new(
             handle
@@ -82,19 +88,90 @@ impl Storage for MemoryStorage {
                 .copied()
                 .collect::<Vec<Key>>()
                 .into_iter()
-                .map(|v| Record {
-                    key: v,
-                    timestamp: None,
-                    size: None,
+                .map(|v| {
+                    Ok(Record {
+                        key: v,
+                        timestamp: None,
+                        size: None,
+                    })
                 })
                 .rev(),
         ))
     }
 }
 
+// the memory storage never blocks, so it serves async requests directly
+#[async_trait]
+impl AsyncStorage for MemoryStorage {
+    async fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+        Storage::set(self, key, data)
+    }
+
+    async fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+        Storage::get(self, key)
+    }
+
+    async fn delete(&self, key: Key) -> Result<(), Error> {
+        Storage::delete(self, key)
+    }
+
+    async fn keys(&self) -> Result<Keys, Error> {
+        Ok(Box::pin(futures::stream::iter(Storage::keys(self)?)))
+    }
+
+    async fn rev(&self) -> Result<Keys, Error> {
+        Ok(Box::pin(futures::stream::iter(Storage::rev(self)?)))
+    }
+}
+
+/// In memory storage collections, a collection is created on first use
+#[derive(Debug, Clone)]
+pub struct MemoryCollections {
+    collections: Arc<Mutex<HashMap<String, MemoryStorage>>>,
+}
+
+impl MemoryCollections {
+    pub fn new() -> MemoryCollections {
+        MemoryCollections {
+            collections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl Collections for MemoryCollections {
+    type Storage = MemoryStorage;
+
+    fn collection(&self, name: &str) -> Result<MemoryStorage, Error> {
+        let mut collections = self.collections.lock().unwrap();
+        Ok(collections
+            .entry(name.into())
+            .or_insert_with(MemoryStorage::new)
+            .clone())
+    }
+}
+
+impl Namespaced for MemoryCollections {
+    type Namespace = MemoryStorage;
+
+    fn namespaces(&self) -> Result<Vec<String>, Error> {
+        Ok(self.collections.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn namespace(&self, name: &str) -> MemoryStorage {
+        // opening a memory collection never fails
+        self.collection(name).unwrap()
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        self.collections.lock().unwrap().remove(name);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use super::MemoryStorage;
+    use crate::storage::Storage;
 
     #[test]
     fn memory_storage() {
@@ -123,8 +200,27 @@ mod tests {
         assert_eq!(None, storage.get(17).unwrap());
 
         // key equality
-        let mut keys = storage.keys().unwrap().map(|r| r.key).collect::<Vec<_>>();
+        let mut keys = storage
+            .keys()
+            .unwrap()
+            .map(|r| r.unwrap().key)
+            .collect::<Vec<_>>();
         keys.sort();
         assert_eq!(keys, vec![key1, key2, key3]);
+
+        // scans resume after the given key
+        let keys = storage
+            .keys_from(key1)
+            .unwrap()
+            .map(|r| r.unwrap().key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![key2, key3]);
+        let keys = storage
+            .rev_from(key2)
+            .unwrap()
+            .map(|r| r.unwrap().key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![key1]);
+        assert_eq!(storage.keys_from(17).unwrap().count(), 0);
     }
 }
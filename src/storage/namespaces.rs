@@ -0,0 +1,552 @@
+//! Every bcdb collection is stored in its own storage collection (a 0-db namespace), which is
+//! created on first use. This allows accounting, dropping and backing up a collection on its own.
+//!
+//! Storage collections assign their own keys, so keys within a collection are not unique across
+//! collections. To keep keys globally resolvable, the key of an object is the key of a forward
+//! record in the shared `objects` collection, which holds the id of the collection of the object,
+//! and the key of the object within that collection. Before collections were split, all objects
+//! were stored in the shared collection, these objects are still found under their own key.
+//!
+//! Collection ids are assigned in order of first use, the name of every collection is stored in
+//! the `collections` registry, at the key of its id minus one (id 0 refers to the shared
+//! collection). Objects are moved out of the shared collection with `migrate`, which replaces
+//! the object with a forward record, so the object keeps its key.
+//!
+//! Updating the data of an object writes a new record, and points the forward record to it. The
+//! keys of the previous versions are kept in a history record in the collection of the object,
+//! a forward record of an object with history carries the key of its history record as well.
+//!
+//! A forward record starts with a magic prefix. Objects in the shared collection were written as
+//! is, so an object which happens to look exactly like a forward record can't be told apart from
+//! one. Since a forward record must point to a registered collection, this is very unlikely.
+
+use super::chunked::{ChunkedStorage, Content, Manifest};
+use super::{AsyncStorage, Error, Key};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::sync::{Arc, RwLock};
+use tokio::sync::Mutex;
+use tokio::task::spawn_blocking;
+
+/// Name of the shared collection, which holds the forward records
+pub const SHARED_COLLECTION: &str = "objects";
+
+/// Name of the collection registry
+pub const REGISTRY_COLLECTION: &str = "collections";
+
+/// Name of the collection where chunks of streamed objects are staged, until the collection of
+/// the object is known
+pub const STAGING_COLLECTION: &str = "uploads";
+
+/// Magic prefix of a forward record
+const MAGIC: &[u8; 8] = b"bcdb:fwd";
+
+/// Size of a forward record: magic (8), collection id (4), key (8)
+const FORWARD_SIZE: usize = 20;
+
+/// Size of a forward record with the key of a history record (8)
+const HISTORY_FORWARD_SIZE: usize = FORWARD_SIZE + 8;
+
+/// A source of storage collections
+pub trait Collections: Clone + Send + Sync + 'static {
+    type Storage: AsyncStorage;
+
+    /// Open the storage collection with the given name, the collection is created if it does
+    /// not exist yet. Opening a collection may block, so it is called on the blocking thread
+    /// pool.
+    fn collection(&self, name: &str) -> Result<Self::Storage, Error>;
+}
+
+impl<F, S> Collections for F
+where
+    F: Fn(&str) -> Result<S, Error> + Clone + Send + Sync + 'static,
+    S: AsyncStorage,
+{
+    type Storage = S;
+
+    fn collection(&self, name: &str) -> Result<S, Error> {
+        self(name)
+    }
+}
+
+/// Location of an object
+pub struct Location<S> {
+    /// id of the storage collection of the object, 0 for the shared collection
+    pub collection: u32,
+    /// storage collection of the object
+    pub storage: S,
+    /// key of the object within its storage collection
+    pub key: Key,
+    /// whether the object was found through a forward record
+    pub forward: bool,
+    /// key of the history record of the object within its storage collection, if any
+    pub history: Option<Key>,
+}
+
+#[derive(Clone)]
+pub struct Namespaces<C>
+where
+    C: Collections,
+{
+    collections: C,
+    shared: C::Storage,
+    registry: C::Storage,
+    staging: C::Storage,
+    inner: Arc<RwLock<Inner<C::Storage>>>,
+    // serializes the registration of collections
+    writes: Arc<Mutex<()>>,
+}
+
+struct Inner<S> {
+    ids: HashMap<String, u32>,
+    names: HashMap<u32, String>,
+    storages: HashMap<u32, S>,
+}
+
+impl<C> Namespaces<C>
+where
+    C: Collections,
+{
+    /// Open the shared collection and the registry of the given collections, and load the
+    /// registered collections.
+    pub async fn new(collections: C) -> Result<Self, Error> {
+        let shared = open(&collections, SHARED_COLLECTION).await?;
+        let registry = open(&collections, REGISTRY_COLLECTION).await?;
+        let staging = open(&collections, STAGING_COLLECTION).await?;
+
+        let mut inner = Inner {
+            ids: HashMap::new(),
+            names: HashMap::new(),
+            storages: HashMap::new(),
+        };
+
+        let mut records = registry.keys().await?;
+        while let Some(record) = records.next().await {
+            let record = record?;
+            let name = match registry.get(record.key).await? {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let name = String::from_utf8(name)
+                .map_err(|_| Error::Protocol("invalid collection name in registry".into()))?;
+            let id = id(record.key)?;
+            inner.ids.insert(name.clone(), id);
+            inner.names.insert(id, name);
+        }
+
+        inner.storages.insert(0, shared.clone());
+
+        Ok(Namespaces {
+            collections,
+            shared,
+            registry,
+            staging,
+            inner: Arc::new(RwLock::new(inner)),
+            writes: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Get the id and storage collection of a bcdb collection. The collection is registered,
+    /// and its storage collection created on first use.
+    pub async fn collection(&self, name: &str) -> Result<(u32, C::Storage), Error> {
+        if let Some(id) = self.id(name) {
+            return Ok((id, self.storage(id).await?));
+        }
+
+        let _guard = self.writes.lock().await;
+        // the collection might have been registered while waiting for the lock
+        if let Some(id) = self.id(name) {
+            return Ok((id, self.storage(id).await?));
+        }
+
+        let key = self.registry.set(None, name.as_bytes()).await?;
+        let id = match id(key) {
+            Ok(id) => id,
+            Err(err) => {
+                self.registry.delete(key).await?;
+                return Err(err);
+            }
+        };
+
+        debug!("registered collection '{}' with id {}", name, id);
+        {
+            let mut inner = self.inner.write().unwrap();
+            inner.ids.insert(name.into(), id);
+            inner.names.insert(id, name.into());
+        }
+
+        Ok((id, self.storage(id).await?))
+    }
+
+    /// Get the id and storage collection of a registered bcdb collection, `None` if the
+    /// collection was never used.
+    pub async fn lookup(&self, name: &str) -> Result<Option<(u32, C::Storage)>, Error> {
+        match self.id(name) {
+            Some(id) => Ok(Some((id, self.storage(id).await?))),
+            None => Ok(None),
+        }
+    }
+
+    /// The registered collections, and their ids
+    pub fn collections(&self) -> Vec<(String, u32)> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .ids
+            .iter()
+            .map(|(name, id)| (name.clone(), *id))
+            .collect()
+    }
+
+    /// The storage collection where chunks of streamed objects are staged.
+    pub fn staging(&self) -> C::Storage {
+        self.staging.clone()
+    }
+
+    /// Write a forward record to an object in the given collection, and return the key of the
+    /// forward record, which is the key of the object.
+    pub async fn link(&self, id: u32, key: Key) -> Result<Key, Error> {
+        self.shared.set(None, &forward(id, key, None)).await
+    }
+
+    /// Point the forward record of an object to another record in the same collection, and to
+    /// the history record of the object if any.
+    pub async fn relink(
+        &self,
+        key: Key,
+        id: u32,
+        target: Key,
+        history: Option<Key>,
+    ) -> Result<(), Error> {
+        self.shared
+            .set(Some(key), &forward(id, target, history))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Delete the forward record of an object
+    pub async fn unlink(&self, key: Key) -> Result<(), Error> {
+        self.shared.delete(key).await
+    }
+
+    /// Find the object with the given key. Returns `None` if the object does not exist.
+    pub async fn resolve(&self, key: Key) -> Result<Option<Location<C::Storage>>, Error> {
+        match self.shared.get(key).await? {
+            Some(data) => self.location(key, &data).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Find the objects with the given keys, the forward records are read in a single batch.
+    /// Locations are returned in the order of the keys, `None` for objects that do not exist.
+    pub async fn resolve_many(
+        &self,
+        keys: &[Key],
+    ) -> Result<Vec<Option<Location<C::Storage>>>, Error> {
+        let records = self.shared.get_many(keys).await?;
+        let mut locations = Vec::with_capacity(keys.len());
+        for (key, data) in keys.iter().zip(records) {
+            match data {
+                Some(data) => locations.push(Some(self.location(*key, &data).await?)),
+                None => locations.push(None),
+            }
+        }
+
+        Ok(locations)
+    }
+
+    /// Move an object from the shared collection to its own collection, and replace it with a
+    /// forward record. Returns the key of the object within its collection, or `None` if the
+    /// object does not exist or was moved already.
+    pub async fn migrate(&self, key: Key, collection: &str) -> Result<Option<Key>, Error> {
+        // the chunk size is not used, since chunks are copied as they are
+        let shared = ChunkedStorage::new(self.shared.clone());
+        let content = match shared.open(key).await? {
+            Some(content) => content,
+            None => return Ok(None),
+        };
+
+        if let Content::Inline(ref data) = content {
+            if self.forwarded(data).is_some() {
+                return Ok(None);
+            }
+        }
+
+        let (id, storage) = self.collection(collection).await?;
+        let storage = ChunkedStorage::new(storage);
+        let target = match content {
+            Content::Inline(data) => storage.set(None, &data).await?,
+            Content::Chunked(manifest) => {
+                let mut chunks = Vec::with_capacity(manifest.chunks.len());
+                for chunk in manifest.chunks.iter() {
+                    let copied = match shared.get_chunk(*chunk).await {
+                        Ok(data) => storage.set_chunk(&data).await,
+                        Err(err) => Err(err),
+                    };
+
+                    match copied {
+                        Ok(chunk) => chunks.push(chunk),
+                        Err(err) => {
+                            storage.delete_chunks(&chunks).await;
+                            return Err(err);
+                        }
+                    }
+                }
+
+                let copy = Manifest {
+                    size: manifest.size,
+                    chunks: chunks,
+                };
+
+                match storage.set_manifest(None, &copy).await {
+                    Ok(key) => key,
+                    Err(err) => {
+                        storage.delete_chunks(&copy.chunks).await;
+                        return Err(err);
+                    }
+                }
+            }
+        };
+
+        // replacing the object deletes its chunks in the shared collection as well
+        if let Err(err) = shared.set(Some(key), &forward(id, target, None)).await {
+            storage.delete(target).await?;
+            return Err(err);
+        }
+
+        Ok(Some(target))
+    }
+
+    /// The location of an object, given the record at its key in the shared collection
+    async fn location(&self, key: Key, data: &[u8]) -> Result<Location<C::Storage>, Error> {
+        let (id, target, history) = match self.forwarded(data) {
+            Some(forward) => forward,
+            // an object which was written before collections were split
+            None => {
+                return Ok(Location {
+                    collection: 0,
+                    storage: self.shared.clone(),
+                    key: key,
+                    forward: false,
+                    history: None,
+                })
+            }
+        };
+
+        Ok(Location {
+            collection: id,
+            storage: self.storage(id).await?,
+            key: target,
+            forward: true,
+            history: history,
+        })
+    }
+
+    fn id(&self, name: &str) -> Option<u32> {
+        self.inner.read().unwrap().ids.get(name).copied()
+    }
+
+    /// Get the storage collection of a registered collection
+    async fn storage(&self, id: u32) -> Result<C::Storage, Error> {
+        {
+            let inner = self.inner.read().unwrap();
+            if let Some(storage) = inner.storages.get(&id) {
+                return Ok(storage.clone());
+            }
+
+            if !inner.names.contains_key(&id) {
+                return Err(Error::Protocol(format!("unknown collection id {}", id)));
+            }
+        }
+
+        let storage = open(&self.collections, &namespace(id)).await?;
+        let mut inner = self.inner.write().unwrap();
+        Ok(inner.storages.entry(id).or_insert(storage).clone())
+    }
+
+    /// Decode a forward record, data is only considered a forward record if it points to a
+    /// registered collection.
+    fn forwarded(&self, data: &[u8]) -> Option<(u32, Key, Option<Key>)> {
+        if (data.len() != FORWARD_SIZE && data.len() != HISTORY_FORWARD_SIZE)
+            || !data.starts_with(MAGIC)
+        {
+            return None;
+        }
+
+        let id = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        let key = Key::from_le_bytes(data[12..20].try_into().unwrap());
+        if !self.inner.read().unwrap().names.contains_key(&id) {
+            return None;
+        }
+
+        let history = match data.len() {
+            HISTORY_FORWARD_SIZE => Some(Key::from_le_bytes(data[20..28].try_into().unwrap())),
+            _ => None,
+        };
+
+        Some((id, key, history))
+    }
+}
+
+/// Open a storage collection on the blocking thread pool
+async fn open<C: Collections>(collections: &C, name: &str) -> Result<C::Storage, Error> {
+    let collections = collections.clone();
+    let name = name.to_owned();
+    match spawn_blocking(move || collections.collection(&name)).await {
+        Ok(result) => result,
+        Err(err) => {
+            error!("failed to open collection: {}", err);
+            Err(Error::Other)
+        }
+    }
+}
+
+/// The name of the storage collection of a collection id
+fn namespace(id: u32) -> String {
+    format!("{}-{}", SHARED_COLLECTION, id)
+}
+
+/// The collection id of a registry key
+fn id(key: Key) -> Result<u32, Error> {
+    match key.checked_add(1).map(u32::try_from) {
+        Some(Ok(id)) => Ok(id),
+        _ => Err(Error::Protocol("too many collections".into())),
+    }
+}
+
+fn forward(id: u32, key: Key, history: Option<Key>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HISTORY_FORWARD_SIZE);
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&id.to_le_bytes());
+    buf.extend_from_slice(&key.to_le_bytes());
+    if let Some(history) = history {
+        buf.extend_from_slice(&history.to_le_bytes());
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryCollections;
+
+    #[tokio::test]
+    async fn namespaces() {
+        let collections = MemoryCollections::new();
+        let namespaces = Namespaces::new(collections.clone()).await.unwrap();
+
+        let (id, storage) = namespaces.collection("people").await.unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(namespaces.collection("people").await.unwrap().0, 1);
+        assert_eq!(namespaces.collection("pets").await.unwrap().0, 2);
+        assert_eq!(namespaces.lookup("pets").await.unwrap().unwrap().0, 2);
+        assert_eq!(namespaces.lookup("plants").await.unwrap().is_none(), true);
+
+        let local = storage.set(None, b"some person").await.unwrap();
+        let key = namespaces.link(id, local).await.unwrap();
+
+        let location = namespaces.resolve(key).await.unwrap().unwrap();
+        assert_eq!(location.forward, true);
+        assert_eq!(location.collection, id);
+        assert_eq!(location.key, local);
+        assert_eq!(
+            location.storage.get(location.key).await.unwrap(),
+            Some(b"some person".to_vec())
+        );
+
+        // the object is stored in the collection of people only
+        let people = collections.collection("objects-1").unwrap();
+        assert_eq!(
+            people.get(local).await.unwrap(),
+            Some(b"some person".to_vec())
+        );
+        assert_eq!(
+            collections
+                .collection("objects-2")
+                .unwrap()
+                .keys()
+                .await
+                .unwrap()
+                .count()
+                .await,
+            0
+        );
+
+        // registered collections are loaded again
+        let namespaces = Namespaces::new(collections.clone()).await.unwrap();
+        let mut registered = namespaces.collections();
+        registered.sort();
+        assert_eq!(
+            registered,
+            vec![("people".to_string(), 1), ("pets".to_string(), 2)]
+        );
+        assert_eq!(namespaces.resolve(key).await.unwrap().unwrap().key, local);
+
+        let locations = namespaces.resolve_many(&[key, 100]).await.unwrap();
+        assert_eq!(
+            locations[0].as_ref().map(|location| location.key),
+            Some(local)
+        );
+        assert_eq!(locations[1].is_none(), true);
+
+        // pointing the object to a new version keeps its key
+        let version = storage.set(None, b"updated person").await.unwrap();
+        namespaces
+            .relink(key, id, version, Some(local))
+            .await
+            .unwrap();
+        let location = namespaces.resolve(key).await.unwrap().unwrap();
+        assert_eq!(location.key, version);
+        assert_eq!(location.history, Some(local));
+
+        namespaces.relink(key, id, version, None).await.unwrap();
+        let location = namespaces.resolve(key).await.unwrap().unwrap();
+        assert_eq!(location.history, None);
+
+        namespaces.unlink(key).await.unwrap();
+        assert_eq!(namespaces.resolve(key).await.unwrap().is_none(), true);
+    }
+
+    #[tokio::test]
+    async fn namespaces_migrate() {
+        let collections = MemoryCollections::new();
+        let shared = collections.collection(SHARED_COLLECTION).unwrap();
+
+        // objects written before collections were split
+        let small = shared.set(None, b"small object").await.unwrap();
+        let chunked = ChunkedStorage::new(shared.clone()).with_chunk_size(4);
+        let large = chunked.set(None, b"large chunked object").await.unwrap();
+
+        let namespaces = Namespaces::new(collections.clone()).await.unwrap();
+        let location = namespaces.resolve(small).await.unwrap().unwrap();
+        assert_eq!(location.forward, false);
+        assert_eq!(location.key, small);
+
+        assert_eq!(namespaces.migrate(small, "test").await.unwrap(), Some(0));
+        assert_eq!(namespaces.migrate(large, "test").await.is_ok(), true);
+        // migrating twice is a no-op
+        assert_eq!(namespaces.migrate(small, "test").await.unwrap(), None);
+
+        // the objects are found under their old keys
+        let location = namespaces.resolve(small).await.unwrap().unwrap();
+        assert_eq!(location.forward, true);
+        assert_eq!(
+            location.storage.get(location.key).await.unwrap(),
+            Some(b"small object".to_vec())
+        );
+
+        let location = namespaces.resolve(large).await.unwrap().unwrap();
+        assert_eq!(
+            ChunkedStorage::new(location.storage)
+                .get(location.key)
+                .await
+                .unwrap(),
+            Some(b"large chunked object".to_vec())
+        );
+
+        // only the forward records are left in the shared collection
+        assert_eq!(shared.keys().await.unwrap().count().await, 2);
+    }
+}
@@ -0,0 +1,712 @@
+//! A storage which replicates all records over multiple storages, usually collections of separate
+//! 0-db instances, so a single failing disk does not lose any data.
+//!
+//! Replicas must agree on the keys of records. Since 0-db in sequential mode assigns keys itself,
+//! inserts are serialized: the first healthy replica assigns the key, and the record is appended to
+//! the other replicas, which must assign the same key. A replica which assigns a lower key is
+//! behind, the missing records are copied to it before the record is appended.
+//!
+//! A replica that fails an operation with an IO error is marked unhealthy, and is not used for
+//! reads or writes until it is repaired. Other errors are caused by the request, and are returned
+//! to the caller, unless another replica already accepted the write, then the replica diverged and
+//! is marked unhealthy as well. A write succeeds if it succeeded on at least the write quorum of
+//! replicas.
+//!
+//! Every replica has a generation. When a replica is marked unhealthy, the replicas which are still
+//! healthy move to a new generation, so a replica which missed writes (including updates of
+//! existing records) has an older generation than the others. The generations can be persisted in
+//! a state file, which is written before the write that was missed returns, so replicas which
+//! missed writes are still known after a restart.
+
+use super::{Error, Key, KeyIter, Storage};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+const GENERATION_SIZE: usize = 8;
+
+#[derive(Clone)]
+pub struct ReplicatedStorage<S> {
+    replicas: Vec<S>,
+    quorum: usize,
+    healthy: Arc<Vec<AtomicBool>>,
+    generations: Arc<Mutex<Generations>>,
+    // replica to start the next read from, so reads are spread over the replicas
+    next: Arc<AtomicUsize>,
+    // writes are serialized, so all replicas assign the same keys
+    writes: Arc<Mutex<()>>,
+}
+
+impl<S> ReplicatedStorage<S>
+where
+    S: Storage,
+{
+    /// Create a new replicated storage over the given replicas. The write quorum defaults to a
+    /// majority of the replicas.
+    ///
+    /// # panics
+    ///
+    /// This function will panic if no replicas are given.
+    pub fn new(replicas: Vec<S>) -> Self {
+        assert!(!replicas.is_empty());
+        let quorum = replicas.len() / 2 + 1;
+        let healthy = replicas.iter().map(|_| AtomicBool::new(true)).collect();
+        let generations = Generations::new(replicas.len());
+        ReplicatedStorage {
+            replicas: replicas,
+            quorum: quorum,
+            healthy: Arc::new(healthy),
+            generations: Arc::new(Mutex::new(generations)),
+            next: Arc::new(AtomicUsize::new(0)),
+            writes: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Set the number of replicas a write must succeed on.
+    ///
+    /// # panics
+    ///
+    /// This function will panic if the quorum is 0, or larger than the number of replicas.
+    pub fn with_quorum(mut self, quorum: usize) -> Self {
+        assert!(quorum > 0 && quorum <= self.replicas.len());
+        self.quorum = quorum;
+        self
+    }
+
+    /// Persist the generations of the replicas in the given state file. Replicas which have an
+    /// older generation in the state file than the others missed writes, and are unhealthy until
+    /// they are repaired.
+    pub fn with_state<P: AsRef<Path>>(self, path: P) -> Result<Self, Error> {
+        let generations = Generations::load(path.as_ref(), self.replicas.len())?;
+        for index in 0..self.replicas.len() {
+            if !generations.is_current(index) {
+                warn!("replica {} missed writes, marking it unhealthy", index);
+                self.healthy[index].store(false, Ordering::SeqCst);
+            }
+        }
+
+        *self.generations.lock().map_err(|_| Error::Other)? = generations;
+        Ok(self)
+    }
+
+    /// Check if the replica at the given index is healthy
+    pub fn is_healthy(&self, index: usize) -> bool {
+        self.healthy[index].load(Ordering::SeqCst)
+    }
+
+    /// Compare the last key of all healthy replicas, and mark the replicas which are behind as
+    /// unhealthy. Should be called before the storage is used, since a replica might have missed
+    /// writes while it was offline. Replicas which missed updates of existing records are only
+    /// found by their generation, so the generations should be persisted with `with_state`.
+    pub fn check(&self) -> Result<(), Error> {
+        let mut last = Vec::with_capacity(self.replicas.len());
+        for (index, replica) in self.replicas.iter().enumerate() {
+            if !self.is_healthy(index) {
+                continue;
+            }
+
+            match replica
+                .rev()
+                .and_then(|mut keys| keys.next().transpose())
+                .map(|record| record.map(|record| record.key))
+            {
+                Ok(key) => last.push((index, key)),
+                Err(err) => self.failed(index, err, false)?,
+            }
+        }
+
+        let max = match last.iter().map(|(_, key)| *key).max() {
+            Some(max) => max,
+            None => return Err(no_healthy_replica()),
+        };
+
+        for (index, key) in last {
+            if key < max {
+                warn!("replica {} is behind, marking it unhealthy", index);
+                self.unhealthy(index)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Repair all unhealthy replicas, by syncing them with a healthy replica. Replicas which are
+    /// repaired are marked healthy again. Returns the number of records that were synced.
+    ///
+    /// All records are compared with the healthy replica, so this is an expensive operation, and
+    /// writes are blocked while a replica is repaired.
+    pub fn repair(&self) -> Result<u64, Error> {
+        let _guard = self.writes.lock().map_err(|_| Error::Other)?;
+        self.check()?;
+
+        let source = match (0..self.replicas.len()).find(|index| self.is_healthy(*index)) {
+            Some(source) => &self.replicas[source],
+            None => return Err(no_healthy_replica()),
+        };
+
+        let mut count = 0;
+        for (index, replica) in self.replicas.iter().enumerate() {
+            if self.is_healthy(index) {
+                continue;
+            }
+
+            match sync(source, replica) {
+                Ok(synced) => {
+                    info!("repaired replica {}, synced {} records", index, synced);
+                    self.generations
+                        .lock()
+                        .map_err(|_| Error::Other)?
+                        .update(index)?;
+                    self.healthy[index].store(true, Ordering::SeqCst);
+                    count += synced;
+                }
+                Err(err) => debug!("failed to repair replica {}: {}", index, err),
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Handle the error of an operation on a replica. A replica which fails with an IO error is
+    /// marked unhealthy, and the operation can continue on the other replicas. Other errors are
+    /// caused by the request and are returned, unless the write was already `accepted` by another
+    /// replica, then the replica diverged, and is marked unhealthy instead.
+    fn failed(&self, index: usize, err: Error, accepted: bool) -> Result<(), Error> {
+        match err {
+            Error::IO(_) => warn!("replica {} failed, marking it unhealthy: {}", index, err),
+            err if accepted => warn!("replica {} diverged, marking it unhealthy: {}", index, err),
+            err => return Err(err),
+        }
+
+        self.unhealthy(index)
+    }
+
+    /// Mark a replica unhealthy, the replicas which are still healthy move to a new generation.
+    fn unhealthy(&self, index: usize) -> Result<(), Error> {
+        let mut generations = self.generations.lock().map_err(|_| Error::Other)?;
+        if !self.healthy[index].swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let healthy: Vec<usize> = (0..self.replicas.len())
+            .filter(|index| self.is_healthy(*index))
+            .collect();
+        generations.advance(&healthy)
+    }
+
+    /// Run an operation on all healthy replicas, failing if it did not succeed on at least the
+    /// write quorum of replicas.
+    fn write<F>(&self, op: F) -> Result<(), Error>
+    where
+        F: Fn(&S) -> Result<(), Error>,
+    {
+        let mut written = 0;
+        for (index, replica) in self.replicas.iter().enumerate() {
+            if !self.is_healthy(index) {
+                continue;
+            }
+
+            match op(replica) {
+                Ok(_) => written += 1,
+                Err(err) => self.failed(index, err, written > 0)?,
+            }
+        }
+
+        self.quorum(written)
+    }
+
+    /// Insert a new record, the first healthy replica assigns the key.
+    fn insert(&self, data: &[u8]) -> Result<Key, Error> {
+        let mut primary: Option<(usize, Key)> = None;
+        let mut written = Vec::with_capacity(self.replicas.len());
+        for (index, replica) in self.replicas.iter().enumerate() {
+            if !self.is_healthy(index) {
+                continue;
+            }
+
+            let result = match primary {
+                None => replica.set(None, data),
+                Some((source, key)) => {
+                    append(&self.replicas[source], replica, key, data).map(|_| key)
+                }
+            };
+
+            match result {
+                Ok(key) => {
+                    if primary.is_none() {
+                        primary = Some((index, key));
+                    }
+                    written.push(index);
+                }
+                Err(err) => self.failed(index, err, primary.is_some())?,
+            }
+        }
+
+        let key = match primary {
+            Some((_, key)) => key,
+            None => return Err(no_healthy_replica()),
+        };
+
+        if let Err(err) = self.quorum(written.len()) {
+            // the key stays used on all replicas, so they still agree on the next key
+            for index in written {
+                if let Err(err) = self.replicas[index].delete(key) {
+                    self.failed(index, err, true)?;
+                }
+            }
+
+            return Err(err);
+        }
+
+        Ok(key)
+    }
+
+    fn quorum(&self, written: usize) -> Result<(), Error> {
+        if written < self.quorum {
+            return Err(Error::Protocol(format!(
+                "write quorum not reached, written to {} of {} required replicas",
+                written, self.quorum
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Run an operation on the healthy replicas, until one of them succeeds. The first replica to
+    /// try is rotated on every call.
+    fn read<F, T>(&self, op: F) -> Result<T, Error>
+    where
+        F: Fn(&S) -> Result<T, Error>,
+    {
+        let count = self.replicas.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for i in 0..count {
+            let index = (start + i) % count;
+            if !self.is_healthy(index) {
+                continue;
+            }
+
+            match op(&self.replicas[index]) {
+                Ok(result) => return Ok(result),
+                Err(err) => self.failed(index, err, false)?,
+            }
+        }
+
+        Err(no_healthy_replica())
+    }
+}
+
+impl<S> Storage for ReplicatedStorage<S>
+where
+    S: Storage,
+{
+    fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+        let _guard = self.writes.lock().map_err(|_| Error::Other)?;
+        match key {
+            Some(key) => self
+                .write(|replica| replica.set(Some(key), data).map(|_| ()))
+                .map(|_| key),
+            None => self.insert(data),
+        }
+    }
+
+    fn delete(&self, key: Key) -> Result<(), Error> {
+        let _guard = self.writes.lock().map_err(|_| Error::Other)?;
+        self.write(|replica| replica.delete(key))
+    }
+
+    fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+        self.read(|replica| replica.get(key))
+    }
+
+    fn keys(&self) -> Result<KeyIter, Error> {
+        self.read(|replica| replica.keys())
+    }
+
+    fn rev(&self) -> Result<KeyIter, Error> {
+        self.read(|replica| replica.rev())
+    }
+
+    fn keys_from(&self, key: Key) -> Result<KeyIter, Error> {
+        self.read(|replica| replica.keys_from(key))
+    }
+
+    fn rev_from(&self, key: Key) -> Result<KeyIter, Error> {
+        self.read(|replica| replica.rev_from(key))
+    }
+}
+
+/// The generations of the replicas, optionally persisted in a state file
+struct Generations {
+    path: Option<PathBuf>,
+    values: Vec<u64>,
+}
+
+impl Generations {
+    fn new(count: usize) -> Generations {
+        Generations {
+            path: None,
+            values: vec![0; count],
+        }
+    }
+
+    /// Load the generations from a state file. If the file does not exist, all replicas start in
+    /// the same generation. Replicas which were added since the file was written start in the
+    /// first generation.
+    fn load(path: &Path, count: usize) -> Result<Generations, Error> {
+        let mut values: Vec<u64> = match fs::read(path) {
+            Ok(data) if data.len() % GENERATION_SIZE == 0 => data
+                .chunks(GENERATION_SIZE)
+                .map(|chunk| {
+                    let mut bytes = [0; GENERATION_SIZE];
+                    bytes.copy_from_slice(chunk);
+                    u64::from_le_bytes(bytes)
+                })
+                .collect(),
+            Ok(_) => {
+                return Err(Error::Protocol(format!(
+                    "invalid replication state file '{}'",
+                    path.display()
+                )))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        values.resize(count, 0);
+        Ok(Generations {
+            path: Some(path.into()),
+            values: values,
+        })
+    }
+
+    fn current(&self) -> u64 {
+        self.values.iter().copied().max().unwrap_or_default()
+    }
+
+    fn is_current(&self, index: usize) -> bool {
+        self.values[index] == self.current()
+    }
+
+    /// Move the given replicas to a new generation
+    fn advance(&mut self, indexes: &[usize]) -> Result<(), Error> {
+        let generation = self.current() + 1;
+        for index in indexes {
+            self.values[*index] = generation;
+        }
+
+        self.persist()
+    }
+
+    /// Move a replica to the current generation, after it was repaired
+    fn update(&mut self, index: usize) -> Result<(), Error> {
+        self.values[index] = self.current();
+        self.persist()
+    }
+
+    /// Write the generations to the state file, the file is replaced atomically
+    fn persist(&self) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let tmp = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp)?;
+        for value in self.values.iter() {
+            file.write_all(&value.to_le_bytes())?;
+        }
+
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+fn no_healthy_replica() -> Error {
+    Error::Protocol("no healthy replica available".into())
+}
+
+/// Append a record to a replica, with the given key. If the replica assigns a lower key, it missed
+/// records, which are copied from the source until the replica assigns the expected key.
+fn append<S: Storage>(source: &S, replica: &S, key: Key, data: &[u8]) -> Result<(), Error> {
+    loop {
+        let assigned = replica.set(None, data)?;
+        if assigned == key {
+            return Ok(());
+        }
+
+        if assigned > key {
+            // the key was used on the replica, but not on the source
+            replica.delete(assigned)?;
+            return Err(Error::Protocol(format!(
+                "replica assigned key {} instead of {}, replicas diverged",
+                assigned, key
+            )));
+        }
+
+        // the replica is behind, fill the missing record
+        match source.get(assigned)? {
+            Some(missing) => replica.set(Some(assigned), &missing)?,
+            None => {
+                replica.delete(assigned)?;
+                assigned
+            }
+        };
+    }
+}
+
+/// Sync a replica with the source. Returns the number of records that were written or deleted.
+fn sync<S: Storage>(source: &S, replica: &S) -> Result<u64, Error> {
+    let mut count = 0;
+    for record in source.keys()? {
+        let record = record?;
+        let data = match source.get(record.key)? {
+            Some(data) => data,
+            None => continue,
+        };
+
+        match replica.get(record.key)? {
+            Some(existing) if existing == data => continue,
+            Some(_) => {
+                replica.set(Some(record.key), &data)?;
+            }
+            None => append(source, replica, record.key, &data)?,
+        };
+
+        count += 1;
+    }
+
+    // records which were deleted while the replica was unhealthy
+    for record in replica.keys()? {
+        let record = record?;
+        if source.get(record.key)?.is_none() {
+            replica.delete(record.key)?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    /// A storage which fails all operations while it is down, and rejects writes while it is
+    /// read only
+    #[derive(Clone)]
+    struct Toggle {
+        storage: MemoryStorage,
+        down: Arc<AtomicBool>,
+        read_only: Arc<AtomicBool>,
+    }
+
+    impl Toggle {
+        fn new() -> Toggle {
+            Toggle {
+                storage: MemoryStorage::new(),
+                down: Arc::new(AtomicBool::new(false)),
+                read_only: Arc::new(AtomicBool::new(false)),
+            }
+        }
+
+        fn set_down(&self, down: bool) {
+            self.down.store(down, Ordering::SeqCst);
+        }
+
+        fn set_read_only(&self, read_only: bool) {
+            self.read_only.store(read_only, Ordering::SeqCst);
+        }
+
+        fn check(&self) -> Result<(), Error> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(Error::IO(None));
+            }
+
+            Ok(())
+        }
+    }
+
+    impl Storage for Toggle {
+        fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, Error> {
+            self.check()?;
+            if self.read_only.load(Ordering::SeqCst) {
+                return Err(Error::Protocol("read only".into()));
+            }
+
+            self.storage.set(key, data)
+        }
+
+        fn delete(&self, key: Key) -> Result<(), Error> {
+            self.check()?;
+            self.storage.delete(key)
+        }
+
+        fn get(&self, key: Key) -> Result<Option<Vec<u8>>, Error> {
+            self.check()?;
+            self.storage.get(key)
+        }
+
+        fn keys(&self) -> Result<KeyIter, Error> {
+            self.check()?;
+            self.storage.keys()
+        }
+
+        fn rev(&self) -> Result<KeyIter, Error> {
+            self.check()?;
+            self.storage.rev()
+        }
+    }
+
+    #[test]
+    fn replicated_storage() {
+        let replicas = vec![Toggle::new(), Toggle::new(), Toggle::new()];
+        let storage = ReplicatedStorage::new(replicas.clone());
+
+        let key1 = storage.set(None, b"first").unwrap();
+        let key2 = storage.set(None, b"second").unwrap();
+        for replica in replicas.iter() {
+            assert_eq!(replica.get(key1).unwrap(), Some(b"first".to_vec()));
+            assert_eq!(replica.get(key2).unwrap(), Some(b"second".to_vec()));
+        }
+
+        // a write succeeds with a single replica down
+        replicas[2].set_down(true);
+        let key3 = storage.set(None, b"third").unwrap();
+        storage.set(Some(key1), b"updated").unwrap();
+        storage.delete(key2).unwrap();
+        assert_eq!(storage.is_healthy(2), false);
+
+        // reads only use healthy replicas
+        replicas[2].set_down(false);
+        for _ in 0..3 {
+            assert_eq!(storage.get(key3).unwrap(), Some(b"third".to_vec()));
+            assert_eq!(storage.get(key1).unwrap(), Some(b"updated".to_vec()));
+            assert_eq!(storage.get(key2).unwrap(), None);
+        }
+
+        // the replica is brought up to date
+        assert_eq!(storage.repair().unwrap(), 3);
+        assert_eq!(storage.is_healthy(2), true);
+        assert_eq!(replicas[2].get(key1).unwrap(), Some(b"updated".to_vec()));
+        assert_eq!(replicas[2].get(key2).unwrap(), None);
+        assert_eq!(replicas[2].get(key3).unwrap(), Some(b"third".to_vec()));
+
+        // all replicas agree on new keys
+        let key4 = storage.set(None, b"fourth").unwrap();
+        for replica in replicas.iter() {
+            assert_eq!(replica.get(key4).unwrap(), Some(b"fourth".to_vec()));
+        }
+
+        // without a quorum writes fail
+        replicas[0].set_down(true);
+        replicas[1].set_down(true);
+        assert_eq!(storage.set(None, b"fifth").is_err(), true);
+        assert_eq!(storage.get(key4).unwrap(), Some(b"fourth".to_vec()));
+    }
+
+    #[test]
+    fn replicated_storage_behind() {
+        let replicas = vec![Toggle::new(), Toggle::new()];
+
+        // the second replica missed some writes while it was offline
+        let key = replicas[0].set(None, b"first").unwrap();
+        replicas[0].set(None, b"second").unwrap();
+        replicas[1].set(None, b"first").unwrap();
+
+        let storage = ReplicatedStorage::new(replicas.clone()).with_quorum(1);
+        storage.check().unwrap();
+        assert_eq!(storage.is_healthy(0), true);
+        assert_eq!(storage.is_healthy(1), false);
+        assert_eq!(storage.get(key + 1).unwrap(), Some(b"second".to_vec()));
+
+        storage.repair().unwrap();
+        assert_eq!(storage.is_healthy(1), true);
+        assert_eq!(replicas[1].get(key + 1).unwrap(), Some(b"second".to_vec()));
+
+        // a replica which is behind is filled before a new record is appended
+        replicas[0].set(None, b"third").unwrap();
+        let key = storage.set(None, b"fourth").unwrap();
+        assert_eq!(replicas[1].get(key - 1).unwrap(), Some(b"third".to_vec()));
+        assert_eq!(replicas[1].get(key).unwrap(), Some(b"fourth".to_vec()));
+    }
+
+    #[test]
+    fn replicated_storage_errors() {
+        let replicas = vec![Toggle::new(), Toggle::new(), Toggle::new()];
+        let storage = ReplicatedStorage::new(replicas.clone());
+        let key = storage.set(None, b"first").unwrap();
+
+        // errors caused by the request are returned, and don't affect the health of replicas
+        for replica in replicas.iter() {
+            replica.set_read_only(true);
+        }
+
+        match storage.set(None, b"second") {
+            Err(Error::Protocol(msg)) => assert_eq!(msg, "read only"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        assert_eq!(storage.set(Some(key), b"updated").is_err(), true);
+        assert!((0..3).all(|index| storage.is_healthy(index)));
+        assert_eq!(storage.get(key).unwrap(), Some(b"first".to_vec()));
+
+        // a replica which rejects a write that another replica accepted diverged
+        replicas[0].set_read_only(false);
+        replicas[2].set_read_only(false);
+        storage.set(Some(key), b"updated").unwrap();
+        assert_eq!(storage.is_healthy(0), true);
+        assert_eq!(storage.is_healthy(1), false);
+        assert_eq!(storage.is_healthy(2), true);
+
+        replicas[1].set_read_only(false);
+        assert_eq!(storage.repair().unwrap(), 1);
+        assert_eq!(replicas[1].get(key).unwrap(), Some(b"updated".to_vec()));
+    }
+
+    #[test]
+    fn replicated_storage_state() {
+        const STATE: &str = "/tmp/bcdb-replicated.test";
+        let _ = std::fs::remove_file(STATE);
+
+        let replicas = vec![Toggle::new(), Toggle::new()];
+        let open = || {
+            ReplicatedStorage::new(replicas.clone())
+                .with_quorum(1)
+                .with_state(STATE)
+                .unwrap()
+        };
+
+        let storage = open();
+        let key = storage.set(None, b"first").unwrap();
+
+        // the second replica misses an update, which does not change its last key
+        replicas[1].set_down(true);
+        storage.set(Some(key), b"updated").unwrap();
+        assert_eq!(storage.is_healthy(1), false);
+        replicas[1].set_down(false);
+
+        // after a restart the replica is still known to be behind
+        let storage = open();
+        storage.check().unwrap();
+        assert_eq!(storage.is_healthy(0), true);
+        assert_eq!(storage.is_healthy(1), false);
+        for _ in 0..2 {
+            assert_eq!(storage.get(key).unwrap(), Some(b"updated".to_vec()));
+        }
+
+        // until it is repaired
+        assert_eq!(storage.repair().unwrap(), 1);
+        let storage = open();
+        assert_eq!(storage.is_healthy(1), true);
+        assert_eq!(replicas[1].get(key).unwrap(), Some(b"updated".to_vec()));
+    }
+}
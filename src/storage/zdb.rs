@@ -1,3 +1,174 @@
+pub mod aio;
+pub mod embedded;
 mod external;
 
 pub use external::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+
+    /// Runs the same set of operations against any zdb backed storage. The collection must be
+    /// empty.
+    fn storage_roundtrip<S: Storage>(storage: S) {
+        let key1 = storage.set(None, b"first").expect("failed to set data");
+        let key2 = storage.set(None, b"second").expect("failed to set data");
+        let key3 = storage.set(None, b"third").expect("failed to set data");
+
+        assert!(key1 < key2 && key2 < key3);
+
+        assert_eq!(storage.get(key1).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(storage.get(key2).unwrap(), Some(b"second".to_vec()));
+        assert_eq!(storage.get(key3).unwrap(), Some(b"third".to_vec()));
+        assert_eq!(storage.get(key3 + 100).unwrap(), None);
+
+        // overwrite key
+        let key = storage.set(Some(key2), b"updated").unwrap();
+        assert_eq!(key, key2);
+        assert_eq!(storage.get(key2).unwrap(), Some(b"updated".to_vec()));
+
+        let keys: Vec<_> = storage.keys().unwrap().map(|r| r.unwrap().key).collect();
+        assert_eq!(keys, vec![key1, key2, key3]);
+
+        let keys: Vec<_> = storage.rev().unwrap().map(|r| r.unwrap().key).collect();
+        assert_eq!(keys, vec![key3, key2, key1]);
+
+        let record = storage.keys().unwrap().next().unwrap().unwrap();
+        assert_eq!(record.size, Some(5));
+        assert!(record.timestamp.is_some());
+
+        storage.delete(key1).expect("failed to delete data");
+        assert_eq!(storage.get(key1).unwrap(), None);
+
+        let keys: Vec<_> = storage.keys().unwrap().map(|r| r.unwrap().key).collect();
+        assert_eq!(keys, vec![key2, key3]);
+
+        // batched operations
+        let batch = storage
+            .set_many(&[
+                (None, &b"fourth"[..]),
+                (Some(key2), &b"batched"[..]),
+                (None, &b"fifth"[..]),
+            ])
+            .expect("failed to set data");
+        assert_eq!(batch.len(), 3);
+        assert!(key3 < batch[0] && batch[0] < batch[2]);
+        assert_eq!(batch[1], key2);
+
+        assert_eq!(
+            storage
+                .get_many(&[batch[2], key1, key2, u64::max_value()])
+                .unwrap(),
+            vec![
+                Some(b"fifth".to_vec()),
+                None,
+                Some(b"batched".to_vec()),
+                None
+            ]
+        );
+
+        storage
+            .delete_many(&[batch[0], batch[2]])
+            .expect("failed to delete data");
+        let keys: Vec<_> = storage.keys().unwrap().map(|r| r.unwrap().key).collect();
+        assert_eq!(keys, vec![key2, key3]);
+
+        // scans resume after the given key
+        let keys: Vec<_> = storage
+            .keys_from(key2)
+            .unwrap()
+            .map(|r| r.unwrap().key)
+            .collect();
+        assert_eq!(keys, vec![key3]);
+        let keys: Vec<_> = storage
+            .rev_from(key3)
+            .unwrap()
+            .map(|r| r.unwrap().key)
+            .collect();
+        assert_eq!(keys, vec![key2]);
+    }
+
+    #[test]
+    #[ignore] // requires a 0-db running in sequential mode on the default port
+    fn external_roundtrip() {
+        storage_roundtrip(Zdb::default().reset("test-roundtrip"));
+    }
+
+    #[tokio::test]
+    #[ignore] // requires a 0-db running in sequential mode on the default port
+    async fn multiplexed_roundtrip() {
+        use crate::storage::AsyncStorage;
+        use futures::StreamExt;
+
+        let zdb = Zdb::default();
+        zdb.clone().reset("test-multiplexed");
+        let storage = zdb.multiplexed().collection("test-multiplexed");
+
+        let key1 = storage.set(None, b"first").await.unwrap();
+        let key2 = storage.set(None, b"second").await.unwrap();
+        assert_eq!(storage.get(key1).await.unwrap(), Some(b"first".to_vec()));
+        assert_eq!(storage.set(Some(key2), b"updated").await.unwrap(), key2);
+
+        let batch = storage
+            .set_many(&[(None, &b"third"[..]), (None, &b"fourth"[..])])
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_many(&[key2, batch[1], 1000]).await.unwrap(),
+            vec![Some(b"updated".to_vec()), Some(b"fourth".to_vec()), None]
+        );
+
+        storage.delete(key1).await.unwrap();
+        let keys: Vec<_> = storage
+            .keys()
+            .await
+            .unwrap()
+            .map(|r| r.unwrap().key)
+            .collect()
+            .await;
+        assert_eq!(keys, vec![key2, batch[0], batch[1]]);
+
+        let keys: Vec<_> = storage
+            .rev()
+            .await
+            .unwrap()
+            .map(|r| r.unwrap().key)
+            .collect()
+            .await;
+        assert_eq!(keys, vec![batch[1], batch[0], key2]);
+
+        let keys: Vec<_> = storage
+            .keys_from(batch[0])
+            .await
+            .unwrap()
+            .map(|r| r.unwrap().key)
+            .collect()
+            .await;
+        assert_eq!(keys, vec![batch[1]]);
+    }
+
+    #[test]
+    fn external_address() {
+        assert!(Zdb::open("9900").is_ok());
+        assert!(Zdb::open("localhost:9900").is_ok());
+        assert!(Zdb::open("redis://storage.local:9900").is_ok());
+        assert!(Zdb::open("unix:///tmp/zdb.sock").is_ok());
+        assert!(Zdb::open("http://localhost:9900").is_err());
+    }
+
+    #[test]
+    fn embedded_roundtrip() {
+        const DIR: &str = "/tmp/bcdb-embedded-zdb.test";
+        let _ = std::fs::remove_dir_all(DIR);
+        let zdb = embedded::Zdb::new(DIR).expect("failed to open embedded zdb");
+
+        storage_roundtrip(zdb.collection("test"));
+
+        // collections are isolated from each other
+        let other = zdb.collection("other");
+        assert_eq!(other.keys().unwrap().count(), 0);
+        let key = other.set(None, b"other data").unwrap();
+        assert_eq!(other.get(key).unwrap(), Some(b"other data".to_vec()));
+    }
+}
@@ -0,0 +1,353 @@
+//! An asynchronous client to a 0-db running as a separate process. Requests are sent without
+//! blocking a thread, over a single multiplexed connection per namespace, which is shared by all
+//! clones of a collection. Since 0-db ties the selected namespace to the connection, every
+//! collection opens its own connection. The connection is opened on first use, and opened again
+//! after it failed.
+
+use super::external::{is_end_of_scan, read_le_key, scan_cursor, zdb_key, ScanEntry, ZdbKey};
+use crate::storage::{AsyncStorage, Error as StorageError, Key, Keys, Record};
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use redis::RedisResult;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Clone)]
+pub struct Zdb {
+    client: redis::Client,
+    // admin password, used to authenticate every connection
+    password: Option<String>,
+    // passwords of namespaces, set when the namespace is created and used to select it
+    secrets: HashMap<String, String>,
+}
+
+#[derive(Clone)]
+pub struct Collection {
+    namespace: Arc<Namespace>,
+    connection: Arc<Mutex<Option<MultiplexedConnection>>>,
+}
+
+/// Everything needed to open a connection to a namespace
+struct Namespace {
+    client: redis::Client,
+    name: String,
+    password: Option<String>,
+    secret: Option<String>,
+}
+
+/// State of a running scan over the keys of a namespace
+struct Scan {
+    conn: MultiplexedConnection,
+    command: &'static str,
+    cursor: Option<Vec<u8>>,
+    buffer: VecDeque<Record>,
+    // set once the scan reached the end, or failed
+    done: bool,
+}
+
+impl Zdb {
+    pub(super) fn new(
+        client: redis::Client,
+        password: Option<String>,
+        secrets: HashMap<String, String>,
+    ) -> Zdb {
+        Zdb {
+            client,
+            password,
+            secrets,
+        }
+    }
+
+    /// Get a reference to a `Collection`. No connection is opened until the collection is used.
+    pub fn collection(&self, name: &str) -> Collection {
+        Collection {
+            namespace: Arc::new(Namespace {
+                client: self.client.clone(),
+                name: name.into(),
+                password: self.password.clone(),
+                secret: self.secrets.get(name).cloned(),
+            }),
+            connection: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Collection {
+    /// Get the connection to the namespace, the connection is opened if there is none (yet).
+    async fn connection(&self) -> Result<MultiplexedConnection, StorageError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(ref conn) = *connection {
+            return Ok(conn.clone());
+        }
+
+        let conn = self.namespace.connect().await?;
+        *connection = Some(conn.clone());
+        Ok(conn)
+    }
+
+    /// Check the result of a request. If the connection failed, it is dropped, so the next
+    /// request opens a new one.
+    async fn check<T>(&self, result: RedisResult<T>) -> Result<T, StorageError> {
+        if let Err(ref err) = result {
+            if err.is_io_error() {
+                debug!("dropping connection to namespace '{}'", self.namespace.name);
+                *self.connection.lock().await = None;
+            }
+        }
+
+        Ok(result?)
+    }
+
+    /// Stream the keys of the namespace, with the given scan command (SCAN or RSCAN), starting
+    /// after the key in the cursor if any.
+    async fn scan(
+        &self,
+        command: &'static str,
+        cursor: Option<Vec<u8>>,
+    ) -> Result<Keys, StorageError> {
+        let scan = Scan {
+            conn: self.connection().await?,
+            command: command,
+            cursor: cursor,
+            buffer: VecDeque::new(),
+            done: false,
+        };
+
+        Ok(Box::pin(futures::stream::unfold(scan, |mut scan| {
+            async move {
+                loop {
+                    if let Some(record) = scan.buffer.pop_front() {
+                        return Some((Ok(record), scan));
+                    }
+
+                    if scan.done {
+                        return None;
+                    }
+
+                    let mut cmd = redis::cmd(scan.command);
+                    if let Some(cursor) = scan.cursor.take() {
+                        cmd.arg(cursor);
+                    }
+
+                    let reply: RedisResult<(Vec<u8>, Vec<ScanEntry>)> =
+                        cmd.query_async(&mut scan.conn).await;
+                    let (cursor, entries) = match reply {
+                        Ok(reply) => reply,
+                        Err(err) => {
+                            scan.done = true;
+                            if is_end_of_scan(&err) {
+                                return None;
+                            }
+                            return Some((Err(err.into()), scan));
+                        }
+                    };
+
+                    // entries are single element vectors of tuples, not regular tuples
+                    scan.done = entries.is_empty();
+                    scan.cursor = Some(cursor);
+                    scan.buffer.extend(entries.into_iter().flatten().map(
+                        |(key, size, timestamp)| Record {
+                            key: read_le_key(&key),
+                            size: Some(size),
+                            timestamp: Some(timestamp),
+                        },
+                    ));
+                }
+            }
+        })))
+    }
+}
+
+impl Namespace {
+    /// Open a new connection, and select the namespace. The namespace is created if it does
+    /// not exist yet.
+    async fn connect(&self) -> RedisResult<MultiplexedConnection> {
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        if let Some(ref password) = self.password {
+            redis::cmd("AUTH")
+                .arg(password)
+                .query_async::<_, ()>(&mut conn)
+                .await?;
+        }
+
+        let namespaces: Vec<String> = redis::cmd("NSLIST").query_async(&mut conn).await?;
+        if !namespaces.contains(&self.name) {
+            redis::cmd("NSNEW")
+                .arg(&self.name)
+                .query_async::<_, ()>(&mut conn)
+                .await?;
+            if let Some(ref secret) = self.secret {
+                // a private namespace can't be read without the password either
+                redis::cmd("NSSET")
+                    .arg(&self.name)
+                    .arg("password")
+                    .arg(secret)
+                    .query_async::<_, ()>(&mut conn)
+                    .await?;
+                redis::cmd("NSSET")
+                    .arg(&self.name)
+                    .arg("public")
+                    .arg(0)
+                    .query_async::<_, ()>(&mut conn)
+                    .await?;
+            }
+        }
+
+        let mut select = redis::cmd("SELECT");
+        select.arg(&self.name);
+        if let Some(ref secret) = self.secret {
+            select.arg(secret);
+        }
+        select.query_async::<_, ()>(&mut conn).await?;
+
+        Ok(conn)
+    }
+}
+
+#[async_trait]
+impl AsyncStorage for Collection {
+    async fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, StorageError> {
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(if let Some(key) = key {
+            Vec::from(&zdb_key(key)?.to_le_bytes()[..])
+        } else {
+            Vec::new()
+        })
+        .arg(data);
+
+        let mut conn = self.connection().await?;
+        let raw_key: Vec<u8> = self.check(cmd.query_async(&mut conn).await).await?;
+
+        match key {
+            Some(key) => Ok(key),
+            None if raw_key.len() == std::mem::size_of::<ZdbKey>() => Ok(read_le_key(&raw_key)),
+            None => Err(StorageError::Protocol("invalid key in SET reply".into())),
+        }
+    }
+
+    async fn get(&self, key: Key) -> Result<Option<Vec<u8>>, StorageError> {
+        let key = match ZdbKey::try_from(key) {
+            Ok(key) => key,
+            // such a key can't exist in zdb
+            Err(_) => return Ok(None),
+        };
+
+        let mut cmd = redis::cmd("GET");
+        cmd.arg(&key.to_le_bytes());
+
+        let mut conn = self.connection().await?;
+        self.check(cmd.query_async(&mut conn).await).await
+    }
+
+    async fn delete(&self, key: Key) -> Result<(), StorageError> {
+        let mut cmd = redis::cmd("DEL");
+        cmd.arg(&zdb_key(key)?.to_le_bytes()[..]);
+
+        let mut conn = self.connection().await?;
+        self.check(cmd.query_async::<_, ()>(&mut conn).await).await
+    }
+
+    async fn keys(&self) -> Result<Keys, StorageError> {
+        self.scan("SCAN", None).await
+    }
+
+    async fn rev(&self) -> Result<Keys, StorageError> {
+        self.scan("RSCAN", None).await
+    }
+
+    async fn keys_from(&self, key: Key) -> Result<Keys, StorageError> {
+        self.scan("SCAN", Some(scan_cursor(key)?)).await
+    }
+
+    async fn rev_from(&self, key: Key) -> Result<Keys, StorageError> {
+        self.scan("RSCAN", Some(scan_cursor(key)?)).await
+    }
+
+    async fn set_many(&self, records: &[(Option<Key>, &[u8])]) -> Result<Vec<Key>, StorageError> {
+        if records.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for (key, data) in records {
+            pipe.cmd("SET")
+                .arg(if let Some(key) = key {
+                    Vec::from(&zdb_key(*key)?.to_le_bytes()[..])
+                } else {
+                    Vec::new()
+                })
+                .arg(*data);
+        }
+
+        let mut conn = self.connection().await?;
+        let raw_keys: Vec<Vec<u8>> = self.check(pipe.query_async(&mut conn).await).await?;
+
+        records
+            .iter()
+            .zip(raw_keys)
+            .map(|((key, _), raw_key)| match key {
+                Some(key) => Ok(*key),
+                None if raw_key.len() == std::mem::size_of::<ZdbKey>() => Ok(read_le_key(&raw_key)),
+                None => Err(StorageError::Protocol("invalid key in SET reply".into())),
+            })
+            .collect()
+    }
+
+    async fn delete_many(&self, keys: &[Key]) -> Result<(), StorageError> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.cmd("DEL")
+                .arg(&zdb_key(*key)?.to_le_bytes()[..])
+                .ignore();
+        }
+
+        let mut conn = self.connection().await?;
+        self.check(pipe.query_async::<_, ()>(&mut conn).await).await
+    }
+
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        let mut pipe = redis::pipe();
+        let mut requested = Vec::with_capacity(keys.len());
+        for (idx, key) in keys.iter().enumerate() {
+            // keys which don't fit can't exist in zdb, so they are not requested
+            if let Ok(key) = ZdbKey::try_from(*key) {
+                pipe.cmd("GET").arg(&key.to_le_bytes());
+                requested.push(idx);
+            }
+        }
+
+        let mut results = vec![None; keys.len()];
+        if requested.is_empty() {
+            return Ok(results);
+        }
+
+        let mut conn = self.connection().await?;
+        let values: Vec<Option<Vec<u8>>> = self.check(pipe.query_async(&mut conn).await).await?;
+        for (idx, value) in requested.into_iter().zip(values) {
+            results[idx] = value;
+        }
+
+        Ok(results)
+    }
+
+    async fn size(&self, key: Key) -> Result<Option<u64>, StorageError> {
+        let key = match ZdbKey::try_from(key) {
+            Ok(key) => key,
+            // such a key can't exist in zdb
+            Err(_) => return Ok(None),
+        };
+
+        // LENGTH looks the size up in the index, without reading the data
+        let mut cmd = redis::cmd("LENGTH");
+        cmd.arg(&key.to_le_bytes());
+
+        let mut conn = self.connection().await?;
+        self.check(cmd.query_async(&mut conn).await).await
+    }
+}
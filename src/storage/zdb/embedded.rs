@@ -1,154 +1,561 @@
+//! This module provides a 0-db which runs inside the bcdb process, by linking against the vendored
+//! libzdb sources. This removes the need to run a separate 0-db process next to bcdb.
+//!
+//! libzdb keeps all of its state in globals, and is not thread safe. As such only a single `Zdb`
+//! can be open at any time in a process, and all calls into the library are serialized.
 mod bindings;
 
 use bindings::*;
 
+use crate::storage::compaction::Namespaced;
+use crate::storage::{Error as StorageError, Key, KeyIter, Record, Storage};
+
+use std::convert::{TryFrom, TryInto};
 use std::ffi::{c_void, CStr, CString};
 use std::io;
 use std::mem;
 use std::os::raw::c_char;
+use std::path::Path;
 use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Set when a `Zdb` is open, since libzdb only supports a single instance per process.
+static OPENED: AtomicBool = AtomicBool::new(false);
 
 /// A wrapper around libzdb, `Zdb` exposes a safe interface to the database
-#[derive(Debug)]
+#[derive(Clone)]
 pub struct Zdb {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// A single namespace in the embedded 0-db. The namespace is created on first use.
+#[derive(Clone)]
+pub struct Collection {
+    inner: Arc<Mutex<Inner>>,
+    namespace: Option<CString>,
+}
+
+struct Inner {
     settings: *mut zdb_settings_t,
-    namespace: *mut namespace_t, // TODO: create separate namespace structs
+    // libzdb only keeps a pointer to the paths, so they need to live as long as the settings
+    #[allow(dead_code)]
+    datapath: CString,
+    #[allow(dead_code)]
+    indexpath: CString,
+}
+
+// The raw pointers in `Inner` point into the libzdb globals. They are only ever dereferenced while
+// holding the lock on the `Inner`, so it is safe to send it to another thread.
+unsafe impl Send for Inner {}
+
+enum Direction {
+    Forward,
+    Backward,
 }
 
+/// An iterator over all keys in a collection.
+pub struct CollectionKeys {
+    collection: Collection,
+    /// the next key to check. Going backward, this is one past the key to check.
+    cursor: Key,
+    direction: Direction,
+    /// set once reading a key failed
+    failed: bool,
+}
+
+/// Owned reply from the libzdb api, the reply is freed when this is dropped.
+struct Reply(*mut zdb_api_t);
+
+/// libzdb in sequential mode uses 4 byte keys, storage keys are converted to and from this.
+type ZdbKey = u32;
+
 impl Zdb {
-    /// Create a new zdb with default settings.
-    /// This will later be refactored to make settings a separate struct
-    pub fn new() -> Zdb {
-        let mut settings;
-        let namespace;
-        // this is safe as it takes no parameters, and always returns a valid structure
-        unsafe {
-            settings = zdb_initialize();
-            (*settings).datapath = "./tmp/zdb/data".as_ptr() as *mut c_char;
-            (*settings).indexpath = "./tmp/zdb/index".as_ptr() as *mut c_char;
-            // TODO: set sequential mode
-            settings = zdb_open(settings);
-            namespace = namespace_get_default();
+    /// Open the embedded 0-db, storing the data and index files in the given directory. The
+    /// directory is created if it does not exist yet. The database runs in sequential mode.
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Zdb, StorageError> {
+        if OPENED.swap(true, Ordering::SeqCst) {
+            return Err(StorageError::IO(Some(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "embedded zdb is already open",
+            ))));
+        }
+
+        let root = root.as_ref();
+        let datapath = match path_to_cstring(&root.join("data")) {
+            Ok(path) => path,
+            Err(err) => {
+                OPENED.store(false, Ordering::SeqCst);
+                return Err(err);
+            }
+        };
+        let indexpath = match path_to_cstring(&root.join("index")) {
+            Ok(path) => path,
+            Err(err) => {
+                OPENED.store(false, Ordering::SeqCst);
+                return Err(err);
+            }
+        };
+
+        // this is safe as the paths outlive the settings (they are stored next to them), and
+        // we made sure above that no other instance is open.
+        let settings = unsafe {
+            let settings = zdb_initialize();
+            (*settings).datapath = datapath.as_ptr() as *mut c_char;
+            (*settings).indexpath = indexpath.as_ptr() as *mut c_char;
+            (*settings).mode = index_mode_t_ZDB_MODE_SEQUENTIAL as i32;
+            zdb_open(settings)
+        };
+
+        if settings.is_null() {
+            OPENED.store(false, Ordering::SeqCst);
+            return Err(StorageError::IO(Some(io::Error::new(
+                io::ErrorKind::Other,
+                "failed to open embedded zdb",
+            ))));
         }
 
-        Zdb {
-            settings,
-            namespace,
+        Ok(Zdb {
+            inner: Arc::new(Mutex::new(Inner {
+                settings,
+                datapath,
+                indexpath,
+            })),
+        })
+    }
+
+    /// Get a reference to a `Collection`.
+    pub fn collection(&self, name: &str) -> Collection {
+        Collection {
+            inner: self.inner.clone(),
+            // a name with a nul byte can't be a valid namespace either way, this will be
+            // reported once the collection is used.
+            namespace: Some(CString::new(name).unwrap_or_default()),
         }
     }
-}
 
-impl super::Storage for Zdb {
-    fn set(&self, data: &[u8]) -> io::Result<super::Key> {
-        let reply;
-        unsafe {
-            reply = zdb_api_set(
-                self.namespace,
-                0 as *mut c_void, // TODO: can this be a null pointer, or should it be??
-                0,
-                data.as_ptr() as *mut c_void, // the data slice must outlive this pointer.
-                data.len() as u64,
-            );
+    fn default_namespace(&self) -> Collection {
+        Collection {
+            inner: self.inner.clone(),
+            namespace: None,
         }
-        let key_bytes: [u8; 4];
-        let key: super::Key;
-        // TODO: check for errors
+    }
+}
+
+impl Storage for Zdb {
+    fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, StorageError> {
+        self.default_namespace().set(key, data)
+    }
+
+    fn get(&self, key: Key) -> Result<Option<Vec<u8>>, StorageError> {
+        self.default_namespace().get(key)
+    }
+
+    fn delete(&self, key: Key) -> Result<(), StorageError> {
+        self.default_namespace().delete(key)
+    }
+
+    fn keys(&self) -> Result<KeyIter, StorageError> {
+        self.default_namespace().keys()
+    }
+
+    fn rev(&self) -> Result<KeyIter, StorageError> {
+        self.default_namespace().rev()
+    }
+
+    fn keys_from(&self, key: Key) -> Result<KeyIter, StorageError> {
+        self.default_namespace().keys_from(key)
+    }
+
+    fn rev_from(&self, key: Key) -> Result<KeyIter, StorageError> {
+        self.default_namespace().rev_from(key)
+    }
+}
+
+impl Namespaced for Zdb {
+    type Namespace = Collection;
+
+    fn namespaces(&self) -> Result<Vec<String>, StorageError> {
+        let _guard = self.inner.lock().map_err(|_| StorageError::Other)?;
+        let mut names = Vec::new();
+        // the namespaces are only walked while holding the lock
         unsafe {
-            match (*reply).status {
-                zdb_api_type_t::ZDB_API_BUFFER => {
-                    let entry = (*reply).payload as *mut zdb_api_buffer_t;
-                    let key_size = (*entry).size;
-                    // TODO: double check key size 32 vs 64
-                    assert!((*entry).size == mem::size_of::<[u8; 4]>() as u64);
-                    // we need to make sure not to take ownership of this data
-                    // TODO: quadruple check this cast
-                    // TODO: do we need to convert endiannes??
-                    // // TODO: can we use ptr::copy?
-                    // let mut data = Vec::with_capacity(4);
-                    // data.sen_len(4)
-                    // ptr::copy_nonoverlapping((*entry).payload, data.as_mut_ptr(), 4);
-                    // key = u32::from_le_bytes(data);
-                    key = ptr::read((*entry).payload as *const u32);
+            let mut ns = namespace_iter();
+            while !ns.is_null() {
+                let name = CStr::from_ptr((*ns).name).to_string_lossy().into_owned();
+                if name != "default" {
+                    names.push(name);
                 }
-                _ => unimplemented!(),
+
+                ns = namespace_iter_next(ns);
             }
         }
-        // make sure we clean up
-        unsafe {
-            zdb_api_reply_free(reply);
-        }
 
-        Ok(key)
+        Ok(names)
     }
 
-    fn get(&self, key: super::Key) -> io::Result<Option<Vec<u8>>> {
-        unimplemented!();
+    fn namespace(&self, name: &str) -> Collection {
+        self.collection(name)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), StorageError> {
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            // such a namespace can't exist
+            Err(_) => return Ok(()),
+        };
+
+        let _guard = self.inner.lock().map_err(|_| StorageError::Other)?;
+        // the namespace is removed while holding the lock, and not used after this
+        unsafe {
+            let ns = namespace_get(name.as_ptr() as *mut c_char);
+            if !ns.is_null() && namespace_delete(ns) != 0 {
+                return Err(StorageError::Protocol(format!(
+                    "failed to remove namespace '{}'",
+                    name.to_string_lossy()
+                )));
+            }
+        }
+
+        Ok(())
     }
 }
 
-impl Drop for Zdb {
+impl Drop for Inner {
     fn drop(&mut self) {
         // this is safe because settings is a field which is not exported, and we initialize
         // it when we create the struct.
         unsafe {
             zdb_close(self.settings);
         }
+        OPENED.store(false, Ordering::SeqCst);
     }
 }
 
-// TODO: verify this
-// impl !std::marker::Sync for Zdb {}
-// impl !std::marker::Send for Zdb {}
+impl Collection {
+    fn lock(&self) -> Result<MutexGuard<Inner>, StorageError> {
+        self.inner.lock().map_err(|_| StorageError::Other)
+    }
 
-fn do_something() {
-    unsafe {
-        let settings = zdb_initialize();
+    /// Get the libzdb namespace for this collection, creating it if needed. This must only be
+    /// called while holding the lock.
+    unsafe fn namespace(&self) -> Result<*mut namespace_t, StorageError> {
+        let name = match self.namespace {
+            None => return Ok(namespace_get_default()),
+            Some(ref name) => name,
+        };
 
-        let mut id = String::from("testing\0");
-        zdb_id_set(id.as_mut_ptr() as *mut c_char);
+        let ns = namespace_get(name.as_ptr() as *mut c_char);
+        if !ns.is_null() {
+            return Ok(ns);
+        }
 
-        println!("[+] ZDB instance ID: {}", zdb_instanceid_get());
+        if namespace_valid_name(name.as_ptr() as *mut c_char) == 0
+            || namespace_create(name.as_ptr() as *mut c_char) == 0
+        {
+            return Err(StorageError::Protocol(format!(
+                "failed to create namespace '{}'",
+                name.to_string_lossy()
+            )));
+        }
 
-        (*settings).datapath = CString::new("/tmp/zdb-example/data").unwrap().into_raw();
-        (*settings).indexpath = CString::new("/tmp/zdb-example/index").unwrap().into_raw();
+        let ns = namespace_get(name.as_ptr() as *mut c_char);
+        if ns.is_null() {
+            return Err(StorageError::Protocol(format!(
+                "namespace '{}' not found after creation",
+                name.to_string_lossy()
+            )));
+        }
 
-        zdb_open(settings);
+        Ok(ns)
+    }
 
-        let ns = namespace_get_default();
-        // write stoof
-        // let key = CString::new("testkey").unwrap().into_raw();
-        let key = "testkey\0".as_ptr();
-        //let data = CString::new("Hello from bcdb").unwrap().into_raw();
-        let data = "Hello from bcdb\0".as_ptr();
-        let reply = zdb_api_set(ns, key as *mut c_void, 7, data as *mut c_void, 20);
-        dump_type((*reply).status);
-        zdb_api_reply_free(reply);
+    /// Get the key which will be assigned to the next inserted object. Since the database
+    /// runs in sequential mode, all keys are lower than this.
+    fn next_key(&self) -> Result<Key, StorageError> {
+        let _guard = self.lock()?;
+        unsafe {
+            let ns = self.namespace()?;
+            Ok(zdb_index_next_id((*ns).index) as Key)
+        }
+    }
 
-        // ensure key exists
-        println!("Check keys existence");
-        let reply = zdb_api_exists(ns, key as *mut c_void, 7);
-        dump_type((*reply).status);
-        zdb_api_reply_free(reply);
+    /// Get the index record for a key, if the key exists and is not deleted.
+    fn record(&self, key: Key) -> Result<Option<Record>, StorageError> {
+        let _guard = self.lock()?;
+        unsafe {
+            let ns = self.namespace()?;
+            if key >= zdb_index_next_id((*ns).index) {
+                return Ok(None);
+            }
 
-        // now read stoof
-        let reply = zdb_api_get(ns, key as *mut c_void, 7);
-        dump_type((*reply).status);
+            let mut raw_key = (key as ZdbKey).to_le_bytes();
+            let entry = index_get(
+                (*ns).index,
+                raw_key.as_mut_ptr() as *mut c_void,
+                raw_key.len() as u8,
+            );
+            if entry.is_null() {
+                return Ok(None);
+            }
 
-        if (*reply).status == zdb_api_type_t::ZDB_API_ENTRY {
-            let entry = (*reply).payload as *mut zdb_api_entry_t;
-            println!("entry: key {:?}", (*entry).key.payload);
-            println!("entry: enry {:?}", (*entry).payload.payload);
-            let actual_value = CStr::from_ptr((*entry).payload.payload as *mut c_char);
-            println!("entry: entry: {}", actual_value.to_str().unwrap());
+            Ok(Some(Record {
+                key,
+                timestamp: Some((*entry).timestamp),
+                size: Some((*entry).length),
+            }))
+        }
+    }
+}
+
+impl Storage for Collection {
+    fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, StorageError> {
+        let _guard = self.lock()?;
+        let mut raw_key = match key {
+            Some(key) => Some(zdb_key(key)?.to_le_bytes()),
+            None => None,
+        };
+        let (key_ptr, key_size) = match raw_key {
+            Some(ref mut k) => (k.as_mut_ptr() as *mut c_void, k.len()),
+            None => (ptr::null_mut(), 0),
+        };
+
+        let reply = unsafe {
+            let ns = self.namespace()?;
+            Reply(zdb_api_set(
+                ns,
+                key_ptr,
+                key_size as size_t,
+                data.as_ptr() as *mut c_void, // libzdb only reads from the payload
+                data.len() as size_t,
+            ))
+        };
+
+        match reply.status() {
+            zdb_api_type_t::ZDB_API_BUFFER => {
+                let raw_key = unsafe { reply.buffer() };
+                debug_assert!(raw_key.len() == mem::size_of::<ZdbKey>());
+                Ok(read_le_key(raw_key))
+            }
+            // the data did not change, so nothing was written
+            zdb_api_type_t::ZDB_API_UP_TO_DATE if key.is_some() => Ok(key.unwrap()),
+            zdb_api_type_t::ZDB_API_INSERT_DENIED => Err(StorageError::Protocol(
+                "can't update a key which does not exist".into(),
+            )),
+            _ => Err(reply.error()),
+        }
+    }
+
+    fn get(&self, key: Key) -> Result<Option<Vec<u8>>, StorageError> {
+        let _guard = self.lock()?;
+        let reply = unsafe {
+            let ns = self.namespace()?;
+            // reading a key which was never assigned reads arbitrary index entries in
+            // sequential mode.
+            if key >= zdb_index_next_id((*ns).index) {
+                return Ok(None);
+            }
+            let mut raw_key = (key as ZdbKey).to_le_bytes();
+            Reply(zdb_api_get(
+                ns,
+                raw_key.as_mut_ptr() as *mut c_void,
+                raw_key.len() as size_t,
+            ))
+        };
+
+        match reply.status() {
+            zdb_api_type_t::ZDB_API_ENTRY => Ok(Some(unsafe { reply.entry_payload() }.to_vec())),
+            zdb_api_type_t::ZDB_API_NOT_FOUND | zdb_api_type_t::ZDB_API_DELETED => Ok(None),
+            _ => Err(reply.error()),
+        }
+    }
+
+    fn delete(&self, key: Key) -> Result<(), StorageError> {
+        let _guard = self.lock()?;
+        let reply = unsafe {
+            let ns = self.namespace()?;
+            if key >= zdb_index_next_id((*ns).index) {
+                return Ok(());
+            }
+            let mut raw_key = (key as ZdbKey).to_le_bytes();
+            Reply(zdb_api_del(
+                ns,
+                raw_key.as_mut_ptr() as *mut c_void,
+                raw_key.len() as size_t,
+            ))
+        };
+
+        match reply.status() {
+            zdb_api_type_t::ZDB_API_SUCCESS
+            | zdb_api_type_t::ZDB_API_NOT_FOUND
+            | zdb_api_type_t::ZDB_API_DELETED => Ok(()),
+            _ => Err(reply.error()),
+        }
+    }
+
+    fn keys(&self) -> Result<KeyIter, StorageError> {
+        Ok(Box::new(CollectionKeys::new(self, 0, Direction::Forward)))
+    }
+
+    fn rev(&self) -> Result<KeyIter, StorageError> {
+        Ok(Box::new(CollectionKeys::new(
+            self,
+            self.next_key()?,
+            Direction::Backward,
+        )))
+    }
+
+    fn keys_from(&self, key: Key) -> Result<KeyIter, StorageError> {
+        Ok(Box::new(CollectionKeys::new(
+            self,
+            key.saturating_add(1),
+            Direction::Forward,
+        )))
+    }
+
+    fn rev_from(&self, key: Key) -> Result<KeyIter, StorageError> {
+        // keys past the last one don't exist, so there is no need to walk over them
+        let cursor = std::cmp::min(key, self.next_key()?);
+        Ok(Box::new(CollectionKeys::new(
+            self,
+            cursor,
+            Direction::Backward,
+        )))
+    }
+
+    fn size(&self, key: Key) -> Result<Option<u64>, StorageError> {
+        // the size is recorded in the index, so the data is not read
+        Ok(self
+            .record(key)?
+            .and_then(|record| record.size)
+            .map(u64::from))
+    }
+}
+
+impl CollectionKeys {
+    fn new(collection: &Collection, cursor: Key, direction: Direction) -> CollectionKeys {
+        CollectionKeys {
+            collection: collection.clone(),
+            cursor: cursor,
+            direction: direction,
+            failed: false,
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>, StorageError> {
+        // keys are assigned sequentially, so we walk over all of them and skip the ones which
+        // have been deleted.
+        loop {
+            let key = match self.direction {
+                Direction::Forward => {
+                    if self.cursor >= self.collection.next_key()? {
+                        return Ok(None);
+                    }
+                    self.cursor += 1;
+                    self.cursor - 1
+                }
+                Direction::Backward => {
+                    if self.cursor == 0 {
+                        return Ok(None);
+                    }
+                    self.cursor -= 1;
+                    self.cursor
+                }
+            };
+
+            if let Some(record) = self.collection.record(key)? {
+                return Ok(Some(record));
+            }
+        }
+    }
+}
+
+impl Iterator for CollectionKeys {
+    type Item = Result<Record, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
         }
-        // this line here triggers a double free error, no clue why
-        zdb_api_reply_free(reply);
 
-        zdb_close(settings);
+        // the error is returned once, after which the iterator ends
+        let result = self.next_record();
+        self.failed = result.is_err();
+        result.transpose()
     }
 }
 
-unsafe fn dump_type(status: zdb_api_type_t) {
-    let msg = zdb_api_debug_type(status);
-    println!("Example: {:?}", msg);
+impl Reply {
+    fn status(&self) -> zdb_api_type_t {
+        // the reply is always a valid pointer returned by the api
+        unsafe { (*self.0).status }
+    }
+
+    /// Get a view on the payload of a `ZDB_API_BUFFER` reply.
+    unsafe fn buffer(&self) -> &[u8] {
+        let buffer = (*self.0).payload as *const zdb_api_buffer_t;
+        slice::from_raw_parts((*buffer).payload, (*buffer).size as usize)
+    }
+
+    /// Get a view on the data of a `ZDB_API_ENTRY` reply.
+    unsafe fn entry_payload(&self) -> &[u8] {
+        let entry = (*self.0).payload as *const zdb_api_entry_t;
+        slice::from_raw_parts((*entry).payload.payload, (*entry).payload.size as usize)
+    }
+
+    /// Convert an unexpected reply to a storage error.
+    fn error(&self) -> StorageError {
+        unsafe {
+            let status = (*self.0).status;
+            if status == zdb_api_type_t::ZDB_API_FAILURE && !(*self.0).payload.is_null() {
+                let msg = CStr::from_ptr((*self.0).payload as *const c_char);
+                return StorageError::Protocol(msg.to_string_lossy().into_owned());
+            }
+
+            let msg = CStr::from_ptr(zdb_api_debug_type(status));
+            StorageError::Protocol(msg.to_string_lossy().into_owned())
+        }
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        // the reply is owned by us, and is not used after this
+        unsafe {
+            zdb_api_reply_free(self.0);
+        }
+    }
+}
+
+fn path_to_cstring(path: &Path) -> Result<CString, StorageError> {
+    let path = path.to_str().ok_or_else(|| {
+        StorageError::IO(Some(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "zdb path is not valid utf-8",
+        )))
+    })?;
+
+    CString::new(path).map_err(|_| {
+        StorageError::IO(Some(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "zdb path contains a nul byte",
+        )))
+    })
+}
+
+fn read_le_key(input: &[u8]) -> Key {
+    let (int_bytes, _) = input.split_at(mem::size_of::<ZdbKey>());
+    ZdbKey::from_le_bytes(
+        int_bytes
+            .try_into()
+            .expect("could not convert bytes to key"),
+    )
+    .into()
+}
+
+/// Convert a storage key to a zdb key, failing if the key does not fit.
+fn zdb_key(key: Key) -> Result<ZdbKey, StorageError> {
+    ZdbKey::try_from(key)
+        .map_err(|_| StorageError::Protocol(format!("key '{}' is out of range for zdb", key)))
 }
@@ -1,14 +1,18 @@
 //! This crate provides a wrapper for a client to a 0-db running as a separate process on the same
 //! system. The 0-db must be running in sequential mode
 
-use crate::storage::{Error as StorageError, Key, Record, Storage};
+use super::aio;
+use crate::storage::compaction::{self, Namespaced};
+use crate::storage::{Error as StorageError, Key, KeyIter, Record, Storage};
 
 use redis::ConnectionLike;
 use redis::RedisError;
 use scheduled_thread_pool::ScheduledThreadPool;
 
-use std::convert::TryInto;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct Zdb {
@@ -16,10 +20,42 @@ pub struct Zdb {
     // Thread pool used by r2d2 to spawn collections. We share one to avoid every connection pool
     // in every namespace allocating one.
     spawn_pool: Arc<ScheduledThreadPool>,
+    // admin password, used to authenticate every connection
+    password: Option<String>,
+    // passwords of namespaces, set when the namespace is created and used to select it
+    secrets: HashMap<String, String>,
+    pool: PoolConfig,
     // upon connection we are always connected to the default namespace
     default_namespace: Collection,
 }
 
+/// Connection pool settings, every collection has its own pool.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Maximum number of connections
+    pub max_size: u32,
+    /// Minimum number of idle connections to keep open
+    pub min_idle: Option<u32>,
+    /// Connections are closed after this lifetime
+    pub max_lifetime: Option<Duration>,
+    /// Idle connections are closed after this timeout
+    pub idle_timeout: Option<Duration>,
+    /// Timeout to wait for a connection from the pool
+    pub connection_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 10,
+            min_idle: Some(1),
+            max_lifetime: None,
+            idle_timeout: None,
+            connection_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Collection {
     pool: r2d2::Pool<ZdbConnectionManager>,
@@ -41,40 +77,110 @@ pub struct CollectionKeys {
     buffer_idx: usize,
     /// direction of iteration
     direction: Direction,
+    /// set once the scan reached the end, or failed
+    done: bool,
 }
 
 // Yes, this is a vec, and yes, this only represents a single element. It is what it is.
-type ScanEntry = Vec<(Vec<u8>, u32, u32)>;
+pub(super) type ScanEntry = Vec<(Vec<u8>, u32, u32)>;
+
+/// 0-db in sequential mode uses 4 byte keys, storage keys are converted to and from this.
+pub(super) type ZdbKey = u32;
 
 /// Zdb connection manager to be used by the r2d2 crate. Because namespaces in zdb are tied to the
 /// actual connection, we need to manage connection pools on namespace lvl.
 #[derive(Debug, Clone)]
 struct ZdbConnectionManager {
     namespace: Option<String>,
+    password: Option<String>,
+    secret: Option<String>,
     client: redis::Client,
 }
 
 impl Zdb {
+    /// Connect to a 0-db listening on the given port on localhost.
     pub fn new(port: u16) -> Zdb {
-        let client = redis::Client::open(format!("redis://localhost:{}", port))
-            .expect("Could not connect to zdb");
+        Zdb::open(&port.to_string()).expect("Could not connect to zdb")
+    }
+
+    /// Connect to a 0-db on the given address. The address is either a port on localhost, a
+    /// `host:port` pair, a `redis://host:port` url, or a `unix:///path/to/socket` url.
+    pub fn open(address: &str) -> Result<Zdb, StorageError> {
+        let url = if address.parse::<u16>().is_ok() {
+            format!("redis://localhost:{}", address)
+        } else if address.contains("://") {
+            address.into()
+        } else {
+            format!("redis://{}", address)
+        };
+
+        let client = redis::Client::open(url.as_str())?;
         let spawn_pool = Arc::new(ScheduledThreadPool::new(2));
-        let default_namespace = Collection::new(client.clone(), None, spawn_pool.clone());
-        Zdb {
+        let zdb = Zdb {
+            default_namespace: Collection::new(
+                ZdbConnectionManager::new(client.clone(), None),
+                &PoolConfig::default(),
+                spawn_pool.clone(),
+            ),
             client,
             spawn_pool,
-            default_namespace,
-        }
+            password: None,
+            secrets: HashMap::new(),
+            pool: PoolConfig::default(),
+        };
+
+        Ok(zdb)
+    }
+
+    /// Authenticate all connections with the given (admin) password.
+    pub fn with_password<P: Into<String>>(mut self, password: P) -> Self {
+        self.password = Some(password.into());
+        self.with_default_namespace()
+    }
+
+    /// Protect a namespace with a password. The password is set when bcdb creates the namespace,
+    /// and is used to select it.
+    pub fn with_namespace_password<N, P>(mut self, namespace: N, password: P) -> Self
+    where
+        N: Into<String>,
+        P: Into<String>,
+    {
+        self.secrets.insert(namespace.into(), password.into());
+        self
+    }
+
+    /// Set the connection pool settings of all collections.
+    pub fn with_pool(mut self, pool: PoolConfig) -> Self {
+        self.pool = pool;
+        self.with_default_namespace()
     }
 
     /// Get a reference to a `Collection`.
     pub fn collection(&self, name: &str) -> Collection {
-        Collection::new(
+        let mut manager = ZdbConnectionManager::new(self.client.clone(), Some(name.into()));
+        manager.password = self.password.clone();
+        // the copy of a namespace which is compacted is protected like the namespace itself
+        let secret = compaction::compacted(name).unwrap_or(name);
+        manager.secret = self.secrets.get(secret).cloned();
+        Collection::new(manager, &self.pool, self.spawn_pool.clone())
+    }
+
+    /// Get an asynchronous client to the same 0-db, with the same passwords. The asynchronous
+    /// client uses a single multiplexed connection per namespace instead of a connection pool.
+    pub fn multiplexed(&self) -> aio::Zdb {
+        aio::Zdb::new(
             self.client.clone(),
-            Some(name.into()),
-            self.spawn_pool.clone(),
+            self.password.clone(),
+            self.secrets.clone(),
         )
     }
+
+    fn with_default_namespace(mut self) -> Self {
+        let mut manager = ZdbConnectionManager::new(self.client.clone(), None);
+        manager.password = self.password.clone();
+        self.default_namespace = Collection::new(manager, &self.pool, self.spawn_pool.clone());
+        self
+    }
 }
 
 impl Storage for Zdb {
@@ -90,13 +196,61 @@ impl Storage for Zdb {
         self.default_namespace.delete(key)
     }
 
-    fn keys(&self) -> Result<Box<dyn Iterator<Item = Record> + Send>, StorageError> {
+    fn keys(&self) -> Result<KeyIter, StorageError> {
         self.default_namespace.keys()
     }
 
-    fn rev(&self) -> Result<Box<dyn Iterator<Item = Record> + Send>, StorageError> {
+    fn rev(&self) -> Result<KeyIter, StorageError> {
         self.default_namespace.rev()
     }
+
+    fn keys_from(&self, key: Key) -> Result<KeyIter, StorageError> {
+        self.default_namespace.keys_from(key)
+    }
+
+    fn rev_from(&self, key: Key) -> Result<KeyIter, StorageError> {
+        self.default_namespace.rev_from(key)
+    }
+
+    fn set_many(&self, records: &[(Option<Key>, &[u8])]) -> Result<Vec<Key>, StorageError> {
+        self.default_namespace.set_many(records)
+    }
+
+    fn delete_many(&self, keys: &[Key]) -> Result<(), StorageError> {
+        self.default_namespace.delete_many(keys)
+    }
+
+    fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        self.default_namespace.get_many(keys)
+    }
+}
+
+impl Namespaced for Zdb {
+    type Namespace = Collection;
+
+    fn namespaces(&self) -> Result<Vec<String>, StorageError> {
+        let namespaces: Vec<String> =
+            redis::cmd("NSLIST").query(&mut *self.default_namespace.pool.get()?)?;
+        Ok(namespaces
+            .into_iter()
+            .filter(|namespace| namespace != "default")
+            .collect())
+    }
+
+    fn namespace(&self, name: &str) -> Collection {
+        self.collection(name)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), StorageError> {
+        if !self.namespaces()?.iter().any(|namespace| namespace == name) {
+            return Ok(());
+        }
+
+        redis::cmd("NSDEL")
+            .arg(name)
+            .query::<()>(&mut *self.default_namespace.pool.get()?)?;
+        Ok(())
+    }
 }
 
 impl Default for Zdb {
@@ -122,28 +276,39 @@ impl Zdb {
 
 impl Collection {
     fn new(
-        client: redis::Client,
-        namespace: Option<String>,
+        manager: ZdbConnectionManager,
+        config: &PoolConfig,
         spawn_pool: Arc<ScheduledThreadPool>,
     ) -> Collection {
-        let manager = ZdbConnectionManager::new(client, namespace);
-        // All these options should be verified and refined once we have some load estimate
         let pool = r2d2::Builder::new()
-            .max_size(10)
-            .min_idle(Some(1))
+            .max_size(config.max_size)
+            .min_idle(config.min_idle)
+            .max_lifetime(config.max_lifetime)
+            .idle_timeout(config.idle_timeout)
+            .connection_timeout(config.connection_timeout)
             .thread_pool(spawn_pool)
-            // TODO: set connection lifetimes
-            // TODO: other configuration
             .build_unchecked(manager);
         Collection { pool }
     }
+
+    /// Scan the keys in the given direction, starting after the key in the cursor if any.
+    fn scan(&self, cursor: Option<Vec<u8>>, direction: Direction) -> Result<KeyIter, StorageError> {
+        Ok(Box::new(CollectionKeys {
+            conn: self.pool.get()?,
+            cursor: cursor,
+            buffer: Vec::new(),
+            buffer_idx: 0,
+            direction: direction,
+            done: false,
+        }))
+    }
 }
 
 impl Storage for Collection {
     fn set(&self, key: Option<Key>, data: &[u8]) -> Result<Key, StorageError> {
         let raw_key: Vec<u8> = redis::cmd("SET")
             .arg(if let Some(key) = key {
-                Vec::from(&key.to_le_bytes()[..])
+                Vec::from(&zdb_key(key)?.to_le_bytes()[..])
             } else {
                 Vec::new()
             })
@@ -153,13 +318,19 @@ impl Storage for Collection {
         match key {
             Some(key) => Ok(key),
             None => {
-                debug_assert!(raw_key.len() == std::mem::size_of::<Key>());
+                debug_assert!(raw_key.len() == std::mem::size_of::<ZdbKey>());
                 Ok(read_le_key(&raw_key))
             }
         }
     }
 
     fn get(&self, key: Key) -> Result<Option<Vec<u8>>, StorageError> {
+        let key = match ZdbKey::try_from(key) {
+            Ok(key) => key,
+            // such a key can't exist in zdb
+            Err(_) => return Ok(None),
+        };
+
         Ok(redis::cmd("GET")
             .arg(&key.to_le_bytes())
             .query(&mut *self.pool.get()?)?)
@@ -167,35 +338,117 @@ impl Storage for Collection {
 
     fn delete(&self, key: Key) -> Result<(), StorageError> {
         redis::cmd("DEL")
-            .arg(&key.to_le_bytes()[..])
+            .arg(&zdb_key(key)?.to_le_bytes()[..])
             .query(&mut *self.pool.get()?)?;
 
         Ok(())
     }
 
-    fn keys(&self) -> Result<Box<dyn Iterator<Item = Record> + Send>, StorageError> {
-        Ok(Box::new(CollectionKeys {
-            conn: self.pool.get()?,
-            cursor: None,
-            buffer: Vec::new(),
-            buffer_idx: 0,
-            direction: Direction::Forward,
-        }))
+    fn keys(&self) -> Result<KeyIter, StorageError> {
+        self.scan(None, Direction::Forward)
     }
 
-    fn rev(&self) -> Result<Box<dyn Iterator<Item = Record> + Send>, StorageError> {
-        Ok(Box::new(CollectionKeys {
-            conn: self.pool.get()?,
-            cursor: None,
-            buffer: Vec::new(),
-            buffer_idx: 0,
-            direction: Direction::Backward,
-        }))
+    fn rev(&self) -> Result<KeyIter, StorageError> {
+        self.scan(None, Direction::Backward)
+    }
+
+    fn keys_from(&self, key: Key) -> Result<KeyIter, StorageError> {
+        // a scan continues after the key it is given
+        self.scan(Some(scan_cursor(key)?), Direction::Forward)
+    }
+
+    fn rev_from(&self, key: Key) -> Result<KeyIter, StorageError> {
+        self.scan(Some(scan_cursor(key)?), Direction::Backward)
+    }
+
+    fn set_many(&self, records: &[(Option<Key>, &[u8])]) -> Result<Vec<Key>, StorageError> {
+        if records.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // all commands are sent at once, and the replies are read afterwards, so the records
+        // are written in a single round trip
+        let mut pipe = redis::pipe();
+        for (key, data) in records {
+            pipe.cmd("SET")
+                .arg(if let Some(key) = key {
+                    Vec::from(&zdb_key(*key)?.to_le_bytes()[..])
+                } else {
+                    Vec::new()
+                })
+                .arg(*data);
+        }
+
+        let raw_keys: Vec<Vec<u8>> = pipe.query(&mut *self.pool.get()?)?;
+
+        records
+            .iter()
+            .zip(raw_keys)
+            .map(|((key, _), raw_key)| match key {
+                Some(key) => Ok(*key),
+                None if raw_key.len() == std::mem::size_of::<ZdbKey>() => Ok(read_le_key(&raw_key)),
+                None => Err(StorageError::Protocol("invalid key in SET reply".into())),
+            })
+            .collect()
+    }
+
+    fn delete_many(&self, keys: &[Key]) -> Result<(), StorageError> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.cmd("DEL")
+                .arg(&zdb_key(*key)?.to_le_bytes()[..])
+                .ignore();
+        }
+
+        pipe.query::<()>(&mut *self.pool.get()?)?;
+
+        Ok(())
+    }
+
+    fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        let mut pipe = redis::pipe();
+        let mut requested = Vec::with_capacity(keys.len());
+        for (idx, key) in keys.iter().enumerate() {
+            // keys which don't fit can't exist in zdb, so they are not requested
+            if let Ok(key) = ZdbKey::try_from(*key) {
+                pipe.cmd("GET").arg(&key.to_le_bytes());
+                requested.push(idx);
+            }
+        }
+
+        let mut results = vec![None; keys.len()];
+        if requested.is_empty() {
+            return Ok(results);
+        }
+
+        let values: Vec<Option<Vec<u8>>> = pipe.query(&mut *self.pool.get()?)?;
+        for (idx, value) in requested.into_iter().zip(values) {
+            results[idx] = value;
+        }
+
+        Ok(results)
+    }
+
+    fn size(&self, key: Key) -> Result<Option<u64>, StorageError> {
+        let key = match ZdbKey::try_from(key) {
+            Ok(key) => key,
+            // such a key can't exist in zdb
+            Err(_) => return Ok(None),
+        };
+
+        // LENGTH looks the size up in the index, without reading the data
+        Ok(redis::cmd("LENGTH")
+            .arg(&key.to_le_bytes())
+            .query(&mut *self.pool.get()?)?)
     }
 }
 
 impl Iterator for CollectionKeys {
-    type Item = Record;
+    type Item = Result<Record, StorageError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // Note that entries are actually single element vectors of tuples, not regular tuples.
@@ -203,13 +456,17 @@ impl Iterator for CollectionKeys {
         if self.buffer_idx < self.buffer.len() {
             self.buffer_idx += 1;
             let rec = &self.buffer[self.buffer_idx - 1][0];
-            let key = read_le_key(&rec.0);
-            return Some(Record {
-                key: key,
+            return Some(Ok(Record {
+                key: read_le_key(&rec.0),
                 size: Some(rec.1),
                 timestamp: Some(rec.2),
-            });
+            }));
+        }
+
+        if self.done {
+            return None;
         }
+
         // No more keys in buffer, fetch a new buffer from the remote
         let mut scan_cmd = match self.direction {
             Direction::Forward => redis::cmd("SCAN"),   //forward scan
@@ -222,39 +479,65 @@ impl Iterator for CollectionKeys {
             scan_cmd.arg(cur);
         }
         let res: (Vec<u8>, Vec<ScanEntry>) = match scan_cmd.query(&mut *self.conn) {
-            Ok(r) => Some(r),
-            Err(_) => None,
-        }?;
+            Ok(r) => r,
+            Err(err) => {
+                self.done = true;
+                if is_end_of_scan(&err) {
+                    return None;
+                }
+                return Some(Err(err.into()));
+            }
+        };
 
         // set the new cursor
         self.cursor = Some(res.0);
-        // set the buffer
+        // set the buffer, and start again at its first entry
         self.buffer = res.1;
-        // reset buffer pointer, set it to one as we will return the first element
-        self.buffer_idx = 1;
+        self.buffer_idx = 0;
+        if self.buffer.is_empty() {
+            self.done = true;
+            return None;
+        }
 
-        let rec = &self.buffer[0][0];
-        let key = read_le_key(&rec.0);
-        Some(Record {
-            key: key,
-            size: Some(rec.1),
-            timestamp: Some(rec.2),
-        })
+        self.next()
     }
 }
 
-fn read_le_key(input: &[u8]) -> Key {
-    let (int_bytes, _) = input.split_at(std::mem::size_of::<Key>());
-    Key::from_le_bytes(
+/// 0-db replies to a scan with a `No more data` error once there are no more keys, any other
+/// error is a failure of the scan.
+pub(super) fn is_end_of_scan(err: &RedisError) -> bool {
+    err.code() == Some("No") && err.detail() == Some("more data")
+}
+
+/// A scan which is given a key as cursor continues after that key.
+pub(super) fn scan_cursor(key: Key) -> Result<Vec<u8>, StorageError> {
+    Ok(Vec::from(&zdb_key(key)?.to_le_bytes()[..]))
+}
+
+pub(super) fn read_le_key(input: &[u8]) -> Key {
+    let (int_bytes, _) = input.split_at(std::mem::size_of::<ZdbKey>());
+    ZdbKey::from_le_bytes(
         int_bytes
             .try_into()
             .expect("could not convert bytes to key"),
     )
+    .into()
+}
+
+/// Convert a storage key to a zdb key, failing if the key does not fit.
+pub(super) fn zdb_key(key: Key) -> Result<ZdbKey, StorageError> {
+    ZdbKey::try_from(key)
+        .map_err(|_| StorageError::Protocol(format!("key '{}' is out of range for zdb", key)))
 }
 
 impl ZdbConnectionManager {
     fn new(client: redis::Client, namespace: Option<String>) -> ZdbConnectionManager {
-        ZdbConnectionManager { client, namespace }
+        ZdbConnectionManager {
+            client,
+            namespace,
+            password: None,
+            secret: None,
+        }
     }
 }
 
@@ -266,6 +549,10 @@ impl r2d2::ManageConnection for ZdbConnectionManager {
 
     fn connect(&self) -> Result<redis::Connection, redis::RedisError> {
         let mut conn = self.client.get_connection()?;
+        if let Some(ref password) = self.password {
+            redis::cmd("AUTH").arg(password).query(&mut conn)?;
+        }
+
         if let Some(ref ns) = self.namespace {
             let namespaces: Vec<String> = redis::cmd("NSLIST").query(&mut conn)?;
             let mut exists = false;
@@ -277,8 +564,27 @@ impl r2d2::ManageConnection for ZdbConnectionManager {
             }
             if !exists {
                 redis::cmd("NSNEW").arg(ns).query(&mut conn)?;
+                if let Some(ref secret) = self.secret {
+                    // a private namespace can't be read without the password either
+                    redis::cmd("NSSET")
+                        .arg(ns)
+                        .arg("password")
+                        .arg(secret)
+                        .query(&mut conn)?;
+                    redis::cmd("NSSET")
+                        .arg(ns)
+                        .arg("public")
+                        .arg(0)
+                        .query(&mut conn)?;
+                }
+            }
+
+            let mut select = redis::cmd("SELECT");
+            select.arg(ns);
+            if let Some(ref secret) = self.secret {
+                select.arg(secret);
             }
-            redis::cmd("SELECT").arg(ns).query(&mut conn)?;
+            select.query(&mut conn)?;
         }
         Ok(conn)
     }